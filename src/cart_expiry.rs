@@ -0,0 +1,248 @@
+use crate::{
+  handler::{
+    cart::{CartForm, CartInfoForm},
+    voucher,
+  },
+  prelude::*,
+  services::Services,
+};
+use chrono::{DateTime, Duration, Utc};
+use gzlib::proto::{
+  purchase::{CartBulkRequest, CartByIdRequest, CartRemoveUplRequest},
+  upl::CartUnlockRequest,
+};
+use serde::{Deserialize, Serialize};
+use std::{collections::HashSet, convert::TryInto, env};
+
+const TTL_ENV_KEY: &'static str = "CART_TTL_MINUTES";
+const WARNING_ENV_KEY: &'static str = "CART_EXPIRY_WARNING_MINUTES";
+// Stale carts are checked this often
+const CHECK_INTERVAL_SECS: u64 = 60;
+
+/// Last time a cart's lifetime was extended
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct CartActivity {
+  pub cart_id: String,
+  pub extended_at: DateTime<Utc>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ExpiringCartForm {
+  pub cart_id: String,
+  pub customer_name: String,
+  pub upl_count: u32,
+  pub owner: u32,
+  pub created_by: u32,
+  pub expires_at: String,
+  pub expired: bool,
+}
+
+fn env_minutes(key: &str, default: i64) -> Duration {
+  Duration::minutes(
+    env::var(key)
+      .ok()
+      .and_then(|v| v.parse::<i64>().ok())
+      .unwrap_or(default),
+  )
+}
+
+/// Cart lifetime since its creation or its last extension
+pub fn ttl() -> Duration {
+  env_minutes(TTL_ENV_KEY, 240)
+}
+
+/// Staff is warned this long before a cart expires
+pub fn warning_window() -> Duration {
+  env_minutes(WARNING_ENV_KEY, 30)
+}
+
+pub fn expires_at(services: &Services, cart_id: &str, created_at: &str) -> Option<DateTime<Utc>> {
+  let created_at = DateTime::parse_from_rfc3339(created_at)
+    .ok()?
+    .with_timezone(&Utc);
+  let base = match services.cart_activity.get(cart_id) {
+    Some(a) if a.extended_at > created_at => a.extended_at,
+    _ => created_at,
+  };
+  Some(base + ttl())
+}
+
+/// Restart cart lifetime
+pub fn extend(services: &Services, cart_id: &str) -> Result<DateTime<Utc>, ApiError> {
+  let activity = services.cart_activity.insert(
+    cart_id,
+    CartActivity {
+      cart_id: cart_id.to_string(),
+      extended_at: Utc::now(),
+    },
+  )?;
+  Ok(activity.extended_at + ttl())
+}
+
+/// Error if the cart has expired, or its lifetime is over
+/// and the expiry job has not archived it yet
+/// Lifetime of a cart never extended runs from its creation,
+/// so it is queried, which also fails for unknown carts
+pub async fn check_active(services: &Services, cart_id: &str) -> Result<(), ApiError> {
+  if services.expired_carts.contains(cart_id) {
+    return Err(ApiError::bad_request("A kosár lejárt!"));
+  }
+  let expires_at = match services.cart_activity.get(cart_id) {
+    Some(a) => a.extended_at + ttl(),
+    None => {
+      let cart = services
+        .purchase
+        .clone()
+        .cart_get_by_id(CartByIdRequest {
+          cart_id: cart_id.to_string(),
+        })
+        .await
+        .map_err(|e| ApiError::from(e))?
+        .into_inner();
+      expires_at(services, cart_id, &cart.created_at)
+        .ok_or(ApiError::internal_error("Hibás kosár létrehozási idő"))?
+    }
+  };
+  if expires_at <= Utc::now() {
+    return Err(ApiError::bad_request("A kosár lejárt!"));
+  }
+  Ok(())
+}
+
+/// Check cart and restart its lifetime
+/// Every handler changing the cart calls it
+pub async fn touch(services: &Services, cart_id: &str) -> Result<(), ApiError> {
+  check_active(services, cart_id).await?;
+  extend(services, cart_id)?;
+  Ok(())
+}
+
+// Open, not archived carts with their expiry
+async fn open_carts(services: &mut Services) -> Result<Vec<ExpiringCartForm>, ApiError> {
+  let cart_ids: Vec<String> = services
+    .purchase
+    .cart_get_all(())
+    .await
+    .map_err(|e| ApiError::from(e))?
+    .into_inner()
+    .cart_ids
+    .into_iter()
+    .filter(|id| !services.expired_carts.contains(id))
+    .collect();
+
+  let mut all = services
+    .purchase
+    .cart_get_info_bulk(CartBulkRequest { cart_ids })
+    .await
+    .map_err(|e| ApiError::from(e))?
+    .into_inner();
+
+  let now = Utc::now();
+  let mut result: Vec<ExpiringCartForm> = Vec::new();
+  while let Some(cart_info) = all.message().await.map_err(|e| ApiError::from(e))? {
+    let info: CartInfoForm = cart_info.into();
+    if let Some(expires_at) = expires_at(services, &info.id, &info.created_at) {
+      result.push(ExpiringCartForm {
+        cart_id: info.id,
+        customer_name: info.customer_name,
+        upl_count: info.upl_count,
+        owner: info.owner,
+        created_by: info.created_by,
+        expires_at: expires_at.to_rfc3339(),
+        expired: expires_at <= now,
+      });
+    }
+  }
+  Ok(result)
+}
+
+/// Carts that expire within the warning window, or already expired
+pub async fn expiring(services: &mut Services) -> Result<Vec<ExpiringCartForm>, ApiError> {
+  let limit = Utc::now() + warning_window();
+  Ok(
+    open_carts(services)
+      .await?
+      .into_iter()
+      .filter(|c| match DateTime::parse_from_rfc3339(&c.expires_at) {
+        Ok(e) => e.with_timezone(&Utc) <= limit,
+        Err(_) => false,
+      })
+      .collect(),
+  )
+}
+
+/// Release cart UPL locks, remove UPLs from cart
+/// and archive the cart
+pub async fn expire_cart(services: &mut Services, cart_id: &str) -> Result<(), ApiError> {
+  let cart: CartForm = services
+    .purchase
+    .cart_get_by_id(CartByIdRequest {
+      cart_id: cart_id.to_string(),
+    })
+    .await
+    .map_err(|e| ApiError::from(e))?
+    .into_inner()
+    .try_into()?;
+
+  // Money or loyalty points are involved, staff must close or clear it
+  if cart.payments.len() > 0 || cart.burned_points.len() > 0 {
+    return Err(ApiError::bad_request(
+      "A kosárhoz fizetés vagy pontbeváltás tartozik, nem járatható le automatikusan!",
+    ));
+  }
+
+  for upl in cart.upls_sku.iter().chain(cart.upls_unique.iter()) {
+    services
+      .upl
+      .release_lock_from_cart(CartUnlockRequest {
+        upl: upl.upl_id.clone(),
+        cart_id: cart.id.clone(),
+        created_by: cart.owner_uid,
+      })
+      .await
+      .map_err(|e| ApiError::from(e))?;
+    services
+      .purchase
+      .cart_remove_upl(CartRemoveUplRequest {
+        cart_id: cart.id.clone(),
+        upl_id: upl.upl_id.clone(),
+      })
+      .await
+      .map_err(|e| ApiError::from(e))?;
+  }
+
+  // Vouchers of the dropped cart were never paid
+  voucher::cancel_cart_vouchers(services, &cart.id)?;
+
+  // Archive cart as it was before expiry
+  services.expired_carts.insert(&cart.id, cart.clone())?;
+  let _ = services.cart_activity.remove(&cart.id);
+  services.display.remove(&cart.id);
+
+  Ok(())
+}
+
+/// Cart expiry job
+/// Runs forever, expires stale carts periodically
+pub async fn run(mut services: Services) {
+  // Carts that cannot be expired, reported once
+  let mut held: HashSet<String> = HashSet::new();
+  loop {
+    tokio::time::sleep(std::time::Duration::from_secs(CHECK_INTERVAL_SECS)).await;
+    let carts = match open_carts(&mut services).await {
+      Ok(c) => c,
+      Err(e) => {
+        eprintln!("Could not query carts for expiry: {:?}", e);
+        continue;
+      }
+    };
+    held.retain(|id| carts.iter().any(|c| &c.cart_id == id));
+    for cart in carts.into_iter().filter(|c| c.expired) {
+      if let Err(e) = expire_cart(&mut services, &cart.cart_id).await {
+        if held.insert(cart.cart_id.clone()) {
+          eprintln!("Could not expire cart {}: {}", cart.cart_id, e.message());
+        }
+      }
+    }
+  }
+}
@@ -0,0 +1,79 @@
+use crate::signature;
+use chrono::{Duration, Utc};
+use std::{
+  collections::HashMap,
+  env,
+  sync::{Arc, Mutex},
+};
+use tokio::sync::broadcast;
+
+const TOKEN_HOURS_ENV_KEY: &'static str = "DISPLAY_TOKEN_HOURS";
+// Messages kept for a slow display
+const CHANNEL_CAPACITY: usize = 16;
+
+/// Per cart channels of customer displays
+/// Every display subscribed to a cart gets its latest state
+#[derive(Debug, Clone)]
+pub struct DisplayHub {
+  channels: Arc<Mutex<HashMap<String, broadcast::Sender<String>>>>,
+}
+
+impl DisplayHub {
+  pub fn new() -> Self {
+    Self {
+      channels: Arc::new(Mutex::new(HashMap::new())),
+    }
+  }
+
+  pub fn has_subscribers(&self, cart_id: &str) -> bool {
+    match self.channels.lock().unwrap().get(cart_id) {
+      Some(tx) => tx.receiver_count() > 0,
+      None => false,
+    }
+  }
+
+  pub fn subscribe(&self, cart_id: &str) -> broadcast::Receiver<String> {
+    self
+      .channels
+      .lock()
+      .unwrap()
+      .entry(cart_id.to_string())
+      .or_insert_with(|| broadcast::channel(CHANNEL_CAPACITY).0)
+      .subscribe()
+  }
+
+  /// Send message to every display of the cart
+  pub fn publish(&self, cart_id: &str, msg: String) {
+    let mut channels = self.channels.lock().unwrap();
+    if let Some(tx) = channels.get(cart_id) {
+      // No display left, drop channel
+      if tx.send(msg).is_err() {
+        channels.remove(cart_id);
+      }
+    }
+  }
+
+  /// Drop the channel of a closed or removed cart
+  /// Its display feeds end
+  pub fn remove(&self, cart_id: &str) {
+    self.channels.lock().unwrap().remove(cart_id);
+  }
+}
+
+fn token_payload(cart_id: &str) -> String {
+  format!("display:{}", cart_id)
+}
+
+/// Read-only display token of a cart
+/// It is valid only for the given cart's display feed, for a limited time
+pub fn create_token(cart_id: &str) -> String {
+  let hours = env::var(TOKEN_HOURS_ENV_KEY)
+    .ok()
+    .and_then(|v| v.parse::<i64>().ok())
+    .unwrap_or(12);
+  signature::create_token(&token_payload(cart_id), Utc::now() + Duration::hours(hours))
+}
+
+pub fn verify_token(cart_id: &str, token: &str) -> bool {
+  signature::verify_token(&token_payload(cart_id), token)
+}
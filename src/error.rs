@@ -42,6 +42,9 @@ impl From<crate::prelude::ApiError> for warp::reject::Rejection {
         warp::http::StatusCode::UNAUTHORIZED,
         "".to_string(),
       )),
+      ApiError::Forbidden(msg) => {
+        warp::reject::custom(ApiRejection::new(warp::http::StatusCode::FORBIDDEN, msg))
+      }
     }
   }
 }
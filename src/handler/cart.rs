@@ -1,9 +1,19 @@
 use std::{
-  convert::{TryFrom, TryInto},
+  collections::HashMap,
+  convert::{Infallible, TryFrom, TryInto},
+  future::Future,
+  time::Duration,
   todo,
 };
 
 use crate::{
+  cart_expiry::{self, ExpiringCartForm},
+  display,
+  handler::{
+    promotion::{self, AppliedPromotionForm},
+    regulated, voucher,
+  },
+  outbox::{self, OutboxTask},
   prelude::*,
   services::{self, Services},
 };
@@ -17,7 +27,7 @@ use gzlib::proto::{
     InvoiceForm,
   },
   loyalty::{loyalty_client::LoyaltyClient, BurnRequest, CardRequest, ClosePurchaseRequest},
-  product::{GetProductRequest, GetSkuRequest, SkuObj},
+  product::{GetProductRequest, GetSkuBulkRequest, GetSkuRequest, SkuObj},
   purchase::{
     upl_info_object::UplKindOpenedSku, AddCommitmentRequest, BurnPointsRequest, CartInfoObject,
     CartObject, CartSetDocumentRequest, DocumentKind, LoyaltyCardAddRequest,
@@ -28,7 +38,7 @@ use gzlib::proto::{
 };
 use proto::{
   customer::GetByIdRequest,
-  pricing::PriceObject,
+  pricing::{GetPriceBulkRequest, PriceObject},
   product::ProductObj,
   purchase::{
     upl_info_object::UplKindSku, CartBulkRequest, CartByIdRequest, CartNewRequest,
@@ -36,7 +46,9 @@ use proto::{
   },
   upl::UplObj,
 };
+use futures_util::StreamExt;
 use serde::{Deserialize, Serialize};
+use tokio::sync::broadcast;
 use warp::reply;
 
 use super::purchase::PurchaseForm;
@@ -51,15 +63,28 @@ pub struct CustomerForm {
   tax_number: String,
 }
 
+impl From<CustomerForm> for super::purchase::CustomerForm {
+  fn from(f: CustomerForm) -> Self {
+    Self {
+      id: f.id,
+      name: f.name,
+      zip: f.zip,
+      location: f.location,
+      street: f.street,
+      tax_number: f.tax_number,
+    }
+  }
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct CartInfoForm {
-  id: String,
-  customer_name: String,
-  upl_count: u32,
-  item_names: Vec<String>,
-  owner: u32,
-  created_by: u32,
-  created_at: String,
+  pub id: String,
+  pub customer_name: String,
+  pub upl_count: u32,
+  pub item_names: Vec<String>,
+  pub owner: u32,
+  pub created_by: u32,
+  pub created_at: String,
 }
 
 impl From<CartInfoObject> for CartInfoForm {
@@ -84,15 +109,15 @@ pub enum UplKindForm {
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct UplInfoForm {
-  upl_id: String,
-  kind: UplKindForm,
-  name: String,
-  retail_price_net: u32,
-  vat: String,
-  retail_price_gross: u32,
-  procurement_net_price: u32,
-  best_before: String,
-  depreciated: bool,
+  pub upl_id: String,
+  pub kind: UplKindForm,
+  pub name: String,
+  pub retail_price_net: u32,
+  pub vat: String,
+  pub retail_price_gross: u32,
+  pub procurement_net_price: u32,
+  pub best_before: String,
+  pub depreciated: bool,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -102,25 +127,110 @@ pub enum ItemKindForm {
   DepreciatedProduct,
 }
 
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum DiscountTargetForm {
+  Sku { sku: u32 },
+  Upl { upl_id: String },
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
-pub struct ItemForm {
-  sku: u32,
-  name: String,
-  piece: u32,
-  retail_price_net: u32,
-  vat: String,
-  retail_price_gross: u32,
-  total_retail_price_net: u32,
-  total_retail_price_gross: u32,
+pub enum DiscountKindForm {
+  Percentage { percentage: u32 },
+  Amount { amount_gross: u32 },
+}
+
+/// Manual discount on a cart line (SKU) or on a UPL
+/// Unit price of the line is lowered in the cart,
+/// original prices are kept here
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct LineDiscountForm {
+  pub target: DiscountTargetForm,
+  pub kind: DiscountKindForm,
+  pub reason: String,
+  pub name: String,
+  pub original_price_net: u32,
+  pub original_price_gross: u32,
+  pub price_net: u32,
+  pub price_gross: u32,
+  pub approved_by: Option<u32>,
+  pub created_by: u32,
+  pub created_at: String,
+}
+
+impl LineDiscountForm {
+  pub fn unit_discount_gross(&self) -> u32 {
+    self.original_price_gross - self.price_gross
+  }
+  pub fn percentage(&self) -> u32 {
+    match self.original_price_gross {
+      0 => 0,
+      _ => (self.unit_discount_gross() as f32 * 100.0 / self.original_price_gross as f32).round()
+        as u32,
+    }
+  }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct CartDiscountsForm {
+  pub cart_id: String,
+  pub discounts: Vec<LineDiscountForm>,
+}
+
+/// Line discounts stored for a cart or purchase
+pub fn get_line_discounts(services: &Services, cart_id: &str) -> Vec<LineDiscountForm> {
+  match services.cart_discounts.get(cart_id) {
+    Some(cd) => cd.discounts,
+    None => Vec::new(),
+  }
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ItemForm {
+  pub sku: u32,
+  pub name: String,
+  pub piece: u32,
+  pub retail_price_net: u32,
+  pub vat: String,
+  pub retail_price_gross: u32,
+  pub total_retail_price_net: u32,
+  pub total_retail_price_gross: u32,
+  // Min quantity of the price tier used, if any
+  #[serde(default)]
+  pub price_tier: Option<u32>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 pub enum PaymentKindForm {
   Cash,
   Card,
   Transfer,
 }
 
+/// Hungarian cash rounding
+/// Cash totals are rounded to 0 or 5 forints, returns the rounding difference
+pub fn cash_rounding(amount: i32) -> i32 {
+  match amount.rem_euclid(5) {
+    r if r <= 2 => -r,
+    r => 5 - r,
+  }
+}
+
+/// Cash rounding of a cash paid cart or purchase
+/// Only the part left for cash is rounded, so card or voucher
+/// payments of any amount can be combined with cash
+pub fn payment_rounding(
+  kind: proto::purchase::PaymentKind,
+  payable: i32,
+  payments: &[proto::purchase::Payment],
+) -> i32 {
+  match kind {
+    proto::purchase::PaymentKind::Cash => {
+      cash_rounding(payable - payments.iter().map(|p| p.amount).sum::<i32>())
+    }
+    _ => 0,
+  }
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct PaymentForm {
   pub id: String,
@@ -143,32 +253,133 @@ pub struct LoyaltyCard {
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct CartForm {
-  ancestor: String,
-  id: String,
-  customer: Option<CustomerForm>,
-  commitment_id: String,
-  commitment_discount_percentage: u32,
-  loyalty_card: Option<LoyaltyCard>,
-  shopping_list: Vec<ItemForm>,
-  upls_sku: Vec<UplInfoForm>,
-  upls_unique: Vec<UplInfoForm>,
-  total_net: u32,
-  total_vat: u32,
-  total_gross: u32,
-  commitment_discount_amount_gross: u32,
-  burned_points: Vec<LoyaltyTransaction>,
-  need_invoice: bool,
-  payment_kind: PaymentKindForm,
-  payments: Vec<PaymentForm>,
-  payable: i32,
-  payment_balance: i32,
-  profit_net: i32,
-  owner_uid: u32,
-  store_id: u32,
-  date_completion: String,
-  payment_duedate: String,
-  created_by: u32,
-  created_at: String,
+  pub ancestor: String,
+  pub id: String,
+  pub customer: Option<CustomerForm>,
+  pub commitment_id: String,
+  pub commitment_discount_percentage: u32,
+  pub loyalty_card: Option<LoyaltyCard>,
+  pub shopping_list: Vec<ItemForm>,
+  pub upls_sku: Vec<UplInfoForm>,
+  pub upls_unique: Vec<UplInfoForm>,
+  pub total_net: u32,
+  pub total_vat: u32,
+  pub total_gross: u32,
+  pub commitment_discount_amount_gross: u32,
+  pub burned_points: Vec<LoyaltyTransaction>,
+  pub need_invoice: bool,
+  pub payment_kind: PaymentKindForm,
+  pub payments: Vec<PaymentForm>,
+  pub payable: i32,
+  pub payment_balance: i32,
+  // Cash rounding difference, only for cash paid carts
+  #[serde(default)]
+  pub cash_rounding: i32,
+  pub profit_net: i32,
+  pub owner_uid: u32,
+  pub store_id: u32,
+  pub date_completion: String,
+  pub payment_duedate: String,
+  pub created_by: u32,
+  pub created_at: String,
+  pub line_discounts: Vec<LineDiscountForm>,
+  // Applied promotions, their discount is set in the line prices
+  #[serde(default)]
+  pub promotions: Vec<AppliedPromotionForm>,
+  #[serde(default)]
+  pub promotion_discount_gross: i32,
+}
+
+impl CartForm {
+  // Attach stored line discounts
+  fn with_discounts(mut self, services: &Services) -> Self {
+    self.line_discounts = get_line_discounts(services, &self.id);
+    self
+  }
+
+  // Mark lines sold at a quantity tier price
+  fn with_price_tiers(mut self, services: &Services) -> Self {
+    for i in self.shopping_list.iter_mut() {
+      i.price_tier = match super::pricing::price_tier(services, i.sku, i.piece) {
+        Some(t) if t.price_gross_retail == i.retail_price_gross => Some(t.min_quantity),
+        _ => None,
+      };
+    }
+    self
+  }
+
+  // Attach stored promotions
+  pub fn with_promotions(mut self, services: &Services) -> Self {
+    self.promotions = promotion::get_cart_promotions(services, &self.id);
+    self.promotion_discount_gross = promotion::total_discount(&self.promotions);
+    self
+  }
+}
+
+/// Re-evaluate promotions after a cart change
+/// and set the promotion price of the cart lines
+pub async fn apply_promotions(
+  services: &mut Services,
+  cart: CartForm,
+) -> Result<CartForm, ApiError> {
+  let promoted = promotion::get_promoted_lines(services, &cart.id);
+  let (lines, promotions) = promotion::evaluate_cart(services, &cart);
+  // Lines with a manual discount keep their price
+  let discounted: Vec<u32> = get_line_discounts(services, &cart.id)
+    .into_iter()
+    .filter_map(|d| match d.target {
+      DiscountTargetForm::Sku { sku } => Some(sku),
+      DiscountTargetForm::Upl { .. } => None,
+    })
+    .collect();
+  let mut cart = cart;
+  for item in cart.shopping_list.clone() {
+    if discounted.contains(&item.sku) {
+      continue;
+    }
+    let (price_net, price_gross) = match lines.iter().find(|l| l.sku == item.sku) {
+      Some(l) => (l.price_net, l.price_gross),
+      None => promotion::base_price(&promoted, &item),
+    };
+    if item.retail_price_net != price_net || item.retail_price_gross != price_gross {
+      cart = set_line_price(
+        services,
+        &cart,
+        &DiscountTargetForm::Sku { sku: item.sku },
+        price_net,
+        price_gross,
+      )
+      .await?;
+    }
+  }
+  promotion::save_cart_promotions(services, &cart.id, lines, promotions)?;
+  Ok(cart.with_promotions(services))
+}
+
+impl From<UplInfoForm> for UplInfoObject {
+  fn from(f: UplInfoForm) -> Self {
+    Self {
+      upl_id: f.upl_id,
+      name: f.name,
+      retail_net_price: f.retail_price_net,
+      vat: f.vat,
+      retail_gross_price: f.retail_price_gross,
+      procurement_net_price: f.procurement_net_price,
+      best_before: f.best_before,
+      depreciated: f.depreciated,
+      upl_kind: Some(match f.kind {
+        UplKindForm::Sku { sku, piece } => {
+          proto::purchase::upl_info_object::UplKind::Sku(UplKindSku { sku, piece })
+        }
+        UplKindForm::OpenedSku { product_id, amount } => {
+          proto::purchase::upl_info_object::UplKind::OpenedSku(UplKindOpenedSku {
+            product_id,
+            amount,
+          })
+        }
+      }),
+    }
+  }
 }
 
 impl From<UplInfoObject> for UplInfoForm {
@@ -203,6 +414,7 @@ impl TryFrom<CartObject> for CartForm {
   fn try_from(f: CartObject) -> Result<Self, Self::Error> {
     let p: proto::purchase::PaymentKind =
       proto::purchase::PaymentKind::from_i32(f.payment_kind).unwrap(); // TODO! FIX IT
+    let rounding = payment_rounding(p, f.payable, &f.payments);
 
     let res = Self {
       ancestor: f.ancestor,
@@ -230,6 +442,7 @@ impl TryFrom<CartObject> for CartForm {
           retail_price_gross: i.retail_price_gross,
           total_retail_price_net: i.total_retail_price_net,
           total_retail_price_gross: i.total_retail_price_gross,
+          price_tier: None,
         })
         .collect(),
       upls_sku: f.upls_sku.iter().map(|u| u.clone().into()).collect(),
@@ -252,7 +465,9 @@ impl TryFrom<CartObject> for CartForm {
         })
         .collect(),
       payable: f.payable,
-      payment_balance: f.payment_balance,
+      // Rounding is not known by the purchase service
+      payment_balance: f.payment_balance - rounding,
+      cash_rounding: rounding,
       profit_net: f.profit_net,
       owner_uid: f.owner_uid,
       store_id: f.store_id,
@@ -280,6 +495,9 @@ impl TryFrom<CartObject> for CartForm {
           burned_points: tr.burned_points,
         })
         .collect::<Vec<LoyaltyTransaction>>(),
+      line_discounts: Vec::new(),
+      promotions: Vec::new(),
+      promotion_discount_gross: 0,
     };
     Ok(res)
   }
@@ -296,6 +514,9 @@ pub struct CartSetSkuPieceForm {
   cart_id: String,
   sku: u32,
   piece: u32,
+  // Set even if stock is missing, when store policy allows it
+  #[serde(default)]
+  override_stock: bool,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -342,6 +563,9 @@ pub struct CartAddSkuForm {
   cart_id: String,
   sku_id: u32,
   piece: u32,
+  // Add even if stock is missing, when store policy allows it
+  #[serde(default)]
+  override_stock: bool,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -356,6 +580,43 @@ pub struct CartAddUplForm {
   upl_id: String,
 }
 
+/// Scale reading or label scale barcode of a divisible product
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct CartAddWeighedForm {
+  cart_id: String,
+  // Scale reading: SKU and amount in product unit
+  sku: Option<u32>,
+  amount: Option<u32>,
+  // Or price/weight embedded EAN-13 of a label scale
+  ean: Option<String>,
+  // Opened UPL to divide; found by SKU and store if not given
+  upl_id: Option<String>,
+  // ID of the new derived UPL; made from the opened UPL ID if not given
+  new_upl: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct CartAddWeighedResultForm {
+  sku: u32,
+  amount: u32,
+  source_upl_id: String,
+  new_upl_id: String,
+  price_net: u32,
+  price_gross: u32,
+  cart: CartForm,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct CartExtendForm {
+  cart_id: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct CartExtendResultForm {
+  cart_id: String,
+  expires_at: String,
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct CartRemoveUplForm {
   cart_id: String,
@@ -395,6 +656,37 @@ pub struct CartAddPaymentForm {
   cart_id: String,
   kind: String,
   amount: i32,
+  // Cash handed over by the customer
+  tendered: Option<i32>,
+  // Voucher code, only for voucher payment
+  voucher_code: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct CartVoidPaymentForm {
+  cart_id: String,
+  payment_id: String,
+  reason: String,
+}
+
+/// Voided cart payment
+/// The original payment is reversed by a transaction with the opposite amount
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct PaymentVoidForm {
+  pub payment_id: String,
+  pub cart_id: String,
+  pub reversal_id: String,
+  pub amount: i32,
+  pub reason: String,
+  pub voided_by: u32,
+  pub voided_at: DateTime<Utc>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct CartAddPaymentResultForm {
+  cart: CartForm,
+  tendered: Option<i32>,
+  change: i32,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -403,6 +695,318 @@ pub struct CartBurnLoyaltyPoints {
   points_to_burn: i32,
 }
 
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub enum CartItemLineForm {
+  Sku { sku: u32, piece: u32 },
+  Upl { upl_id: String },
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct CartAddItemsForm {
+  cart_id: String,
+  items: Vec<CartItemLineForm>,
+  // Add SKU lines even if stock is missing, when store policy allows it
+  #[serde(default)]
+  override_stock: bool,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct CartItemLineResultForm {
+  line: CartItemLineForm,
+  success: bool,
+  error: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct CartAddItemsResultForm {
+  lines: Vec<CartItemLineResultForm>,
+  cart: CartForm,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct CartSetDiscountForm {
+  cart_id: String,
+  target: DiscountTargetForm,
+  kind: DiscountKindForm,
+  reason: String,
+  // Approving manager when approved by PIN
+  #[serde(default)]
+  manager_uid: Option<u32>,
+  manager_pin: Option<String>,
+  manager_token: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct CartRemoveDiscountForm {
+  cart_id: String,
+  target: DiscountTargetForm,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct CartScanForm {
+  cart_id: String,
+  code: String,
+  // Add scanned SKU even if stock is missing, when store policy allows it
+  #[serde(default)]
+  override_stock: bool,
+}
+
+/// What a scanned code was interpreted as
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub enum ScanKindForm {
+  Upl { upl_id: String },
+  Sku { sku: u32 },
+  Ean { ean: String, sku: u32 },
+  LoyaltyCard { card_id: String },
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct CartScanResultForm {
+  interpreted_as: ScanKindForm,
+  cart: CartForm,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum CartCheckSeverity {
+  // Cart cannot be closed
+  Error,
+  // Cart can be closed, but cashier should check it
+  Warning,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub enum CartCheckKind {
+  UnpaidBalance { payable: i32, paid: i32 },
+  Overpaid { payable: i32, paid: i32 },
+  UplLockLost { upl_id: String },
+  UplDepreciated { upl_id: String },
+  UplExpired { upl_id: String, best_before: String },
+  MissingCustomer,
+  MissingTaxNumber,
+  DiscontinuedSku { sku: u32 },
+  MissingPermit,
+  PermitExpired,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct CartCheckForm {
+  kind: CartCheckKind,
+  severity: CartCheckSeverity,
+  message: String,
+}
+
+/// Pre-close checklist of a cart
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct CartValidationForm {
+  cart_id: String,
+  // No blocking problem found
+  ok: bool,
+  checks: Vec<CartCheckForm>,
+}
+
+impl CartValidationForm {
+  fn add(&mut self, kind: CartCheckKind, severity: CartCheckSeverity, message: String) {
+    if severity == CartCheckSeverity::Error {
+      self.ok = false;
+    }
+    self.checks.push(CartCheckForm {
+      kind,
+      severity,
+      message,
+    });
+  }
+
+  // Blocking problems in one message
+  fn error_message(&self) -> String {
+    self
+      .checks
+      .iter()
+      .filter(|c| c.severity == CartCheckSeverity::Error)
+      .map(|c| c.message.clone())
+      .collect::<Vec<String>>()
+      .join(" ")
+  }
+}
+
+// Max attempts of a single cart close step
+const CLOSE_STEP_ATTEMPTS: u32 = 3;
+
+/// Cart close steps in execution order
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum CloseStep {
+  PurchaseClose,
+  UplCloseCart,
+  VoucherActivate,
+  RegulatedSaleRegister,
+  InvoiceCreate,
+  InvoiceSetId,
+  CommitmentAddPurchase,
+  LoyaltyClosePurchase,
+  LoyaltySetSummary,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub enum CloseStepStatus {
+  Done,
+  Skipped,
+  Failed { error: String },
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct CloseStepReport {
+  step: CloseStep,
+  status: CloseStepStatus,
+  attempts: u32,
+}
+
+/// Result of cart close
+/// Lists every step with its result, the failed ones separately,
+/// and the failed ones put into the outbox for a later retry.
+/// Saved after every step, so the progress of an interrupted
+/// close can be checked later.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct CartCloseReport {
+  purchase_id: String,
+  steps: Vec<CloseStepReport>,
+  failed_steps: Vec<CloseStep>,
+  queued_steps: Vec<CloseStep>,
+  finished: bool,
+  updated_at: DateTime<Utc>,
+}
+
+impl CartCloseReport {
+  fn new(purchase_id: String) -> Self {
+    Self {
+      purchase_id,
+      steps: Vec::new(),
+      failed_steps: Vec::new(),
+      queued_steps: Vec::new(),
+      finished: false,
+      updated_at: Utc::now(),
+    }
+  }
+
+  // Persist current progress
+  fn save(&mut self, services: &Services) {
+    self.updated_at = Utc::now();
+    if let Err(e) = services
+      .close_reports
+      .insert(&self.purchase_id, self.clone())
+    {
+      eprintln!(
+        "Could not save close report of purchase {}: {:?}",
+        self.purchase_id, e
+      );
+    }
+  }
+
+  fn finish(&mut self, services: &Services) {
+    self.finished = true;
+    self.save(services);
+  }
+
+  // Put failed step into the outbox
+  fn queue(&mut self, services: &Services, step: CloseStep, task: OutboxTask) {
+    let error = match self.steps.iter().rev().find(|s| s.step == step) {
+      Some(CloseStepReport {
+        status: CloseStepStatus::Failed { error },
+        ..
+      }) => error.clone(),
+      _ => String::new(),
+    };
+    match outbox::enqueue(services, task, &error) {
+      Ok(_) => self.queued_steps.push(step),
+      Err(e) => eprintln!(
+        "Could not queue step {:?} for purchase {}: {:?}",
+        step, self.purchase_id, e
+      ),
+    }
+    self.save(services);
+  }
+
+  fn done(&mut self, services: &Services, step: CloseStep, attempts: u32) {
+    self.steps.push(CloseStepReport {
+      step,
+      status: CloseStepStatus::Done,
+      attempts,
+    });
+    self.save(services);
+  }
+
+  fn skipped(&mut self, services: &Services, step: CloseStep) {
+    self.steps.push(CloseStepReport {
+      step,
+      status: CloseStepStatus::Skipped,
+      attempts: 0,
+    });
+    self.save(services);
+  }
+
+  fn failed(&mut self, services: &Services, step: CloseStep, error: String, attempts: u32) {
+    eprintln!(
+      "Cart close step {:?} failed for purchase {}: {}",
+      step, self.purchase_id, error
+    );
+    self.steps.push(CloseStepReport {
+      step,
+      status: CloseStepStatus::Failed { error },
+      attempts,
+    });
+    self.failed_steps.push(step);
+    self.save(services);
+  }
+
+  // Record step result, and return its value if it was successful
+  fn result<T>(
+    &mut self,
+    services: &Services,
+    step: CloseStep,
+    res: Result<T, tonic::Status>,
+    attempts: u32,
+  ) -> Option<T> {
+    match res {
+      Ok(v) => {
+        self.done(services, step, attempts);
+        Some(v)
+      }
+      Err(e) => {
+        self.failed(services, step, e.message().to_string(), attempts);
+        None
+      }
+    }
+  }
+}
+
+// Run an idempotent cart close step, and retry it with backoff if it fails.
+// Steps that would be applied twice on a repeated call (invoice create,
+// commitment and loyalty purchase) must not use this; they are called once
+// and put into the outbox, which checks them before applying again.
+// Returns the step result and the number of attempts
+async fn retry_step<T, F, Fut>(mut step: F) -> (Result<T, tonic::Status>, u32)
+where
+  F: FnMut() -> Fut,
+  Fut: Future<Output = Result<tonic::Response<T>, tonic::Status>>,
+{
+  let mut attempts = 0;
+  loop {
+    attempts += 1;
+    match step().await {
+      Ok(r) => return (Ok(r.into_inner()), attempts),
+      Err(e) => {
+        // Invalid or missing data won't be fixed by a retry
+        let permanent = match e.code() {
+          tonic::Code::InvalidArgument | tonic::Code::NotFound => true,
+          _ => false,
+        };
+        if permanent || attempts >= CLOSE_STEP_ATTEMPTS {
+          return (Err(e), attempts);
+        }
+        tokio::time::sleep(Duration::from_millis(200 * attempts as u64)).await;
+      }
+    }
+  }
+}
+
 pub async fn new_cart(uid: u32, mut services: Services, f: NewCartForm) -> ApiResult {
   let res: CartForm = services
     .purchase
@@ -419,26 +1023,247 @@ pub async fn new_cart(uid: u32, mut services: Services, f: NewCartForm) -> ApiRe
   Ok(reply::json(&res))
 }
 
-pub async fn cart_get_all(_uid: u32, mut services: Services) -> ApiResult {
-  let res: Vec<String> = services
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct DisplayLineForm {
+  name: String,
+  piece: u32,
+  unit_price_gross: u32,
+  total_price_gross: u32,
+  // Discount percentage if the line has any
+  discount_percentage: Option<u32>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct DisplayPromotionForm {
+  name: String,
+  discount_gross: u32,
+}
+
+/// Cart state shown on the customer display
+/// Only what the customer may see, no procurement prices or profit
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct CartDisplayForm {
+  cart_id: String,
+  customer_name: Option<String>,
+  lines: Vec<DisplayLineForm>,
+  promotions: Vec<DisplayPromotionForm>,
+  total_gross: u32,
+  burned_points: i32,
+  payable: i32,
+  cash_rounding: i32,
+  paid: i32,
+  loyalty_balance: Option<i32>,
+}
+
+impl CartDisplayForm {
+  fn new(cart: &CartForm, loyalty_balance: Option<i32>) -> Self {
+    let discount = |target: DiscountTargetForm| {
+      cart
+        .line_discounts
+        .iter()
+        .find(|d| d.target == target)
+        .map(|d| d.percentage())
+    };
+    let mut lines: Vec<DisplayLineForm> = cart
+      .shopping_list
+      .iter()
+      .map(|i| DisplayLineForm {
+        name: i.name.clone(),
+        piece: i.piece,
+        unit_price_gross: i.retail_price_gross,
+        total_price_gross: i.total_retail_price_gross,
+        discount_percentage: discount(DiscountTargetForm::Sku { sku: i.sku }),
+      })
+      .collect();
+    for u in cart.upls_sku.iter().chain(cart.upls_unique.iter()) {
+      lines.push(DisplayLineForm {
+        name: u.name.clone(),
+        piece: 1,
+        unit_price_gross: u.retail_price_gross,
+        total_price_gross: u.retail_price_gross,
+        discount_percentage: discount(DiscountTargetForm::Upl {
+          upl_id: u.upl_id.clone(),
+        }),
+      });
+    }
+    // Promotion lines are split by VAT, show them once
+    let mut promotions: Vec<DisplayPromotionForm> = Vec::new();
+    for p in &cart.promotions {
+      match promotions.iter_mut().find(|m| m.name == p.name) {
+        Some(m) => m.discount_gross += p.discount_gross,
+        None => promotions.push(DisplayPromotionForm {
+          name: p.name.clone(),
+          discount_gross: p.discount_gross,
+        }),
+      }
+    }
+    Self {
+      cart_id: cart.id.clone(),
+      customer_name: cart.customer.as_ref().map(|c| c.name.clone()),
+      lines,
+      promotions,
+      total_gross: cart.total_gross,
+      burned_points: cart.burned_points.iter().map(|b| b.burned_points).sum(),
+      payable: cart.payable,
+      cash_rounding: cart.cash_rounding,
+      paid: cart.payments.iter().map(|p| p.amount).sum(),
+      loyalty_balance,
+    }
+  }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct CartDisplayTokenForm {
+  cart_id: String,
+  token: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct DisplayTokenQuery {
+  token: String,
+}
+
+async fn display_form(services: &mut Services, cart: &CartForm) -> CartDisplayForm {
+  let loyalty_balance = match &cart.loyalty_card {
+    Some(lc) => services
+      .loyalty
+      .get_account_by_card_id(CardRequest {
+        card_id: lc.card_id.clone(),
+      })
+      .await
+      .ok()
+      .map(|a| a.into_inner().balance_points),
+    None => None,
+  };
+  CartDisplayForm::new(&cart.clone().with_discounts(services), loyalty_balance)
+}
+
+// Push cart state to its customer displays
+async fn notify_display(services: &mut Services, cart: &CartForm) {
+  if !services.display.has_subscribers(&cart.id) {
+    return;
+  }
+  let form = display_form(services, cart).await;
+  if let Ok(msg) = serde_json::to_string(&form) {
+    services.display.publish(&cart.id, msg);
+  }
+}
+
+pub async fn cart_display_token(cart_id: String, _uid: u32, mut services: Services) -> ApiResult {
+  // Check if cart exists
+  let cart: CartForm = services
     .purchase
-    .cart_get_all(())
+    .cart_get_by_id(CartByIdRequest { cart_id })
     .await
     .map_err(|e| ApiError::from(e))?
     .into_inner()
-    .cart_ids;
-  Ok(reply::json(&res))
+    .try_into()?;
+  Ok(reply::json(&CartDisplayTokenForm {
+    token: display::create_token(&cart.id),
+    cart_id: cart.id,
+  }))
 }
 
-pub async fn cart_get_by_id(cart_id: String, _uid: u32, mut services: Services) -> ApiResult {
-  let res: CartForm = services
+/// Server-sent events feed of a cart for the customer display
+/// First event is the current cart state, then every change is pushed
+pub async fn cart_display_feed(
+  cart_id: String,
+  q: DisplayTokenQuery,
+  mut services: Services,
+) -> Result<impl warp::Reply, warp::Rejection> {
+  if !display::verify_token(&cart_id, &q.token) {
+    return Err(ApiError::unauthorized().into());
+  }
+
+  // Subscribe first, so no change is lost while querying the cart
+  let rx = services.display.subscribe(&cart_id);
+
+  let cart: CartForm = services
     .purchase
     .cart_get_by_id(CartByIdRequest { cart_id })
     .await
     .map_err(|e| ApiError::from(e))?
     .into_inner()
     .try_into()?;
-  Ok(reply::json(&res))
+  let first = serde_json::to_string(&display_form(&mut services, &cart).await)
+    .map_err(|_| ApiError::internal_error("Kosár szerializációs hiba"))?;
+
+  let changes = futures_util::stream::unfold(rx, |mut rx| async move {
+    loop {
+      match rx.recv().await {
+        Ok(msg) => return Some((msg, rx)),
+        // Display was too slow, the latest state comes anyway
+        Err(broadcast::error::RecvError::Lagged(_)) => continue,
+        Err(broadcast::error::RecvError::Closed) => return None,
+      }
+    }
+  });
+
+  let events = futures_util::stream::once(async move { first })
+    .chain(changes)
+    .map(|msg| Ok::<_, Infallible>(warp::sse::Event::default().event("cart").data(msg)));
+
+  Ok(warp::sse::reply(warp::sse::keep_alive().stream(events)))
+}
+
+pub async fn cart_get_all(_uid: u32, mut services: Services) -> ApiResult {
+  let res: Vec<String> = services
+    .purchase
+    .cart_get_all(())
+    .await
+    .map_err(|e| ApiError::from(e))?
+    .into_inner()
+    .cart_ids
+    .into_iter()
+    // Hide expired carts
+    .filter(|id| !services.expired_carts.contains(id))
+    .collect();
+  Ok(reply::json(&res))
+}
+
+pub async fn cart_get_expiring(_uid: u32, mut services: Services) -> ApiResult {
+  let res: Vec<ExpiringCartForm> = cart_expiry::expiring(&mut services).await?;
+  Ok(reply::json(&res))
+}
+
+pub async fn cart_get_expired(_uid: u32, services: Services) -> ApiResult {
+  let res: Vec<CartForm> = services.expired_carts.get_all();
+  Ok(reply::json(&res))
+}
+
+pub async fn cart_extend(_uid: u32, mut services: Services, f: CartExtendForm) -> ApiResult {
+  cart_expiry::check_active(&services, &f.cart_id).await?;
+  // Check if cart exists
+  let cart: CartForm = services
+    .purchase
+    .cart_get_by_id(CartByIdRequest {
+      cart_id: f.cart_id.clone(),
+    })
+    .await
+    .map_err(|e| ApiError::from(e))?
+    .into_inner()
+    .try_into()?;
+  let expires_at = cart_expiry::extend(&services, &cart.id)?;
+  Ok(reply::json(&CartExtendResultForm {
+    cart_id: cart.id,
+    expires_at: expires_at.to_rfc3339(),
+  }))
+}
+
+pub async fn cart_get_by_id(cart_id: String, _uid: u32, mut services: Services) -> ApiResult {
+  let res: CartForm = services
+    .purchase
+    .cart_get_by_id(CartByIdRequest { cart_id })
+    .await
+    .map_err(|e| ApiError::from(e))?
+    .into_inner()
+    .try_into()?;
+  Ok(reply::json(
+    &res
+      .with_discounts(&services)
+      .with_price_tiers(&services)
+      .with_promotions(&services),
+  ))
 }
 
 pub async fn get_bulk(_uid: u32, mut services: Services, cart_ids: Vec<String>) -> ApiResult {
@@ -456,17 +1281,16 @@ pub async fn get_bulk(_uid: u32, mut services: Services, cart_ids: Vec<String>)
   Ok(reply::json(&result))
 }
 
-pub async fn cart_add_customer(
-  _uid: u32,
-  mut services: Services,
-  f: CartAddCustomerForm,
-) -> ApiResult {
+// Add customer to cart with its active commitment
+pub async fn add_customer(
+  services: &mut Services,
+  cart_id: String,
+  customer_id: u32,
+) -> Result<CartForm, ApiError> {
   // First query customer
   let customer: proto::customer::CustomerObj = services
     .customer
-    .get_by_id(GetByIdRequest {
-      customer_id: f.customer_id,
-    })
+    .get_by_id(GetByIdRequest { customer_id })
     .await
     .map_err(|e| ApiError::from(e))?
     .into_inner();
@@ -489,7 +1313,7 @@ pub async fn cart_add_customer(
         let _ = services
           .purchase
           .cart_commitment_add(AddCommitmentRequest {
-            cart_id: f.cart_id.clone(),
+            cart_id: cart_id.clone(),
             commitment_id: ac.commitment_id,
             discount_percentage: ac.discount_percentage,
           })
@@ -504,10 +1328,10 @@ pub async fn cart_add_customer(
   //
 
   // Add customer
-  let res: CartForm = services
+  services
     .purchase
     .cart_add_customer(proto::purchase::CartAddCustomerReuqest {
-      cart_id: f.cart_id,
+      cart_id,
       customer_id: customer.id,
       customer_name: customer.name,
       customer_zip: customer.address_zip,
@@ -518,7 +1342,19 @@ pub async fn cart_add_customer(
     .await
     .map_err(|e| ApiError::from(e))?
     .into_inner()
-    .try_into()?;
+    .try_into()
+}
+
+pub async fn cart_add_customer(
+  _uid: u32,
+  mut services: Services,
+  f: CartAddCustomerForm,
+) -> ApiResult {
+  cart_expiry::touch(&services, &f.cart_id).await?;
+  let res = add_customer(&mut services, f.cart_id, f.customer_id).await?;
+  let res = res.with_promotions(&services);
+  notify_display(&mut services, &res).await;
+
   Ok(reply::json(&res))
 }
 
@@ -527,6 +1363,7 @@ pub async fn cart_remove_customer(
   mut services: Services,
   f: CartRemoveCustomerForm,
 ) -> ApiResult {
+  cart_expiry::touch(&services, &f.cart_id).await?;
   // First query cart
   let cart: CartForm = services
     .purchase
@@ -559,14 +1396,95 @@ pub async fn cart_remove_customer(
     .map_err(|e| ApiError::from(e))?
     .into_inner()
     .try_into()?;
+  let res = res.with_promotions(&services);
+  notify_display(&mut services, &res).await;
+
   Ok(reply::json(&res))
 }
 
-pub async fn cart_add_sku(_uid: u32, mut services: Services, f: CartAddSkuForm) -> ApiResult {
+// Add already queried SKU with its price to cart
+// Line piece may reach a quantity price tier
+pub async fn add_sku_priced(
+  services: &mut Services,
+  cart_id: String,
+  sku_obj: &SkuObj,
+  sku_price: &PriceObject,
+  piece: u32,
+) -> Result<CartForm, ApiError> {
+  voucher::check_not_voucher_sku(sku_obj.sku)?;
+  regulated::require_permit(services, &cart_id, sku_obj.product_id)?;
+  let cart: CartForm = services
+    .purchase
+    .cart_add_sku(proto::purchase::CartAddSkuRequest {
+      cart_id,
+      sku_id: sku_obj.sku,
+      piece,
+      name: sku_obj.display_name.clone(),
+      vat: sku_price.vat.clone(),
+      retail_price_net: sku_price.price_net_retail,
+      retail_price_gross: sku_price.price_gross_retail,
+    })
+    .await
+    .map_err(|e| ApiError::from(e))?
+    .into_inner()
+    .try_into()?;
+  apply_price_tier(services, cart, sku_obj.sku, sku_price).await
+}
+
+/// Healthy stock of the given SKU in the given store that can be sold
+/// UPLs locked to a cart, delivery or inventory are not counted
+pub async fn sku_stock(services: &mut Services, sku: u32, store_id: u32) -> Result<u32, ApiError> {
+  let upl_ids = services
+    .upl
+    .get_by_sku_and_location(proto::upl::BySkuAndLocationRequest {
+      location: Some(proto::upl::by_sku_and_location_request::Location::Stock(
+        store_id,
+      )),
+      sku,
+    })
+    .await
+    .map_err(|e| ApiError::from(e))?
+    .into_inner()
+    .upl_ids;
+  if upl_ids.len() == 0 {
+    return Ok(0);
+  }
+
+  let mut all = services
+    .upl
+    .get_bulk(proto::upl::BulkRequest { upl_ids })
+    .await
+    .map_err(|e| ApiError::from(e))?
+    .into_inner();
+  let mut available = 0;
+  while let Some(upl) = all.message().await.map_err(|e| ApiError::from(e))? {
+    let unlocked = match upl.lock {
+      None | Some(upl_obj::Lock::None(_)) => true,
+      _ => false,
+    };
+    // Opened and derived UPLs are not sold as SKU pieces
+    let sku_unit = match upl.kind {
+      Some(upl_obj::Kind::Sku(_)) | Some(upl_obj::Kind::BulkSku(_)) => true,
+      _ => false,
+    };
+    if upl.is_healty && !upl.is_archived && unlocked && sku_unit {
+      available += upl.upl_piece;
+    }
+  }
+  Ok(available)
+}
+
+// Add SKU with its current price to cart
+async fn add_sku(
+  services: &mut Services,
+  cart_id: String,
+  sku_id: u32,
+  piece: u32,
+) -> Result<CartForm, ApiError> {
   // First query sku
   let sku_obj: SkuObj = services
     .product
-    .get_sku(proto::product::GetSkuRequest { sku_id: f.sku_id })
+    .get_sku(proto::product::GetSkuRequest { sku_id })
     .await
     .map_err(|e| ApiError::from(e))?
     .into_inner();
@@ -574,31 +1492,226 @@ pub async fn cart_add_sku(_uid: u32, mut services: Services, f: CartAddSkuForm)
   // Then query its price
   let sku_price: PriceObject = services
     .pricing
-    .get_price(proto::pricing::GetPriceRequest { sku: f.sku_id })
+    .get_price(proto::pricing::GetPriceRequest { sku: sku_id })
     .await
     .map_err(|e| ApiError::from(e))?
     .into_inner();
 
   // Then query to add SKU to cart
-  let res: CartForm = services
+  add_sku_priced(services, cart_id, &sku_obj, &sku_price, piece).await
+}
+
+// Set the quantity tier price of a SKU line by its piece
+// Lines with a manual discount keep their price
+async fn apply_price_tier(
+  services: &mut Services,
+  cart: CartForm,
+  sku: u32,
+  sku_price: &PriceObject,
+) -> Result<CartForm, ApiError> {
+  if !services.price_tiers.contains(&sku.to_string()) {
+    return Ok(cart);
+  }
+  let item = match cart.shopping_list.iter().find(|i| i.sku == sku) {
+    Some(i) => i.clone(),
+    None => return Ok(cart),
+  };
+  let target = DiscountTargetForm::Sku { sku };
+  if get_line_discounts(services, &cart.id)
+    .iter()
+    .any(|d| d.target == target)
+  {
+    return Ok(cart);
+  }
+  // Tier prices are fixed, the base price may have been lowered below them since
+  let (price_net, price_gross) = match super::pricing::price_tier(services, sku, item.piece) {
+    Some(t) if t.price_gross_retail < sku_price.price_gross_retail => {
+      (t.price_net_retail, t.price_gross_retail)
+    }
+    _ => (sku_price.price_net_retail, sku_price.price_gross_retail),
+  };
+  if item.retail_price_net == price_net && item.retail_price_gross == price_gross {
+    return Ok(cart);
+  }
+  set_line_price(services, &cart, &target, price_net, price_gross).await
+}
+
+/// Check if the cart's store has enough stock for the requested
+/// additional SKU piece, and decide by the store sale policy
+pub async fn check_sku_stock(
+  services: &mut Services,
+  cart_id: &str,
+  sku: u32,
+  piece: u32,
+  override_stock: bool,
+) -> Result<(), ApiError> {
+  let cart: CartForm = services
     .purchase
-    .cart_add_sku(proto::purchase::CartAddSkuRequest {
-      cart_id: f.cart_id,
-      sku_id: f.sku_id,
-      piece: f.piece,
-      name: sku_obj.display_name,
-      vat: sku_price.vat,
-      retail_price_net: sku_price.price_net_retail,
-      retail_price_gross: sku_price.price_gross_retail,
+    .cart_get_by_id(CartByIdRequest {
+      cart_id: cart_id.to_string(),
     })
     .await
     .map_err(|e| ApiError::from(e))?
     .into_inner()
     .try_into()?;
+  check_cart_sku_stock(services, &cart, sku, piece, override_stock).await
+}
+
+async fn check_cart_sku_stock(
+  services: &mut Services,
+  cart: &CartForm,
+  sku: u32,
+  piece: u32,
+  override_stock: bool,
+) -> Result<(), ApiError> {
+  let policy = super::stock::sale_policy(services, cart.store_id);
+  if policy == super::stock::SalePolicyForm::Allow {
+    return Ok(());
+  }
+
+  let available = sku_stock(services, sku, cart.store_id).await?;
+  let in_cart: u32 = cart
+    .shopping_list
+    .iter()
+    .filter(|i| i.sku == sku)
+    .map(|i| i.piece)
+    .sum();
+  if in_cart + piece <= available {
+    return Ok(());
+  }
+
+  let msg = format!(
+    "Nincs elég készlet! Elérhető: {} db, kosárban: {} db, kért: {} db.",
+    available, in_cart, piece
+  );
+  match policy {
+    super::stock::SalePolicyForm::Warn if override_stock => Ok(()),
+    super::stock::SalePolicyForm::Warn => Err(ApiError::bad_request(&format!(
+      "{} Felülbírálással hozzáadható.",
+      msg
+    ))),
+    _ => Err(ApiError::bad_request(&msg)),
+  }
+}
+
+pub async fn cart_add_sku(_uid: u32, mut services: Services, f: CartAddSkuForm) -> ApiResult {
+  cart_expiry::touch(&services, &f.cart_id).await?;
+  check_sku_stock(
+    &mut services,
+    &f.cart_id,
+    f.sku_id,
+    f.piece,
+    f.override_stock,
+  )
+  .await?;
+  let res = add_sku(&mut services, f.cart_id, f.sku_id, f.piece).await?;
+  let res = apply_promotions(&mut services, res)
+    .await?
+    .with_price_tiers(&services);
+  notify_display(&mut services, &res).await;
+
   Ok(reply::json(&res))
 }
 
+pub async fn cart_add_items(uid: u32, mut services: Services, f: CartAddItemsForm) -> ApiResult {
+  cart_expiry::touch(&services, &f.cart_id).await?;
+  let mut sku_ids: Vec<u32> = f
+    .items
+    .iter()
+    .filter_map(|i| match i {
+      CartItemLineForm::Sku { sku, .. } => Some(*sku),
+      CartItemLineForm::Upl { .. } => None,
+    })
+    .collect();
+  sku_ids.sort();
+  sku_ids.dedup();
+
+  // Query SKUs in bulk
+  let mut skus: HashMap<u32, SkuObj> = HashMap::new();
+  let mut all = services
+    .product
+    .get_sku_bulk(GetSkuBulkRequest {
+      sku_id: sku_ids.clone(),
+    })
+    .await
+    .map_err(|e| ApiError::from(e))?
+    .into_inner();
+  while let Some(sku) = all.message().await.map_err(|e| ApiError::from(e))? {
+    skus.insert(sku.sku, sku);
+  }
+
+  // Query prices in bulk
+  let mut prices: HashMap<u32, PriceObject> = HashMap::new();
+  let mut all = services
+    .pricing
+    .get_price_bulk(GetPriceBulkRequest { skus: sku_ids })
+    .await
+    .map_err(|e| ApiError::from(e))?
+    .into_inner();
+  while let Some(price) = all.message().await.map_err(|e| ApiError::from(e))? {
+    prices.insert(price.sku, price);
+  }
+
+  // Apply lines one by one
+  let mut lines: Vec<CartItemLineResultForm> = Vec::new();
+  for line in f.items {
+    let res = match &line {
+      CartItemLineForm::Sku { sku, piece } => match (skus.get(sku), prices.get(sku)) {
+        (Some(sku_obj), Some(sku_price)) => {
+          match check_sku_stock(&mut services, &f.cart_id, *sku, *piece, f.override_stock).await {
+            Ok(_) => add_sku_priced(&mut services, f.cart_id.clone(), sku_obj, sku_price, *piece)
+              .await
+              .map(|_| ()),
+            Err(e) => Err(e),
+          }
+        }
+        (None, _) => Err(ApiError::bad_request(&format!(
+          "A SKU nem található: {}",
+          sku
+        ))),
+        (_, None) => Err(ApiError::bad_request(&format!(
+          "A SKU-hoz nincs ár beállítva: {}",
+          sku
+        ))),
+      },
+      CartItemLineForm::Upl { upl_id } => {
+        add_upl(uid, &mut services, f.cart_id.clone(), upl_id.clone())
+          .await
+          .map(|_| ())
+      }
+    };
+    lines.push(CartItemLineResultForm {
+      line,
+      success: res.is_ok(),
+      error: res.err().map(|e| e.message()),
+    });
+  }
+
+  // Query final cart
+  let cart: CartForm = services
+    .purchase
+    .cart_get_by_id(CartByIdRequest { cart_id: f.cart_id })
+    .await
+    .map_err(|e| ApiError::from(e))?
+    .into_inner()
+    .try_into()?;
+
+  let cart = apply_promotions(&mut services, cart).await?;
+  notify_display(&mut services, &cart).await;
+
+  Ok(reply::json(&CartAddItemsResultForm { lines, cart }))
+}
+
 pub async fn cart_remove_sku(_uid: u32, mut services: Services, f: CartRemoveSkuForm) -> ApiResult {
+  cart_expiry::touch(&services, &f.cart_id).await?;
+  voucher::check_not_voucher_sku(f.sku_id)?;
+  // Line is removed, so is its discount
+  remove_line_discount(
+    &services,
+    &f.cart_id,
+    &DiscountTargetForm::Sku { sku: f.sku_id },
+  )?;
+
   // Then query to add SKU to cart
   let res: CartForm = services
     .purchase
@@ -610,6 +1723,9 @@ pub async fn cart_remove_sku(_uid: u32, mut services: Services, f: CartRemoveSku
     .map_err(|e| ApiError::from(e))?
     .into_inner()
     .try_into()?;
+  let res = apply_promotions(&mut services, res).await?;
+  notify_display(&mut services, &res).await;
+
   Ok(reply::json(&res))
 }
 
@@ -618,6 +1734,36 @@ pub async fn cart_set_sku_piece(
   mut services: Services,
   f: CartSetSkuPieceForm,
 ) -> ApiResult {
+  cart_expiry::touch(&services, &f.cart_id).await?;
+  voucher::check_not_voucher_sku(f.sku)?;
+
+  // Only the increase needs stock
+  let cart: CartForm = services
+    .purchase
+    .cart_get_by_id(CartByIdRequest {
+      cart_id: f.cart_id.clone(),
+    })
+    .await
+    .map_err(|e| ApiError::from(e))?
+    .into_inner()
+    .try_into()?;
+  let current: u32 = cart
+    .shopping_list
+    .iter()
+    .filter(|i| i.sku == f.sku)
+    .map(|i| i.piece)
+    .sum();
+  if f.piece > current {
+    check_cart_sku_stock(
+      &mut services,
+      &cart,
+      f.sku,
+      f.piece - current,
+      f.override_stock,
+    )
+    .await?;
+  }
+
   // Then query to add SKU to cart
   let res: CartForm = services
     .purchase
@@ -630,22 +1776,52 @@ pub async fn cart_set_sku_piece(
     .map_err(|e| ApiError::from(e))?
     .into_inner()
     .try_into()?;
+
+  // Piece change may move the line into another price tier
+  let res = match services.price_tiers.contains(&f.sku.to_string()) {
+    true => {
+      let sku_price: PriceObject = services
+        .pricing
+        .get_price(proto::pricing::GetPriceRequest { sku: f.sku })
+        .await
+        .map_err(|e| ApiError::from(e))?
+        .into_inner();
+      apply_price_tier(&mut services, res, f.sku, &sku_price).await?
+    }
+    false => res,
+  };
+  let res = apply_promotions(&mut services, res)
+    .await?
+    .with_price_tiers(&services);
+  notify_display(&mut services, &res).await;
+
   Ok(reply::json(&res))
 }
 
-pub async fn cart_add_upl(uid: u32, mut services: Services, f: CartAddUplForm) -> ApiResult {
+// Add UPL to cart and lock it to the cart
+pub async fn add_upl(
+  uid: u32,
+  services: &mut Services,
+  cart_id: String,
+  upl_id: String,
+) -> Result<CartForm, ApiError> {
+  // Expired carts cannot lock UPLs again
+  cart_expiry::check_active(services, &cart_id).await?;
   // First query UPL
   let upl_obj: UplObj = services
     .upl
-    .get_by_id(proto::upl::ByIdRequest { upl_id: f.upl_id })
+    .get_by_id(proto::upl::ByIdRequest { upl_id })
     .await
     .map_err(|e| ApiError::from(e))?
     .into_inner();
 
+  // Regulated products need the buyer's permit
+  regulated::require_permit(services, &cart_id, upl_obj.product_id)?;
+
   // Then query Cart
   let cart_obj: CartObject = services
     .purchase
-    .cart_get_by_id(proto::purchase::CartByIdRequest { cart_id: f.cart_id })
+    .cart_get_by_id(proto::purchase::CartByIdRequest { cart_id })
     .await
     .map_err(|e| ApiError::from(e))?
     .into_inner();
@@ -654,22 +1830,16 @@ pub async fn cart_add_upl(uid: u32, mut services: Services, f: CartAddUplForm) -
   match upl_obj.location.unwrap() {
     proto::upl::upl_obj::Location::Stock(stock_id) => {
       if stock_id != cart_obj.store_id {
-        return Err(
-          ApiError::bad_request(&format!(
-            "A kért UPL nem a kosár lokációján van, nem tehető a kosárba! UPL: {}, kosár: {}",
-            stock_id, cart_obj.store_id
-          ))
-          .into(),
-        );
+        return Err(ApiError::bad_request(&format!(
+          "A kért UPL nem a kosár lokációján van, nem tehető a kosárba! UPL: {}, kosár: {}",
+          stock_id, cart_obj.store_id
+        )));
       }
     }
     _ => {
-      return Err(
-        ApiError::bad_request(
-          "A kért UPL nem értékesíthető! Vagy selejtezett, eladott, vagy szállítás alatt van",
-        )
-        .into(),
-      )
+      return Err(ApiError::bad_request(
+        "A kért UPL nem értékesíthető! Vagy selejtezett, eladott, vagy szállítás alatt van",
+      ))
     }
   }
 
@@ -677,7 +1847,7 @@ pub async fn cart_add_upl(uid: u32, mut services: Services, f: CartAddUplForm) -
   match upl_obj.lock.unwrap() {
     proto::upl::upl_obj::Lock::CartLock(cart_id) => {
       if cart_obj.id == cart_id {
-        return Err(ApiError::bad_request("A kért UPL már a kosárban van!").into());
+        return Err(ApiError::bad_request("A kért UPL már a kosárban van!"));
       }
     }
     _ => (),
@@ -802,89 +1972,1001 @@ pub async fn cart_add_upl(uid: u32, mut services: Services, f: CartAddUplForm) -
     }
   }
 
-  Ok(reply::json(&cart))
+  Ok(cart)
 }
 
-pub async fn cart_remove_upl(uid: u32, mut services: Services, f: CartRemoveUplForm) -> ApiResult {
-  // Try to get UPL
-  let upl_obj: UplObj = services
+pub async fn cart_add_upl(uid: u32, mut services: Services, f: CartAddUplForm) -> ApiResult {
+  cart_expiry::touch(&services, &f.cart_id).await?;
+  let res = add_upl(uid, &mut services, f.cart_id, f.upl_id).await?;
+  let res = apply_promotions(&mut services, res).await?;
+  notify_display(&mut services, &res).await;
+
+  Ok(reply::json(&res))
+}
+
+// Resolve scanned code
+// Order is UPL ID, EAN/GTIN, loyalty card ID, then SKU ID
+async fn resolve_scan(services: &mut Services, code: &str) -> Result<ScanKindForm, ApiError> {
+  // Only a NotFound answer means the code is of another kind,
+  // any other error is returned
+
+  // Try as UPL
+  match services
     .upl
-    .get_by_id(proto::upl::ByIdRequest { upl_id: f.upl_id })
+    .get_by_id(proto::upl::ByIdRequest {
+      upl_id: code.to_string(),
+    })
     .await
-    .map_err(|e| ApiError::from(e))?
-    .into_inner();
+  {
+    Ok(_) => {
+      return Ok(ScanKindForm::Upl {
+        upl_id: code.to_string(),
+      })
+    }
+    Err(e) if e.code() == tonic::Code::NotFound => (),
+    Err(e) => return Err(ApiError::from(e)),
+  }
 
-  // Check if UPL is locked into this cart
-  // Return error if not
-  match upl_obj.lock.unwrap() {
-    proto::upl::upl_obj::Lock::CartLock(cart_id) => {
-      if cart_id != f.cart_id {
-        return Err(ApiError::bad_request("Az adott UPL egy másik kosárhoz van rendelve!").into());
-      }
+  // Try as EAN/GTIN
+  if super::product::is_valid_gtin(code) {
+    if let Some(barcode) = services.sku_barcodes.get(code) {
+      return Ok(ScanKindForm::Ean {
+        ean: code.to_string(),
+        sku: barcode.sku,
+      });
     }
-    _ => return Err(ApiError::bad_request("Az adott UPL nincs a kosárban!").into()),
   }
 
-  // Try to release lock
-  services
-    .upl
-    .release_lock_from_cart(proto::upl::CartUnlockRequest {
-      upl: upl_obj.id.clone(),
-      cart_id: f.cart_id.clone(),
-      created_by: uid,
+  // Try as loyalty card
+  match services
+    .loyalty
+    .get_account_by_card_id(CardRequest {
+      card_id: code.to_string(),
     })
     .await
-    .map_err(|e| ApiError::from(e))?
-    .into_inner();
+  {
+    Ok(_) => {
+      return Ok(ScanKindForm::LoyaltyCard {
+        card_id: code.to_string(),
+      })
+    }
+    Err(e) if e.code() == tonic::Code::NotFound => (),
+    Err(e) => return Err(ApiError::from(e)),
+  }
 
-  // Try to remove from cart
-  let res: CartForm = services
-    .purchase
-    .cart_remove_upl(proto::purchase::CartRemoveUplRequest {
-      cart_id: f.cart_id.clone(),
-      upl_id: upl_obj.id.clone(),
-    })
-    .await
-    .map_err(|e| ApiError::from(e))?
-    .into_inner()
-    .try_into()?;
+  // Try as SKU ID
+  if let Ok(sku_id) = code.parse::<u32>() {
+    match services.product.get_sku(GetSkuRequest { sku_id }).await {
+      Ok(_) => return Ok(ScanKindForm::Sku { sku: sku_id }),
+      Err(e) if e.code() == tonic::Code::NotFound => (),
+      Err(e) => return Err(ApiError::from(e)),
+    }
+  }
+
+  Err(ApiError::bad_request(&format!(
+    "A beolvasott kód nem azonosítható: {}",
+    code
+  )))
+}
+
+pub async fn cart_scan(uid: u32, mut services: Services, f: CartScanForm) -> ApiResult {
+  cart_expiry::touch(&services, &f.cart_id).await?;
+  let code = f.code.trim().to_string();
+  let interpreted_as = resolve_scan(&mut services, &code).await?;
+
+  let cart = match &interpreted_as {
+    ScanKindForm::Upl { upl_id } => add_upl(uid, &mut services, f.cart_id, upl_id.clone()).await?,
+    ScanKindForm::Sku { sku } | ScanKindForm::Ean { sku, .. } => {
+      check_sku_stock(&mut services, &f.cart_id, *sku, 1, f.override_stock).await?;
+      add_sku(&mut services, f.cart_id, *sku, 1).await?
+    }
+    ScanKindForm::LoyaltyCard { card_id } => {
+      add_loyalty_card(&mut services, f.cart_id, card_id.clone()).await?
+    }
+  };
+
+  let cart = apply_promotions(&mut services, cart).await?;
+  notify_display(&mut services, &cart).await;
+
+  Ok(reply::json(&CartScanResultForm {
+    interpreted_as,
+    cart,
+  }))
+}
+
+// Value embedded in label scale barcodes; price by default
+fn scale_ean_embeds_price() -> bool {
+  match std::env::var("SCALE_EAN_VALUE") {
+    Ok(v) => v != "weight",
+    Err(_) => true,
+  }
+}
+
+/// Price or weight embedded EAN-13 of label scales
+/// 2x prefix, 5 digit SKU, 5 digit value, check digit
+/// Returns SKU and embedded value
+pub fn parse_scale_ean(code: &str) -> Option<(u32, u32)> {
+  if code.len() != 13 || !code.starts_with('2') || !super::product::is_valid_gtin(code) {
+    return None;
+  }
+  let sku = code[2..7].parse::<u32>().ok()?;
+  let value = code[7..12].parse::<u32>().ok()?;
+  Some((sku, value))
+}
+
+// Check that an opened UPL of the SKU can be divided in the store
+// Returns its amount left
+fn check_opened_upl(upl: &UplObj, sku: u32, store_id: u32, amount: u32) -> Result<u32, ApiError> {
+  match &upl.location {
+    Some(upl_obj::Location::Stock(stock_id)) if *stock_id == store_id => (),
+    _ => {
+      return Err(ApiError::bad_request(
+        "A bontott UPL nem a kosár boltjában van!",
+      ))
+    }
+  }
+  match &upl.lock {
+    Some(upl_obj::Lock::None(_)) => (),
+    _ => return Err(ApiError::bad_request("A bontott UPL zárolva van!")),
+  }
+  if upl.depreciation.is_some() {
+    return Err(ApiError::bad_request("A bontott UPL selejtezett!"));
+  }
+  match &upl.kind {
+    Some(upl_obj::Kind::OpenedSku(opened)) if opened.sku == sku => match opened.amount >= amount {
+      true => Ok(opened.amount),
+      false => Err(ApiError::bad_request(&format!(
+        "A bontott UPL-ben nincs elegendő mennyiség! Maradt: {}, kért: {}",
+        opened.amount, amount
+      ))),
+    },
+    _ => Err(ApiError::bad_request(&format!(
+      "A UPL nem a SKU bontott UPL-je! SKU: {}",
+      sku
+    ))),
+  }
+}
+
+// ID for a UPL divided from the given one
+// Numbered after its successors, skipping IDs already in use
+async fn new_divided_upl_id(services: &mut Services, upl: &UplObj) -> Result<String, ApiError> {
+  let mut n = match &upl.kind {
+    Some(upl_obj::Kind::OpenedSku(opened)) => opened.successors.len() + 1,
+    _ => 1,
+  };
+  loop {
+    let upl_id = format!("{}-{}", upl.id, n);
+    if !upl_exists(services, &upl_id).await? {
+      return Ok(upl_id);
+    }
+    n += 1;
+  }
+}
+
+// Check both active and archived UPLs
+async fn upl_exists(services: &mut Services, upl_id: &str) -> Result<bool, ApiError> {
+  let active = services
+    .upl
+    .get_by_id(proto::upl::ByIdRequest {
+      upl_id: upl_id.to_string(),
+    })
+    .await;
+  let archived = services
+    .upl
+    .get_by_id_archive(proto::upl::ByIdRequest {
+      upl_id: upl_id.to_string(),
+    })
+    .await;
+  for res in vec![active, archived] {
+    match res {
+      Ok(_) => return Ok(true),
+      Err(e) if e.code() == tonic::Code::NotFound => (),
+      Err(e) => return Err(ApiError::from(e)),
+    }
+  }
+  Ok(false)
+}
+
+// Opened UPL of the SKU in the store with enough amount left
+// The one with the least amount is used up first
+async fn find_opened_upl(
+  services: &mut Services,
+  sku: u32,
+  store_id: u32,
+  amount: u32,
+) -> Result<UplObj, ApiError> {
+  let upl_ids = services
+    .upl
+    .get_by_sku_and_location(proto::upl::BySkuAndLocationRequest {
+      sku,
+      location: Some(proto::upl::by_sku_and_location_request::Location::Stock(
+        store_id,
+      )),
+    })
+    .await
+    .map_err(|e| ApiError::from(e))?
+    .into_inner()
+    .upl_ids;
+
+  let mut candidates: Vec<(UplObj, u32)> = Vec::new();
+  let mut all = services
+    .upl
+    .get_bulk(proto::upl::BulkRequest { upl_ids })
+    .await
+    .map_err(|e| ApiError::from(e))?
+    .into_inner();
+  while let Some(upl) = all.message().await.map_err(|e| ApiError::from(e))? {
+    // Only free, healthy UPLs
+    if let Ok(left) = check_opened_upl(&upl, sku, store_id, amount) {
+      candidates.push((upl, left));
+    }
+  }
+  candidates
+    .into_iter()
+    .min_by_key(|(_, left)| *left)
+    .map(|(upl, _)| upl)
+    .ok_or(ApiError::bad_request(&format!(
+      "Nincs a kért mennyiséghez elegendő bontott UPL a boltban! SKU: {}, mennyiség: {}",
+      sku, amount
+    )))
+}
+
+pub async fn cart_add_weighed(
+  uid: u32,
+  mut services: Services,
+  f: CartAddWeighedForm,
+) -> ApiResult {
+  cart_expiry::touch(&services, &f.cart_id).await?;
+  // Check if cart valid
+  let cart: CartForm = services
+    .purchase
+    .cart_get_by_id(CartByIdRequest {
+      cart_id: f.cart_id.clone(),
+    })
+    .await
+    .map_err(|e| ApiError::from(e))?
+    .into_inner()
+    .try_into()?;
+
+  // SKU and amount or label price
+  let (sku, amount, label_price) = match (&f.ean, f.sku, f.amount) {
+    (Some(ean), _, _) => {
+      let (sku, value) =
+        parse_scale_ean(ean.trim()).ok_or(ApiError::bad_request("A vonalkód nem mérlegcímke!"))?;
+      match scale_ean_embeds_price() {
+        true => (sku, None, Some(value)),
+        false => (sku, Some(value), None),
+      }
+    }
+    (None, Some(sku), Some(amount)) => (sku, Some(amount), None),
+    _ => {
+      return Err(
+        ApiError::bad_request("SKU és mennyiség, vagy mérlegcímke megadása kötelező!").into(),
+      )
+    }
+  };
+
+  let sku_obj: SkuObj = services
+    .product
+    .get_sku(GetSkuRequest { sku_id: sku })
+    .await
+    .map_err(|e| ApiError::from(e))?
+    .into_inner();
+  if !sku_obj.can_divide || sku_obj.divisible_amount == 0 {
+    return Err(ApiError::bad_request("A SKU nem osztható!").into());
+  }
+  let sku_price: PriceObject = services
+    .pricing
+    .get_price(proto::pricing::GetPriceRequest { sku })
+    .await
+    .map_err(|e| ApiError::from(e))?
+    .into_inner();
+  if sku_price.price_gross_retail == 0 {
+    return Err(ApiError::bad_request("A SKU-hoz nincs ár beállítva!").into());
+  }
+
+  // Amount and price by unit price
+  // Unit price is the SKU price divided by its divisible amount
+  let (amount, price_gross) = match (amount, label_price) {
+    (Some(amount), _) => (
+      amount,
+      (sku_price.price_gross_retail as f64 * amount as f64 / sku_obj.divisible_amount as f64)
+        .round() as u32,
+    ),
+    (None, Some(price)) => (
+      (price as f64 * sku_obj.divisible_amount as f64 / sku_price.price_gross_retail as f64).round()
+        as u32,
+      price,
+    ),
+    (None, None) => {
+      return Err(ApiError::bad_request("A mennyiség vagy az ár megadása kötelező!").into())
+    }
+  };
+  if amount == 0 {
+    return Err(ApiError::bad_request("A mennyiség nem lehet 0!").into());
+  }
+  let price_net = (sku_price.price_net_retail as f64 * price_gross as f64
+    / sku_price.price_gross_retail as f64)
+    .round() as u32;
+
+  // Divided UPL cannot be put back, so everything is checked before
+  regulated::require_permit(&services, &cart.id, sku_obj.product_id)?;
+  let source_upl = match &f.upl_id {
+    Some(upl_id) => {
+      let upl: UplObj = services
+        .upl
+        .get_by_id(proto::upl::ByIdRequest {
+          upl_id: upl_id.clone(),
+        })
+        .await
+        .map_err(|e| ApiError::from(e))?
+        .into_inner();
+      check_opened_upl(&upl, sku, cart.store_id, amount)?;
+      upl
+    }
+    None => find_opened_upl(&mut services, sku, cart.store_id, amount).await?,
+  };
+  let source_upl_id = source_upl.id.clone();
+  let new_upl = match &f.new_upl {
+    Some(n) => {
+      let n = n.trim().to_string();
+      if n.len() == 0 || upl_exists(&mut services, &n).await? {
+        return Err(ApiError::bad_request("Az új UPL azonosító már foglalt!").into());
+      }
+      n
+    }
+    None => new_divided_upl_id(&mut services, &source_upl).await?,
+  };
+
+  // Divide opened UPL
+  services
+    .upl
+    .divide(proto::upl::DivideRequest {
+      upl: source_upl_id.clone(),
+      new_upl: new_upl.clone(),
+      requested_amount: amount,
+      created_by: uid,
+    })
+    .await
+    .map_err(|e| ApiError::from(e))?;
+
+  // Add derived UPL, then set its price
+  let res = add_upl(uid, &mut services, f.cart_id, new_upl.clone()).await?;
+  let res = set_line_price(
+    &mut services,
+    &res,
+    &DiscountTargetForm::Upl {
+      upl_id: new_upl.clone(),
+    },
+    price_net,
+    price_gross,
+  )
+  .await?;
+
+  let res = apply_promotions(&mut services, res).await?;
+  notify_display(&mut services, &res).await;
+
+  Ok(reply::json(&CartAddWeighedResultForm {
+    sku,
+    amount,
+    source_upl_id,
+    new_upl_id: new_upl,
+    price_net,
+    price_gross,
+    cart: res,
+  }))
+}
+
+pub async fn cart_remove_upl(uid: u32, mut services: Services, f: CartRemoveUplForm) -> ApiResult {
+  cart_expiry::touch(&services, &f.cart_id).await?;
+  // Try to get UPL
+  let upl_obj: UplObj = services
+    .upl
+    .get_by_id(proto::upl::ByIdRequest { upl_id: f.upl_id })
+    .await
+    .map_err(|e| ApiError::from(e))?
+    .into_inner();
+
+  // Check if UPL is locked into this cart
+  // Return error if not
+  match upl_obj.lock.unwrap() {
+    proto::upl::upl_obj::Lock::CartLock(cart_id) => {
+      if cart_id != f.cart_id {
+        return Err(ApiError::bad_request("Az adott UPL egy másik kosárhoz van rendelve!").into());
+      }
+    }
+    _ => return Err(ApiError::bad_request("Az adott UPL nincs a kosárban!").into()),
+  }
+
+  // Try to release lock
+  services
+    .upl
+    .release_lock_from_cart(proto::upl::CartUnlockRequest {
+      upl: upl_obj.id.clone(),
+      cart_id: f.cart_id.clone(),
+      created_by: uid,
+    })
+    .await
+    .map_err(|e| ApiError::from(e))?
+    .into_inner();
+
+  // UPL is removed, so is its discount
+  remove_line_discount(
+    &services,
+    &f.cart_id,
+    &DiscountTargetForm::Upl {
+      upl_id: upl_obj.id.clone(),
+    },
+  )?;
+
+  // Try to remove from cart
+  let res: CartForm = services
+    .purchase
+    .cart_remove_upl(proto::purchase::CartRemoveUplRequest {
+      cart_id: f.cart_id.clone(),
+      upl_id: upl_obj.id.clone(),
+    })
+    .await
+    .map_err(|e| ApiError::from(e))?
+    .into_inner()
+    .try_into()?;
+
+  let res = apply_promotions(&mut services, res).await?;
+  notify_display(&mut services, &res).await;
 
   Ok(reply::json(&res))
 }
 
-pub async fn cart_set_payment(
-  _uid: u32,
-  mut services: Services,
-  f: CartSetPaymentForm,
-) -> ApiResult {
-  let res: CartForm = services
+// Remove stored line discount if there is any
+fn remove_line_discount(
+  services: &Services,
+  cart_id: &str,
+  target: &DiscountTargetForm,
+) -> Result<(), ApiError> {
+  if services.cart_discounts.contains(cart_id) {
+    services.cart_discounts.update(cart_id, |cd| {
+      cd.discounts.retain(|d| &d.target != target);
+      Ok(())
+    })?;
+  }
+  Ok(())
+}
+
+// Set line unit price in cart
+// SKU lines and UPLs are removed and added back with the new price
+async fn set_line_price(
+  services: &mut Services,
+  cart: &CartForm,
+  target: &DiscountTargetForm,
+  price_net: u32,
+  price_gross: u32,
+) -> Result<CartForm, ApiError> {
+  match target {
+    DiscountTargetForm::Sku { sku } => {
+      let item = cart
+        .shopping_list
+        .iter()
+        .find(|i| i.sku == *sku)
+        .ok_or(ApiError::bad_request("A SKU nincs a kosárban!"))?;
+      services
+        .purchase
+        .cart_remove_sku(CartRemoveSkuRequest {
+          cart_id: cart.id.clone(),
+          sku_id: *sku,
+        })
+        .await
+        .map_err(|e| ApiError::from(e))?;
+      let add = |price_net: u32, price_gross: u32| proto::purchase::CartAddSkuRequest {
+        cart_id: cart.id.clone(),
+        sku_id: *sku,
+        piece: item.piece,
+        name: item.name.clone(),
+        vat: item.vat.clone(),
+        retail_price_net: price_net,
+        retail_price_gross: price_gross,
+      };
+      match services
+        .purchase
+        .cart_add_sku(add(price_net, price_gross))
+        .await
+      {
+        Ok(r) => r.into_inner().try_into(),
+        Err(e) => {
+          // Put back the line with its current price
+          if let Err(re) = services
+            .purchase
+            .cart_add_sku(add(item.retail_price_net, item.retail_price_gross))
+            .await
+          {
+            eprintln!(
+              "Could not restore SKU {} in cart {}: {}",
+              sku,
+              cart.id,
+              re.message()
+            );
+          }
+          Err(ApiError::from(e))
+        }
+      }
+    }
+    DiscountTargetForm::Upl { upl_id } => {
+      let original = cart
+        .upls_sku
+        .iter()
+        .chain(cart.upls_unique.iter())
+        .find(|u| &u.upl_id == upl_id)
+        .ok_or(ApiError::bad_request("Az adott UPL nincs a kosárban!"))?
+        .clone();
+      let mut upl = original.clone();
+      upl.retail_price_net = price_net;
+      upl.retail_price_gross = price_gross;
+      services
+        .purchase
+        .cart_remove_upl(proto::purchase::CartRemoveUplRequest {
+          cart_id: cart.id.clone(),
+          upl_id: upl_id.clone(),
+        })
+        .await
+        .map_err(|e| ApiError::from(e))?;
+      match services
+        .purchase
+        .cart_add_upl(proto::purchase::CartAddUplRequest {
+          cart_id: cart.id.clone(),
+          upl: Some(upl.into()),
+        })
+        .await
+      {
+        Ok(r) => r.into_inner().try_into(),
+        Err(e) => {
+          // Put back the UPL with its current price
+          if let Err(re) = services
+            .purchase
+            .cart_add_upl(proto::purchase::CartAddUplRequest {
+              cart_id: cart.id.clone(),
+              upl: Some(original.into()),
+            })
+            .await
+          {
+            eprintln!(
+              "Could not restore UPL {} in cart {}: {}",
+              upl_id,
+              cart.id,
+              re.message()
+            );
+          }
+          Err(ApiError::from(e))
+        }
+      }
+    }
+  }
+}
+
+// Discount percentage above which manager approval is required
+fn discount_approval_threshold() -> u32 {
+  std::env::var("DISCOUNT_APPROVAL_THRESHOLD")
+    .ok()
+    .and_then(|v| v.parse::<u32>().ok())
+    .unwrap_or(10)
+}
+
+pub async fn cart_set_discount(
+  uid: u32,
+  mut services: Services,
+  f: CartSetDiscountForm,
+) -> ApiResult {
+  cart_expiry::touch(&services, &f.cart_id).await?;
+  if f.reason.trim().len() == 0 {
+    return Err(ApiError::bad_request("A kedvezmény okának megadása kötelező!").into());
+  }
+  if let DiscountTargetForm::Sku { sku } = &f.target {
+    voucher::check_not_voucher_sku(*sku)?;
+  }
+
+  let cart: CartForm = services
+    .purchase
+    .cart_get_by_id(CartByIdRequest {
+      cart_id: f.cart_id.clone(),
+    })
+    .await
+    .map_err(|e| ApiError::from(e))?
+    .into_inner()
+    .try_into()?;
+
+  // Original line price and name
+  // If line already has a discount, its original price is the base
+  // A promotion price is replaced by the manual discount
+  let promoted = promotion::get_promoted_lines(&services, &cart.id);
+  let (name, price_net, price_gross) = match &f.target {
+    DiscountTargetForm::Sku { sku } => cart
+      .shopping_list
+      .iter()
+      .find(|i| i.sku == *sku)
+      .map(|i| {
+        let (net, gross) = promotion::base_price(&promoted, i);
+        (i.name.clone(), net, gross)
+      })
+      .ok_or(ApiError::bad_request("A SKU nincs a kosárban!"))?,
+    DiscountTargetForm::Upl { upl_id } => cart
+      .upls_sku
+      .iter()
+      .chain(cart.upls_unique.iter())
+      .find(|u| &u.upl_id == upl_id)
+      .map(|u| (u.name.clone(), u.retail_price_net, u.retail_price_gross))
+      .ok_or(ApiError::bad_request("Az adott UPL nincs a kosárban!"))?,
+  };
+  let (original_price_net, original_price_gross) = match get_line_discounts(&services, &cart.id)
+    .into_iter()
+    .find(|d| d.target == f.target)
+  {
+    Some(d) => (d.original_price_net, d.original_price_gross),
+    None => (price_net, price_gross),
+  };
+
+  // Calculate discounted gross price
+  let new_price_gross = match &f.kind {
+    DiscountKindForm::Percentage { percentage } => {
+      if *percentage == 0 || *percentage > 100 {
+        return Err(ApiError::bad_request("A kedvezmény mértéke 1-100% között lehet!").into());
+      }
+      (original_price_gross as f32 * (100 - percentage) as f32 / 100.0).round() as u32
+    }
+    DiscountKindForm::Amount { amount_gross } => {
+      if *amount_gross == 0 || *amount_gross > original_price_gross {
+        return Err(ApiError::bad_request("A kedvezmény összege nem megfelelő!").into());
+      }
+      original_price_gross - amount_gross
+    }
+  };
+  // Net price keeps the original net/gross ratio
+  let new_price_net = match original_price_gross {
+    0 => 0,
+    _ => (original_price_net as f32 * new_price_gross as f32 / original_price_gross as f32).round()
+      as u32,
+  };
+
+  let mut discount = LineDiscountForm {
+    target: f.target.clone(),
+    kind: f.kind.clone(),
+    reason: f.reason.clone(),
+    name,
+    original_price_net,
+    original_price_gross,
+    price_net: new_price_net,
+    price_gross: new_price_gross,
+    approved_by: None,
+    created_by: uid,
+    created_at: Utc::now().to_rfc3339(),
+  };
+
+  // Check approval
+  // Exact amounts are compared, as a rounded percentage could let a bigger discount through
+  if discount.unit_discount_gross() as u64 * 100
+    > original_price_gross as u64 * discount_approval_threshold() as u64
+  {
+    discount.approved_by = Some(super::user::manager_approval(
+      &services,
+      f.manager_uid,
+      &f.manager_pin,
+      &f.manager_token,
+    )?);
+  }
+
+  // Set new price in cart
+  // Line is restored with its current price if it fails
+  let res = set_line_price(
+    &mut services,
+    &cart,
+    &f.target,
+    new_price_net,
+    new_price_gross,
+  )
+  .await?;
+
+  // Store discount
+  let mut discounts: Vec<LineDiscountForm> = get_line_discounts(&services, &cart.id)
+    .into_iter()
+    .filter(|d| d.target != f.target)
+    .collect();
+  discounts.push(discount);
+  services.cart_discounts.insert(
+    &cart.id,
+    CartDiscountsForm {
+      cart_id: cart.id.clone(),
+      discounts,
+    },
+  )?;
+
+  let res = apply_promotions(&mut services, res).await?;
+  notify_display(&mut services, &res).await;
+
+  Ok(reply::json(&res.with_discounts(&services)))
+}
+
+pub async fn cart_remove_discount(
+  _uid: u32,
+  mut services: Services,
+  f: CartRemoveDiscountForm,
+) -> ApiResult {
+  cart_expiry::touch(&services, &f.cart_id).await?;
+  let discount = get_line_discounts(&services, &f.cart_id)
+    .into_iter()
+    .find(|d| d.target == f.target)
+    .ok_or(ApiError::bad_request("A tételen nincs kedvezmény!"))?;
+
+  let cart: CartForm = services
+    .purchase
+    .cart_get_by_id(CartByIdRequest {
+      cart_id: f.cart_id.clone(),
+    })
+    .await
+    .map_err(|e| ApiError::from(e))?
+    .into_inner()
+    .try_into()?;
+
+  // Restore original price
+  let res = set_line_price(
+    &mut services,
+    &cart,
+    &f.target,
+    discount.original_price_net,
+    discount.original_price_gross,
+  )
+  .await?;
+
+  remove_line_discount(&services, &f.cart_id, &f.target)?;
+
+  let res = apply_promotions(&mut services, res).await?;
+  notify_display(&mut services, &res).await;
+
+  Ok(reply::json(&res.with_discounts(&services)))
+}
+
+pub async fn cart_set_payment(
+  _uid: u32,
+  mut services: Services,
+  f: CartSetPaymentForm,
+) -> ApiResult {
+  cart_expiry::touch(&services, &f.cart_id).await?;
+  let res: CartForm = services
+    .purchase
+    .cart_set_payment(proto::purchase::CartSetPaymentRequest {
+      cart_id: f.cart_id,
+      payment_kind: match f.payment_kind.as_str() {
+        "Cash" | "cash" => proto::purchase::PaymentKind::Cash,
+        "Card" | "card" => proto::purchase::PaymentKind::Card,
+        "Transfer" | "transfer" => proto::purchase::PaymentKind::Transfer,
+        _ => return Err(ApiError::bad_request("A megadott fizetési kód nem megfelelő!").into()),
+      } as i32,
+    })
+    .await
+    .map_err(|e| ApiError::from(e))?
+    .into_inner()
+    .try_into()?;
+
+  let res = res.with_promotions(&services);
+  notify_display(&mut services, &res).await;
+
+  Ok(reply::json(&res))
+}
+
+pub async fn cart_add_payment(
+  uid: u32,
+  mut services: Services,
+  f: CartAddPaymentForm,
+) -> ApiResult {
+  cart_expiry::touch(&services, &f.cart_id).await?;
+  // Check if cart valid
+  let cart: CartForm = services
+    .purchase
+    .cart_get_by_id(proto::purchase::CartByIdRequest {
+      cart_id: f.cart_id.clone(),
+    })
+    .await
+    .map_err(|e| ApiError::from(e))?
+    .into_inner()
+    .try_into()?;
+  let cart = cart.with_promotions(&services);
+
+  // Cash amount must be rounded, and the tendered cash must cover it
+  let change = match f.kind.as_str() {
+    "Cash" | "cash" => {
+      if f.amount % 5 != 0 {
+        return Err(
+          ApiError::bad_request("Készpénzes fizetésnél az összeg csak 0-ra vagy 5-re végződhet!")
+            .into(),
+        );
+      }
+      // Rounded remainder is the most that can be paid in cash
+      let paid: i32 = cart.payments.iter().map(|p| p.amount).sum();
+      let due = cart.payable + cart.cash_rounding - paid;
+      if f.amount > due {
+        return Err(
+          ApiError::bad_request(&format!(
+            "A készpénzes fizetés több a hátraléknál! Hátralék: {} HUF",
+            due
+          ))
+          .into(),
+        );
+      }
+      match f.tendered {
+        Some(tendered) if tendered < f.amount => {
+          return Err(ApiError::bad_request("Az átadott készpénz kevesebb a fizetendőnél!").into())
+        }
+        Some(tendered) => tendered - f.amount,
+        None => 0,
+      }
+    }
+    _ => {
+      if f.tendered.is_some() {
+        return Err(
+          ApiError::bad_request("Átadott összeg csak készpénzes fizetésnél adható meg!").into(),
+        );
+      }
+      0
+    }
+  };
+
+  let is_voucher = match f.kind.as_str() {
+    "Voucher" | "voucher" => true,
+    _ => false,
+  };
+  if !is_voucher && f.voucher_code.is_some() {
+    return Err(
+      ApiError::bad_request("Utalvány kód csak utalványos fizetésnél adható meg!").into(),
+    );
+  }
+
+  // Do payment
+  let (payment_id, amount) = match is_voucher {
+    // Voucher payment has no cash transaction, its balance is reduced instead
+    true => {
+      let code = f.voucher_code.clone().ok_or(ApiError::bad_request(
+        "Utalványos fizetésnél az utalvány kódja kötelező!",
+      ))?;
+      // Vouchers sold in this cart cannot be paid by voucher
+      let redeemed: i32 = cart
+        .payments
+        .iter()
+        .filter(|p| p.id.starts_with(voucher::PAYMENT_PREFIX))
+        .map(|p| p.amount)
+        .sum();
+      let vouchers_sold: i32 = cart
+        .shopping_list
+        .iter()
+        .filter(|i| voucher::is_voucher_sku(i.sku))
+        .map(|i| i.total_retail_price_gross as i32)
+        .sum();
+      if redeemed + f.amount > cart.payable + cart.cash_rounding - vouchers_sold {
+        return Err(
+          ApiError::bad_request("Utalvánnyal legfeljebb a termékek ára fizethető!").into(),
+        );
+      }
+      let payment_id = voucher::redeem(&services, &code, &f.cart_id, f.amount, uid)?;
+      (payment_id, f.amount)
+    }
+    false => {
+      let transaction = services
+        .cash
+        .create_transaction(NewTransaction {
+          kind: match f.kind.as_str() {
+            "Cash" | "cash" => gzlib::proto::cash::TransactionKind::KindCash,
+            "Card" | "card" => gzlib::proto::cash::TransactionKind::KindCard,
+            "Transfer" | "transfer" => gzlib::proto::cash::TransactionKind::KindTransfer,
+            _ => {
+              return Err(
+                ApiError::bad_request("A megadott tranzakció típus nem megfelelő!").into(),
+              )
+            }
+          } as i32,
+          amount: f.amount,
+          reference: "".to_string(),
+          comment: "".to_string(),
+          created_by: uid,
+          cart_id: Some(proto::cash::new_transaction::CartId::Cart(
+            f.cart_id.clone(),
+          )),
+        })
+        .await
+        .map_err(|e| ApiError::from(e))?
+        .into_inner();
+      (transaction.transaction_id, transaction.amount)
+    }
+  };
+
+  let res: CartForm = match services
     .purchase
-    .cart_set_payment(proto::purchase::CartSetPaymentRequest {
+    .cart_add_payment(proto::purchase::CartAddPaymentRequest {
       cart_id: f.cart_id,
-      payment_kind: match f.payment_kind.as_str() {
-        "Cash" | "cash" => proto::purchase::PaymentKind::Cash,
-        "Card" | "card" => proto::purchase::PaymentKind::Card,
-        "Transfer" | "transfer" => proto::purchase::PaymentKind::Transfer,
-        _ => return Err(ApiError::bad_request("A megadott fizetési kód nem megfelelő!").into()),
-      } as i32,
+      payment_id: payment_id.clone(),
+      amount,
+    })
+    .await
+  {
+    Ok(r) => r.into_inner().try_into()?,
+    Err(e) => {
+      // Give back the redeemed amount to the voucher
+      if is_voucher {
+        voucher::void_redemption(&services, &payment_id)?;
+      }
+      return Err(ApiError::from(e).into());
+    }
+  };
+
+  let res = res.with_promotions(&services);
+  notify_display(&mut services, &res).await;
+
+  Ok(reply::json(&CartAddPaymentResultForm {
+    cart: res,
+    tendered: f.tendered,
+    change,
+  }))
+}
+
+// Give back a voucher payment to its voucher, or create the reversing
+// cash transaction. Returns the reversal ID, the reversed amount
+// and the cash transaction kind, None for voucher payments.
+async fn reverse_payment(
+  services: &mut Services,
+  cart_id: &str,
+  payment_id: &str,
+  reason: &str,
+  uid: u32,
+) -> Result<(String, i32, Option<i32>), ApiError> {
+  // Voucher payment is given back to the voucher balance
+  if payment_id.starts_with(voucher::PAYMENT_PREFIX) {
+    let amount = voucher::void_redemption(services, payment_id)?;
+    return Ok((format!("{}_sztorno", payment_id), amount, None));
+  }
+
+  // Query original transaction to get its kind
+  let original = services
+    .cash
+    .get_by_id(proto::cash::ByIdRequest {
+      transaction_id: payment_id.to_string(),
     })
     .await
     .map_err(|e| ApiError::from(e))?
-    .into_inner()
-    .try_into()?;
+    .into_inner();
 
-  Ok(reply::json(&res))
+  // Create reversing transaction
+  let reversal = services
+    .cash
+    .create_transaction(NewTransaction {
+      kind: original.kind,
+      amount: -original.amount,
+      reference: original.transaction_id.clone(),
+      comment: format!("Sztornó: {}", reason),
+      created_by: uid,
+      cart_id: Some(proto::cash::new_transaction::CartId::Cart(
+        cart_id.to_string(),
+      )),
+    })
+    .await
+    .map_err(|e| ApiError::from(e))?
+    .into_inner();
+  Ok((
+    reversal.transaction_id,
+    original.amount,
+    Some(original.kind),
+  ))
 }
 
-pub async fn cart_add_payment(
+pub async fn cart_void_payment(
   uid: u32,
   mut services: Services,
-  f: CartAddPaymentForm,
+  f: CartVoidPaymentForm,
 ) -> ApiResult {
+  cart_expiry::touch(&services, &f.cart_id).await?;
+  super::user::require_role(&services, uid, super::user::RoleForm::Manager)?;
+
+  if f.reason.trim().len() == 0 {
+    return Err(ApiError::bad_request("A sztornó indoklása kötelező!").into());
+  }
+
   // Check if cart valid
   let cart: CartForm = services
     .purchase
-    .cart_get_by_id(proto::purchase::CartByIdRequest {
+    .cart_get_by_id(CartByIdRequest {
       cart_id: f.cart_id.clone(),
     })
     .await
@@ -892,46 +2974,283 @@ pub async fn cart_add_payment(
     .into_inner()
     .try_into()?;
 
-  // Do payment
-  let transaction = services
-    .cash
-    .create_transaction(NewTransaction {
-      kind: match f.kind.as_str() {
-        "Cash" | "cash" => gzlib::proto::cash::TransactionKind::KindCash,
-        "Card" | "card" => gzlib::proto::cash::TransactionKind::KindCard,
-        "Transfer" | "transfer" => gzlib::proto::cash::TransactionKind::KindTransfer,
-        _ => {
-          return Err(ApiError::bad_request("A megadott tranzakció típus nem megfelelő!").into())
+  // Check payment
+  let payment =
+    cart
+      .payments
+      .iter()
+      .find(|p| p.id == f.payment_id)
+      .ok_or(ApiError::bad_request(
+        "A megadott fizetés nem tartozik a kosárhoz!",
+      ))?;
+  let is_reversal = services
+    .payment_voids
+    .filter(|v| v.reversal_id == payment.id)
+    .len()
+    > 0;
+  if is_reversal {
+    return Err(ApiError::bad_request("Sztornó tranzakció nem sztornózható!").into());
+  }
+
+  // Claim the void before any reversal,
+  // so a double submit cannot reverse the payment twice
+  let claimed = services.payment_voids.insert_absent(
+    &payment.id,
+    PaymentVoidForm {
+      payment_id: payment.id.clone(),
+      cart_id: f.cart_id.clone(),
+      reversal_id: String::new(),
+      amount: payment.amount,
+      reason: f.reason.clone(),
+      voided_by: uid,
+      voided_at: Utc::now(),
+    },
+  )?;
+  if !claimed {
+    return Err(ApiError::bad_request("A fizetés már sztornózva van!").into());
+  }
+
+  // Reversing cash transaction kind, None for voucher payments
+  let (reversal_id, amount, cash_kind) =
+    match reverse_payment(&mut services, &f.cart_id, &payment.id, &f.reason, uid).await {
+      Ok(r) => r,
+      Err(e) => {
+        let _ = services.payment_voids.remove(&payment.id);
+        return Err(e.into());
+      }
+    };
+
+  // Add reversal to cart; payment balance is recalculated
+  let res: CartForm = match services
+    .purchase
+    .cart_add_payment(proto::purchase::CartAddPaymentRequest {
+      cart_id: f.cart_id.clone(),
+      payment_id: reversal_id.clone(),
+      amount: -amount,
+    })
+    .await
+  {
+    Ok(r) => r.into_inner().try_into()?,
+    Err(e) => {
+      // Undo the reversal, the payment stays valid
+      let undo = match cash_kind {
+        None => voucher::restore_redemption(&services, &payment.id),
+        Some(kind) => services
+          .cash
+          .create_transaction(NewTransaction {
+            kind,
+            amount,
+            reference: reversal_id.clone(),
+            comment: "Sztornó visszavonása".to_string(),
+            created_by: uid,
+            cart_id: Some(proto::cash::new_transaction::CartId::Cart(
+              f.cart_id.clone(),
+            )),
+          })
+          .await
+          .map(|_| ())
+          .map_err(|e| ApiError::from(e)),
+      };
+      if let Err(ue) = undo {
+        eprintln!("Could not undo reversal {}: {:?}", reversal_id, ue);
+      }
+      let _ = services.payment_voids.remove(&payment.id);
+      return Err(ApiError::from(e).into());
+    }
+  };
+
+  services.payment_voids.update(&payment.id, |v| {
+    v.reversal_id = reversal_id;
+    v.amount = amount;
+    Ok(())
+  })?;
+
+  let res = res.with_promotions(&services);
+  notify_display(&mut services, &res).await;
+
+  Ok(reply::json(&res))
+}
+
+// Check cart before close
+async fn validate_cart(
+  services: &mut Services,
+  cart: &CartForm,
+) -> Result<CartValidationForm, ApiError> {
+  let mut res = CartValidationForm {
+    cart_id: cart.id.clone(),
+    ok: true,
+    checks: Vec::new(),
+  };
+
+  // Check payments
+  // Cash paid carts are checked against the rounded payable
+  let payable = cart.payable + cart.cash_rounding;
+  let paid: i32 = cart.payments.iter().map(|p| p.amount).sum();
+  if paid < payable {
+    res.add(
+      CartCheckKind::UnpaidBalance { payable, paid },
+      // Transfer is paid later
+      match cart.payment_kind {
+        PaymentKindForm::Transfer => CartCheckSeverity::Warning,
+        _ => CartCheckSeverity::Error,
+      },
+      format!(
+        "A kosár nincs kifizetve! Fizetendő: {} HUF, fizetve: {} HUF.",
+        payable, paid
+      ),
+    );
+  }
+  if paid > payable {
+    res.add(
+      CartCheckKind::Overpaid { payable, paid },
+      CartCheckSeverity::Error,
+      format!(
+        "A kosár túl van fizetve! Fizetendő: {} HUF, fizetve: {} HUF.",
+        payable, paid
+      ),
+    );
+  }
+
+  // Check invoice data
+  if cart.need_invoice {
+    match &cart.customer {
+      Some(c) => {
+        if c.tax_number.trim().len() == 0 {
+          res.add(
+            CartCheckKind::MissingTaxNumber,
+            CartCheckSeverity::Warning,
+            "A vevőnek nincs adószáma.".to_string(),
+          );
         }
-      } as i32,
-      amount: f.amount,
-      reference: "".to_string(),
-      comment: "".to_string(),
-      created_by: uid,
-      cart_id: Some(proto::cash::new_transaction::CartId::Cart(
-        f.cart_id.clone(),
-      )),
+      }
+      None => res.add(
+        CartCheckKind::MissingCustomer,
+        CartCheckSeverity::Error,
+        "Számlához vevő megadása kötelező!".to_string(),
+      ),
+    }
+  }
+
+  // Check UPLs
+  let upls: Vec<&UplInfoForm> = cart
+    .upls_sku
+    .iter()
+    .chain(cart.upls_unique.iter())
+    .collect();
+  let now = Utc::now();
+  for upl in &upls {
+    if upl.depreciated {
+      res.add(
+        CartCheckKind::UplDepreciated {
+          upl_id: upl.upl_id.clone(),
+        },
+        CartCheckSeverity::Warning,
+        format!("Selejtezett termék a kosárban! UPL: {}", upl.upl_id),
+      );
+    }
+    if let Ok(best_before) = DateTime::parse_from_rfc3339(&upl.best_before) {
+      if best_before.with_timezone(&Utc) < now {
+        res.add(
+          CartCheckKind::UplExpired {
+            upl_id: upl.upl_id.clone(),
+            best_before: upl.best_before.clone(),
+          },
+          CartCheckSeverity::Error,
+          format!("Lejárt szavatosságú termék a kosárban! UPL: {}", upl.upl_id),
+        );
+      }
+    }
+  }
+
+  // Check UPL locks
+  let mut locked: Vec<String> = Vec::new();
+  let mut all = services
+    .upl
+    .get_bulk(proto::upl::BulkRequest {
+      upl_ids: upls.iter().map(|u| u.upl_id.clone()).collect(),
     })
     .await
     .map_err(|e| ApiError::from(e))?
     .into_inner();
+  while let Some(upl) = all.message().await.map_err(|e| ApiError::from(e))? {
+    if let Some(upl_obj::Lock::CartLock(cart_id)) = &upl.lock {
+      if cart_id == &cart.id {
+        locked.push(upl.id.clone());
+      }
+    }
+  }
+  for upl in &upls {
+    if !locked.contains(&upl.upl_id) {
+      res.add(
+        CartCheckKind::UplLockLost {
+          upl_id: upl.upl_id.clone(),
+        },
+        CartCheckSeverity::Error,
+        format!(
+          "A UPL már nem ehhez a kosárhoz van zárolva! UPL: {}",
+          upl.upl_id
+        ),
+      );
+    }
+  }
 
-  let res: CartForm = services
-    .purchase
-    .cart_add_payment(proto::purchase::CartAddPaymentRequest {
-      cart_id: f.cart_id,
-      payment_id: transaction.transaction_id,
-      amount: transaction.amount,
+  // Check SKUs
+  let mut all = services
+    .product
+    .get_sku_bulk(GetSkuBulkRequest {
+      sku_id: cart.shopping_list.iter().map(|i| i.sku).collect(),
     })
     .await
     .map_err(|e| ApiError::from(e))?
+    .into_inner();
+  while let Some(sku) = all.message().await.map_err(|e| ApiError::from(e))? {
+    if sku.discontinued {
+      res.add(
+        CartCheckKind::DiscontinuedSku { sku: sku.sku },
+        CartCheckSeverity::Warning,
+        format!("Kifutott termék a kosárban! SKU: {}", sku.sku),
+      );
+    }
+  }
+
+  // Check permit of regulated products
+  if regulated::regulated_lines(services, cart).await?.len() > 0 {
+    match regulated::cart_permit(services, &cart.id) {
+      Some(p) if p.is_valid() => (),
+      Some(p) => res.add(
+        CartCheckKind::PermitExpired,
+        CartCheckSeverity::Error,
+        format!(
+          "A vevő engedélye lejárt! Engedélyszám: {}, érvényes: {}",
+          p.permit_number, p.valid_until
+        ),
+      ),
+      None => res.add(
+        CartCheckKind::MissingPermit,
+        CartCheckSeverity::Error,
+        "Engedélyköteles termék a kosárban! A vevő engedélyszáma és neve kötelező.".to_string(),
+      ),
+    }
+  }
+
+  Ok(res)
+}
+
+pub async fn cart_validate(cart_id: String, _uid: u32, mut services: Services) -> ApiResult {
+  let cart: CartForm = services
+    .purchase
+    .cart_get_by_id(CartByIdRequest { cart_id })
+    .await
+    .map_err(|e| ApiError::from(e))?
     .into_inner()
     .try_into()?;
-
+  let cart = cart.with_promotions(&services);
+  let res = validate_cart(&mut services, &cart).await?;
   Ok(reply::json(&res))
 }
 
 pub async fn cart_close(uid: u32, mut services: Services, f: CartCloseForm) -> ApiResult {
+  cart_expiry::check_active(&services, &f.cart_id).await?;
   // Check if cart valid
   let cart: CartForm = services
     .purchase
@@ -943,65 +3262,185 @@ pub async fn cart_close(uid: u32, mut services: Services, f: CartCloseForm) -> A
     .into_inner()
     .try_into()?;
 
-  let cart_closed: CartForm = services
+  // Promotions are final from here
+  let cart = apply_promotions(&mut services, cart).await?;
+
+  // Run pre-close checks
+  let validation = validate_cart(&mut services, &cart).await?;
+  if !validation.ok {
+    return Err(ApiError::bad_request(&validation.error_message()).into());
+  }
+  // Regulated sale is put into the register before the purchase exists,
+  // so no regulated product is sold without its register entry
+  let regulated_lines = regulated::regulated_lines(&mut services, &cart).await?;
+  let registered = regulated_lines.len() > 0;
+  if registered {
+    regulated::register_sale(&services, &cart, regulated_lines, uid)?;
+  }
+
+  // Close cart into purchase
+  // Only the register entry is made yet if this step fails, so we remove it
+  // and return the error
+  let cart_closed = match services
     .purchase
     .cart_close(proto::purchase::CartCloseRequest {
       cart_id: f.cart_id.clone(),
     })
     .await
-    .map_err(|e| ApiError::from(e))?
-    .into_inner()
-    .try_into()?;
+  {
+    Ok(r) => r.into_inner(),
+    Err(e) => {
+      if registered {
+        if let Err(re) = regulated::unregister_sale(&services, &cart.id) {
+          eprintln!("Could not unregister sale of cart {}: {:?}", cart.id, re);
+        }
+      }
+      return Err(ApiError::from(e).into());
+    }
+  };
+  let cart_closed: CartForm = cart_closed.try_into()?;
+
+  let mut report = CartCloseReport::new(cart_closed.id.clone());
+  report.done(&services, CloseStep::PurchaseClose, 1);
+  match registered {
+    true => report.done(&services, CloseStep::RegulatedSaleRegister, 1),
+    false => report.skipped(&services, CloseStep::RegulatedSaleRegister),
+  }
+
+  // Keep searchable data for purchase search,
+  // a failed purchase is indexed by the next backfill
+  if let Err(e) = super::purchase::reindex_purchase(&mut services, &cart_closed.id).await {
+    eprintln!("Could not index purchase {}: {:?}", cart_closed.id, e);
+  }
+
+  // From here the purchase is closed and cannot be rolled back.
+  // Idempotent steps are retried, the others are called once.
+  // Every failed step is put into the outbox, so it is finished later.
 
   // Move all locked UPLs into its cart
-  services
-    .upl
-    .close_cart(proto::upl::CloseCartRequest {
-      cart_id: f.cart_id,
+  let (res, attempts) = retry_step(|| {
+    let mut client = services.upl.clone();
+    let req = proto::upl::CloseCartRequest {
+      cart_id: f.cart_id.clone(),
       created_by: uid,
-    })
-    .await
-    .map_err(|e| ApiError::from(e))?;
+    };
+    async move { client.close_cart(req).await }
+  })
+  .await;
+  if report
+    .result(&services, CloseStep::UplCloseCart, res, attempts)
+    .is_none()
+  {
+    report.queue(
+      &services,
+      CloseStep::UplCloseCart,
+      OutboxTask::UplCloseCart {
+        cart_id: f.cart_id.clone(),
+        created_by: uid,
+      },
+    );
+  }
+
+  // Activate vouchers sold in the cart
+  if voucher::cart_vouchers(&services, &cart_closed.id).len() > 0 {
+    match voucher::activate_cart_vouchers(&services, &cart_closed.id) {
+      Ok(_) => report.done(&services, CloseStep::VoucherActivate, 1),
+      Err(e) => {
+        report.failed(&services, CloseStep::VoucherActivate, e.message(), 1);
+        report.queue(
+          &services,
+          CloseStep::VoucherActivate,
+          OutboxTask::VoucherActivate {
+            purchase_id: cart_closed.id.clone(),
+          },
+        );
+      }
+    }
+  } else {
+    report.skipped(&services, CloseStep::VoucherActivate);
+  }
 
   // Create invoice if needed
   if cart_closed.need_invoice {
     // Query purchase
-    let purchase: PurchaseForm = services
-      .purchase
-      .purchase_get_by_id(PurchaseByIdRequest {
+    let (purchase, attempts) = retry_step(|| {
+      let mut client = services.purchase.clone();
+      let req = PurchaseByIdRequest {
         purchase_id: cart_closed.id.clone(),
-      })
-      .await
-      .map_err(|e| ApiError::from(e))?
-      .into_inner()
-      .into();
-
-    // Convert purchase form into invoice request
-    let invoice_request: InvoiceForm = purchase.clone().into();
-
-    // Try start invoice creation as bg task
-    let invoice_data = services
-      .invoice
-      .create_new(invoice_request)
-      .await
-      .map_err(|e| ApiError::from(e))?
-      .into_inner();
+      };
+      async move { client.purchase_get_by_id(req).await }
+    })
+    .await;
+
+    let invoice_data = match purchase {
+      Ok(purchase) => {
+        // Convert purchase form into invoice request
+        let invoice_request: InvoiceForm = PurchaseForm::from(purchase)
+          .with_discounts(&services)
+          .with_vouchers(&services)
+          .with_promotions(&services)
+          .into();
+
+        // Start invoice creation as bg task
+        // Not retried, a repeated call would create a second invoice
+        let res = services
+          .invoice
+          .create_new(invoice_request)
+          .await
+          .map(|r| r.into_inner());
+        report.result(&services, CloseStep::InvoiceCreate, res, 1)
+      }
+      Err(e) => report.result(&services, CloseStep::InvoiceCreate, Err(e), attempts),
+    };
 
     // Set invoice internal ID to purchase
-    services
-      .purchase
-      .purchase_set_invoice_id(PurchaseSetInvoiceIdRequest {
-        purchase_id: purchase.purchase_id.clone(),
-        invoice_id: invoice_data.id,
-      })
-      .await
-      .map_err(|e| ApiError::from(e))?;
+    match invoice_data {
+      Some(invoice_data) => {
+        let (res, attempts) = retry_step(|| {
+          let mut client = services.purchase.clone();
+          let req = PurchaseSetInvoiceIdRequest {
+            purchase_id: cart_closed.id.clone(),
+            invoice_id: invoice_data.id.clone(),
+          };
+          async move { client.purchase_set_invoice_id(req).await }
+        })
+        .await;
+        if report
+          .result(&services, CloseStep::InvoiceSetId, res, attempts)
+          .is_none()
+        {
+          report.queue(
+            &services,
+            CloseStep::InvoiceSetId,
+            OutboxTask::InvoiceSetId {
+              purchase_id: cart_closed.id.clone(),
+              invoice_id: invoice_data.id.clone(),
+            },
+          );
+        }
+      }
+      None => {
+        report.skipped(&services, CloseStep::InvoiceSetId);
+        // Outbox creates the invoice and sets its ID as well
+        report.queue(
+          &services,
+          CloseStep::InvoiceCreate,
+          OutboxTask::InvoiceCreate {
+            purchase_id: cart_closed.id.clone(),
+          },
+        );
+      }
+    }
+  } else {
+    report.skipped(&services, CloseStep::InvoiceCreate);
+    report.skipped(&services, CloseStep::InvoiceSetId);
   }
 
   // If it has commitment, add purchase to commitment
-  if let Some(customer) = cart_closed.customer {
-    if cart_closed.commitment_id.len() > 0 {
-      let _ = services
+  match &cart_closed.customer {
+    Some(customer) if cart_closed.commitment_id.len() > 0 => {
+      // Not retried, a repeated call would add the purchase twice
+      let res = services
         .commitment
         .add_purchase(AddPurchaseRequest {
           customer_id: customer.id,
@@ -1011,37 +3450,108 @@ pub async fn cart_close(uid: u32, mut services: Services, f: CartCloseForm) -> A
           total_gross: cart_closed.total_gross,
           applied_discount: cart_closed.commitment_discount_percentage,
         })
-        .await;
+        .await
+        .map(|r| r.into_inner());
+      if report
+        .result(&services, CloseStep::CommitmentAddPurchase, res, 1)
+        .is_none()
+      {
+        report.queue(
+          &services,
+          CloseStep::CommitmentAddPurchase,
+          OutboxTask::CommitmentAddPurchase {
+            customer_id: customer.id,
+            commitment_id: cart_closed.commitment_id.clone(),
+            purchase_id: cart_closed.id.clone(),
+            total_net: cart_closed.total_net,
+            total_gross: cart_closed.total_gross,
+            applied_discount: cart_closed.commitment_discount_percentage,
+          },
+        );
+      }
     }
+    _ => report.skipped(&services, CloseStep::CommitmentAddPurchase),
   }
 
   // If it has loyalty card, add purchase to loyalty card
-  if let Some(lc) = cart_closed.loyalty_card {
-    match services
-      .loyalty
-      .close_purchase(ClosePurchaseRequest {
-        account_id: lc.account_id,
-        purchase_id: cart_closed.id.clone(),
-        total_gross: cart_closed.total_gross,
-        created_by: cart_closed.created_by,
-      })
-      .await
-    {
-      Ok(s) => {
-        let summary = s.into_inner();
-        // Set loyalty summary to purchase
-        let _ = services // TODO! Error handling?
-          .purchase
-          .purchase_set_loyalty_summary(summary)
+  match &cart_closed.loyalty_card {
+    Some(lc) => {
+      // Not retried, a repeated call would earn points twice
+      let res = services
+        .loyalty
+        .close_purchase(ClosePurchaseRequest {
+          account_id: lc.account_id.clone(),
+          purchase_id: cart_closed.id.clone(),
+          total_gross: cart_closed.total_gross,
+          created_by: cart_closed.created_by,
+        })
+        .await
+        .map(|r| r.into_inner());
+
+      // Set loyalty summary to purchase
+      match report.result(&services, CloseStep::LoyaltyClosePurchase, res, 1) {
+        Some(summary) => {
+          let (res, attempts) = retry_step(|| {
+            let mut client = services.purchase.clone();
+            let req = summary.clone();
+            async move { client.purchase_set_loyalty_summary(req).await }
+          })
           .await;
+          if report
+            .result(&services, CloseStep::LoyaltySetSummary, res, attempts)
+            .is_none()
+          {
+            report.queue(
+              &services,
+              CloseStep::LoyaltySetSummary,
+              OutboxTask::loyalty_set_summary(summary),
+            );
+          }
+        }
+        None => {
+          report.skipped(&services, CloseStep::LoyaltySetSummary);
+          // Outbox sets the summary as a follow-up task
+          report.queue(
+            &services,
+            CloseStep::LoyaltyClosePurchase,
+            OutboxTask::LoyaltyClosePurchase {
+              account_id: lc.account_id.clone(),
+              purchase_id: cart_closed.id.clone(),
+              total_gross: cart_closed.total_gross,
+              created_by: cart_closed.created_by,
+            },
+          );
+        }
       }
-      // TODO! Error handling?
-      Err(_) => (),
+    }
+    None => {
+      report.skipped(&services, CloseStep::LoyaltyClosePurchase);
+      report.skipped(&services, CloseStep::LoyaltySetSummary);
     }
   }
+  report.finish(&services);
+
+  // Closed cart cannot expire
+  let _ = services.cart_activity.remove(&cart_closed.id);
+
+  let cart_closed = cart_closed.with_promotions(&services);
+  notify_display(&mut services, &cart_closed).await;
+  // Displays got the final state, their feeds end
+  services.display.remove(&cart_closed.id);
 
-  // Return nothing if success
-  Ok(reply::json(&""))
+  // Return close report
+  Ok(reply::json(&report))
+}
+
+pub async fn cart_get_close_report(
+  purchase_id: String,
+  _uid: u32,
+  services: Services,
+) -> ApiResult {
+  match services.close_reports.get(&purchase_id) {
+    Some(report) => Ok(reply::json(&report)),
+    None => Err(ApiError::not_found().into()),
+  }
 }
 
 pub async fn cart_set_need_invoice(
@@ -1049,6 +3559,7 @@ pub async fn cart_set_need_invoice(
   mut services: Services,
   f: CartSetInvoiceForm,
 ) -> ApiResult {
+  cart_expiry::touch(&services, &f.cart_id).await?;
   // Check if cart valid
   let res: CartForm = services
     .purchase
@@ -1064,37 +3575,52 @@ pub async fn cart_set_need_invoice(
     .into_inner()
     .try_into()?;
 
+  let res = res.with_promotions(&services);
+  notify_display(&mut services, &res).await;
+
   Ok(reply::json(&res))
 }
 
-pub async fn cart_add_loyalty_card(
-  uid: u32,
-  mut services: Services,
-  f: CartAddLoyaltyCard,
-) -> ApiResult {
+// Add loyalty card with its account to cart
+pub async fn add_loyalty_card(
+  services: &mut Services,
+  cart_id: String,
+  loyalty_card_id: String,
+) -> Result<CartForm, ApiError> {
   // Query loyalty card
   let loyalty_account = services
     .loyalty
     .get_account_by_card_id(CardRequest {
-      card_id: f.loyalty_card_id.clone(),
+      card_id: loyalty_card_id.clone(),
     })
     .await
     .map_err(|e| ApiError::from(e))?
     .into_inner();
 
   // Add loyalty card to cart
-  let res: CartForm = services
+  services
     .purchase
     .cart_loyalty_card_add(LoyaltyCardAddRequest {
-      cart_id: f.cart_id,
+      cart_id,
       account_id: loyalty_account.account_id,
-      card_id: f.loyalty_card_id,
+      card_id: loyalty_card_id,
       loyalty_level: loyalty_account.loyalty_level,
     })
     .await
     .map_err(|e| ApiError::from(e))?
     .into_inner()
-    .try_into()?;
+    .try_into()
+}
+
+pub async fn cart_add_loyalty_card(
+  uid: u32,
+  mut services: Services,
+  f: CartAddLoyaltyCard,
+) -> ApiResult {
+  cart_expiry::touch(&services, &f.cart_id).await?;
+  let res = add_loyalty_card(&mut services, f.cart_id, f.loyalty_card_id).await?;
+  let res = res.with_promotions(&services);
+  notify_display(&mut services, &res).await;
 
   Ok(reply::json(&res))
 }
@@ -1104,6 +3630,7 @@ pub async fn cart_remove_loyalty_card(
   mut services: Services,
   f: CartRemoveLoyaltyCard,
 ) -> ApiResult {
+  cart_expiry::touch(&services, &f.cart_id).await?;
   // Remove loyalty card to cart
   let res: CartForm = services
     .purchase
@@ -1113,6 +3640,9 @@ pub async fn cart_remove_loyalty_card(
     .into_inner()
     .try_into()?;
 
+  let res = res.with_promotions(&services);
+  notify_display(&mut services, &res).await;
+
   Ok(reply::json(&res))
 }
 
@@ -1121,6 +3651,7 @@ pub async fn cart_remove_commitment(
   mut services: Services,
   f: CartRemoveLoyaltyCard,
 ) -> ApiResult {
+  cart_expiry::touch(&services, &f.cart_id).await?;
   // Remove loyalty card to cart
   let res: CartForm = services
     .purchase
@@ -1133,6 +3664,9 @@ pub async fn cart_remove_commitment(
     .into_inner()
     .try_into()?;
 
+  let res = res.with_promotions(&services);
+  notify_display(&mut services, &res).await;
+
   Ok(reply::json(&res))
 }
 
@@ -1141,6 +3675,7 @@ pub async fn cart_burn_loyalty_points(
   mut services: Services,
   f: CartBurnLoyaltyPoints,
 ) -> ApiResult {
+  cart_expiry::touch(&services, &f.cart_id).await?;
   // Query cart and loyalty data
   let cart: CartForm = services
     .purchase
@@ -1200,5 +3735,75 @@ pub async fn cart_burn_loyalty_points(
     .into_inner()
     .try_into()?;
 
+  let res = res.with_promotions(&services);
+  notify_display(&mut services, &res).await;
+
   Ok(reply::json(&res))
 }
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_cash_rounding() {
+    assert_eq!(cash_rounding(100), 0);
+    assert_eq!(cash_rounding(101), -1);
+    assert_eq!(cash_rounding(102), -2);
+    assert_eq!(cash_rounding(103), 2);
+    assert_eq!(cash_rounding(104), 1);
+    assert_eq!(cash_rounding(105), 0);
+    assert_eq!(cash_rounding(0), 0);
+    // Negative amounts, e.g. refunds
+    assert_eq!(cash_rounding(-1), 1);
+    assert_eq!(cash_rounding(-3), -2);
+  }
+
+  #[test]
+  fn test_payment_rounding() {
+    use proto::purchase::{Payment, PaymentKind};
+    let payment = |amount| Payment {
+      payment_id: String::new(),
+      amount,
+    };
+    // Whole payable in cash
+    assert_eq!(payment_rounding(PaymentKind::Cash, 1003, &[]), 2);
+    // Only the cash remainder is rounded
+    assert_eq!(
+      payment_rounding(PaymentKind::Cash, 1003, &[payment(500)]),
+      2
+    );
+    assert_eq!(
+      payment_rounding(PaymentKind::Cash, 1003, &[payment(501)]),
+      -2
+    );
+    // Rounding stays the same once the remainder is paid in cash
+    assert_eq!(
+      payment_rounding(PaymentKind::Cash, 1003, &[payment(500), payment(505)]),
+      2
+    );
+    assert_eq!(
+      payment_rounding(PaymentKind::Cash, 1003, &[payment(501), payment(500)]),
+      -2
+    );
+    // Paid fully by card
+    assert_eq!(
+      payment_rounding(PaymentKind::Cash, 1003, &[payment(1003)]),
+      0
+    );
+    assert_eq!(payment_rounding(PaymentKind::Card, 1003, &[]), 0);
+  }
+
+  #[test]
+  fn test_parse_scale_ean() {
+    assert_eq!(parse_scale_ean("2100123012503"), Some((123, 1250)));
+    assert_eq!(parse_scale_ean("2000042000998"), Some((42, 99)));
+    // Wrong check digit
+    assert_eq!(parse_scale_ean("2100123012504"), None);
+    // Not a scale prefix
+    assert_eq!(parse_scale_ean("5998200470042"), None);
+    // Not EAN-13
+    assert_eq!(parse_scale_ean("210012301250"), None);
+    assert_eq!(parse_scale_ean(""), None);
+  }
+}
@@ -1,13 +1,15 @@
 use std::collections::HashMap;
 
-use crate::{prelude::*, services::Services};
+use crate::{prelude::*, receipt::Branding, services::Services};
 use gzlib::proto::{
   self,
   invoice::{ByIdRequest, DownloadRequest, DownloadResponse},
   latex::Content,
+  purchase::PurchaseByIdRequest,
 };
 use proto::invoice::invoice_client::*;
 use serde::{Deserialize, Serialize};
+use tinytemplate::{format_unescaped, TinyTemplate};
 use tonic::transport::Channel;
 use warp::reply;
 
@@ -70,11 +72,16 @@ pub async fn get_invoice_data(
   Ok(reply::json(&res))
 }
 
-pub async fn download(uid: u32, mut services: Services, f: InvoiceDownloadForm) -> ApiResult {
+/// Invoice PDF wrapped with the store header
+pub async fn branded_invoice_pdf(
+  services: &mut Services,
+  invoice_id: &str,
+  branding: &Branding,
+) -> Result<Vec<u8>, ApiError> {
   let pdf_base64: String = services
     .invoice
     .download(DownloadRequest {
-      invoice_id: f.invoice_id,
+      invoice_id: invoice_id.to_string(),
     })
     .await
     .map_err(|e| ApiError::from(e))?
@@ -82,42 +89,52 @@ pub async fn download(uid: u32, mut services: Services, f: InvoiceDownloadForm)
     .pdf_base64;
 
   let template = r#"
-    \documentclass{standalone}
-    \usepackage{graphicx}
-    \usepackage{tabto}
-    \usepackage[utf8]{inputenc}
-    \usepackage[T1]{fontenc}
-    \usepackage{pdfpages}
+    \documentclass\{standalone}
+    \usepackage\{graphicx}
+    \usepackage\{tabto}
+    \usepackage[utf8]\{inputenc}
+    \usepackage[T1]\{fontenc}
+    \usepackage\{pdfpages}
     
-    \begin{document}
+    \begin\{document}
       
-      \begin{minipage}[left]{7cm}
+      \begin\{minipage}[left]\{7cm}
         \centering
-          \vspace{0.3cm}
-          \includegraphics[width=50px]{icon.jpg} \\
-          \Huge{\textbf{GardenZilla}} \\
-          \vspace{0.2cm}
-          \normalsize{\textmd{Kert és Otthon}}\\
-          \vspace{0cm}
+          \vspace\{0.3cm}
+          \includegraphics[width=50px]\{icon.jpg} \\
+          \Huge\{\textbf\{{name}}} \\
+          \vspace\{0.2cm}
+          \normalsize\{\textmd\{{subtitle}}}\\
+          \vspace\{0cm}
           
-          \hspace*{-0.5cm}\includegraphics[]{invoice.pdf}
-      \end{minipage}
+          \hspace*\{-0.5cm}\includegraphics[]\{invoice.pdf}
+      \end\{minipage}
       
       
-    \end{document}"#;
+    \end\{document}"#;
 
-  let icon_bytes = include_bytes!("../../static/icon.jpg");
+  // TinyTemplate is not Send, so it must be dropped before the latex call
+  let main_latex_file = {
+    let mut tt = TinyTemplate::new();
+    // Disable HTML escape
+    tt.set_default_formatter(&format_unescaped);
+    tt.add_template("invoice", template)
+      .map_err(|_| ApiError::internal_error("Hibás számla sablon!"))?;
+    tt.render("invoice", &branding.latex_escaped())
+      .map_err(|_| ApiError::internal_error("Hibás számla sablon!"))?
+  };
 
-  let invoice_bytes = base64::decode(pdf_base64).expect("Error while decoding B64 document");
+  let invoice_bytes =
+    base64::decode(pdf_base64).map_err(|_| ApiError::internal_error("Hibás számla PDF!"))?;
 
   // Call latex service
   let result = services
     .latex
     .process(Content {
-      main_latex_file: template.as_bytes().to_owned(),
+      main_latex_file: main_latex_file.as_bytes().to_owned(),
       attachments: {
         let mut files: HashMap<String, Vec<u8>> = HashMap::new();
-        files.insert("icon.jpg".to_string(), icon_bytes.to_vec());
+        files.insert("icon.jpg".to_string(), branding.logo.clone());
         files.insert("invoice.pdf".to_string(), invoice_bytes);
         files
       },
@@ -125,9 +142,31 @@ pub async fn download(uid: u32, mut services: Services, f: InvoiceDownloadForm)
     .await
     .map_err(|e| ApiError::bad_request("Hiba a latex szerviztől"))?
     .into_inner();
+  Ok(result.content)
+}
+
+pub async fn download(uid: u32, mut services: Services, f: InvoiceDownloadForm) -> ApiResult {
+  // Branding of the store the invoiced purchase was made in
+  let purchase_id = services
+    .invoice
+    .get_by_id(ByIdRequest {
+      id: f.invoice_id.clone(),
+    })
+    .await
+    .map_err(|e| ApiError::from(e))?
+    .into_inner()
+    .purchase_id;
+  let purchase: super::purchase::PurchaseForm = services
+    .purchase
+    .purchase_get_by_id(PurchaseByIdRequest { purchase_id })
+    .await
+    .map_err(|e| ApiError::from(e))?
+    .into_inner()
+    .into();
+  let branding = super::purchase::purchase_branding(&services, &purchase);
 
   let res: InvoicePdfForm = InvoicePdfForm {
-    pdf_base64: base64::encode(result.content),
+    pdf_base64: base64::encode(branded_invoice_pdf(&mut services, &f.invoice_id, &branding).await?),
   };
 
   Ok(reply::json(&res))
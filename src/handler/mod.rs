@@ -5,12 +5,20 @@ pub mod customer;
 pub mod invoice;
 pub mod login;
 pub mod loyalty;
+pub mod outbox;
 pub mod pricing;
 pub mod procurement;
 pub mod product;
+pub mod promotion;
 pub mod purchase;
+pub mod purchase_email;
+pub mod purchase_return;
+pub mod quote;
+pub mod receivable;
+pub mod regulated;
 pub mod sku_image;
 pub mod source;
 pub mod stock;
 pub mod upl;
 pub mod user;
+pub mod voucher;
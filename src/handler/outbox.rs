@@ -0,0 +1,90 @@
+use crate::{
+  outbox::{self, OutboxItem, OutboxTask, STUCK_ATTEMPTS},
+  prelude::*,
+  services::Services,
+};
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+use warp::reply;
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct OutboxItemForm {
+  id: String,
+  task: OutboxTask,
+  attempts: u32,
+  last_error: String,
+  next_attempt_at: String,
+  stuck: bool,
+  taken: bool,
+  created_at: String,
+}
+
+impl From<OutboxItem> for OutboxItemForm {
+  fn from(f: OutboxItem) -> Self {
+    let taken = f.is_taken(Utc::now());
+    Self {
+      id: f.id,
+      task: f.task,
+      attempts: f.attempts,
+      last_error: f.last_error,
+      next_attempt_at: f.next_attempt_at.to_rfc3339(),
+      stuck: f.attempts >= STUCK_ATTEMPTS,
+      taken,
+      created_at: f.created_at.to_rfc3339(),
+    }
+  }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct OutboxRetryForm {
+  id: String,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ReconcileForm {
+  days: i64,
+}
+
+pub async fn get_all(uid: u32, services: Services) -> ApiResult {
+  super::user::require_role(&services, uid, super::user::RoleForm::Admin)?;
+  let res: Vec<OutboxItemForm> = services
+    .outbox
+    .get_all()
+    .into_iter()
+    .map(|i| i.into())
+    .collect();
+  Ok(reply::json(&res))
+}
+
+pub async fn get_stuck(uid: u32, services: Services) -> ApiResult {
+  super::user::require_role(&services, uid, super::user::RoleForm::Admin)?;
+  let res: Vec<OutboxItemForm> = services
+    .outbox
+    .filter(|i| i.attempts >= STUCK_ATTEMPTS)
+    .into_iter()
+    .map(|i| i.into())
+    .collect();
+  Ok(reply::json(&res))
+}
+
+pub async fn retry(uid: u32, mut services: Services, f: OutboxRetryForm) -> ApiResult {
+  super::user::require_role(&services, uid, super::user::RoleForm::Admin)?;
+
+  // Make item due now
+  services.outbox.update(&f.id, |i| {
+    i.next_attempt_at = Utc::now();
+    Ok(())
+  })?;
+
+  // Fails if the worker is executing it right now
+  outbox::process_item(&mut services, &f.id).await?;
+
+  let res: Option<OutboxItemForm> = services.outbox.get(&f.id).map(|i| i.into());
+  Ok(reply::json(&res))
+}
+
+pub async fn reconcile(uid: u32, mut services: Services, f: ReconcileForm) -> ApiResult {
+  super::user::require_role(&services, uid, super::user::RoleForm::Admin)?;
+  let res = outbox::reconcile(&mut services, f.days).await?;
+  Ok(reply::json(&res))
+}
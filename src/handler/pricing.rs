@@ -1,4 +1,5 @@
 use crate::{prelude::*, services::Services};
+use chrono::{DateTime, Utc};
 use gzlib::proto::{
   pricing::{
     GetPriceBulkRequest, GetPriceRequest, PriceChangesRequest, PriceHistoryObject, PriceObject,
@@ -45,6 +46,43 @@ pub struct PriceHistoryForm {
   created_by: u32,
 }
 
+/// Quantity price tier
+/// Unit price for lines of at least `min_quantity` pieces
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct PriceTierForm {
+  pub min_quantity: u32,
+  pub price_net_retail: u32,
+  pub price_gross_retail: u32,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SkuPriceTiersForm {
+  pub sku: u32,
+  // Ordered by min quantity
+  pub tiers: Vec<PriceTierForm>,
+  pub updated_by: u32,
+  pub updated_at: DateTime<Utc>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct SetPriceTiersForm {
+  sku: u32,
+  tiers: Vec<PriceTierForm>,
+}
+
+/// Price tier for the given piece, if the SKU has one
+pub fn price_tier(services: &Services, sku: u32, piece: u32) -> Option<PriceTierForm> {
+  select_tier(services.price_tiers.get(&sku.to_string())?.tiers, piece)
+}
+
+// Tier with the largest min quantity reached by the piece
+fn select_tier(tiers: Vec<PriceTierForm>, piece: u32) -> Option<PriceTierForm> {
+  tiers
+    .into_iter()
+    .filter(|t| t.min_quantity <= piece)
+    .max_by_key(|t| t.min_quantity)
+}
+
 impl From<PriceHistoryObject> for PriceHistoryForm {
   fn from(pho: PriceHistoryObject) -> Self {
     Self {
@@ -149,3 +187,94 @@ pub async fn get_latest_price_changes(
     .price_ids;
   Ok(reply::json(&ids))
 }
+
+pub async fn get_tiers(sku: u32, _uid: u32, services: Services) -> ApiResult {
+  let res: Vec<PriceTierForm> = match services.price_tiers.get(&sku.to_string()) {
+    Some(t) => t.tiers,
+    None => Vec::new(),
+  };
+  Ok(reply::json(&res))
+}
+
+pub async fn set_tiers(uid: u32, mut services: Services, f: SetPriceTiersForm) -> ApiResult {
+  super::user::require_role(&services, uid, super::user::RoleForm::Manager)?;
+
+  // Tiers are based on the SKU retail price
+  let base: PriceObject = services
+    .pricing
+    .get_price(GetPriceRequest { sku: f.sku })
+    .await
+    .map_err(|e| ApiError::from(e))?
+    .into_inner();
+
+  let mut tiers = f.tiers;
+  tiers.sort_by_key(|t| t.min_quantity);
+  for (i, t) in tiers.iter().enumerate() {
+    if t.min_quantity < 2 {
+      return Err(ApiError::bad_request("A sávos ár legalább 2 darabtól adható meg!").into());
+    }
+    if i > 0 && tiers[i - 1].min_quantity == t.min_quantity {
+      return Err(ApiError::bad_request("Egy darabszámhoz csak egy sávos ár tartozhat!").into());
+    }
+    if t.price_net_retail > t.price_gross_retail {
+      return Err(ApiError::bad_request("A nettó ár nem lehet nagyobb a bruttó árnál!").into());
+    }
+    // Larger quantity cannot cost more per piece
+    let prev_gross = match i {
+      0 => base.price_gross_retail,
+      _ => tiers[i - 1].price_gross_retail,
+    };
+    if t.price_gross_retail > prev_gross {
+      return Err(
+        ApiError::bad_request(&format!(
+          "A {} darabtól érvényes ár nem lehet magasabb az előző sávnál!",
+          t.min_quantity
+        ))
+        .into(),
+      );
+    }
+  }
+
+  // No tier left, remove SKU
+  if tiers.len() == 0 {
+    services.price_tiers.remove(&f.sku.to_string())?;
+    return Ok(reply::json(&tiers));
+  }
+
+  let res = services.price_tiers.insert(
+    &f.sku.to_string(),
+    SkuPriceTiersForm {
+      sku: f.sku,
+      tiers,
+      updated_by: uid,
+      updated_at: Utc::now(),
+    },
+  )?;
+  Ok(reply::json(&res.tiers))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn tier(min_quantity: u32, price_gross_retail: u32) -> PriceTierForm {
+    PriceTierForm {
+      min_quantity,
+      price_net_retail: price_gross_retail,
+      price_gross_retail,
+    }
+  }
+
+  #[test]
+  fn test_select_tier() {
+    let tiers = vec![tier(10, 80), tier(5, 90)];
+    let gross = |piece: u32| select_tier(tiers.clone(), piece).map(|t| t.price_gross_retail);
+    assert_eq!(gross(1), None);
+    assert_eq!(gross(4), None);
+    assert_eq!(gross(5), Some(90));
+    assert_eq!(gross(9), Some(90));
+    assert_eq!(gross(10), Some(80));
+    assert_eq!(gross(100), Some(80));
+    assert!(select_tier(Vec::new(), 10).is_none());
+  }
+}
@@ -36,6 +36,17 @@ pub struct ProductForm {
   pub perishable: bool,
   pub created_at: String,
   pub created_by: u32,
+  // Sale needs a permit and is registered
+  #[serde(default)]
+  pub regulated: bool,
+}
+
+impl ProductForm {
+  // Attach regulated flag
+  fn with_regulated(mut self, services: &Services) -> Self {
+    self.regulated = is_regulated(services, self.product_id);
+    self
+  }
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -72,12 +83,51 @@ pub struct SkuSetDiscontinuedForm {
   pub discontinued: bool,
 }
 
+/// EAN/GTIN barcode assigned to a SKU
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct SkuBarcodeForm {
+  pub ean: String,
+  pub sku: u32,
+  pub created_by: u32,
+  pub created_at: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SkuSetBarcodeForm {
+  pub sku: u32,
+  pub ean: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SkuRemoveBarcodeForm {
+  pub ean: String,
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct ProductSetPerishableForm {
   pub product_id: u32,
   pub perishable: bool,
 }
 
+/// Regulated product, e.g. professional plant protection product
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct RegulatedProductForm {
+  pub product_id: u32,
+  pub created_by: u32,
+  pub created_at: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ProductSetRegulatedForm {
+  pub product_id: u32,
+  pub regulated: bool,
+}
+
+/// Whether the product can only be sold with a permit
+pub fn is_regulated(services: &Services, product_id: u32) -> bool {
+  services.regulated_products.contains(&product_id.to_string())
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct ProductSetDiscontinuedForm {
   pub product_id: u32,
@@ -106,6 +156,7 @@ impl From<ProductObj> for ProductForm {
       perishable: p.perishable,
       created_at: p.created_at,
       created_by: p.created_by,
+      regulated: false,
     }
   }
 }
@@ -164,7 +215,7 @@ pub async fn get_product_by_id(pid: u32, _: u32, mut services: Services) -> ApiR
     .map_err(|e| ApiError::from(e))?
     .into_inner()
     .into();
-  Ok(warp::reply::json(&product))
+  Ok(warp::reply::json(&product.with_regulated(&services)))
 }
 
 pub async fn get_product_bulk(_: u32, mut services: Services, product_ids: Vec<u32>) -> ApiResult {
@@ -177,7 +228,7 @@ pub async fn get_product_bulk(_: u32, mut services: Services, product_ids: Vec<u
 
   let mut result: Vec<ProductForm> = Vec::new();
   while let Some(user) = products.message().await.map_err(|e| ApiError::from(e))? {
-    result.push(user.into());
+    result.push(ProductForm::from(user).with_regulated(&services));
   }
   Ok(warp::reply::json(&result))
 }
@@ -256,6 +307,44 @@ pub async fn product_set_perishable(
   Ok(reply::json(&sku))
 }
 
+pub async fn product_set_regulated(
+  uid: u32,
+  mut services: Services,
+  f: ProductSetRegulatedForm,
+) -> ApiResult {
+  super::user::require_role(&services, uid, super::user::RoleForm::Manager)?;
+
+  // Check if product exists
+  let product: ProductForm = services
+    .product
+    .get_product(GetProductRequest {
+      product_id: f.product_id,
+    })
+    .await
+    .map_err(|e| ApiError::from(e))?
+    .into_inner()
+    .into();
+
+  match f.regulated {
+    true => {
+      services.regulated_products.insert(
+        &f.product_id.to_string(),
+        RegulatedProductForm {
+          product_id: f.product_id,
+          created_by: uid,
+          created_at: chrono::Utc::now().to_rfc3339(),
+        },
+      )?;
+    }
+    false => {
+      services
+        .regulated_products
+        .remove(&f.product_id.to_string())?;
+    }
+  }
+  Ok(reply::json(&product.with_regulated(&services)))
+}
+
 pub async fn create_sku(uid: u32, mut services: Services, ns: NewSkuForm) -> ApiResult {
   let sku: SkuForm = services
     .product
@@ -418,3 +507,98 @@ pub async fn sku_set_discontinued(
     .into();
   Ok(reply::json(&sku))
 }
+
+/// Check EAN-8, UPC-A, EAN-13 or GTIN-14 code and its check digit
+pub fn is_valid_gtin(code: &str) -> bool {
+  match code.len() {
+    8 | 12 | 13 | 14 => (),
+    _ => return false,
+  }
+  let digits = match code
+    .chars()
+    .map(|c| c.to_digit(10))
+    .collect::<Option<Vec<u32>>>()
+  {
+    Some(d) => d,
+    None => return false,
+  };
+  let (check, body) = digits.split_last().unwrap();
+  // Weights are 3 and 1 from the right, starting next to the check digit
+  let sum: u32 = body
+    .iter()
+    .rev()
+    .enumerate()
+    .map(|(i, d)| if i % 2 == 0 { d * 3 } else { *d })
+    .sum();
+  (10 - sum % 10) % 10 == *check
+}
+
+pub async fn sku_set_barcode(uid: u32, mut services: Services, f: SkuSetBarcodeForm) -> ApiResult {
+  if !is_valid_gtin(&f.ean) {
+    return Err(ApiError::bad_request("A megadott vonalkód nem érvényes EAN/GTIN kód!").into());
+  }
+
+  // Check SKU
+  services
+    .product
+    .get_sku(GetSkuRequest { sku_id: f.sku })
+    .await
+    .map_err(|e| ApiError::from(e))?;
+
+  if let Some(barcode) = services.sku_barcodes.get(&f.ean) {
+    if barcode.sku != f.sku {
+      return Err(
+        ApiError::bad_request(&format!(
+          "A vonalkód már egy másik SKU-hoz tartozik! SKU: {}",
+          barcode.sku
+        ))
+        .into(),
+      );
+    }
+  }
+
+  let res = services.sku_barcodes.insert(
+    &f.ean,
+    SkuBarcodeForm {
+      ean: f.ean.clone(),
+      sku: f.sku,
+      created_by: uid,
+      created_at: chrono::Utc::now().to_rfc3339(),
+    },
+  )?;
+  Ok(reply::json(&res))
+}
+
+pub async fn sku_remove_barcode(
+  _uid: u32,
+  services: Services,
+  f: SkuRemoveBarcodeForm,
+) -> ApiResult {
+  let res = services.sku_barcodes.remove(&f.ean)?;
+  Ok(reply::json(&res))
+}
+
+pub async fn sku_get_barcodes(sku: u32, _uid: u32, services: Services) -> ApiResult {
+  let res = services.sku_barcodes.filter(|b| b.sku == sku);
+  Ok(reply::json(&res))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_is_valid_gtin() {
+    // EAN-13, EAN-8, UPC-A, GTIN-14
+    assert!(is_valid_gtin("4006381333931"));
+    assert!(is_valid_gtin("96385074"));
+    assert!(is_valid_gtin("036000291452"));
+    assert!(is_valid_gtin("10012345678902"));
+    // Wrong check digit
+    assert!(!is_valid_gtin("4006381333932"));
+    // Wrong length or not digits
+    assert!(!is_valid_gtin("400638133"));
+    assert!(!is_valid_gtin("40063813339a1"));
+    assert!(!is_valid_gtin(""));
+  }
+}
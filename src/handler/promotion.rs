@@ -0,0 +1,513 @@
+use crate::{prelude::*, services::Services};
+use chrono::{DateTime, Local, NaiveDate, Utc};
+use serde::{Deserialize, Serialize};
+use warp::reply;
+
+use super::cart::{CartForm, DiscountTargetForm, ItemForm};
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub enum PromotionRuleForm {
+  // Buy `buy` pieces, pay for `pay`; the cheapest pieces are free
+  MultiBuy {
+    skus: Vec<u32>,
+    buy: u32,
+    pay: u32,
+  },
+  // One piece of every SKU for a fixed gross price
+  Bundle {
+    skus: Vec<u32>,
+    price_gross: u32,
+  },
+  // Percentage off a product set
+  PercentageOff {
+    skus: Vec<u32>,
+    percentage: u32,
+  },
+  // Percentage off the whole basket above a minimum gross total
+  BasketThreshold {
+    min_total_gross: u32,
+    percentage: u32,
+  },
+}
+
+impl PromotionRuleForm {
+  // Evaluation order; item rules go first, basket rules see their result
+  fn priority(&self) -> u8 {
+    match self {
+      PromotionRuleForm::MultiBuy { .. } => 0,
+      PromotionRuleForm::Bundle { .. } => 1,
+      PromotionRuleForm::PercentageOff { .. } => 2,
+      PromotionRuleForm::BasketThreshold { .. } => 3,
+    }
+  }
+
+  fn check(&self) -> Result<(), ApiError> {
+    match self {
+      PromotionRuleForm::MultiBuy { skus, buy, pay } => {
+        if skus.len() == 0 {
+          return Err(ApiError::bad_request("Legalább egy SKU megadása kötelező!"));
+        }
+        if *pay == 0 || pay >= buy {
+          return Err(ApiError::bad_request(
+            "A fizetendő darabszám nem megfelelő!",
+          ));
+        }
+      }
+      PromotionRuleForm::Bundle { skus, .. } => {
+        if skus.len() < 2 {
+          return Err(ApiError::bad_request("A csomaghoz legalább két SKU kell!"));
+        }
+      }
+      PromotionRuleForm::PercentageOff { skus, percentage } => {
+        if skus.len() == 0 {
+          return Err(ApiError::bad_request("Legalább egy SKU megadása kötelező!"));
+        }
+        if *percentage == 0 || *percentage > 100 {
+          return Err(ApiError::bad_request(
+            "A kedvezmény mértéke 1-100% között lehet!",
+          ));
+        }
+      }
+      PromotionRuleForm::BasketThreshold { percentage, .. } => {
+        if *percentage == 0 || *percentage > 100 {
+          return Err(ApiError::bad_request(
+            "A kedvezmény mértéke 1-100% között lehet!",
+          ));
+        }
+      }
+    }
+    Ok(())
+  }
+}
+
+/// Rule based promotion
+/// Evaluated on every cart change
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct PromotionForm {
+  pub promotion_id: String,
+  pub name: String,
+  pub rule: PromotionRuleForm,
+  pub valid_from: NaiveDate,
+  pub valid_until: NaiveDate,
+  // Empty means every store
+  pub store_ids: Vec<u32>,
+  pub enabled: bool,
+  pub created_by: u32,
+  pub created_at: DateTime<Utc>,
+}
+
+impl PromotionForm {
+  pub fn is_active(&self, store_id: u32) -> bool {
+    let today = Local::today().naive_local();
+    self.enabled
+      && self.valid_from <= today
+      && today <= self.valid_until
+      && (self.store_ids.len() == 0 || self.store_ids.contains(&store_id))
+  }
+}
+
+/// Promotion applied on a cart
+/// One line per promotion and VAT rate
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct AppliedPromotionForm {
+  pub promotion_id: String,
+  pub name: String,
+  pub vat: String,
+  pub discount_net: u32,
+  pub discount_gross: u32,
+}
+
+/// Cart line sold at a promotion price
+/// Its discount is set in the line price, like a manual discount
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct PromotedLineForm {
+  pub sku: u32,
+  pub original_price_net: u32,
+  pub original_price_gross: u32,
+  pub price_net: u32,
+  pub price_gross: u32,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct CartPromotionsForm {
+  pub cart_id: String,
+  pub promotions: Vec<AppliedPromotionForm>,
+  #[serde(default)]
+  pub lines: Vec<PromotedLineForm>,
+  pub evaluated_at: DateTime<Utc>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct PromotionNewForm {
+  name: String,
+  rule: PromotionRuleForm,
+  // YYYY-MM-DD
+  valid_from: String,
+  // YYYY-MM-DD
+  valid_until: String,
+  store_ids: Vec<u32>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct PromotionSetEnabledForm {
+  promotion_id: String,
+  enabled: bool,
+}
+
+// Cart line during evaluation
+struct Line {
+  sku: u32,
+  piece: u32,
+  unit_net: u32,
+  unit_gross: u32,
+  vat: String,
+  total_gross: u32,
+  // Pieces not used by an item promotion yet
+  free_pieces: u32,
+  // Discount given on the line so far
+  discount_gross: u32,
+  // Discount by promotion index
+  discounts: Vec<(usize, u32)>,
+}
+
+// Note discount of a promotion on a line
+fn add_discount(line: &mut Line, promotion: usize, gross: u32) {
+  if gross == 0 {
+    return;
+  }
+  line.discount_gross += gross;
+  match line.discounts.iter_mut().find(|d| d.0 == promotion) {
+    Some(d) => d.1 += gross,
+    None => line.discounts.push((promotion, gross)),
+  }
+}
+
+/// Unit price of a cart line without its promotion price
+pub fn base_price(promoted: &[PromotedLineForm], item: &ItemForm) -> (u32, u32) {
+  match promoted.iter().find(|l| {
+    l.sku == item.sku
+      && l.price_net == item.retail_price_net
+      && l.price_gross == item.retail_price_gross
+  }) {
+    Some(l) => (l.original_price_net, l.original_price_gross),
+    None => (item.retail_price_net, item.retail_price_gross),
+  }
+}
+
+/// Evaluate promotions on a cart
+/// Returns the promotion price of the lines and the applied promotions
+/// SKUs with a manual line discount are left out,
+/// and one piece gets at most one item promotion
+pub fn evaluate(
+  promotions: &[PromotionForm],
+  cart: &CartForm,
+  promoted: &[PromotedLineForm],
+  excluded_skus: &[u32],
+) -> (Vec<PromotedLineForm>, Vec<AppliedPromotionForm>) {
+  let mut lines: Vec<Line> = cart
+    .shopping_list
+    .iter()
+    .filter(|i| !excluded_skus.contains(&i.sku))
+    .map(|i| {
+      // Lines are evaluated at their price without promotions
+      let (unit_net, unit_gross) = base_price(promoted, i);
+      Line {
+        sku: i.sku,
+        piece: i.piece,
+        unit_net,
+        unit_gross,
+        vat: i.vat.clone(),
+        total_gross: unit_gross * i.piece,
+        free_pieces: i.piece,
+        discount_gross: 0,
+        discounts: Vec::new(),
+      }
+    })
+    .collect();
+
+  let mut promotions: Vec<&PromotionForm> = promotions.iter().collect();
+  promotions.sort_by_key(|p| (p.rule.priority(), p.promotion_id.clone()));
+
+  for (pi, p) in promotions.iter().enumerate() {
+    match &p.rule {
+      PromotionRuleForm::MultiBuy { skus, buy, pay } => {
+        // Every piece by line index, most expensive first
+        let mut pieces: Vec<usize> = lines
+          .iter()
+          .enumerate()
+          .filter(|(_, l)| skus.contains(&l.sku))
+          .flat_map(|(i, l)| std::iter::repeat(i).take(l.free_pieces as usize))
+          .collect();
+        pieces.sort_by(|a, b| lines[*b].unit_gross.cmp(&lines[*a].unit_gross));
+        let groups = pieces.len() / *buy as usize;
+        for group in pieces[..groups * *buy as usize].chunks(*buy as usize) {
+          for (n, i) in group.iter().enumerate() {
+            lines[*i].free_pieces -= 1;
+            // Cheapest pieces of the group are free
+            if n >= *pay as usize {
+              let gross = lines[*i].unit_gross;
+              add_discount(&mut lines[*i], pi, gross);
+            }
+          }
+        }
+      }
+      PromotionRuleForm::Bundle { skus, price_gross } => {
+        let idx: Vec<usize> = skus
+          .iter()
+          .filter_map(|sku| lines.iter().position(|l| l.sku == *sku))
+          .collect();
+        if idx.len() < skus.len() {
+          continue;
+        }
+        let count = idx.iter().map(|i| lines[*i].free_pieces).min().unwrap_or(0);
+        let full: u32 = idx.iter().map(|i| lines[*i].unit_gross).sum();
+        if count == 0 || full <= *price_gross {
+          continue;
+        }
+        // Bundle discount is shared by unit price
+        let discount = full - price_gross;
+        let mut left = discount;
+        for (n, i) in idx.iter().enumerate() {
+          let share = match n == idx.len() - 1 {
+            true => left,
+            false => ((discount as f32 * lines[*i].unit_gross as f32 / full as f32).round() as u32)
+              .min(left),
+          };
+          left -= share;
+          lines[*i].free_pieces -= count;
+          add_discount(&mut lines[*i], pi, share * count);
+        }
+      }
+      PromotionRuleForm::PercentageOff { skus, percentage } => {
+        for l in lines.iter_mut().filter(|l| skus.contains(&l.sku)) {
+          let gross = (l.unit_gross as f32 * l.free_pieces as f32 * *percentage as f32 / 100.0)
+            .round() as u32;
+          l.free_pieces = 0;
+          add_discount(l, pi, gross);
+        }
+      }
+      PromotionRuleForm::BasketThreshold {
+        min_total_gross,
+        percentage,
+      } => {
+        let basket: u32 = lines
+          .iter()
+          .map(|l| l.total_gross.saturating_sub(l.discount_gross))
+          .sum();
+        if basket < *min_total_gross {
+          continue;
+        }
+        for l in lines.iter_mut() {
+          let gross = (l.total_gross.saturating_sub(l.discount_gross) as f32 * *percentage as f32
+            / 100.0)
+            .round() as u32;
+          add_discount(l, pi, gross);
+        }
+      }
+    }
+  }
+
+  let mut res_lines: Vec<PromotedLineForm> = Vec::new();
+  let mut res: Vec<AppliedPromotionForm> = Vec::new();
+  for l in &lines {
+    if l.discount_gross == 0 || l.piece == 0 {
+      continue;
+    }
+    // Line price is set per unit, so the discount is rounded to units
+    let unit_discount =
+      ((l.discount_gross as f32 / l.piece as f32).round() as u32).min(l.unit_gross);
+    if unit_discount == 0 {
+      continue;
+    }
+    let price_gross = l.unit_gross - unit_discount;
+    // Net keeps the net/gross ratio of the line
+    let price_net = match l.unit_gross {
+      0 => 0,
+      _ => (l.unit_net as f32 * price_gross as f32 / l.unit_gross as f32).round() as u32,
+    };
+    res_lines.push(PromotedLineForm {
+      sku: l.sku,
+      original_price_net: l.unit_net,
+      original_price_gross: l.unit_gross,
+      price_net,
+      price_gross,
+    });
+
+    // Share the rounded line discount between its promotions
+    let line_gross = unit_discount * l.piece;
+    let line_net = l.unit_net.saturating_sub(price_net) * l.piece;
+    let (mut left_gross, mut left_net) = (line_gross, line_net);
+    for (n, (pi, gross)) in l.discounts.iter().enumerate() {
+      let (share_gross, share_net) = match n == l.discounts.len() - 1 {
+        true => (left_gross, left_net),
+        false => {
+          let ratio = *gross as f32 / l.discount_gross as f32;
+          (
+            ((line_gross as f32 * ratio).round() as u32).min(left_gross),
+            ((line_net as f32 * ratio).round() as u32).min(left_net),
+          )
+        }
+      };
+      left_gross -= share_gross;
+      left_net -= share_net;
+      let promotion = promotions[*pi];
+      match res
+        .iter_mut()
+        .find(|a| a.promotion_id == promotion.promotion_id && a.vat == l.vat)
+      {
+        Some(a) => {
+          a.discount_net += share_net;
+          a.discount_gross += share_gross;
+        }
+        None => res.push(AppliedPromotionForm {
+          promotion_id: promotion.promotion_id.clone(),
+          name: promotion.name.clone(),
+          vat: l.vat.clone(),
+          discount_net: share_net,
+          discount_gross: share_gross,
+        }),
+      }
+    }
+  }
+  (res_lines, res)
+}
+
+/// Total gross discount of applied promotions
+pub fn total_discount(promotions: &[AppliedPromotionForm]) -> i32 {
+  promotions.iter().map(|p| p.discount_gross as i32).sum()
+}
+
+/// Promotions stored for a cart or purchase
+pub fn get_cart_promotions(services: &Services, cart_id: &str) -> Vec<AppliedPromotionForm> {
+  match services.cart_promotions.get(cart_id) {
+    Some(cp) => cp.promotions,
+    None => Vec::new(),
+  }
+}
+
+/// Lines of a cart set to a promotion price
+pub fn get_promoted_lines(services: &Services, cart_id: &str) -> Vec<PromotedLineForm> {
+  match services.cart_promotions.get(cart_id) {
+    Some(cp) => cp.lines,
+    None => Vec::new(),
+  }
+}
+
+/// Evaluate the active promotions on a cart
+/// Nothing is stored, see `save_cart_promotions`
+pub fn evaluate_cart(
+  services: &Services,
+  cart: &CartForm,
+) -> (Vec<PromotedLineForm>, Vec<AppliedPromotionForm>) {
+  let active = services.promotions.filter(|p| p.is_active(cart.store_id));
+  // Discounted lines and the voucher line are not promoted
+  let mut excluded: Vec<u32> = super::cart::get_line_discounts(services, &cart.id)
+    .into_iter()
+    .filter_map(|d| match d.target {
+      DiscountTargetForm::Sku { sku } => Some(sku),
+      DiscountTargetForm::Upl { .. } => None,
+    })
+    .collect();
+  excluded.extend(super::voucher::voucher_sku());
+  let promoted = get_promoted_lines(services, &cart.id);
+  evaluate(&active, cart, &promoted, &excluded)
+}
+
+/// Store promotions applied on a cart
+pub fn save_cart_promotions(
+  services: &Services,
+  cart_id: &str,
+  lines: Vec<PromotedLineForm>,
+  promotions: Vec<AppliedPromotionForm>,
+) -> Result<(), ApiError> {
+  if lines.len() == 0 {
+    if services.cart_promotions.contains(cart_id) {
+      services.cart_promotions.remove(cart_id)?;
+    }
+  } else {
+    services.cart_promotions.insert(
+      cart_id,
+      CartPromotionsForm {
+        cart_id: cart_id.to_string(),
+        promotions,
+        lines,
+        evaluated_at: Utc::now(),
+      },
+    )?;
+  }
+  Ok(())
+}
+
+// Next promotion ID after the given ones, e.g. AK20210301001
+fn next_promotion_id<'a>(ids: impl Iterator<Item = &'a String>) -> String {
+  let prefix = format!("AK{}", Local::today().format("%Y%m%d"));
+  let last = ids
+    .filter_map(|id| id.strip_prefix(&prefix))
+    .filter_map(|n| n.parse::<u32>().ok())
+    .max()
+    .unwrap_or(0);
+  format!("{}{:03}", prefix, last + 1)
+}
+
+pub async fn new_promotion(uid: u32, services: Services, f: PromotionNewForm) -> ApiResult {
+  super::user::require_role(&services, uid, super::user::RoleForm::Manager)?;
+
+  if f.name.trim().len() == 0 {
+    return Err(ApiError::bad_request("Az akció nevének megadása kötelező!").into());
+  }
+  f.rule.check()?;
+  let valid_from = NaiveDate::parse_from_str(&f.valid_from, "%Y-%m-%d")
+    .map_err(|_| ApiError::bad_request("Hibás kezdő dátum! (ÉÉÉÉ-HH-NN)"))?;
+  let valid_until = NaiveDate::parse_from_str(&f.valid_until, "%Y-%m-%d")
+    .map_err(|_| ApiError::bad_request("Hibás záró dátum! (ÉÉÉÉ-HH-NN)"))?;
+  if valid_until < valid_from {
+    return Err(ApiError::bad_request("A záró dátum nem lehet a kezdő dátum előtt!").into());
+  }
+
+  // ID is generated under the storage lock
+  let res = services.promotions.insert_new(|promotions| {
+    let promotion_id = next_promotion_id(promotions.keys());
+    Ok((
+      promotion_id.clone(),
+      PromotionForm {
+        promotion_id,
+        name: f.name,
+        rule: f.rule,
+        valid_from,
+        valid_until,
+        store_ids: f.store_ids,
+        enabled: true,
+        created_by: uid,
+        created_at: Utc::now(),
+      },
+    ))
+  })?;
+  Ok(reply::json(&res))
+}
+
+pub async fn set_enabled(uid: u32, services: Services, f: PromotionSetEnabledForm) -> ApiResult {
+  super::user::require_role(&services, uid, super::user::RoleForm::Manager)?;
+  let res = services.promotions.update(&f.promotion_id, |p| {
+    p.enabled = f.enabled;
+    Ok(())
+  })?;
+  Ok(reply::json(&res))
+}
+
+pub async fn get_all(_uid: u32, services: Services) -> ApiResult {
+  let res: Vec<PromotionForm> = services.promotions.get_all();
+  Ok(reply::json(&res))
+}
+
+pub async fn get_active(store_id: u32, _uid: u32, services: Services) -> ApiResult {
+  let res: Vec<PromotionForm> = services.promotions.filter(|p| p.is_active(store_id));
+  Ok(reply::json(&res))
+}
+
+pub async fn get_by_id(promotion_id: String, _uid: u32, services: Services) -> ApiResult {
+  let res: PromotionForm = services
+    .promotions
+    .get(&promotion_id)
+    .ok_or(ApiError::not_found())?;
+  Ok(reply::json(&res))
+}
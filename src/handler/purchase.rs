@@ -1,7 +1,7 @@
 use std::{collections::HashMap, convert::TryInto};
 
 use crate::{prelude::*, services::Services};
-use chrono::{DateTime, Utc};
+use chrono::{DateTime, Local, NaiveDate, Utc};
 use gzlib::proto::{
   self,
   commitment::PurchaseInfo,
@@ -10,42 +10,57 @@ use gzlib::proto::{
     InvoiceForm,
   },
   latex::Content,
-  purchase::{purchase_object::ItemKind, PurchaseByIdRequest, PurchaseInfoObject, PurchaseObject},
+  pricing::GetPriceRequest,
+  product::GetSkuRequest,
+  purchase::{
+    purchase_object::ItemKind, CartByIdRequest, CartNewRequest, CartRemoveRequest,
+    PurchaseByIdRequest, PurchaseInfoObject, PurchaseObject,
+  },
 };
 use serde::{Deserialize, Serialize};
 use warp::reply;
 
-use super::cart::{PaymentForm, PaymentKindForm, UplInfoForm};
+use super::cart::{
+  add_customer, add_loyalty_card, add_sku_priced, apply_promotions, cash_rounding, check_sku_stock,
+  get_line_discounts, payment_rounding, sku_stock, CartForm, DiscountTargetForm, LineDiscountForm,
+  PaymentForm, PaymentKindForm, UplInfoForm, UplKindForm,
+};
+use super::{
+  promotion::{self, AppliedPromotionForm, PromotedLineForm},
+  purchase_email::{self, EmailSendForm},
+  purchase_return, receivable,
+  voucher::{self, VoucherForm},
+};
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct PurchaseInfoForm {
-  purchase_id: String,
-  customer: Option<CustomerForm>,
-  upl_count: u32,
-  total_net_price: u32,
-  total_vat: u32,
-  total_gross_price: u32,
-  balance: i32,
-  payable: i32,
-  document_invoice: bool,
-  invoice_id: String,
-  date_completion: String,
-  payment_duedate: String,
-  payment_expired: bool,
-  profit_net: i32,
-  restored: bool,
-  created_by: u32,
-  created_at: String,
+  pub purchase_id: String,
+  pub customer: Option<CustomerForm>,
+  pub upl_count: u32,
+  pub total_net_price: u32,
+  pub total_vat: u32,
+  pub total_gross_price: u32,
+  pub balance: i32,
+  pub payable: i32,
+  pub document_invoice: bool,
+  pub invoice_id: String,
+  pub date_completion: String,
+  pub payment_duedate: String,
+  pub payment_expired: bool,
+  pub profit_net: i32,
+  pub restored: bool,
+  pub created_by: u32,
+  pub created_at: String,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct CustomerForm {
-  id: u32,
-  name: String,
-  zip: String,
-  location: String,
-  street: String,
-  tax_number: String,
+  pub id: u32,
+  pub name: String,
+  pub zip: String,
+  pub location: String,
+  pub street: String,
+  pub tax_number: String,
 }
 
 impl From<PurchaseInfoObject> for PurchaseInfoForm {
@@ -103,6 +118,16 @@ pub struct ItemForm {
   pub upl_ids: Vec<String>,
 }
 
+impl ItemForm {
+  /// Line of the gift vouchers sold in the purchase
+  pub fn is_voucher(&self) -> bool {
+    match self.kind {
+      ItemKindForm::Sku => voucher::is_voucher_sku(self.product_id),
+      _ => false,
+    }
+  }
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct LoyaltyTransaction {
   pub loyalty_account_id: String,
@@ -112,13 +137,13 @@ pub struct LoyaltyTransaction {
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct LoyaltyCard {
-  account_id: String,
-  card_id: String,
-  loyalty_level: String,
-  balance_opening: i32,
-  burned_points: i32,
-  earned_points: i32,
-  balance_closing: i32,
+  pub account_id: String,
+  pub card_id: String,
+  pub loyalty_level: String,
+  pub balance_opening: i32,
+  pub burned_points: i32,
+  pub earned_points: i32,
+  pub balance_closing: i32,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -142,19 +167,126 @@ pub struct PurchaseForm {
   pub burned_points: Vec<LoyaltyTransaction>,
   pub payable: i32,
   pub payment_balance: i32,
+  // Cash rounding difference, only for cash paid purchases
+  pub cash_rounding: i32,
   pub date_completion: String,
   pub payment_duedate: String,
   pub profit_net: i32,
   pub restored: bool,
   pub owner_uid: u32,
+  pub store_id: u32,
   pub created_by: u32,
   pub created_at: String,
+  pub line_discounts: Vec<LineDiscountForm>,
+  // Gift vouchers sold in the purchase
+  pub vouchers: Vec<VoucherForm>,
+  // Applied promotions, their discount is set in the line prices
+  pub promotions: Vec<AppliedPromotionForm>,
+  pub promotion_discount_gross: i32,
+  // Lines sold at a promotion price
+  pub promoted_lines: Vec<PromotedLineForm>,
+  // UPLs taken back by purchase returns
+  pub returned_upl_ids: Vec<String>,
+  // Receipts and invoices sent by email
+  pub email_sends: Vec<EmailSendForm>,
+}
+
+impl PurchaseForm {
+  // Attach stored line discounts
+  pub fn with_discounts(mut self, services: &Services) -> Self {
+    self.line_discounts = get_line_discounts(services, &self.purchase_id);
+    self
+  }
+
+  // Attach vouchers sold in the purchase
+  pub fn with_vouchers(mut self, services: &Services) -> Self {
+    self.vouchers = voucher::cart_vouchers(services, &self.purchase_id);
+    self
+  }
+
+  // Attach promotions applied at close
+  pub fn with_promotions(mut self, services: &Services) -> Self {
+    self.promotions = promotion::get_cart_promotions(services, &self.purchase_id);
+    self.promotion_discount_gross = promotion::total_discount(&self.promotions);
+    self.promoted_lines = promotion::get_promoted_lines(services, &self.purchase_id);
+    self
+  }
+
+  // Balance with promotions, sold vouchers and later payments
+  pub fn with_late_payments(mut self, services: &Services) -> Self {
+    self.payment_balance += receivable::balance_adjustment(services, &self.purchase_id);
+    self
+  }
+
+  // Attach documents sent by email
+  pub fn with_email_sends(mut self, services: &Services) -> Self {
+    self.email_sends = purchase_email::purchase_email_sends(services, &self.purchase_id);
+    self
+  }
+
+  // Attach UPLs taken back by returns
+  pub fn with_returns(mut self, services: &Services) -> Self {
+    self.returned_upl_ids = purchase_return::returned_pieces(services, &self.purchase_id)
+      .into_iter()
+      .map(|(upl_id, _)| upl_id)
+      .collect();
+    self
+  }
+
+  /// Promotion price of the given item if it has any
+  pub fn item_promotion(&self, item: &ItemForm) -> Option<&PromotedLineForm> {
+    let sku = self.item_sku(item)?;
+    self
+      .promoted_lines
+      .iter()
+      .find(|l| l.sku == sku && l.price_gross == item.retail_price_gross)
+  }
+
+  /// Line discount applied on the given item if it has any
+  /// Matched by its target: UPL discounts by the item UPLs,
+  /// SKU discounts by the SKU of the item
+  pub fn item_discount(&self, item: &ItemForm) -> Option<&LineDiscountForm> {
+    let upl_discount = self.line_discounts.iter().find(|d| match &d.target {
+      DiscountTargetForm::Upl { upl_id } => item.upl_ids.contains(upl_id),
+      DiscountTargetForm::Sku { .. } => false,
+    });
+    if upl_discount.is_some() {
+      return upl_discount;
+    }
+    match item.kind {
+      ItemKindForm::Sku => {
+        let sku = self.item_sku(item)?;
+        self
+          .line_discounts
+          .iter()
+          .find(|d| d.target == DiscountTargetForm::Sku { sku })
+      }
+      _ => None,
+    }
+  }
+
+  /// SKU of a purchase item, based on its UPLs
+  /// None for derived products
+  pub fn item_sku(&self, item: &ItemForm) -> Option<u32> {
+    match item.kind {
+      ItemKindForm::DerivedProduct => None,
+      _ => self
+        .upl_info_objects
+        .iter()
+        .filter(|u| item.upl_ids.contains(&u.upl_id))
+        .find_map(|u| match u.kind {
+          UplKindForm::Sku { sku, .. } => Some(sku),
+          _ => None,
+        }),
+    }
+  }
 }
 
 impl From<PurchaseObject> for PurchaseForm {
   fn from(f: PurchaseObject) -> Self {
     let p: proto::purchase::PaymentKind =
       proto::purchase::PaymentKind::from_i32(f.payment_kind).unwrap(); // TODO! FIX IT
+    let rounding = payment_rounding(p, f.payable, &f.payments);
 
     Self {
       purchase_id: f.id,
@@ -242,35 +374,88 @@ impl From<PurchaseObject> for PurchaseForm {
         })
         .collect::<Vec<LoyaltyTransaction>>(),
       payable: f.payable,
-      payment_balance: f.payment_balance,
+      // Rounding is not known by the purchase service
+      payment_balance: f.payment_balance - rounding,
+      cash_rounding: rounding,
       date_completion: f.date_completion,
       payment_duedate: f.payment_duedate,
       profit_net: f.profit_net,
       restored: f.restored,
       owner_uid: f.owner_uid,
+      store_id: f.store_id,
       created_by: f.created_by,
       created_at: f.created_at,
+      line_discounts: Vec::new(),
+      vouchers: Vec::new(),
+      promotions: Vec::new(),
+      promotion_discount_gross: 0,
+      promoted_lines: Vec::new(),
+      returned_upl_ids: Vec::new(),
+      email_sends: Vec::new(),
     }
   }
 }
 
 impl From<PurchaseForm> for InvoiceForm {
   fn from(f: PurchaseForm) -> Self {
-    let mut items: Vec<Item> = f
-      .items
-      .iter()
-      .map(|i| Item {
+    // Voucher line is invoiced voucher by voucher below
+    let mut items: Vec<Item> = Vec::new();
+    for i in f.items.iter().filter(|i| !i.is_voucher()) {
+      // Promotion is a separate line after the item at its original price
+      let promotion = match f.item_discount(i) {
+        Some(_) => None,
+        None => f.item_promotion(i),
+      };
+      let (unit_net, total_net, total_gross) = match promotion {
+        Some(p) => (
+          p.original_price_net as i32,
+          (p.original_price_net * i.piece) as i32,
+          (p.original_price_gross * i.piece) as i32,
+        ),
+        None => (
+          i.retail_price_net as i32,
+          i.total_retail_price_net as i32,
+          i.total_retail_price_gross as i32,
+        ),
+      };
+      items.push(Item {
         name: i.name.clone(),
         quantity: i.piece as i32,
         unit: "db".to_string(),
-        price_unit_net: i.retail_price_net as i32,
+        price_unit_net: unit_net,
         vat: i.vat.clone(),
-        total_price_net: i.total_retail_price_net as i32,
-        total_price_vat: i.total_retail_price_gross as i32 - i.total_retail_price_net as i32, // TODO! Fix it
-        total_price_gross: i.total_retail_price_gross as i32,
-        comment: "".to_string(),
-      })
-      .collect();
+        total_price_net: total_net,
+        total_price_vat: total_gross - total_net, // TODO! Fix it
+        total_price_gross: total_gross,
+        // Note line discount if it has any
+        comment: match f.item_discount(i) {
+          Some(d) => format!(
+            "Kedvezmény: {}% ({}), eredeti bruttó egységár: {} Ft",
+            d.percentage(),
+            d.reason,
+            d.original_price_gross
+          ),
+          None => "".to_string(),
+        },
+      });
+      // Insert promotion discount of the item
+      // Its totals are the difference to the sold line, so the invoice totals stay the same
+      if let Some(p) = promotion {
+        let net = i.total_retail_price_net as i32 - total_net;
+        let gross = i.total_retail_price_gross as i32 - total_gross;
+        items.push(Item {
+          name: format!("Akció: {}", i.name),
+          quantity: 1,
+          unit: "db".to_string(),
+          price_unit_net: net,
+          vat: i.vat.clone(),
+          total_price_net: net,
+          total_price_vat: gross - net,
+          total_price_gross: gross,
+          comment: format!("Akciós bruttó egységár: {} Ft", p.price_gross),
+        });
+      }
+    }
 
     // Insert commitment discount if have one
     if f.commitment_id.len() > 0 {
@@ -309,6 +494,42 @@ impl From<PurchaseForm> for InvoiceForm {
       });
     }
 
+    // Insert cash rounding if have one
+    // Rounding is not part of the VAT base
+    if f.cash_rounding != 0 {
+      items.push(Item {
+        name: "Kerekítés".to_string(),
+        quantity: 1,
+        unit: "db".to_string(),
+        price_unit_net: f.cash_rounding,
+        vat: VAT_OUT_OF_SCOPE.to_string(),
+        total_price_net: f.cash_rounding,
+        total_price_vat: 0,
+        total_price_gross: f.cash_rounding,
+        comment: "Készpénzes fizetés kerekítése".to_string(),
+      });
+    }
+
+    // Insert sold gift vouchers
+    // Multi-purpose voucher sale is outside the VAT scope,
+    // VAT is charged on the goods it is redeemed for
+    for v in &f.vouchers {
+      items.push(Item {
+        name: "Ajándékutalvány".to_string(),
+        quantity: 1,
+        unit: "db".to_string(),
+        price_unit_net: v.value,
+        vat: VAT_OUT_OF_SCOPE.to_string(),
+        total_price_net: v.value,
+        total_price_vat: 0,
+        total_price_gross: v.value,
+        comment: format!(
+          "Többcélú utalvány ({}), az ÁFA a beváltáskor fizetendő",
+          v.code
+        ),
+      });
+    }
+
     Self {
       purchase_id: f.purchase_id,
       customer: Some(Customer {
@@ -370,7 +591,7 @@ impl From<PurchaseForm> for PurchaseInfoForm {
       invoice_id: f.invoice_id,
       date_completion: f.date_completion,
       payment_duedate: f.payment_duedate.clone(),
-      payment_expired: DateTime::parse_from_rfc3339(&f.payment_duedate).unwrap() > Utc::now(),
+      payment_expired: receivable::is_overdue(f.payment_balance, &f.payment_duedate),
       profit_net: f.profit_net,
       restored: f.restored,
       created_by: f.created_by,
@@ -379,6 +600,370 @@ impl From<PurchaseForm> for PurchaseInfoForm {
   }
 }
 
+/// Purchase data kept for search, so purchase queries
+/// do not read every purchase from the purchase service
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct PurchaseIndexForm {
+  pub purchase_id: String,
+  pub store_id: u32,
+  pub payment_kind: PaymentKindForm,
+  pub customer_id: Option<u32>,
+  pub document_invoice: bool,
+  pub total_gross_price: u32,
+  // Balance known by the purchase service
+  pub balance: i32,
+  pub payment_duedate: String,
+  pub restored: bool,
+  pub created_by: u32,
+  pub created_at: String,
+}
+
+impl From<&PurchaseForm> for PurchaseIndexForm {
+  fn from(f: &PurchaseForm) -> Self {
+    Self {
+      purchase_id: f.purchase_id.clone(),
+      store_id: f.store_id,
+      payment_kind: f.payment_kind.clone(),
+      customer_id: f.customer.as_ref().map(|c| c.id),
+      document_invoice: f.need_invoice,
+      total_gross_price: f.total_gross_price,
+      balance: f.payment_balance,
+      payment_duedate: f.payment_duedate.clone(),
+      restored: f.restored,
+      created_by: f.created_by,
+      created_at: f.created_at.clone(),
+    }
+  }
+}
+
+impl PurchaseIndexForm {
+  /// Balance with payments not known by the purchase service
+  pub fn balance(&self, services: &Services) -> i32 {
+    self.balance + receivable::balance_adjustment(services, &self.purchase_id)
+  }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+pub enum PurchaseSortForm {
+  CreatedAt,
+  TotalGross,
+  Balance,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct PurchaseQueryForm {
+  // YYYY-MM-DD, by created at
+  from: Option<String>,
+  // YYYY-MM-DD, by created at
+  till: Option<String>,
+  store_id: Option<u32>,
+  created_by: Option<u32>,
+  customer_id: Option<u32>,
+  payment_kind: Option<PaymentKindForm>,
+  document_invoice: Option<bool>,
+  unpaid: Option<bool>,
+  restored: Option<bool>,
+  sort: Option<PurchaseSortForm>,
+  // Newest or largest first by default
+  ascending: Option<bool>,
+  // Page size, 50 by default
+  limit: Option<u32>,
+  // Next cursor of the previous page
+  cursor: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct PurchasePageForm {
+  purchases: Vec<PurchaseInfoForm>,
+  // None on the last page
+  next_cursor: Option<String>,
+  // Number of matching purchases
+  total: u32,
+}
+
+// Invoice VAT code of items outside the VAT scope (ÁFA hatályán kívüli)
+pub const VAT_OUT_OF_SCOPE: &'static str = "TAHK";
+
+const PURCHASE_PAGE_DEFAULT: u32 = 50;
+const PURCHASE_PAGE_MAX: u32 = 500;
+
+// Number of purchases written into the index at once during backfill
+const INDEX_BACKFILL_BATCH: usize = 500;
+
+/// Keep searchable data of a purchase
+pub fn index_purchase(
+  services: &Services,
+  purchase: &PurchaseForm,
+) -> Result<PurchaseIndexForm, ApiError> {
+  services
+    .purchase_index
+    .insert(&purchase.purchase_id, PurchaseIndexForm::from(purchase))
+}
+
+/// Query a purchase and keep its searchable data
+pub async fn reindex_purchase(
+  services: &mut Services,
+  purchase_id: &str,
+) -> Result<PurchaseIndexForm, ApiError> {
+  let purchase: PurchaseForm = services
+    .purchase
+    .purchase_get_by_id(PurchaseByIdRequest {
+      purchase_id: purchase_id.to_string(),
+    })
+    .await
+    .map_err(|e| ApiError::from(e))?
+    .into_inner()
+    .into();
+  index_purchase(services, &purchase)
+}
+
+/// Index purchases missing from the index,
+/// closed before indexing or with failed indexing at close.
+/// Returns the number of indexed purchases.
+pub async fn backfill_index(services: &mut Services) -> Result<usize, ApiError> {
+  let purchase_ids = services
+    .purchase
+    .purchase_get_all(())
+    .await
+    .map_err(|e| ApiError::from(e))?
+    .into_inner()
+    .purchase_ids;
+
+  let mut count = 0;
+  let mut batch: Vec<(String, PurchaseIndexForm)> = Vec::new();
+  for purchase_id in purchase_ids {
+    if services.purchase_index.contains(&purchase_id) {
+      continue;
+    }
+    let purchase: PurchaseForm = services
+      .purchase
+      .purchase_get_by_id(PurchaseByIdRequest {
+        purchase_id: purchase_id.clone(),
+      })
+      .await
+      .map_err(|e| ApiError::from(e))?
+      .into_inner()
+      .into();
+    batch.push((purchase_id, PurchaseIndexForm::from(&purchase)));
+    if batch.len() >= INDEX_BACKFILL_BATCH {
+      count += batch.len();
+      services
+        .purchase_index
+        .insert_many(std::mem::take(&mut batch))?;
+    }
+  }
+  count += batch.len();
+  services.purchase_index.insert_many(batch)?;
+  Ok(count)
+}
+
+/// Backfill the purchase index at startup,
+/// retried until the purchase service answers
+pub async fn run_index_backfill(mut services: Services) {
+  loop {
+    match backfill_index(&mut services).await {
+      Ok(count) => {
+        if count > 0 {
+          eprintln!("Purchase index backfilled with {} purchases", count);
+        }
+        return;
+      }
+      Err(e) => eprintln!("Purchase index backfill failed: {:?}", e),
+    }
+    tokio::time::sleep(std::time::Duration::from_secs(60)).await;
+  }
+}
+
+impl PurchaseInfoForm {
+  /// Balance with cash rounding and later payments
+  pub fn with_late_payments(mut self, services: &Services) -> Self {
+    // Rounding is not known by the purchase service, the balance of
+    // a cash purchase is the rounding of its cash part
+    if let Some(index) = services.purchase_index.get(&self.purchase_id) {
+      if index.payment_kind == PaymentKindForm::Cash {
+        self.balance -= cash_rounding(-self.balance);
+      }
+    }
+    self.balance += receivable::balance_adjustment(services, &self.purchase_id);
+    self.payment_expired = receivable::is_overdue(self.balance, &self.payment_duedate);
+    self
+  }
+
+  /// Negative balance means the purchase is not fully paid
+  pub fn is_unpaid(&self) -> bool {
+    self.balance < 0
+  }
+}
+
+// Sort key of an indexed purchase
+fn sort_key(index: &PurchaseIndexForm, balance: i32, sort: PurchaseSortForm) -> i64 {
+  match sort {
+    PurchaseSortForm::CreatedAt => DateTime::parse_from_rfc3339(&index.created_at)
+      .map(|d| d.timestamp())
+      .unwrap_or(0),
+    PurchaseSortForm::TotalGross => index.total_gross_price as i64,
+    PurchaseSortForm::Balance => balance as i64,
+  }
+}
+
+// Cursor is the sort key and ID of the last purchase of the page
+fn encode_cursor(key: i64, purchase_id: &str) -> String {
+  base64::encode(format!("{}:{}", key, purchase_id))
+}
+
+fn decode_cursor(cursor: &str) -> Result<(i64, String), ApiError> {
+  let invalid = || ApiError::bad_request("Hibás lapozási kurzor!");
+  let decoded = base64::decode(cursor).map_err(|_| invalid())?;
+  let decoded = String::from_utf8(decoded).map_err(|_| invalid())?;
+  let mut parts = decoded.splitn(2, ':');
+  let key = parts
+    .next()
+    .and_then(|k| k.parse::<i64>().ok())
+    .ok_or(invalid())?;
+  let purchase_id = parts.next().ok_or(invalid())?.to_string();
+  Ok((key, purchase_id))
+}
+
+// Query filters on an indexed purchase
+fn index_matches(
+  f: &PurchaseQueryForm,
+  from: Option<NaiveDate>,
+  till: Option<NaiveDate>,
+  index: &PurchaseIndexForm,
+  balance: i32,
+) -> bool {
+  if from.is_some() || till.is_some() {
+    let day = match DateTime::parse_from_rfc3339(&index.created_at) {
+      Ok(d) => d.with_timezone(&Local).naive_local().date(),
+      Err(_) => return false,
+    };
+    if from.map(|from| day < from).unwrap_or(false) || till.map(|till| day > till).unwrap_or(false)
+    {
+      return false;
+    }
+  }
+  if let Some(store_id) = f.store_id {
+    if index.store_id != store_id {
+      return false;
+    }
+  }
+  if let Some(created_by) = f.created_by {
+    if index.created_by != created_by {
+      return false;
+    }
+  }
+  if let Some(customer_id) = f.customer_id {
+    if index.customer_id != Some(customer_id) {
+      return false;
+    }
+  }
+  if let Some(payment_kind) = &f.payment_kind {
+    if &index.payment_kind != payment_kind {
+      return false;
+    }
+  }
+  if let Some(invoice) = f.document_invoice {
+    if index.document_invoice != invoice {
+      return false;
+    }
+  }
+  if let Some(unpaid) = f.unpaid {
+    if (balance < 0) != unpaid {
+      return false;
+    }
+  }
+  if let Some(restored) = f.restored {
+    if index.restored != restored {
+      return false;
+    }
+  }
+  true
+}
+
+pub async fn purchase_query(_uid: u32, mut services: Services, f: PurchaseQueryForm) -> ApiResult {
+  let parse_date = |d: &Option<String>| -> Result<Option<NaiveDate>, ApiError> {
+    match d {
+      Some(d) => NaiveDate::parse_from_str(d, "%Y-%m-%d")
+        .map(|d| Some(d))
+        .map_err(|_| ApiError::bad_request("Hibás dátum! (ÉÉÉÉ-HH-NN)")),
+      None => Ok(None),
+    }
+  };
+  let from = parse_date(&f.from)?;
+  let till = parse_date(&f.till)?;
+  let sort = f.sort.unwrap_or(PurchaseSortForm::CreatedAt);
+  let ascending = f.ascending.unwrap_or(false);
+  let limit = f
+    .limit
+    .unwrap_or(PURCHASE_PAGE_DEFAULT)
+    .min(PURCHASE_PAGE_MAX)
+    .max(1) as usize;
+  let cursor = match &f.cursor {
+    Some(c) => Some(decode_cursor(c)?),
+    None => None,
+  };
+
+  // Filter and sort the local index,
+  // only the purchases of the page are queried
+  let mut result: Vec<(i64, String)> = Vec::new();
+  for index in services.purchase_index.get_all() {
+    let balance = index.balance(&services);
+    if index_matches(&f, from, till, &index, balance) {
+      result.push((sort_key(&index, balance, sort), index.purchase_id));
+    }
+  }
+
+  // Purchase ID makes the order stable for equal keys
+  result.sort();
+  if !ascending {
+    result.reverse();
+  }
+  let total = result.len() as u32;
+
+  // Skip purchases up to the cursor
+  if let Some(cursor) = cursor {
+    result.retain(|i| match ascending {
+      true => i > &cursor,
+      false => i < &cursor,
+    });
+  }
+
+  let next_cursor = match result.len() > limit {
+    true => {
+      let (key, purchase_id) = &result[limit - 1];
+      Some(encode_cursor(*key, purchase_id))
+    }
+    false => None,
+  };
+  result.truncate(limit);
+
+  let purchase_ids: Vec<String> = result.into_iter().map(|(_, id)| id).collect();
+  let mut infos: HashMap<String, PurchaseInfoForm> = HashMap::new();
+  if purchase_ids.len() > 0 {
+    let mut page = services
+      .purchase
+      .purchase_get_info_bulk(proto::purchase::PurchaseBulkRequest {
+        purchase_ids: purchase_ids.clone(),
+      })
+      .await
+      .map_err(|e| ApiError::from(e))?
+      .into_inner();
+    while let Some(pinfo) = page.message().await.map_err(|e| ApiError::from(e))? {
+      let info = PurchaseInfoForm::from(pinfo).with_late_payments(&services);
+      infos.insert(info.purchase_id.clone(), info);
+    }
+  }
+
+  Ok(reply::json(&PurchasePageForm {
+    purchases: purchase_ids
+      .iter()
+      .filter_map(|id| infos.remove(id))
+      .collect(),
+    next_cursor,
+    total,
+  }))
+}
+
 #[derive(Serialize, Deserialize, Debug)]
 pub struct PurchaseIdForm {
   purchase_id: String,
@@ -386,7 +971,32 @@ pub struct PurchaseIdForm {
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct PdfBase64Form {
-  pdf_base64: String,
+  pub pdf_base64: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+pub enum ReceiptFormatForm {
+  Pdf,
+  EscPos58,
+  EscPos80,
+}
+
+impl Default for ReceiptFormatForm {
+  fn default() -> Self {
+    ReceiptFormatForm::Pdf
+  }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ReceiptQueryForm {
+  purchase_id: String,
+  #[serde(default)]
+  format: ReceiptFormatForm,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct EscPosBase64Form {
+  pub escpos_base64: String,
 }
 
 pub async fn purchase_info_get_by_id(
@@ -403,10 +1013,200 @@ pub async fn purchase_info_get_by_id(
     .map_err(|e| ApiError::from(e))?
     .into_inner()
     .into();
-  let info: PurchaseInfoForm = res.into();
+  let info: PurchaseInfoForm = res
+    .with_promotions(&services)
+    .with_late_payments(&services)
+    .into();
   Ok(reply::json(&info))
 }
 
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ReorderForm {
+  // Store of the new cart
+  store_id: u32,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ReorderLineForm {
+  sku: Option<u32>,
+  name: String,
+  piece: u32,
+  previous_price_gross: u32,
+  current_price_gross: Option<u32>,
+  available_piece: Option<u32>,
+  discontinued: bool,
+  in_stock: bool,
+  added: bool,
+  error: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ReorderResultForm {
+  purchase_id: String,
+  lines: Vec<ReorderLineForm>,
+  cart: CartForm,
+}
+
+// Add reordered item to cart with its current price
+// Discontinued SKUs are not added, missing stock is only reported
+async fn reorder_sku(
+  services: &mut Services,
+  cart_id: String,
+  store_id: u32,
+  sku_id: u32,
+  item: &ItemForm,
+) -> Result<ReorderLineForm, ApiError> {
+  let sku_obj = services
+    .product
+    .get_sku(GetSkuRequest { sku_id })
+    .await
+    .map_err(|e| ApiError::from(e))?
+    .into_inner();
+
+  let mut line = ReorderLineForm {
+    sku: Some(sku_id),
+    name: item.name.clone(),
+    piece: item.piece,
+    previous_price_gross: item.retail_price_gross,
+    current_price_gross: None,
+    available_piece: None,
+    discontinued: sku_obj.discontinued,
+    in_stock: false,
+    added: false,
+    error: None,
+  };
+
+  if sku_obj.discontinued {
+    return Ok(line);
+  }
+
+  let price = services
+    .pricing
+    .get_price(GetPriceRequest { sku: sku_id })
+    .await
+    .map_err(|e| ApiError::from(e))?
+    .into_inner();
+  let available_piece = sku_stock(services, sku_id, store_id).await?;
+
+  check_sku_stock(services, &cart_id, sku_id, item.piece, false).await?;
+  add_sku_priced(services, cart_id, &sku_obj, &price, item.piece).await?;
+
+  line.current_price_gross = Some(price.price_gross_retail);
+  line.available_piece = Some(available_piece);
+  line.in_stock = available_piece >= item.piece;
+  line.added = true;
+  Ok(line)
+}
+
+// Add customer and loyalty card of the purchase to the new cart
+async fn reorder_customer(
+  services: &mut Services,
+  cart_id: &str,
+  purchase: &PurchaseForm,
+) -> Result<(), ApiError> {
+  if let Some(customer) = &purchase.customer {
+    add_customer(services, cart_id.to_string(), customer.id).await?;
+  }
+  if let Some(lc) = &purchase.loyalty_card {
+    add_loyalty_card(services, cart_id.to_string(), lc.card_id.clone()).await?;
+  }
+  Ok(())
+}
+
+pub async fn purchase_reorder(
+  purchase_id: String,
+  uid: u32,
+  mut services: Services,
+  f: ReorderForm,
+) -> ApiResult {
+  let purchase: PurchaseForm = services
+    .purchase
+    .purchase_get_by_id(PurchaseByIdRequest { purchase_id })
+    .await
+    .map_err(|e| ApiError::from(e))?
+    .into_inner()
+    .into();
+
+  // Create new cart in the caller's store
+  let cart: CartForm = services
+    .purchase
+    .cart_new(CartNewRequest {
+      store_id: f.store_id,
+      owner_id: uid,
+      created_by: uid,
+    })
+    .await
+    .map_err(|e| ApiError::from(e))?
+    .into_inner()
+    .try_into()?;
+
+  if let Err(e) = reorder_customer(&mut services, &cart.id, &purchase).await {
+    // Drop the new cart, it would be left behind
+    let _ = services
+      .purchase
+      .cart_remove(CartRemoveRequest {
+        cart_id: cart.id.clone(),
+      })
+      .await;
+    return Err(e.into());
+  }
+
+  let mut lines: Vec<ReorderLineForm> = Vec::new();
+  for item in &purchase.items {
+    let sku_id = match purchase.item_sku(item) {
+      Some(s) => s,
+      // Derived products are unique, they cannot be reordered
+      None => {
+        lines.push(ReorderLineForm {
+          sku: None,
+          name: item.name.clone(),
+          piece: item.piece,
+          previous_price_gross: item.retail_price_gross,
+          current_price_gross: None,
+          available_piece: None,
+          discontinued: false,
+          in_stock: false,
+          added: false,
+          error: Some("Egyedi termék, nem rendelhető újra.".to_string()),
+        });
+        continue;
+      }
+    };
+    let line = match reorder_sku(&mut services, cart.id.clone(), f.store_id, sku_id, item).await {
+      Ok(l) => l,
+      Err(e) => ReorderLineForm {
+        sku: Some(sku_id),
+        name: item.name.clone(),
+        piece: item.piece,
+        previous_price_gross: item.retail_price_gross,
+        current_price_gross: None,
+        available_piece: None,
+        discontinued: false,
+        in_stock: false,
+        added: false,
+        error: Some(e.message()),
+      },
+    };
+    lines.push(line);
+  }
+
+  // Query final cart state
+  let cart: CartForm = services
+    .purchase
+    .cart_get_by_id(CartByIdRequest { cart_id: cart.id })
+    .await
+    .map_err(|e| ApiError::from(e))?
+    .into_inner()
+    .try_into()?;
+  let cart = apply_promotions(&mut services, cart).await?;
+
+  Ok(reply::json(&ReorderResultForm {
+    purchase_id: purchase.purchase_id,
+    lines,
+    cart,
+  }))
+}
+
 pub async fn purchase_get_by_id(
   purchase_id: String,
   _uid: u32,
@@ -419,7 +1219,15 @@ pub async fn purchase_get_by_id(
     .map_err(|e| ApiError::from(e))?
     .into_inner()
     .into();
-  Ok(reply::json(&res))
+  Ok(reply::json(
+    &res
+      .with_discounts(&services)
+      .with_vouchers(&services)
+      .with_promotions(&services)
+      .with_late_payments(&services)
+      .with_returns(&services)
+      .with_email_sends(&services),
+  ))
 }
 
 pub async fn purchase_get_all(_uid: u32, mut services: Services) -> ApiResult {
@@ -443,32 +1251,49 @@ pub async fn get_bulk(_uid: u32, mut services: Services, purchase_ids: Vec<Strin
 
   let mut result: Vec<PurchaseInfoForm> = Vec::new();
   while let Some(pinfo) = all.message().await.map_err(|e| ApiError::from(e))? {
-    result.push(pinfo.into());
+    result.push(PurchaseInfoForm::from(pinfo).with_late_payments(&services));
   }
   Ok(reply::json(&result))
 }
 
-pub async fn get_receipt(_uid: u32, mut services: Services, f: PurchaseIdForm) -> ApiResult {
-  let res: PurchaseForm = services
-    .purchase
-    .purchase_get_by_id(PurchaseByIdRequest {
-      purchase_id: f.purchase_id,
-    })
-    .await
-    .map_err(|e| ApiError::from(e))?
-    .into_inner()
-    .into();
+/// Document branding of the store the purchase was made in
+pub fn purchase_branding(services: &Services, purchase: &PurchaseForm) -> crate::receipt::Branding {
+  super::stock::store_branding(services, purchase.store_id)
+}
 
-  let receipt = crate::receipt::Receipt::new(
-    res.purchase_id,
+/// Receipt of a purchase
+/// Purchase must have its discounts, vouchers and promotions attached
+pub fn purchase_receipt(
+  services: &Services,
+  res: &PurchaseForm,
+  branding: crate::receipt::Branding,
+) -> crate::receipt::Receipt {
+  crate::receipt::Receipt::new(
+    res.purchase_id.clone(),
+    // Voucher line is printed voucher by voucher
     res
       .items
       .iter()
+      .filter(|i| !i.is_voucher())
       .map(|i| crate::receipt::Item {
         sku: "-".to_string(),
         name: i.name.clone(),
         piece: i.piece,
         gross_price_total: i.total_retail_price_gross,
+        has_discount: res.item_discount(i).is_some() || res.item_promotion(i).is_some(),
+        discount: match (res.item_discount(i), res.item_promotion(i)) {
+          (Some(d), _) => format!(
+            "-{}% ({}), -{} HUF/db",
+            d.percentage(),
+            d.reason,
+            d.unit_discount_gross()
+          ),
+          (None, Some(p)) => format!(
+            "akciós ár, -{} HUF/db",
+            p.original_price_gross - p.price_gross
+          ),
+          (None, None) => "".to_string(),
+        },
       })
       .collect(),
     res.total_gross_price as i32,
@@ -500,15 +1325,45 @@ pub async fn get_receipt(_uid: u32, mut services: Services, f: PurchaseIdForm) -
       None => 0,
     },
     res.total_gross_price as i32,
+    res
+      .promotions
+      .iter()
+      .map(|p| crate::receipt::Promotion {
+        name: p.name.clone(),
+        discount: p.discount_gross,
+      })
+      .collect(),
+    res.payable,
+    res.cash_rounding,
+    res
+      .vouchers
+      .iter()
+      .map(|v| crate::receipt::Voucher {
+        code: v.code.clone(),
+        amount: v.value as u32,
+      })
+      .collect(),
+    voucher::cart_redemptions(&services, &res.purchase_id)
+      .into_iter()
+      .map(|(code, r)| crate::receipt::Voucher {
+        code,
+        amount: r.amount as u32,
+      })
+      .collect(),
     DateTime::parse_from_rfc3339(&res.created_at)
       .unwrap()
       .with_timezone(&Utc),
-  );
+    branding,
+  )
+}
 
+/// Receipt rendered into PDF by the latex service
+pub async fn receipt_pdf(
+  services: &mut Services,
+  receipt: &crate::receipt::Receipt,
+) -> Result<Vec<u8>, ApiError> {
   let template = receipt.to_latex();
 
-  let icon_bytes = include_bytes!("../../static/icon.jpg");
-
   // Call latex service
   let result = services
     .latex
@@ -516,17 +1371,71 @@ pub async fn get_receipt(_uid: u32, mut services: Services, f: PurchaseIdForm) -
       main_latex_file: template.as_bytes().to_owned(),
       attachments: {
         let mut files: HashMap<String, Vec<u8>> = HashMap::new();
-        files.insert("logo.jpg".to_string(), icon_bytes.to_vec());
+        files.insert("logo.jpg".to_string(), receipt.logo().to_vec());
         files
       },
     })
     .await
     .map_err(|e| ApiError::bad_request("Hiba a latex szerviztől"))?
     .into_inner();
+  Ok(result.content)
+}
+
+pub async fn get_receipt(_uid: u32, mut services: Services, f: ReceiptQueryForm) -> ApiResult {
+  let res: PurchaseForm = services
+    .purchase
+    .purchase_get_by_id(PurchaseByIdRequest {
+      purchase_id: f.purchase_id,
+    })
+    .await
+    .map_err(|e| ApiError::from(e))?
+    .into_inner()
+    .into();
+  let res = res
+    .with_discounts(&services)
+    .with_vouchers(&services)
+    .with_promotions(&services);
+
+  let branding = purchase_branding(&services, &res);
+  let receipt = purchase_receipt(&services, &res, branding);
+
+  // Thermal printers get the raw byte stream
+  let paper = match f.format {
+    ReceiptFormatForm::Pdf => None,
+    ReceiptFormatForm::EscPos58 => Some(crate::receipt::PaperWidth::Mm58),
+    ReceiptFormatForm::EscPos80 => Some(crate::receipt::PaperWidth::Mm80),
+  };
+  if let Some(paper) = paper {
+    return Ok(reply::json(&EscPosBase64Form {
+      escpos_base64: base64::encode(receipt.to_escpos(paper)),
+    }));
+  }
 
   let res: PdfBase64Form = PdfBase64Form {
-    pdf_base64: base64::encode(result.content),
+    pdf_base64: base64::encode(receipt_pdf(&mut services, &receipt).await?),
   };
 
   Ok(reply::json(&res))
 }
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_cursor() {
+    let cursor = encode_cursor(1609459200, "P_1:2");
+    assert_eq!(
+      decode_cursor(&cursor).unwrap(),
+      (1609459200, "P_1:2".to_string())
+    );
+    let cursor = encode_cursor(-1500, "P_2");
+    assert_eq!(decode_cursor(&cursor).unwrap(), (-1500, "P_2".to_string()));
+    // Not base64
+    assert!(decode_cursor("???").is_err());
+    // Missing purchase ID
+    assert!(decode_cursor(&base64::encode("1609459200")).is_err());
+    // Invalid sort key
+    assert!(decode_cursor(&base64::encode("abc:P_1")).is_err());
+  }
+}
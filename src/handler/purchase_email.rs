@@ -0,0 +1,326 @@
+use std::{env, fs};
+
+use crate::{prelude::*, receipt::Branding, services::Services, signature};
+use chrono::{DateTime, Duration, Local, Utc};
+use gzlib::proto::{customer::GetByIdRequest, email::EmailRequest, purchase::PurchaseByIdRequest};
+use serde::{Deserialize, Serialize};
+use tinytemplate::{format_unescaped, TinyTemplate};
+use warp::reply;
+
+use super::{
+  invoice::branded_invoice_pdf,
+  purchase::{purchase_branding, purchase_receipt, receipt_pdf, PurchaseForm},
+};
+
+// Email body template file, the built in one is used if not set
+const TEMPLATE_FILE_ENV_KEY: &'static str = "PURCHASE_EMAIL_TEMPLATE";
+// Public API address used in download links, e.g. https://api.example.com
+const PUBLIC_URL_ENV_KEY: &'static str = "API_PUBLIC_URL";
+// Download link validity
+const LINK_VALID_DAYS_ENV_KEY: &'static str = "PURCHASE_DOCUMENT_LINK_DAYS";
+
+// Email service cannot carry attachments,
+// so the document is sent as a signed download link
+const DEFAULT_TEMPLATE: &'static str = "Tisztelt {customer_name}!
+
+Köszönjük, hogy nálunk vásárolt. A {document_name} az alábbi linken töltheti le:
+
+{document_link}
+
+A link {link_valid_until}-ig érvényes.
+
+Vásárlás azonosító: {purchase_id}
+Dátum: {date}
+Végösszeg: {total_gross} Ft
+
+Üdvözlettel:
+{store_name}
+{store_address}
+";
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum SendDocumentForm {
+  Receipt,
+  Invoice,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct PurchaseSendForm {
+  // Customer email is used if not given
+  email: Option<String>,
+  // Invoice if the purchase has one, receipt otherwise
+  document: Option<SendDocumentForm>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct DocumentLinkQuery {
+  document: SendDocumentForm,
+  token: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub enum EmailSendStatusForm {
+  Sent,
+  Failed { error: String },
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct EmailSendForm {
+  pub to: String,
+  pub document: SendDocumentForm,
+  pub status: EmailSendStatusForm,
+  pub sent_by: u32,
+  pub sent_at: DateTime<Utc>,
+}
+
+/// Documents emailed from a purchase
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct PurchaseEmailsForm {
+  pub purchase_id: String,
+  pub sends: Vec<EmailSendForm>,
+}
+
+// Email body context
+#[derive(Serialize)]
+struct EmailContext {
+  customer_name: String,
+  document_name: String,
+  purchase_id: String,
+  invoice_id: String,
+  date: String,
+  total_gross: i32,
+  document_link: String,
+  link_valid_until: String,
+  store_name: String,
+  store_address: String,
+}
+
+/// Email sends of a purchase
+pub fn purchase_email_sends(services: &Services, purchase_id: &str) -> Vec<EmailSendForm> {
+  match services.purchase_emails.get(purchase_id) {
+    Some(e) => e.sends,
+    None => Vec::new(),
+  }
+}
+
+fn record_send(
+  services: &Services,
+  purchase_id: &str,
+  send: EmailSendForm,
+) -> Result<(), ApiError> {
+  let mut sends = purchase_email_sends(services, purchase_id);
+  sends.push(send);
+  services.purchase_emails.insert(
+    purchase_id,
+    PurchaseEmailsForm {
+      purchase_id: purchase_id.to_string(),
+      sends,
+    },
+  )?;
+  Ok(())
+}
+
+// Render email body from the configured template
+fn email_body(context: &EmailContext) -> Result<String, ApiError> {
+  let template = match env::var(TEMPLATE_FILE_ENV_KEY) {
+    Ok(path) => fs::read_to_string(&path)
+      .map_err(|_| ApiError::internal_error("Az e-mail sablon nem olvasható!"))?,
+    Err(_) => DEFAULT_TEMPLATE.to_string(),
+  };
+  let mut tt = TinyTemplate::new();
+  tt.set_default_formatter(&format_unescaped);
+  tt.add_template("email", &template)
+    .map_err(|_| ApiError::internal_error("Hibás e-mail sablon!"))?;
+  tt.render("email", context)
+    .map_err(|_| ApiError::internal_error("Hibás e-mail sablon!"))
+}
+
+// Download link token payload
+fn link_payload(purchase_id: &str, document: SendDocumentForm) -> String {
+  format!("purchase_document:{}:{:?}", purchase_id, document)
+}
+
+// Signed download link of a purchase document with its expiry
+fn document_link(
+  purchase_id: &str,
+  document: SendDocumentForm,
+) -> Result<(String, DateTime<Utc>), ApiError> {
+  let base = env::var(PUBLIC_URL_ENV_KEY)
+    .map_err(|_| ApiError::internal_error("Nincs beállítva a nyilvános API cím!"))?;
+  let days = env::var(LINK_VALID_DAYS_ENV_KEY)
+    .ok()
+    .and_then(|v| v.parse::<i64>().ok())
+    .unwrap_or(30);
+  let valid_until = Utc::now() + Duration::days(days);
+  let token = signature::create_token(&link_payload(purchase_id, document), valid_until);
+  Ok((
+    format!(
+      "{}/purchase/document/{}?document={:?}&token={}",
+      base.trim_end_matches('/'),
+      purchase_id,
+      document,
+      token
+    ),
+    valid_until,
+  ))
+}
+
+// Purchase with its discounts, vouchers and promotions
+async fn get_purchase(
+  services: &mut Services,
+  purchase_id: String,
+) -> Result<PurchaseForm, ApiError> {
+  let purchase: PurchaseForm = services
+    .purchase
+    .purchase_get_by_id(PurchaseByIdRequest { purchase_id })
+    .await
+    .map_err(|e| ApiError::from(e))?
+    .into_inner()
+    .into();
+  Ok(
+    purchase
+      .with_discounts(&services)
+      .with_vouchers(&services)
+      .with_promotions(&services),
+  )
+}
+
+// Document PDF with its file name
+async fn document_pdf(
+  services: &mut Services,
+  purchase: &PurchaseForm,
+  document: SendDocumentForm,
+  branding: Branding,
+) -> Result<(String, Vec<u8>), ApiError> {
+  match document {
+    SendDocumentForm::Receipt => {
+      let receipt = purchase_receipt(services, purchase, branding);
+      let pdf = receipt_pdf(services, &receipt).await?;
+      Ok((format!("nyugta_{}.pdf", purchase.purchase_id), pdf))
+    }
+    SendDocumentForm::Invoice => {
+      let pdf = branded_invoice_pdf(services, &purchase.invoice_id, &branding).await?;
+      Ok((format!("szamla_{}.pdf", purchase.purchase_id), pdf))
+    }
+  }
+}
+
+pub async fn send(
+  purchase_id: String,
+  uid: u32,
+  mut services: Services,
+  f: PurchaseSendForm,
+) -> ApiResult {
+  let purchase = get_purchase(&mut services, purchase_id).await?;
+
+  let document = match f.document {
+    Some(d) => d,
+    None if purchase.invoice_id.len() > 0 => SendDocumentForm::Invoice,
+    None => SendDocumentForm::Receipt,
+  };
+  if document == SendDocumentForm::Invoice && purchase.invoice_id.len() == 0 {
+    return Err(ApiError::bad_request("A vásárláshoz nem tartozik számla!").into());
+  }
+
+  // Given address, or the one stored for the customer
+  let to = match &f.email {
+    Some(email) => email.trim().to_string(),
+    None => match &purchase.customer {
+      Some(c) => services
+        .customer
+        .get_by_id(GetByIdRequest { customer_id: c.id })
+        .await
+        .map_err(|e| ApiError::from(e))?
+        .into_inner()
+        .email
+        .trim()
+        .to_string(),
+      None => String::new(),
+    },
+  };
+  if to.len() == 0 {
+    return Err(ApiError::bad_request("Nincs megadva e-mail cím!").into());
+  }
+  if !to.contains('@') {
+    return Err(ApiError::bad_request("Hibás e-mail cím!").into());
+  }
+
+  let branding = purchase_branding(&services, &purchase);
+  let (link, valid_until) = document_link(&purchase.purchase_id, document)?;
+  let body = email_body(&EmailContext {
+    customer_name: match &purchase.customer {
+      Some(c) => c.name.clone(),
+      None => "Vásárlónk".to_string(),
+    },
+    document_name: match document {
+      SendDocumentForm::Receipt => "nyugtamellékletet".to_string(),
+      SendDocumentForm::Invoice => "számlát".to_string(),
+    },
+    document_link: link,
+    link_valid_until: valid_until
+      .with_timezone(&Local)
+      .format("%Y-%m-%d")
+      .to_string(),
+    purchase_id: purchase.purchase_id.clone(),
+    invoice_id: purchase.invoice_id.clone(),
+    date: DateTime::parse_from_rfc3339(&purchase.created_at)
+      .map(|d| d.with_timezone(&Local).format("%Y-%m-%d %H:%M").to_string())
+      .unwrap_or(purchase.created_at.clone()),
+    total_gross: purchase.payable + purchase.cash_rounding,
+    store_name: branding.name.clone(),
+    store_address: branding.address.clone(),
+  })?;
+  let sent = services
+    .email
+    .send_email(EmailRequest {
+      to: to.clone(),
+      subject: match document {
+        SendDocumentForm::Receipt => format!("Nyugtamelléklet - {}", purchase.purchase_id),
+        SendDocumentForm::Invoice => format!("Számla - {}", purchase.purchase_id),
+      },
+      body,
+    })
+    .await;
+
+  let send = EmailSendForm {
+    to,
+    document,
+    status: match &sent {
+      Ok(_) => EmailSendStatusForm::Sent,
+      Err(e) => EmailSendStatusForm::Failed {
+        error: e.message().to_string(),
+      },
+    },
+    sent_by: uid,
+    sent_at: Utc::now(),
+  };
+  record_send(&services, &purchase.purchase_id, send.clone())?;
+
+  if let Err(e) = sent {
+    return Err(ApiError::from(e).into());
+  }
+  Ok(reply::json(&send))
+}
+
+/// Document download from an emailed link
+/// Not authenticated, the signed token grants access to the one document
+pub async fn download(
+  purchase_id: String,
+  q: DocumentLinkQuery,
+  mut services: Services,
+) -> Result<impl warp::Reply, warp::Rejection> {
+  if !signature::verify_token(&link_payload(&purchase_id, q.document), &q.token) {
+    return Err(ApiError::unauthorized().into());
+  }
+  let purchase = get_purchase(&mut services, purchase_id).await?;
+  if q.document == SendDocumentForm::Invoice && purchase.invoice_id.len() == 0 {
+    return Err(ApiError::not_found().into());
+  }
+  let branding = purchase_branding(&services, &purchase);
+  let (file_name, pdf) = document_pdf(&mut services, &purchase, q.document, branding).await?;
+  Ok(reply::with_header(
+    reply::with_header(pdf, "Content-Type", "application/pdf"),
+    "Content-Disposition",
+    format!("attachment; filename=\"{}\"", file_name),
+  ))
+}
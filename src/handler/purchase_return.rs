@@ -0,0 +1,841 @@
+use std::collections::HashMap;
+
+use crate::{prelude::*, services::Services};
+use chrono::{DateTime, Utc};
+use gzlib::proto::{
+  self,
+  cash::{NewTransaction, TransactionKind},
+  invoice::{invoice_form::Item, InvoiceForm},
+  loyalty::BurnRequest,
+  purchase::{PurchaseByIdRequest, PurchaseRestoreRequest},
+  upl::{upl_obj, ByIdRequest, DepreciationRequest, UplNew},
+};
+use serde::{Deserialize, Serialize};
+use warp::reply;
+
+use super::{
+  cart::{cash_rounding, CloseStepStatus, PaymentKindForm, UplKindForm},
+  purchase::PurchaseForm,
+};
+
+/// What happens with a returned UPL
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum ReturnDispositionForm {
+  // Goes back to the store stock
+  Restock,
+  // Damaged, goes back to the store stock depreciated
+  Discard { depreciation_id: u32 },
+}
+
+/// Selected UPL or item to return
+/// UPL ID or product ID is required, piece defaults to all not yet returned
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ReturnSelectForm {
+  upl_id: Option<String>,
+  product_id: Option<u32>,
+  piece: Option<u32>,
+  #[serde(default)]
+  damaged: bool,
+  // Depreciation reason, required for damaged goods
+  depreciation_id: Option<u32>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct PurchaseReturnNewForm {
+  // Store where the goods are returned
+  store_id: u32,
+  reason: String,
+  lines: Vec<ReturnSelectForm>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ReturnLineForm {
+  pub upl_id: String,
+  pub product_id: u32,
+  pub name: String,
+  pub piece: u32,
+  pub vat: String,
+  pub total_price_net: u32,
+  pub total_price_gross: u32,
+  pub disposition: ReturnDispositionForm,
+  // UPL the returned goods are put into the stock with
+  #[serde(default)]
+  pub restocked_upl_id: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum ReturnStep {
+  CashRefund,
+  UplRestock,
+  PurchaseRestore,
+  CreditNote,
+  LoyaltyReversal,
+  CommitmentAdjustment,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ReturnStepReport {
+  step: ReturnStep,
+  status: CloseStepStatus,
+}
+
+/// Returned value to take off the customer's commitment
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct CommitmentAdjustmentForm {
+  pub customer_id: u32,
+  pub commitment_id: String,
+  pub total_net: i32,
+  pub total_gross: i32,
+}
+
+/// Processed purchase return
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct PurchaseReturnForm {
+  pub return_id: String,
+  pub purchase_id: String,
+  pub store_id: u32,
+  pub reason: String,
+  pub lines: Vec<ReturnLineForm>,
+  // Returned goods value at purchase prices
+  pub goods_gross: i32,
+  // Proportional commitment discount
+  pub discount_gross: i32,
+  // Earned minus burned points of the returned goods, taken off the account
+  pub loyalty_points_reversed: i32,
+  // Burned points over the earned ones, given back in money
+  pub loyalty_points_refunded: i32,
+  pub refund_amount: i32,
+  // Unpaid balance taken off instead of refunding
+  pub receivable_reduction: i32,
+  pub refund_transaction_id: Option<String>,
+  pub credit_note_id: Option<String>,
+  pub commitment_adjustment: Option<CommitmentAdjustmentForm>,
+  pub steps: Vec<ReturnStepReport>,
+  pub created_by: u32,
+  pub created_at: DateTime<Utc>,
+}
+
+impl PurchaseReturnForm {
+  fn done(&mut self, step: ReturnStep) {
+    self.steps.push(ReturnStepReport {
+      step,
+      status: CloseStepStatus::Done,
+    });
+  }
+
+  fn skipped(&mut self, step: ReturnStep) {
+    self.steps.push(ReturnStepReport {
+      step,
+      status: CloseStepStatus::Skipped,
+    });
+  }
+
+  fn failed(&mut self, step: ReturnStep, error: String) {
+    eprintln!(
+      "Return step {:?} failed for return {}: {}",
+      step, self.return_id, error
+    );
+    self.steps.push(ReturnStepReport {
+      step,
+      status: CloseStepStatus::Failed { error },
+    });
+  }
+}
+
+/// Returns of a purchase
+pub fn purchase_returns(services: &Services, purchase_id: &str) -> Vec<PurchaseReturnForm> {
+  let mut res = services
+    .purchase_returns
+    .filter(|r| r.purchase_id == purchase_id);
+  res.sort_by_key(|r| r.created_at);
+  res
+}
+
+/// Already returned pieces by UPL ID
+pub fn returned_pieces(services: &Services, purchase_id: &str) -> HashMap<String, u32> {
+  count_returned(purchase_returns(services, purchase_id).iter())
+}
+
+/// Unpaid balance of a purchase taken off by its returns
+pub fn receivable_reduction(services: &Services, purchase_id: &str) -> i32 {
+  purchase_returns(services, purchase_id)
+    .iter()
+    .map(|r| r.receivable_reduction)
+    .sum()
+}
+
+// Returned pieces of the given returns by UPL ID
+fn count_returned<'a>(
+  returns: impl Iterator<Item = &'a PurchaseReturnForm>,
+) -> HashMap<String, u32> {
+  let mut res: HashMap<String, u32> = HashMap::new();
+  for r in returns {
+    for l in &r.lines {
+      *res.entry(l.upl_id.clone()).or_insert(0) += l.piece;
+    }
+  }
+  res
+}
+
+// Every purchased UPL is returned in full
+fn fully_returned(purchase: &PurchaseForm, returned: &HashMap<String, u32>) -> bool {
+  purchase
+    .upl_info_objects
+    .iter()
+    .all(|u| returned.get(&u.upl_id).cloned().unwrap_or(0) >= upl_piece(purchase, &u.upl_id))
+}
+
+// Piece of a purchased UPL
+fn upl_piece(purchase: &PurchaseForm, upl_id: &str) -> u32 {
+  purchase
+    .upl_info_objects
+    .iter()
+    .find(|u| u.upl_id == upl_id)
+    .map(|u| match u.kind {
+      UplKindForm::Sku { piece, .. } => piece,
+      UplKindForm::OpenedSku { .. } => 1,
+    })
+    .unwrap_or(1)
+}
+
+// Resolve selections into returned UPL lines at purchase prices
+fn return_lines(
+  purchase: &PurchaseForm,
+  returned: &HashMap<String, u32>,
+  selected: &[ReturnSelectForm],
+) -> Result<Vec<ReturnLineForm>, ApiError> {
+  let mut taken: HashMap<String, u32> = HashMap::new();
+  let mut res: Vec<ReturnLineForm> = Vec::new();
+
+  for s in selected {
+    let disposition = match (s.damaged, s.depreciation_id) {
+      (true, Some(depreciation_id)) => ReturnDispositionForm::Discard { depreciation_id },
+      (true, None) => {
+        return Err(ApiError::bad_request(
+          "Sérült terméknél a selejtezés okának megadása kötelező!",
+        ))
+      }
+      (false, _) => ReturnDispositionForm::Restock,
+    };
+    let upl_ids: Vec<String> = match (&s.upl_id, s.product_id) {
+      (Some(upl_id), _) => vec![upl_id.clone()],
+      (None, Some(product_id)) => purchase
+        .items
+        .iter()
+        .filter(|i| i.product_id == product_id)
+        .flat_map(|i| i.upl_ids.clone())
+        .collect(),
+      (None, None) => {
+        return Err(ApiError::bad_request(
+          "UPL azonosító vagy termék azonosító megadása kötelező!",
+        ))
+      }
+    };
+    if upl_ids.len() == 0 {
+      return Err(ApiError::bad_request(
+        "A termék nem szerepel a vásárlásban!",
+      ));
+    }
+
+    let mut wanted = s.piece;
+    if wanted == Some(0) {
+      return Err(ApiError::bad_request("A mennyiség nem lehet 0!"));
+    }
+    for upl_id in upl_ids {
+      let item = purchase
+        .items
+        .iter()
+        .find(|i| i.upl_ids.contains(&upl_id))
+        .ok_or(ApiError::bad_request(&format!(
+          "A UPL nem szerepel a vásárlásban! {}",
+          upl_id
+        )))?;
+      let left = upl_piece(purchase, &upl_id)
+        .saturating_sub(returned.get(&upl_id).cloned().unwrap_or(0))
+        .saturating_sub(taken.get(&upl_id).cloned().unwrap_or(0));
+      let piece = match wanted {
+        Some(w) => w.min(left),
+        None => left,
+      };
+      if piece == 0 {
+        continue;
+      }
+      *taken.entry(upl_id.clone()).or_insert(0) += piece;
+      if let Some(w) = wanted.as_mut() {
+        *w -= piece;
+      }
+      res.push(ReturnLineForm {
+        upl_id,
+        product_id: item.product_id,
+        name: item.name.clone(),
+        piece,
+        vat: item.vat.clone(),
+        total_price_net: item.retail_price_net * piece,
+        total_price_gross: item.retail_price_gross * piece,
+        disposition: disposition.clone(),
+        restocked_upl_id: None,
+      });
+      if wanted == Some(0) {
+        break;
+      }
+    }
+    match wanted {
+      Some(w) if w > 0 => {
+        return Err(ApiError::bad_request(
+          "A visszavenni kívánt mennyiség több a még vissza nem vett mennyiségnél!",
+        ))
+      }
+      _ => (),
+    }
+  }
+
+  if res.len() == 0 {
+    return Err(ApiError::bad_request("Nincs visszavehető tétel!"));
+  }
+  Ok(res)
+}
+
+// Split a gross amount by the VAT rates of the returned lines
+// Net keeps the net/gross ratio of the lines of the rate
+// Returns VAT, net and gross by rate
+fn split_by_vat(lines: &[ReturnLineForm], gross: i32) -> Vec<(String, i32, i32)> {
+  let mut rates: Vec<(String, i32, i32)> = Vec::new();
+  for l in lines {
+    match rates.iter_mut().find(|r| r.0 == l.vat) {
+      Some(r) => {
+        r.1 += l.total_price_net as i32;
+        r.2 += l.total_price_gross as i32;
+      }
+      None => rates.push((
+        l.vat.clone(),
+        l.total_price_net as i32,
+        l.total_price_gross as i32,
+      )),
+    }
+  }
+  let total: i32 = rates.iter().map(|r| r.2).sum();
+  let mut res: Vec<(String, i32, i32)> = Vec::new();
+  let mut left = gross;
+  for (n, (vat, rate_net, rate_gross)) in rates.iter().enumerate() {
+    let share = match (n == rates.len() - 1, total) {
+      (true, _) => left,
+      (false, 0) => 0,
+      (false, _) => (gross as f64 * *rate_gross as f64 / total as f64).round() as i32,
+    };
+    left -= share;
+    let net = match rate_gross {
+      0 => share,
+      _ => (share as f64 * *rate_net as f64 / *rate_gross as f64).round() as i32,
+    };
+    if share != 0 {
+      res.push((vat.clone(), net, share));
+    }
+  }
+  res
+}
+
+// Put a returned UPL back into the store stock
+// Sold UPLs are archived, so a new UPL is made from the archived one,
+// damaged goods are depreciated right away
+async fn restock_upl(
+  services: &mut Services,
+  r: &PurchaseReturnForm,
+  line: &ReturnLineForm,
+  new_upl_id: String,
+) -> Result<String, tonic::Status> {
+  let upl = services
+    .upl
+    .get_by_id_archive(ByIdRequest {
+      upl_id: line.upl_id.clone(),
+    })
+    .await?
+    .into_inner();
+  let is_opened = match upl.kind {
+    Some(upl_obj::Kind::OpenedSku(_)) => true,
+    _ => false,
+  };
+  services
+    .upl
+    .create_new(UplNew {
+      upl_id: new_upl_id.clone(),
+      product_id: upl.product_id,
+      product_unit: upl.product_unit,
+      sku: upl.sku_id,
+      piece: line.piece,
+      sku_divisible_amount: upl.sku_divisible_amount,
+      sku_divisible: upl.is_divisible,
+      sku_net_price: upl.price_net,
+      sku_vat: upl.vat,
+      sku_gross_price: upl.price_gross,
+      procurement_id: upl.procurement_id,
+      procurement_net_price_sku: upl.procurement_net_price_sku,
+      stock_id: r.store_id,
+      best_before: upl.best_before,
+      is_opened,
+      created_by: r.created_by,
+    })
+    .await?;
+  if let ReturnDispositionForm::Discard { depreciation_id } = line.disposition {
+    services
+      .upl
+      .set_depreciation(DepreciationRequest {
+        upl: new_upl_id.clone(),
+        depreciation_id,
+        depreciation_comment: format!("Visszáru: {}, {}", r.return_id, r.reason),
+        created_by: r.created_by,
+      })
+      .await?;
+  }
+  Ok(new_upl_id)
+}
+
+// Credit note of the returned lines
+// Based on the original invoice data, with negative item lines and
+// the proportional discounts written back
+fn credit_note(purchase: &PurchaseForm, r: &PurchaseReturnForm, burned_share: i32) -> InvoiceForm {
+  let comment = format!("Helyesbítés, eredeti számla: {}", purchase.invoice_id);
+  let mut items: Vec<Item> = r
+    .lines
+    .iter()
+    .map(|l| Item {
+      name: l.name.clone(),
+      quantity: -(l.piece as i32),
+      unit: "db".to_string(),
+      price_unit_net: (l.total_price_net / l.piece) as i32,
+      vat: l.vat.clone(),
+      total_price_net: -(l.total_price_net as i32),
+      total_price_vat: -(l.total_price_gross as i32 - l.total_price_net as i32),
+      total_price_gross: -(l.total_price_gross as i32),
+      comment: comment.clone(),
+    })
+    .collect();
+
+  let discounts = vec![
+    ("Kedvezmények arányos része", r.discount_gross),
+    ("Törzsvásárlói kedvezmény arányos része", burned_share),
+  ];
+  // One line per VAT rate, so the VAT base is corrected by rate
+  for (name, gross) in discounts {
+    for (vat, net, gross) in split_by_vat(&r.lines, gross) {
+      items.push(Item {
+        name: name.to_string(),
+        quantity: 1,
+        unit: "db".to_string(),
+        price_unit_net: net,
+        vat,
+        total_price_net: net,
+        total_price_vat: gross - net,
+        total_price_gross: gross,
+        comment: comment.clone(),
+      });
+    }
+  }
+
+  let mut res: InvoiceForm = purchase.clone().into();
+  res.purchase_id = r.return_id.clone();
+  res.total_net = items.iter().map(|i| i.total_price_net).sum();
+  res.total_vat = items.iter().map(|i| i.total_price_vat).sum();
+  res.total_gross = items.iter().map(|i| i.total_price_gross).sum();
+  res.items = items;
+  res.date = Utc::now().to_rfc3339();
+  res.completion_date = Utc::now().to_rfc3339();
+  res.created_by = r.created_by;
+  res
+}
+
+// Part of a purchase level value falling on the returned goods
+fn share(purchase: &PurchaseForm, goods_gross: i32, value: i32) -> i32 {
+  match purchase.total_gross_price {
+    0 => 0,
+    total => (value as f64 * goods_gross as f64 / total as f64).round() as i32,
+  }
+}
+
+// Points of the returned goods to reverse and to refund
+// Points cannot be credited to the account, so burned points over the
+// earned ones are given back in money (1 point is 1 HUF)
+fn points_share(purchase: &PurchaseForm, goods_gross: i32) -> (i32, i32) {
+  let burned_share = share(purchase, goods_gross, purchase.burned_loyalty_points as i32);
+  let earned_share = match &purchase.loyalty_card {
+    Some(lc) => share(purchase, goods_gross, lc.earned_points),
+    None => 0,
+  };
+  match earned_share - burned_share {
+    n if n >= 0 => (n, 0),
+    n => (0, -n),
+  }
+}
+
+// Return record of the resolved lines
+// Returned value pays the unpaid balance first, only the rest is refunded
+fn return_form(
+  purchase: &PurchaseForm,
+  f: &PurchaseReturnNewForm,
+  lines: Vec<ReturnLineForm>,
+  balance: i32,
+  uid: u32,
+) -> PurchaseReturnForm {
+  // Purchase level discounts are taken off proportionally
+  // Promotions are already in the line prices
+  let goods_gross: i32 = lines.iter().map(|l| l.total_price_gross as i32).sum();
+  let discount_gross = share(
+    purchase,
+    goods_gross,
+    purchase.commitment_discount_amount_gross as i32,
+  );
+  let burned_share = share(purchase, goods_gross, purchase.burned_loyalty_points as i32);
+  let (_, points_refunded) = points_share(purchase, goods_gross);
+  let value = goods_gross - discount_gross - burned_share + points_refunded;
+  let receivable_reduction = value.min(-balance).max(0);
+  let mut refund_amount = value - receivable_reduction;
+  if let PaymentKindForm::Cash = purchase.payment_kind {
+    refund_amount += cash_rounding(refund_amount);
+  }
+  PurchaseReturnForm {
+    return_id: String::new(),
+    purchase_id: purchase.purchase_id.clone(),
+    store_id: f.store_id,
+    reason: f.reason.trim().to_string(),
+    lines,
+    goods_gross,
+    discount_gross,
+    loyalty_points_reversed: 0,
+    loyalty_points_refunded: points_refunded,
+    refund_amount,
+    receivable_reduction,
+    refund_transaction_id: None,
+    credit_note_id: None,
+    commitment_adjustment: None,
+    steps: Vec::new(),
+    created_by: uid,
+    created_at: Utc::now(),
+  }
+}
+
+pub async fn new_return(
+  purchase_id: String,
+  uid: u32,
+  mut services: Services,
+  f: PurchaseReturnNewForm,
+) -> ApiResult {
+  super::user::require_role(&services, uid, super::user::RoleForm::Manager)?;
+
+  if f.reason.trim().len() == 0 {
+    return Err(ApiError::bad_request("A visszavét okának megadása kötelező!").into());
+  }
+
+  let purchase: PurchaseForm = services
+    .purchase
+    .purchase_get_by_id(PurchaseByIdRequest { purchase_id })
+    .await
+    .map_err(|e| ApiError::from(e))?
+    .into_inner()
+    .into();
+  let purchase = purchase
+    .with_discounts(&services)
+    .with_promotions(&services);
+
+  // Balance without earlier returns, those are counted under the lock
+  let balance = purchase.payment_balance
+    + super::receivable::unrecorded_payments(&services, &purchase.purchase_id);
+
+  // Lines are resolved and the return is recorded under the storage lock,
+  // so concurrent returns cannot take back the same pieces
+  let mut res = services.purchase_returns.insert_new(|returns| {
+    let previous: Vec<&PurchaseReturnForm> = returns
+      .values()
+      .filter(|r| r.purchase_id == purchase.purchase_id)
+      .collect();
+    let returned = count_returned(previous.iter().cloned());
+    let reduced: i32 = previous.iter().map(|r| r.receivable_reduction).sum();
+    let lines = return_lines(&purchase, &returned, &f.lines)?;
+    let mut res = return_form(&purchase, &f, lines, balance + reduced, uid);
+    res.return_id = format!("{}_R{}", purchase.purchase_id, previous.len() + 1);
+    Ok((res.return_id.clone(), res))
+  })?;
+  let refund_amount = res.refund_amount;
+  let goods_gross = res.goods_gross;
+  let goods_net: i32 = res.lines.iter().map(|l| l.total_price_net as i32).sum();
+  let burned_share = share(
+    &purchase,
+    goods_gross,
+    purchase.burned_loyalty_points as i32,
+  );
+  let (points_reversed, _) = points_share(&purchase, goods_gross);
+
+  // Refund; only the return record exists yet if it fails
+  if refund_amount > 0 {
+    match services
+      .cash
+      .create_transaction(NewTransaction {
+        kind: match purchase.payment_kind {
+          PaymentKindForm::Cash => TransactionKind::KindCash,
+          PaymentKindForm::Card => TransactionKind::KindCard,
+          PaymentKindForm::Transfer => TransactionKind::KindTransfer,
+        } as i32,
+        amount: -refund_amount,
+        reference: res.return_id.clone(),
+        comment: format!("Visszáru: {}", res.reason),
+        created_by: uid,
+        cart_id: Some(proto::cash::new_transaction::CartId::Cart(
+          purchase.purchase_id.clone(),
+        )),
+      })
+      .await
+    {
+      Ok(r) => {
+        res.refund_transaction_id = Some(r.into_inner().transaction_id);
+        res.done(ReturnStep::CashRefund);
+      }
+      Err(e) => {
+        services.purchase_returns.remove(&res.return_id)?;
+        return Err(ApiError::from(e).into());
+      }
+    }
+  } else {
+    res.skipped(ReturnStep::CashRefund);
+  }
+
+  // Put returned goods back into the store
+  let mut restock_errors: Vec<String> = Vec::new();
+  for n in 0..res.lines.len() {
+    let new_upl_id = format!("{}-{}", res.return_id, n + 1);
+    let line = res.lines[n].clone();
+    match restock_upl(&mut services, &res, &line, new_upl_id).await {
+      Ok(upl_id) => res.lines[n].restocked_upl_id = Some(upl_id),
+      Err(e) => restock_errors.push(format!("{}: {}", line.upl_id, e.message())),
+    }
+  }
+  match restock_errors.len() {
+    0 => res.done(ReturnStep::UplRestock),
+    _ => res.failed(ReturnStep::UplRestock, restock_errors.join(", ")),
+  }
+
+  // Mark purchase as restored once all of its goods are returned
+  let returned = returned_pieces(&services, &purchase.purchase_id);
+  if purchase.restored || !fully_returned(&purchase, &returned) {
+    res.skipped(ReturnStep::PurchaseRestore);
+  } else {
+    match services
+      .purchase
+      .purchase_restore(PurchaseRestoreRequest {
+        purchase_id: purchase.purchase_id.clone(),
+      })
+      .await
+    {
+      Ok(restored) => {
+        let restored = PurchaseForm::from(restored.into_inner());
+        if let Err(e) = super::purchase::index_purchase(&services, &restored) {
+          eprintln!("Could not index purchase {}: {:?}", restored.purchase_id, e);
+        }
+        res.done(ReturnStep::PurchaseRestore)
+      }
+      Err(e) => res.failed(ReturnStep::PurchaseRestore, e.message().to_string()),
+    }
+  }
+
+  // Credit note if the purchase had an invoice
+  if purchase.invoice_id.len() > 0 {
+    let req = credit_note(&purchase, &res, burned_share);
+    match services.invoice.create_new(req).await {
+      Ok(r) => {
+        res.credit_note_id = Some(r.into_inner().id);
+        res.done(ReturnStep::CreditNote);
+      }
+      Err(e) => res.failed(ReturnStep::CreditNote, e.message().to_string()),
+    }
+  } else {
+    res.skipped(ReturnStep::CreditNote);
+  }
+
+  // Take earned points of the returned goods off the account
+  match &purchase.loyalty_card {
+    Some(lc) if points_reversed > 0 => {
+      match services
+        .loyalty
+        .burn_points(BurnRequest {
+          account_id: lc.account_id.clone(),
+          purchase_id: res.return_id.clone(),
+          points_to_burn: points_reversed,
+          created_by: uid,
+        })
+        .await
+      {
+        Ok(_) => {
+          res.loyalty_points_reversed = points_reversed;
+          res.done(ReturnStep::LoyaltyReversal);
+        }
+        Err(e) => res.failed(ReturnStep::LoyaltyReversal, e.message().to_string()),
+      }
+    }
+    _ => res.skipped(ReturnStep::LoyaltyReversal),
+  }
+
+  // Commitment only takes purchases, so the adjustment is kept with the return
+  match &purchase.customer {
+    Some(customer) if purchase.commitment_id.len() > 0 => {
+      let commitment_share = share(
+        &purchase,
+        goods_gross,
+        purchase.commitment_discount_amount_gross as i32,
+      );
+      let commitment_share_net: i32 = split_by_vat(&res.lines, commitment_share)
+        .iter()
+        .map(|(_, net, _)| net)
+        .sum();
+      res.commitment_adjustment = Some(CommitmentAdjustmentForm {
+        customer_id: customer.id,
+        commitment_id: purchase.commitment_id.clone(),
+        total_net: -(goods_net - commitment_share_net),
+        total_gross: -(goods_gross - commitment_share),
+      });
+      res.done(ReturnStep::CommitmentAdjustment);
+    }
+    _ => res.skipped(ReturnStep::CommitmentAdjustment),
+  }
+
+  let res = services
+    .purchase_returns
+    .insert(&res.return_id, res.clone())?;
+  Ok(reply::json(&res))
+}
+
+pub async fn get_returns(purchase_id: String, _uid: u32, services: Services) -> ApiResult {
+  let res = purchase_returns(&services, &purchase_id);
+  Ok(reply::json(&res))
+}
+
+/// Returns taken in a store, with the disposition of their UPLs
+pub async fn get_store_returns(store_id: u32, _uid: u32, services: Services) -> ApiResult {
+  let mut res = services.purchase_returns.filter(|r| r.store_id == store_id);
+  res.sort_by_key(|r| r.created_at);
+  Ok(reply::json(&res))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::handler::{
+    cart::UplInfoForm,
+    purchase::{ItemForm, ItemKindForm},
+  };
+  use gzlib::proto::purchase::PurchaseObject;
+
+  // Purchase of a bulk UPL of 3 pieces and a single UPL of the same product
+  fn purchase() -> PurchaseForm {
+    let mut res = PurchaseForm::from(PurchaseObject::default());
+    res.items.push(ItemForm {
+      kind: ItemKindForm::Sku,
+      product_id: 7,
+      name: "Termék".to_string(),
+      piece: 4,
+      retail_price_net: 100,
+      vat: "27".to_string(),
+      retail_price_gross: 127,
+      total_retail_price_net: 400,
+      total_retail_price_gross: 508,
+      upl_ids: vec!["bulk".to_string(), "single".to_string()],
+    });
+    for (upl_id, piece) in vec![("bulk", 3), ("single", 1)] {
+      res.upl_info_objects.push(UplInfoForm {
+        upl_id: upl_id.to_string(),
+        kind: UplKindForm::Sku { sku: 1, piece },
+        name: "Termék".to_string(),
+        retail_price_net: 100,
+        vat: "27".to_string(),
+        retail_price_gross: 127,
+        procurement_net_price: 50,
+        best_before: "".to_string(),
+        depreciated: false,
+      });
+    }
+    res
+  }
+
+  fn select(upl_id: Option<&str>, product_id: Option<u32>, piece: Option<u32>) -> ReturnSelectForm {
+    ReturnSelectForm {
+      upl_id: upl_id.map(|u| u.to_string()),
+      product_id,
+      piece,
+      damaged: false,
+      depreciation_id: None,
+    }
+  }
+
+  #[test]
+  fn test_return_lines() {
+    let purchase = purchase();
+    let none: HashMap<String, u32> = HashMap::new();
+
+    // Whole product, every UPL
+    let lines = return_lines(&purchase, &none, &[select(None, Some(7), None)]).unwrap();
+    assert_eq!(lines.len(), 2);
+    assert_eq!(lines.iter().map(|l| l.piece).sum::<u32>(), 4);
+    assert_eq!(lines[0].total_price_gross, 381);
+
+    // Part of a bulk UPL
+    let lines = return_lines(&purchase, &none, &[select(Some("bulk"), None, Some(2))]).unwrap();
+    assert_eq!(lines.len(), 1);
+    assert_eq!(lines[0].piece, 2);
+    assert_eq!(lines[0].total_price_net, 200);
+
+    // Already returned pieces are left out
+    let mut returned: HashMap<String, u32> = HashMap::new();
+    returned.insert("bulk".to_string(), 3);
+    let lines = return_lines(&purchase, &returned, &[select(None, Some(7), None)]).unwrap();
+    assert_eq!(lines.len(), 1);
+    assert_eq!(lines[0].upl_id, "single");
+    assert!(return_lines(&purchase, &returned, &[select(Some("bulk"), None, None)]).is_err());
+
+    // More than left, unknown product, damaged without reason
+    assert!(return_lines(&purchase, &none, &[select(Some("bulk"), None, Some(4))]).is_err());
+    assert!(return_lines(&purchase, &none, &[select(None, Some(8), None)]).is_err());
+    let mut damaged = select(Some("single"), None, None);
+    damaged.damaged = true;
+    assert!(return_lines(&purchase, &none, &[damaged]).is_err());
+  }
+
+  #[test]
+  fn test_return_form() {
+    let mut purchase = purchase();
+    purchase.payment_kind = PaymentKindForm::Transfer;
+    purchase.total_gross_price = 508;
+    let f = PurchaseReturnNewForm {
+      store_id: 1,
+      reason: "Hibás".to_string(),
+      lines: vec![select(Some("single"), None, None)],
+    };
+    let lines = || return_lines(&purchase, &HashMap::new(), &f.lines).unwrap();
+
+    // Paid purchase is refunded
+    let res = return_form(&purchase, &f, lines(), 0, 1);
+    assert_eq!((res.refund_amount, res.receivable_reduction), (127, 0));
+
+    // Unpaid balance is reduced first
+    let res = return_form(&purchase, &f, lines(), -300, 1);
+    assert_eq!((res.refund_amount, res.receivable_reduction), (0, 127));
+    let res = return_form(&purchase, &f, lines(), -100, 1);
+    assert_eq!((res.refund_amount, res.receivable_reduction), (27, 100));
+  }
+
+  #[test]
+  fn test_split_by_vat() {
+    let line = |vat: &str, net: u32, gross: u32| ReturnLineForm {
+      upl_id: "u".to_string(),
+      product_id: 1,
+      name: "".to_string(),
+      piece: 1,
+      vat: vat.to_string(),
+      total_price_net: net,
+      total_price_gross: gross,
+      disposition: ReturnDispositionForm::Restock,
+      restocked_upl_id: None,
+    };
+    let lines = vec![line("27", 1000, 1270), line("5", 1000, 1050)];
+    let res = split_by_vat(&lines, -232);
+    assert_eq!(res.len(), 2);
+    assert_eq!(res.iter().map(|r| r.2).sum::<i32>(), -232);
+    assert_eq!(res[0], ("27".to_string(), -100, -127));
+    assert_eq!(res[1], ("5".to_string(), -100, -105));
+  }
+}
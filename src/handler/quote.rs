@@ -0,0 +1,476 @@
+use std::{collections::HashMap, convert::TryInto};
+
+use crate::{prelude::*, services::Services};
+use chrono::{DateTime, Local, NaiveDate, Utc};
+use gzlib::proto::{
+  latex::Content,
+  pricing::{GetPriceRequest, PriceObject},
+  product::GetSkuRequest,
+  purchase::{CartByIdRequest, CartNewRequest, CartRemoveRequest},
+};
+use serde::{Deserialize, Serialize};
+use warp::reply;
+
+use super::{
+  cart::{
+    add_customer, add_sku_priced, add_upl, apply_promotions, check_sku_stock, sku_stock, CartForm,
+    UplKindForm,
+  },
+  purchase::{CustomerForm, PdfBase64Form},
+};
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub enum QuoteLineKindForm {
+  Sku { sku: u32 },
+  Upl { upl_id: String },
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct QuoteLineForm {
+  pub kind: QuoteLineKindForm,
+  pub name: String,
+  pub piece: u32,
+  pub price_net: u32,
+  pub vat: String,
+  pub price_gross: u32,
+  pub total_price_gross: u32,
+}
+
+/// Price offer made from a cart
+/// Lines and prices are a snapshot of the cart at quote creation
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct QuoteForm {
+  pub quote_id: String,
+  pub cart_id: String,
+  pub store_id: u32,
+  pub customer: Option<CustomerForm>,
+  pub lines: Vec<QuoteLineForm>,
+  pub total_net: u32,
+  pub total_vat: u32,
+  pub total_gross: u32,
+  pub valid_until: NaiveDate,
+  pub note: String,
+  pub converted_cart_id: Option<String>,
+  pub converted_at: Option<DateTime<Utc>>,
+  pub created_by: u32,
+  pub created_at: DateTime<Utc>,
+}
+
+impl QuoteForm {
+  pub fn is_valid(&self) -> bool {
+    Local::today().naive_local() <= self.valid_until
+  }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct QuoteNewForm {
+  cart_id: String,
+  // YYYY-MM-DD
+  valid_until: String,
+  note: String,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct QuoteIdForm {
+  quote_id: String,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct QuoteToCartForm {
+  quote_id: String,
+  // Use quoted prices instead of current ones
+  // Only while the quote is valid
+  keep_quoted_prices: bool,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct QuoteToCartLineForm {
+  line: QuoteLineForm,
+  current_price_gross: Option<u32>,
+  price_changed: bool,
+  available_piece: Option<u32>,
+  in_stock: bool,
+  success: bool,
+  error: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct QuoteToCartResultForm {
+  quote_id: String,
+  lines: Vec<QuoteToCartLineForm>,
+  cart: CartForm,
+}
+
+// Next quote ID after the given ones, e.g. AJ20210503001
+fn next_quote_id<'a>(ids: impl Iterator<Item = &'a String>) -> String {
+  let prefix = format!("AJ{}", Local::today().format("%Y%m%d"));
+  let last = ids
+    .filter_map(|id| id.strip_prefix(&prefix))
+    .filter_map(|n| n.parse::<u32>().ok())
+    .max()
+    .unwrap_or(0);
+  format!("{}{:03}", prefix, last + 1)
+}
+
+pub async fn new_quote(uid: u32, mut services: Services, f: QuoteNewForm) -> ApiResult {
+  let valid_until = NaiveDate::parse_from_str(&f.valid_until, "%Y-%m-%d")
+    .map_err(|_| ApiError::bad_request("Hibás érvényességi dátum! (ÉÉÉÉ-HH-NN)"))?;
+  if valid_until < Local::today().naive_local() {
+    return Err(ApiError::bad_request("Az érvényességi dátum nem lehet a múltban!").into());
+  }
+
+  let cart: CartForm = services
+    .purchase
+    .cart_get_by_id(CartByIdRequest { cart_id: f.cart_id })
+    .await
+    .map_err(|e| ApiError::from(e))?
+    .into_inner()
+    .try_into()?;
+
+  // Requested SKUs with their cart prices
+  let mut lines: Vec<QuoteLineForm> = cart
+    .shopping_list
+    .iter()
+    .map(|i| QuoteLineForm {
+      kind: QuoteLineKindForm::Sku { sku: i.sku },
+      name: i.name.clone(),
+      piece: i.piece,
+      price_net: i.retail_price_net,
+      vat: i.vat.clone(),
+      price_gross: i.retail_price_gross,
+      total_price_gross: i.total_retail_price_gross,
+    })
+    .collect();
+
+  // Opened products are unique, they are quoted by UPL
+  for upl in &cart.upls_unique {
+    if let UplKindForm::OpenedSku { .. } = upl.kind {
+      lines.push(QuoteLineForm {
+        kind: QuoteLineKindForm::Upl {
+          upl_id: upl.upl_id.clone(),
+        },
+        name: upl.name.clone(),
+        piece: 1,
+        price_net: upl.retail_price_net,
+        vat: upl.vat.clone(),
+        price_gross: upl.retail_price_gross,
+        total_price_gross: upl.retail_price_gross,
+      });
+    }
+  }
+
+  if lines.len() == 0 {
+    return Err(ApiError::bad_request("Üres kosárból nem készíthető árajánlat!").into());
+  }
+
+  let note = f.note;
+  let res = services.quotes.insert_new(|quotes| {
+    let quote_id = next_quote_id(quotes.keys());
+    Ok((
+      quote_id.clone(),
+      QuoteForm {
+        quote_id,
+        cart_id: cart.id,
+        store_id: cart.store_id,
+        customer: cart.customer.map(|c| c.into()),
+        lines,
+        total_net: cart.total_net,
+        total_vat: cart.total_vat,
+        total_gross: cart.total_gross,
+        valid_until,
+        note,
+        converted_cart_id: None,
+        converted_at: None,
+        created_by: uid,
+        created_at: Utc::now(),
+      },
+    ))
+  })?;
+
+  Ok(reply::json(&res))
+}
+
+pub async fn get_all(_uid: u32, services: Services) -> ApiResult {
+  let res: Vec<QuoteForm> = services.quotes.get_all();
+  Ok(reply::json(&res))
+}
+
+pub async fn get_by_id(quote_id: String, _uid: u32, services: Services) -> ApiResult {
+  let res: QuoteForm = services
+    .quotes
+    .get(&quote_id)
+    .ok_or(ApiError::not_found())?;
+  Ok(reply::json(&res))
+}
+
+pub async fn get_pdf(_uid: u32, mut services: Services, f: QuoteIdForm) -> ApiResult {
+  let quote: QuoteForm = services
+    .quotes
+    .get(&f.quote_id)
+    .ok_or(ApiError::not_found())?;
+
+  let template = crate::quote::Quote::new(
+    quote.quote_id.clone(),
+    quote.customer.as_ref().map(|c| c.name.clone()),
+    match &quote.customer {
+      Some(c) => format!("{} {}, {}", c.zip, c.location, c.street),
+      None => "".to_string(),
+    },
+    match &quote.customer {
+      Some(c) => c.tax_number.clone(),
+      None => "".to_string(),
+    },
+    quote
+      .lines
+      .iter()
+      .map(|l| crate::quote::Item {
+        sku: match &l.kind {
+          QuoteLineKindForm::Sku { sku } => format!("{}", sku),
+          QuoteLineKindForm::Upl { .. } => "-".to_string(),
+        },
+        name: l.name.clone(),
+        piece: l.piece,
+        unit_price_gross: l.price_gross,
+        total_price_gross: l.total_price_gross,
+      })
+      .collect(),
+    quote.total_net,
+    quote.total_vat,
+    quote.total_gross,
+    quote.valid_until,
+    quote.note.clone(),
+    quote.created_at,
+  )
+  .to_latex();
+
+  let icon_bytes = include_bytes!("../../static/icon.jpg");
+
+  // Call latex service
+  let result = services
+    .latex
+    .process(Content {
+      main_latex_file: template.as_bytes().to_owned(),
+      attachments: {
+        let mut files: HashMap<String, Vec<u8>> = HashMap::new();
+        files.insert("logo.jpg".to_string(), icon_bytes.to_vec());
+        files
+      },
+    })
+    .await
+    .map_err(|_| ApiError::bad_request("Hiba a latex szerviztől"))?
+    .into_inner();
+
+  let res: PdfBase64Form = PdfBase64Form {
+    pdf_base64: base64::encode(result.content),
+  };
+
+  Ok(reply::json(&res))
+}
+
+// Add quoted SKU line to cart
+// Current price and stock are checked and reported
+async fn add_quoted_sku(
+  services: &mut Services,
+  cart_id: String,
+  store_id: u32,
+  line: &QuoteLineForm,
+  sku_id: u32,
+  keep_quoted_price: bool,
+) -> Result<QuoteToCartLineForm, ApiError> {
+  let sku_obj = services
+    .product
+    .get_sku(GetSkuRequest { sku_id })
+    .await
+    .map_err(|e| ApiError::from(e))?
+    .into_inner();
+
+  let current_price: PriceObject = services
+    .pricing
+    .get_price(GetPriceRequest { sku: sku_id })
+    .await
+    .map_err(|e| ApiError::from(e))?
+    .into_inner();
+
+  let available_piece = sku_stock(services, sku_id, store_id).await?;
+
+  let price = match keep_quoted_price {
+    true => PriceObject {
+      sku: sku_id,
+      price_net_retail: line.price_net,
+      vat: line.vat.clone(),
+      price_gross_retail: line.price_gross,
+      ..Default::default()
+    },
+    false => current_price.clone(),
+  };
+
+  check_sku_stock(services, &cart_id, sku_id, line.piece, false).await?;
+  add_sku_priced(services, cart_id, &sku_obj, &price, line.piece).await?;
+
+  Ok(QuoteToCartLineForm {
+    line: line.clone(),
+    current_price_gross: Some(current_price.price_gross_retail),
+    price_changed: current_price.price_gross_retail != line.price_gross,
+    available_piece: Some(available_piece),
+    in_stock: available_piece >= line.piece,
+    success: true,
+    error: None,
+  })
+}
+
+pub async fn to_cart(uid: u32, mut services: Services, f: QuoteToCartForm) -> ApiResult {
+  let quote: QuoteForm = services
+    .quotes
+    .get(&f.quote_id)
+    .ok_or(ApiError::not_found())?;
+
+  if let Some(cart_id) = &quote.converted_cart_id {
+    return Err(
+      ApiError::bad_request(&format!(
+        "Az árajánlatból már készült kosár! Kosár: {}",
+        cart_id
+      ))
+      .into(),
+    );
+  }
+
+  if f.keep_quoted_prices && !quote.is_valid() {
+    return Err(
+      ApiError::bad_request("Az árajánlat lejárt, az ajánlott árak nem tarthatók!").into(),
+    );
+  }
+
+  // Create new cart
+  let mut cart: CartForm = services
+    .purchase
+    .cart_new(CartNewRequest {
+      store_id: quote.store_id,
+      owner_id: uid,
+      created_by: uid,
+    })
+    .await
+    .map_err(|e| ApiError::from(e))?
+    .into_inner()
+    .try_into()?;
+
+  if let Some(customer) = &quote.customer {
+    cart = add_customer(&mut services, cart.id.clone(), customer.id).await?;
+  }
+
+  let mut lines: Vec<QuoteToCartLineForm> = Vec::new();
+  for line in &quote.lines {
+    let res = match &line.kind {
+      QuoteLineKindForm::Sku { sku } => {
+        add_quoted_sku(
+          &mut services,
+          cart.id.clone(),
+          quote.store_id,
+          line,
+          *sku,
+          f.keep_quoted_prices,
+        )
+        .await
+      }
+      // Opened products cannot be priced by hand,
+      // they are added only if still available
+      QuoteLineKindForm::Upl { upl_id } => {
+        add_upl(uid, &mut services, cart.id.clone(), upl_id.clone())
+          .await
+          .map(|c| {
+            let current_price_gross = c
+              .upls_unique
+              .iter()
+              .find(|u| &u.upl_id == upl_id)
+              .map(|u| u.retail_price_gross);
+            QuoteToCartLineForm {
+              line: line.clone(),
+              current_price_gross,
+              price_changed: current_price_gross != Some(line.price_gross),
+              available_piece: Some(1),
+              in_stock: true,
+              success: true,
+              error: None,
+            }
+          })
+      }
+    };
+    lines.push(match res {
+      Ok(r) => r,
+      Err(e) => QuoteToCartLineForm {
+        line: line.clone(),
+        current_price_gross: None,
+        price_changed: false,
+        available_piece: None,
+        in_stock: false,
+        success: false,
+        error: Some(e.message()),
+      },
+    });
+  }
+
+  // Nothing could be added, drop the empty cart and keep the quote open
+  if !lines.iter().any(|l| l.success) {
+    let _ = services
+      .purchase
+      .cart_remove(CartRemoveRequest {
+        cart_id: cart.id.clone(),
+      })
+      .await;
+    services.display.remove(&cart.id);
+    let errors = lines
+      .iter()
+      .filter_map(|l| l.error.clone())
+      .collect::<Vec<String>>()
+      .join(" ");
+    return Err(
+      ApiError::bad_request(&format!(
+        "Az árajánlat egyik tétele sem tehető kosárba! {}",
+        errors
+      ))
+      .into(),
+    );
+  }
+
+  // Mark quote as converted
+  services.quotes.update(&quote.quote_id, |q| {
+    q.converted_cart_id = Some(cart.id.clone());
+    q.converted_at = Some(Utc::now());
+    Ok(())
+  })?;
+
+  // Query final cart state
+  let cart: CartForm = services
+    .purchase
+    .cart_get_by_id(CartByIdRequest { cart_id: cart.id })
+    .await
+    .map_err(|e| ApiError::from(e))?
+    .into_inner()
+    .try_into()?;
+  let cart = apply_promotions(&mut services, cart).await?;
+
+  Ok(reply::json(&QuoteToCartResultForm {
+    quote_id: quote.quote_id,
+    lines,
+    cart,
+  }))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_next_quote_id() {
+    let prefix = format!("AJ{}", Local::today().format("%Y%m%d"));
+    assert_eq!(
+      next_quote_id(Vec::<String>::new().iter()),
+      format!("{}001", prefix)
+    );
+    let ids = vec![
+      format!("{}001", prefix),
+      format!("{}003", prefix),
+      "AJ19991231007".to_string(),
+    ];
+    assert_eq!(next_quote_id(ids.iter()), format!("{}004", prefix));
+  }
+}
@@ -0,0 +1,574 @@
+use std::{collections::HashMap, env};
+
+use crate::{prelude::*, services::Services};
+use chrono::{DateTime, Duration, Local, NaiveDate, Utc};
+use gzlib::proto::{
+  self,
+  cash::{NewTransaction, TransactionKind},
+  customer::GetByIdRequest,
+  email::EmailRequest,
+  purchase::{Payment, PurchaseAddPaymentRequest, PurchaseBulkRequest, PurchaseByIdRequest},
+};
+use serde::{Deserialize, Serialize};
+use warp::reply;
+
+use super::{
+  cart::PaymentKindForm,
+  purchase::{index_purchase, PurchaseForm, PurchaseInfoForm},
+  purchase_return,
+};
+
+const REMINDER_INTERVAL_ENV_KEY: &'static str = "RECEIVABLE_REMINDER_DAYS";
+
+/// Bank transfer payment recorded after the purchase was closed
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct LatePaymentForm {
+  pub transaction_id: String,
+  pub amount: i32,
+  // Bank statement reference
+  pub reference: String,
+  pub paid_at: NaiveDate,
+  pub created_by: u32,
+  pub created_at: DateTime<Utc>,
+  // Added to the purchase in the purchase service
+  pub recorded: bool,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct PurchasePaymentsForm {
+  pub purchase_id: String,
+  pub payments: Vec<LatePaymentForm>,
+}
+
+/// Last payment reminder sent for a purchase
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ReminderForm {
+  pub purchase_id: String,
+  pub sent_to: String,
+  pub sent_at: DateTime<Utc>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct AddPaymentForm {
+  purchase_id: String,
+  amount: i32,
+  reference: String,
+  // YYYY-MM-DD, today by default
+  paid_at: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct AgingQueryForm {
+  customer_id: Option<u32>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ReceivableForm {
+  pub purchase_id: String,
+  pub invoice_id: String,
+  pub payment_duedate: String,
+  // Negative if not due yet
+  pub days_overdue: i64,
+  pub outstanding: i32,
+}
+
+/// Outstanding amount of a customer by days overdue
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct AgingRowForm {
+  pub customer_id: Option<u32>,
+  pub customer_name: String,
+  pub current: i32,
+  pub days_1_30: i32,
+  pub days_31_60: i32,
+  pub days_60_plus: i32,
+  pub total: i32,
+  pub receivables: Vec<ReceivableForm>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct SendRemindersForm {
+  customer_id: Option<u32>,
+  // Only purchases overdue at least this many days, 1 by default
+  min_days_overdue: Option<i64>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ReminderResultForm {
+  customer_id: u32,
+  email: String,
+  purchase_ids: Vec<String>,
+  error: Option<String>,
+}
+
+/// Payments recorded after close
+pub fn late_payments(services: &Services, purchase_id: &str) -> Vec<LatePaymentForm> {
+  match services.purchase_payments.get(purchase_id) {
+    Some(p) => p.payments,
+    None => Vec::new(),
+  }
+}
+
+/// Late payments not added to the purchase service
+pub fn unrecorded_payments(services: &Services, purchase_id: &str) -> i32 {
+  late_payments(services, purchase_id)
+    .iter()
+    .filter(|p| !p.recorded)
+    .map(|p| p.amount)
+    .sum()
+}
+
+/// Balance difference not known by the purchase service:
+/// payments not added to the purchase service and unpaid amounts
+/// taken off by returns are added
+pub fn balance_adjustment(services: &Services, purchase_id: &str) -> i32 {
+  unrecorded_payments(services, purchase_id)
+    + purchase_return::receivable_reduction(services, purchase_id)
+}
+
+/// Unpaid purchase with its due date passed
+pub fn is_overdue(balance: i32, payment_duedate: &str) -> bool {
+  match DateTime::parse_from_rfc3339(payment_duedate) {
+    Ok(due) => balance < 0 && due < Utc::now(),
+    Err(_) => false,
+  }
+}
+
+// Days passed since the due date
+fn days_overdue(payment_duedate: &str) -> i64 {
+  match DateTime::parse_from_rfc3339(payment_duedate) {
+    Ok(due) => {
+      (Local::today().naive_local() - due.with_timezone(&Local).naive_local().date()).num_days()
+    }
+    Err(_) => 0,
+  }
+}
+
+// Unpaid transfer purchases from the purchase index, with late payments
+async fn unpaid_purchases(
+  services: &mut Services,
+  customer_id: Option<u32>,
+) -> Result<Vec<PurchaseInfoForm>, ApiError> {
+  // Only transfer sales are paid later
+  let purchase_ids: Vec<String> = services
+    .purchase_index
+    .filter(|i| {
+      i.payment_kind == PaymentKindForm::Transfer
+        && !i.restored
+        && customer_id
+          .map(|c| i.customer_id == Some(c))
+          .unwrap_or(true)
+    })
+    .into_iter()
+    .filter(|i| i.balance(services) < 0)
+    .map(|i| i.purchase_id)
+    .collect();
+  if purchase_ids.len() == 0 {
+    return Ok(Vec::new());
+  }
+
+  let mut all = services
+    .purchase
+    .purchase_get_info_bulk(PurchaseBulkRequest { purchase_ids })
+    .await
+    .map_err(|e| ApiError::from(e))?
+    .into_inner();
+
+  let mut res: Vec<PurchaseInfoForm> = Vec::new();
+  while let Some(pinfo) = all.message().await.map_err(|e| ApiError::from(e))? {
+    let info = PurchaseInfoForm::from(pinfo).with_late_payments(services);
+    if info.is_unpaid() {
+      res.push(info);
+    }
+  }
+  Ok(res)
+}
+
+// Update a late payment, found by its creation time
+fn update_payment<F>(
+  services: &Services,
+  purchase_id: &str,
+  created_at: DateTime<Utc>,
+  f: F,
+) -> Result<PurchasePaymentsForm, ApiError>
+where
+  F: FnOnce(&mut LatePaymentForm),
+{
+  services.purchase_payments.update(purchase_id, |p| {
+    if let Some(l) = p.payments.iter_mut().find(|l| l.created_at == created_at) {
+      f(l);
+    }
+    Ok(())
+  })
+}
+
+pub async fn add_payment(uid: u32, mut services: Services, f: AddPaymentForm) -> ApiResult {
+  super::user::require_role(&services, uid, super::user::RoleForm::Manager)?;
+
+  if f.amount <= 0 {
+    return Err(ApiError::bad_request("A befizetett összegnek pozitívnak kell lennie!").into());
+  }
+  if f.reference.trim().len() == 0 {
+    return Err(ApiError::bad_request("A banki hivatkozás megadása kötelező!").into());
+  }
+  let paid_at = match &f.paid_at {
+    Some(d) => NaiveDate::parse_from_str(d, "%Y-%m-%d")
+      .map_err(|_| ApiError::bad_request("Hibás befizetési dátum! (ÉÉÉÉ-HH-NN)"))?,
+    None => Local::today().naive_local(),
+  };
+
+  let purchase: PurchaseForm = services
+    .purchase
+    .purchase_get_by_id(PurchaseByIdRequest {
+      purchase_id: f.purchase_id.clone(),
+    })
+    .await
+    .map_err(|e| ApiError::from(e))?
+    .into_inner()
+    .into();
+  let purchase_id = purchase.purchase_id.clone();
+  let reduced = purchase_return::receivable_reduction(&services, &purchase_id);
+
+  // Outstanding check and the payment record are one storage update,
+  // so concurrent payments cannot pay more than the debt.
+  // Payments are counted from here, not from the purchase
+  let created_at = Utc::now();
+  services.purchase_payments.insert_absent(
+    &purchase_id,
+    PurchasePaymentsForm {
+      purchase_id: purchase_id.clone(),
+      payments: Vec::new(),
+    },
+  )?;
+  services.purchase_payments.update(&purchase_id, |p| {
+    let known: i32 = purchase
+      .payments
+      .iter()
+      .filter(|pp| p.payments.iter().any(|l| l.transaction_id == pp.id))
+      .map(|pp| pp.amount)
+      .sum();
+    let late: i32 = p.payments.iter().map(|l| l.amount).sum();
+    let outstanding = -(purchase.payment_balance - known + late + reduced);
+    if outstanding <= 0 {
+      return Err(ApiError::bad_request("A vásárlás már ki van fizetve!"));
+    }
+    if f.amount > outstanding {
+      return Err(ApiError::bad_request(&format!(
+        "A befizetés több a tartozásnál! Tartozás: {} Ft",
+        outstanding
+      )));
+    }
+    // Transaction ID is set once the cash transaction is created
+    p.payments.push(LatePaymentForm {
+      transaction_id: String::new(),
+      amount: f.amount,
+      reference: f.reference.trim().to_string(),
+      paid_at,
+      created_by: uid,
+      created_at,
+      recorded: false,
+    });
+    Ok(())
+  })?;
+
+  let transaction = match services
+    .cash
+    .create_transaction(NewTransaction {
+      kind: TransactionKind::KindTransfer as i32,
+      amount: f.amount,
+      reference: f.reference.trim().to_string(),
+      comment: format!("Utólagos átutalás ({})", purchase_id),
+      created_by: uid,
+      cart_id: Some(proto::cash::new_transaction::CartId::Cart(
+        purchase_id.clone(),
+      )),
+    })
+    .await
+  {
+    Ok(r) => r.into_inner(),
+    Err(e) => {
+      services.purchase_payments.update(&purchase_id, |p| {
+        p.payments.retain(|l| l.created_at != created_at);
+        Ok(())
+      })?;
+      return Err(ApiError::from(e).into());
+    }
+  };
+  update_payment(&services, &purchase_id, created_at, |l| {
+    l.transaction_id = transaction.transaction_id.clone()
+  })?;
+
+  // Add the payment to the purchase as well,
+  // if it fails the payment is still counted from here
+  let updated = match services
+    .purchase
+    .purchase_add_payment(PurchaseAddPaymentRequest {
+      purchase_id: purchase_id.clone(),
+      payment: Some(Payment {
+        payment_id: transaction.transaction_id.clone(),
+        amount: f.amount,
+      }),
+    })
+    .await
+  {
+    Ok(r) => {
+      update_payment(&services, &purchase_id, created_at, |l| l.recorded = true)?;
+      let updated = PurchaseForm::from(r.into_inner());
+      if let Err(e) = index_purchase(&services, &updated) {
+        eprintln!("Could not index purchase {}: {:?}", updated.purchase_id, e);
+      }
+      Some(updated)
+    }
+    Err(e) => {
+      eprintln!(
+        "Could not add payment to purchase {}: {}",
+        purchase_id,
+        e.message()
+      );
+      None
+    }
+  };
+
+  let info: PurchaseInfoForm = updated
+    .unwrap_or(purchase)
+    .with_promotions(&services)
+    .with_late_payments(&services)
+    .into();
+  Ok(reply::json(&info))
+}
+
+pub async fn get_payments(purchase_id: String, _uid: u32, services: Services) -> ApiResult {
+  let res = late_payments(&services, &purchase_id);
+  Ok(reply::json(&res))
+}
+
+impl AgingRowForm {
+  // Add receivable into its aging bucket
+  fn add(&mut self, r: ReceivableForm) {
+    match r.days_overdue {
+      d if d <= 0 => self.current += r.outstanding,
+      d if d <= 30 => self.days_1_30 += r.outstanding,
+      d if d <= 60 => self.days_31_60 += r.outstanding,
+      _ => self.days_60_plus += r.outstanding,
+    }
+    self.total += r.outstanding;
+    self.receivables.push(r);
+  }
+}
+
+pub async fn get_aging(_uid: u32, mut services: Services, f: AgingQueryForm) -> ApiResult {
+  let mut rows: HashMap<Option<u32>, AgingRowForm> = HashMap::new();
+  for info in unpaid_purchases(&mut services, f.customer_id).await? {
+    let days = days_overdue(&info.payment_duedate);
+    let row = rows
+      .entry(info.customer.as_ref().map(|c| c.id))
+      .or_insert(AgingRowForm {
+        customer_id: info.customer.as_ref().map(|c| c.id),
+        customer_name: match &info.customer {
+          Some(c) => c.name.clone(),
+          None => "Ismeretlen vevő".to_string(),
+        },
+        current: 0,
+        days_1_30: 0,
+        days_31_60: 0,
+        days_60_plus: 0,
+        total: 0,
+        receivables: Vec::new(),
+      });
+    row.add(ReceivableForm {
+      purchase_id: info.purchase_id,
+      invoice_id: info.invoice_id,
+      payment_duedate: info.payment_duedate,
+      days_overdue: days,
+      outstanding: -info.balance,
+    });
+  }
+
+  let mut res: Vec<AgingRowForm> = rows.into_iter().map(|(_, r)| r).collect();
+  for r in res.iter_mut() {
+    r.receivables.sort_by_key(|i| -i.days_overdue);
+  }
+  res.sort_by_key(|r| -r.total);
+  Ok(reply::json(&res))
+}
+
+// Reminder email text
+fn reminder_body(customer_name: &str, receivables: &[ReceivableForm]) -> String {
+  let mut body = format!(
+    "Tisztelt {}!\n\nNyilvántartásunk szerint az alábbi vásárlások ellenértéke lejárt:\n\n",
+    customer_name
+  );
+  for r in receivables {
+    body.push_str(&format!(
+      "- Vásárlás: {}, számla: {}, fizetési határidő: {}, hátralék: {} Ft\n",
+      r.purchase_id,
+      match r.invoice_id.len() {
+        0 => "-",
+        _ => &r.invoice_id,
+      },
+      DateTime::parse_from_rfc3339(&r.payment_duedate)
+        .map(|d| d.format("%Y-%m-%d").to_string())
+        .unwrap_or(r.payment_duedate.clone()),
+      r.outstanding
+    ));
+  }
+  body.push_str(
+    "\nKérjük, az összeget mielőbb egyenlítse ki. Amennyiben már rendezte, levelünket tekintse tárgytalannak.\n\nÜdvözlettel:\nGardenZilla",
+  );
+  body
+}
+
+pub async fn send_reminders(uid: u32, mut services: Services, f: SendRemindersForm) -> ApiResult {
+  super::user::require_role(&services, uid, super::user::RoleForm::Manager)?;
+
+  let min_days = f.min_days_overdue.unwrap_or(1).max(1);
+  let interval_days = env::var(REMINDER_INTERVAL_ENV_KEY)
+    .ok()
+    .and_then(|v| v.parse::<i64>().ok())
+    .unwrap_or(7);
+
+  // Overdue purchases by customer
+  // Purchases reminded in the interval are left out
+  let mut by_customer: HashMap<u32, (String, Vec<ReceivableForm>)> = HashMap::new();
+  for info in unpaid_purchases(&mut services, f.customer_id).await? {
+    let days = days_overdue(&info.payment_duedate);
+    if days < min_days {
+      continue;
+    }
+    if let Some(r) = services.receivable_reminders.get(&info.purchase_id) {
+      if r.sent_at > Utc::now() - Duration::days(interval_days) {
+        continue;
+      }
+    }
+    let customer = match &info.customer {
+      Some(c) => c.clone(),
+      None => continue,
+    };
+    by_customer
+      .entry(customer.id)
+      .or_insert((customer.name.clone(), Vec::new()))
+      .1
+      .push(ReceivableForm {
+        purchase_id: info.purchase_id,
+        invoice_id: info.invoice_id,
+        payment_duedate: info.payment_duedate,
+        days_overdue: days,
+        outstanding: -info.balance,
+      });
+  }
+
+  let mut res: Vec<ReminderResultForm> = Vec::new();
+  for (customer_id, (name, receivables)) in by_customer {
+    let purchase_ids: Vec<String> = receivables.iter().map(|r| r.purchase_id.clone()).collect();
+    let email = match services
+      .customer
+      .get_by_id(GetByIdRequest { customer_id })
+      .await
+    {
+      Ok(c) => c.into_inner().email,
+      Err(e) => {
+        res.push(ReminderResultForm {
+          customer_id,
+          email: String::new(),
+          purchase_ids,
+          error: Some(e.message().to_string()),
+        });
+        continue;
+      }
+    };
+    if email.trim().len() == 0 {
+      res.push(ReminderResultForm {
+        customer_id,
+        email,
+        purchase_ids,
+        error: Some("A vevőnek nincs e-mail címe!".to_string()),
+      });
+      continue;
+    }
+
+    let sent = services
+      .email
+      .send_email(EmailRequest {
+        to: email.clone(),
+        subject: "Fizetési emlékeztető".to_string(),
+        body: reminder_body(&name, &receivables),
+      })
+      .await;
+    let error = match sent {
+      Ok(_) => {
+        for purchase_id in &purchase_ids {
+          services.receivable_reminders.insert(
+            purchase_id,
+            ReminderForm {
+              purchase_id: purchase_id.clone(),
+              sent_to: email.clone(),
+              sent_at: Utc::now(),
+            },
+          )?;
+        }
+        None
+      }
+      Err(e) => Some(e.message().to_string()),
+    };
+    res.push(ReminderResultForm {
+      customer_id,
+      email,
+      purchase_ids,
+      error,
+    });
+  }
+  Ok(reply::json(&res))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_is_overdue() {
+    let past = (Utc::now() - Duration::days(3)).to_rfc3339();
+    let future = (Utc::now() + Duration::days(3)).to_rfc3339();
+    assert!(is_overdue(-100, &past));
+    assert!(!is_overdue(0, &past));
+    assert!(!is_overdue(100, &past));
+    assert!(!is_overdue(-100, &future));
+    assert!(!is_overdue(-100, ""));
+    assert!(!is_overdue(-100, "2021-01-01"));
+  }
+
+  #[test]
+  fn test_aging_buckets() {
+    let mut row = AgingRowForm {
+      customer_id: Some(1),
+      customer_name: "Vevő".to_string(),
+      current: 0,
+      days_1_30: 0,
+      days_31_60: 0,
+      days_60_plus: 0,
+      total: 0,
+      receivables: Vec::new(),
+    };
+    for &(days, outstanding) in &[
+      (-5, 1),
+      (0, 2),
+      (1, 10),
+      (30, 20),
+      (31, 100),
+      (60, 200),
+      (61, 1000),
+    ] {
+      row.add(ReceivableForm {
+        purchase_id: format!("P_{}", days),
+        invoice_id: String::new(),
+        payment_duedate: String::new(),
+        days_overdue: days,
+        outstanding,
+      });
+    }
+    assert_eq!(row.current, 3);
+    assert_eq!(row.days_1_30, 30);
+    assert_eq!(row.days_31_60, 300);
+    assert_eq!(row.days_60_plus, 1000);
+    assert_eq!(row.total, 1333);
+    assert_eq!(row.receivables.len(), 7);
+  }
+}
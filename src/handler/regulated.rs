@@ -0,0 +1,286 @@
+use std::collections::HashMap;
+
+use crate::{cart_expiry, prelude::*, services::Services};
+use chrono::{DateTime, Local, NaiveDate, TimeZone, Utc};
+use gzlib::proto::{product::GetSkuBulkRequest, purchase::CartByIdRequest};
+use serde::{Deserialize, Serialize};
+use warp::reply;
+
+use super::{
+  cart::{CartForm, UplKindForm},
+  product::is_regulated,
+};
+
+/// Permit of the buyer of regulated products
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct PermitForm {
+  pub cart_id: String,
+  pub permit_number: String,
+  pub holder_name: String,
+  pub valid_until: NaiveDate,
+  pub created_by: u32,
+  pub created_at: DateTime<Utc>,
+}
+
+impl PermitForm {
+  pub fn is_valid(&self) -> bool {
+    Local::today().naive_local() <= self.valid_until
+  }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct CartSetPermitForm {
+  cart_id: String,
+  permit_number: String,
+  holder_name: String,
+  // YYYY-MM-DD
+  valid_until: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct RegulatedSaleLineForm {
+  pub product_id: u32,
+  pub sku: Option<u32>,
+  pub upl_id: Option<String>,
+  pub name: String,
+  pub piece: u32,
+}
+
+/// Register entry of a regulated sale
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct RegulatedSaleForm {
+  pub purchase_id: String,
+  pub store_id: u32,
+  pub permit_number: String,
+  pub holder_name: String,
+  pub permit_valid_until: NaiveDate,
+  pub lines: Vec<RegulatedSaleLineForm>,
+  pub sold_by: u32,
+  pub sold_at: DateTime<Utc>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct RegisterQueryForm {
+  // YYYY-MM-DD
+  from: String,
+  // YYYY-MM-DD
+  till: String,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct CsvBase64Form {
+  pub csv_base64: String,
+}
+
+/// Permit recorded for a cart or purchase
+pub fn cart_permit(services: &Services, cart_id: &str) -> Option<PermitForm> {
+  services.cart_permits.get(cart_id)
+}
+
+/// Regulated product can only be added with a valid permit
+pub fn require_permit(services: &Services, cart_id: &str, product_id: u32) -> Result<(), ApiError> {
+  if !is_regulated(services, product_id) {
+    return Ok(());
+  }
+  match cart_permit(services, cart_id) {
+    Some(p) if p.is_valid() => Ok(()),
+    Some(_) => Err(ApiError::bad_request(
+      "A vevő engedélye lejárt! Engedélyköteles termék nem értékesíthető.",
+    )),
+    None => Err(ApiError::bad_request(
+      "Engedélyköteles termék! Előbb adja meg a vevő engedélyszámát és nevét.",
+    )),
+  }
+}
+
+/// Regulated lines of a cart
+pub async fn regulated_lines(
+  services: &mut Services,
+  cart: &CartForm,
+) -> Result<Vec<RegulatedSaleLineForm>, ApiError> {
+  // Nothing is regulated, no need to query SKUs
+  if services.regulated_products.get_all().len() == 0 {
+    return Ok(Vec::new());
+  }
+
+  let upls = cart.upls_sku.iter().chain(cart.upls_unique.iter());
+  let mut sku_ids: Vec<u32> = cart.shopping_list.iter().map(|i| i.sku).collect();
+  for upl in upls.clone() {
+    if let UplKindForm::Sku { sku, .. } = upl.kind {
+      sku_ids.push(sku);
+    }
+  }
+
+  // SKU product IDs
+  let mut products: HashMap<u32, u32> = HashMap::new();
+  let mut all = services
+    .product
+    .get_sku_bulk(GetSkuBulkRequest { sku_id: sku_ids })
+    .await
+    .map_err(|e| ApiError::from(e))?
+    .into_inner();
+  while let Some(sku) = all.message().await.map_err(|e| ApiError::from(e))? {
+    products.insert(sku.sku, sku.product_id);
+  }
+
+  let mut res: Vec<RegulatedSaleLineForm> = Vec::new();
+  for i in &cart.shopping_list {
+    if let Some(product_id) = products.get(&i.sku) {
+      if is_regulated(services, *product_id) {
+        res.push(RegulatedSaleLineForm {
+          product_id: *product_id,
+          sku: Some(i.sku),
+          upl_id: None,
+          name: i.name.clone(),
+          piece: i.piece,
+        });
+      }
+    }
+  }
+  for upl in upls {
+    let (product_id, sku, piece) = match upl.kind {
+      UplKindForm::Sku { sku, piece } => match products.get(&sku) {
+        Some(product_id) => (*product_id, Some(sku), piece),
+        None => continue,
+      },
+      // Opened UPLs are sold by their amount
+      UplKindForm::OpenedSku { product_id, amount } => (product_id, None, amount),
+    };
+    if is_regulated(services, product_id) {
+      res.push(RegulatedSaleLineForm {
+        product_id,
+        sku,
+        upl_id: Some(upl.upl_id.clone()),
+        name: upl.name.clone(),
+        piece,
+      });
+    }
+  }
+  Ok(res)
+}
+
+/// Put a closed cart's regulated lines into the register
+pub fn register_sale(
+  services: &Services,
+  cart: &CartForm,
+  lines: Vec<RegulatedSaleLineForm>,
+  uid: u32,
+) -> Result<RegulatedSaleForm, ApiError> {
+  let permit = cart_permit(services, &cart.id).ok_or(ApiError::bad_request(
+    "Engedélyköteles termék! Előbb adja meg a vevő engedélyszámát és nevét.",
+  ))?;
+  services.regulated_sales.insert(
+    &cart.id,
+    RegulatedSaleForm {
+      purchase_id: cart.id.clone(),
+      store_id: cart.store_id,
+      permit_number: permit.permit_number,
+      holder_name: permit.holder_name,
+      permit_valid_until: permit.valid_until,
+      lines,
+      sold_by: uid,
+      sold_at: Utc::now(),
+    },
+  )
+}
+
+/// Remove the register entry of a cart that could not be closed
+pub fn unregister_sale(services: &Services, cart_id: &str) -> Result<(), ApiError> {
+  if services.regulated_sales.contains(cart_id) {
+    services.regulated_sales.remove(cart_id)?;
+  }
+  Ok(())
+}
+
+pub async fn set_permit(uid: u32, mut services: Services, f: CartSetPermitForm) -> ApiResult {
+  if f.permit_number.trim().len() == 0 || f.holder_name.trim().len() == 0 {
+    return Err(ApiError::bad_request("Az engedélyszám és a név megadása kötelező!").into());
+  }
+  let valid_until = NaiveDate::parse_from_str(&f.valid_until, "%Y-%m-%d")
+    .map_err(|_| ApiError::bad_request("Hibás érvényességi dátum! (ÉÉÉÉ-HH-NN)"))?;
+  if valid_until < Local::today().naive_local() {
+    return Err(ApiError::bad_request("A megadott engedély lejárt!").into());
+  }
+
+  cart_expiry::touch(&services, &f.cart_id).await?;
+
+  // Check if cart exists
+  let cart = services
+    .purchase
+    .cart_get_by_id(CartByIdRequest { cart_id: f.cart_id })
+    .await
+    .map_err(|e| ApiError::from(e))?
+    .into_inner();
+
+  let res = services.cart_permits.insert(
+    &cart.id,
+    PermitForm {
+      cart_id: cart.id.clone(),
+      permit_number: f.permit_number.trim().to_string(),
+      holder_name: f.holder_name.trim().to_string(),
+      valid_until,
+      created_by: uid,
+      created_at: Utc::now(),
+    },
+  )?;
+  Ok(reply::json(&res))
+}
+
+pub async fn get_permit(cart_id: String, _uid: u32, services: Services) -> ApiResult {
+  let res: Option<PermitForm> = cart_permit(&services, &cart_id);
+  Ok(reply::json(&res))
+}
+
+// Register entries sold in the given period
+fn register(
+  services: &Services,
+  f: &RegisterQueryForm,
+) -> Result<Vec<RegulatedSaleForm>, ApiError> {
+  let from = NaiveDate::parse_from_str(&f.from, "%Y-%m-%d")
+    .map_err(|_| ApiError::bad_request("Hibás kezdő dátum! (ÉÉÉÉ-HH-NN)"))?;
+  let till = NaiveDate::parse_from_str(&f.till, "%Y-%m-%d")
+    .map_err(|_| ApiError::bad_request("Hibás záró dátum! (ÉÉÉÉ-HH-NN)"))?;
+  let mut res = services.regulated_sales.filter(|s| {
+    let day = Local
+      .from_utc_datetime(&s.sold_at.naive_utc())
+      .naive_local()
+      .date();
+    from <= day && day <= till
+  });
+  res.sort_by_key(|s| s.sold_at);
+  Ok(res)
+}
+
+pub async fn get_register(uid: u32, services: Services, f: RegisterQueryForm) -> ApiResult {
+  super::user::require_role(&services, uid, super::user::RoleForm::Manager)?;
+  let res = register(&services, &f)?;
+  Ok(reply::json(&res))
+}
+
+pub async fn get_register_csv(uid: u32, services: Services, f: RegisterQueryForm) -> ApiResult {
+  super::user::require_role(&services, uid, super::user::RoleForm::Manager)?;
+  let mut csv = String::from(
+    "Dátum;Vásárlás azonosító;Bolt;Engedélyszám;Engedélyes neve;Termék;SKU;UPL;Mennyiség\n",
+  );
+  for s in register(&services, &f)? {
+    for l in &s.lines {
+      csv.push_str(&format!(
+        "{};{};{};{};{};{};{};{};{}\n",
+        Local
+          .from_utc_datetime(&s.sold_at.naive_utc())
+          .format("%Y-%m-%d %H:%M"),
+        s.purchase_id,
+        s.store_id,
+        s.permit_number.replace(";", ","),
+        s.holder_name.replace(";", ","),
+        l.name.replace(";", ","),
+        l.sku.map(|s| s.to_string()).unwrap_or_default(),
+        l.upl_id.clone().unwrap_or_default(),
+        l.piece
+      ));
+    }
+  }
+  Ok(reply::json(&CsvBase64Form {
+    csv_base64: base64::encode(csv),
+  }))
+}
@@ -1,4 +1,5 @@
-use crate::{prelude::*, services::Services};
+use crate::{prelude::*, receipt::Branding, services::Services};
+use chrono::{DateTime, Utc};
 use gzlib::proto::stock::{CreateNewRequest, GetByIdRequest, StockObject};
 use serde::{Deserialize, Serialize};
 use warp::reply;
@@ -24,6 +25,88 @@ impl From<StockObject> for StockForm {
   }
 }
 
+/// What happens when a cart asks for more than the store has
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum SalePolicyForm {
+  // Never sell more than the stock
+  Refuse,
+  // Refuse with a warning, cashier can override it
+  Warn,
+  // No stock check
+  Allow,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct StockSalePolicyForm {
+  pub stock_id: u32,
+  pub policy: SalePolicyForm,
+  pub updated_by: u32,
+  pub updated_at: DateTime<Utc>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct SetSalePolicyForm {
+  stock_id: u32,
+  policy: SalePolicyForm,
+}
+
+/// Sale policy of a store, Warn if not set
+pub fn sale_policy(services: &Services, stock_id: u32) -> SalePolicyForm {
+  match services.stock_policies.get(&stock_id.to_string()) {
+    Some(p) => p.policy,
+    None => SalePolicyForm::Warn,
+  }
+}
+
+/// Store details printed on receipts and invoices
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct StoreDocumentSettingsForm {
+  pub stock_id: u32,
+  pub name: String,
+  pub subtitle: String,
+  pub address: String,
+  pub tax_number: String,
+  pub footer: String,
+  // JPEG logo, the default one is used if not set
+  pub logo_jpg_base64: Option<String>,
+  pub updated_by: u32,
+  pub updated_at: DateTime<Utc>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct SetDocumentSettingsForm {
+  stock_id: u32,
+  name: String,
+  subtitle: String,
+  address: String,
+  tax_number: String,
+  footer: String,
+  logo_jpg_base64: Option<String>,
+}
+
+/// Document branding of a store, the default one if not set
+pub fn store_branding(services: &Services, stock_id: u32) -> Branding {
+  let default = Branding::default();
+  let settings = match services.store_documents.get(&stock_id.to_string()) {
+    Some(s) => s,
+    None => return default,
+  };
+  Branding {
+    name: settings.name,
+    subtitle: settings.subtitle,
+    address: settings.address,
+    tax_number: settings.tax_number,
+    footer: settings.footer,
+    logo: match settings
+      .logo_jpg_base64
+      .and_then(|l| base64::decode(l).ok())
+    {
+      Some(logo) => logo,
+      None => default.logo,
+    },
+  }
+}
+
 #[derive(Serialize, Deserialize, Debug)]
 pub struct NewStockForm {
   name: String,
@@ -86,3 +169,96 @@ pub async fn get_all(_uid: u32, mut services: Services) -> ApiResult {
 
   Ok(warp::reply::json(&result))
 }
+
+pub async fn get_sale_policy(stock_id: u32, _uid: u32, services: Services) -> ApiResult {
+  Ok(reply::json(&sale_policy(&services, stock_id)))
+}
+
+pub async fn set_sale_policy(uid: u32, mut services: Services, f: SetSalePolicyForm) -> ApiResult {
+  super::user::require_role(&services, uid, super::user::RoleForm::Manager)?;
+
+  // Check if stock exists
+  let stock: StockForm = services
+    .stock
+    .get_by_id(GetByIdRequest {
+      stock_id: f.stock_id,
+    })
+    .await
+    .map_err(|e| ApiError::from(e))?
+    .into_inner()
+    .into();
+
+  let res = services.stock_policies.insert(
+    &stock.stock_id.to_string(),
+    StockSalePolicyForm {
+      stock_id: stock.stock_id,
+      policy: f.policy,
+      updated_by: uid,
+      updated_at: Utc::now(),
+    },
+  )?;
+  Ok(reply::json(&res))
+}
+
+pub async fn get_document_settings(stock_id: u32, _uid: u32, services: Services) -> ApiResult {
+  Ok(reply::json(
+    &services.store_documents.get(&stock_id.to_string()),
+  ))
+}
+
+pub async fn set_document_settings(
+  uid: u32,
+  mut services: Services,
+  f: SetDocumentSettingsForm,
+) -> ApiResult {
+  super::user::require_role(&services, uid, super::user::RoleForm::Manager)?;
+
+  if f.name.trim().len() == 0 {
+    return Err(ApiError::bad_request("A bolt neve kötelező!").into());
+  }
+  if f.address.trim().len() == 0 {
+    return Err(ApiError::bad_request("A bolt címe kötelező!").into());
+  }
+
+  // Logo must be a JPEG image, the latex service can only embed that
+  let logo_jpg_base64 = match f.logo_jpg_base64 {
+    Some(logo) if logo.len() > 0 => {
+      let bytes =
+        base64::decode(&logo).map_err(|_| ApiError::bad_request("Hibás logó kódolás!"))?;
+      match image::guess_format(&bytes) {
+        Ok(image::ImageFormat::Jpeg) => (),
+        _ => return Err(ApiError::bad_request("A logónak JPEG képnek kell lennie!").into()),
+      }
+      image::load_from_memory(&bytes).map_err(|_| ApiError::bad_request("Hibás logó kép!"))?;
+      Some(logo)
+    }
+    _ => None,
+  };
+
+  // Check if stock exists
+  let stock: StockForm = services
+    .stock
+    .get_by_id(GetByIdRequest {
+      stock_id: f.stock_id,
+    })
+    .await
+    .map_err(|e| ApiError::from(e))?
+    .into_inner()
+    .into();
+
+  let res = services.store_documents.insert(
+    &stock.stock_id.to_string(),
+    StoreDocumentSettingsForm {
+      stock_id: stock.stock_id,
+      name: f.name.trim().to_string(),
+      subtitle: f.subtitle.trim().to_string(),
+      address: f.address.trim().to_string(),
+      tax_number: f.tax_number.trim().to_string(),
+      footer: f.footer.trim().to_string(),
+      logo_jpg_base64,
+      updated_by: uid,
+      updated_at: Utc::now(),
+    },
+  )?;
+  Ok(reply::json(&res))
+}
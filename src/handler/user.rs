@@ -1,8 +1,149 @@
 use crate::{prelude::*, services::Services};
+use chrono::{DateTime, Duration, Utc};
+use crypto::pbkdf2;
 use gzlib::proto::{email::EmailRequest, user::*};
+use once_cell::sync::Lazy;
 use serde::{Deserialize, Serialize};
+use std::{collections::HashMap, env, sync::Mutex};
 use warp::reply;
 
+// Comma separated list of admin UIDs
+const ADMIN_UIDS_ENV_KEY: &'static str = "API_ADMIN_UIDS";
+// PBKDF2 rounds of PIN hashes
+const PIN_HASH_ROUNDS: u32 = 10000;
+// Failed PIN attempts after which the PIN is locked
+const PIN_MAX_FAILURES: u32 = 5;
+// Lock period after too many failed PIN attempts
+const PIN_LOCK_SECS: i64 = 900;
+
+// Failed PIN attempts by manager UID, with the time of the last one
+static PIN_FAILURES: Lazy<Mutex<HashMap<u32, (u32, DateTime<Utc>)>>> =
+  Lazy::new(|| Mutex::new(HashMap::new()));
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, PartialOrd)]
+pub enum RoleForm {
+  Cashier,
+  Manager,
+  Admin,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct UserRoleForm {
+  pub uid: u32,
+  pub role: RoleForm,
+  pub pin_hash: String,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct SetRoleForm {
+  uid: u32,
+  role: RoleForm,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct SetPinForm {
+  pin: String,
+}
+
+/// Role of a user
+/// Admins come from env, others from the role store;
+/// everybody else is a cashier.
+pub fn get_role(services: &Services, uid: u32) -> RoleForm {
+  let is_admin = env::var(ADMIN_UIDS_ENV_KEY)
+    .unwrap_or_default()
+    .split(',')
+    .any(|i| i.trim().parse::<u32>() == Ok(uid));
+  if is_admin {
+    return RoleForm::Admin;
+  }
+  match services.user_roles.get(&uid.to_string()) {
+    Some(r) => r.role,
+    None => RoleForm::Cashier,
+  }
+}
+
+/// Error if user has lower role than required
+pub fn require_role(services: &Services, uid: u32, role: RoleForm) -> Result<(), ApiError> {
+  if get_role(services, uid) < role {
+    return Err(ApiError::forbidden("Nincs jogosultsága a művelethez!"));
+  }
+  Ok(())
+}
+
+fn hash_pin(pin: &str) -> Result<String, ApiError> {
+  pbkdf2::pbkdf2_simple(pin, PIN_HASH_ROUNDS)
+    .map_err(|_| ApiError::internal_error("Hiba a PIN mentése során!"))
+}
+
+fn verify_pin(role: &UserRoleForm, pin: &str) -> bool {
+  pbkdf2::pbkdf2_check(pin, &role.pin_hash).unwrap_or(false)
+}
+
+// Error if the PIN of the user is locked after too many failed attempts
+fn check_pin_lock(uid: u32) -> Result<(), ApiError> {
+  match PIN_FAILURES.lock().unwrap().get(&uid) {
+    Some((failures, last)) if *failures >= PIN_MAX_FAILURES => {
+      if *last + Duration::seconds(PIN_LOCK_SECS) > Utc::now() {
+        return Err(ApiError::forbidden(
+          "Túl sok hibás PIN próbálkozás, próbálja újra később!",
+        ));
+      }
+      Ok(())
+    }
+    _ => Ok(()),
+  }
+}
+
+fn register_pin_result(uid: u32, success: bool) {
+  let mut failures = PIN_FAILURES.lock().unwrap();
+  if success {
+    failures.remove(&uid);
+    return;
+  }
+  let now = Utc::now();
+  let entry = failures.entry(uid).or_insert((0, now));
+  // Lock has expired, counting starts again
+  if entry.0 >= PIN_MAX_FAILURES {
+    entry.0 = 0;
+  }
+  entry.0 += 1;
+  entry.1 = now;
+}
+
+/// Manager approval by the approving manager's PIN or login token
+/// Returns the approver UID
+pub fn manager_approval(
+  services: &Services,
+  manager_uid: Option<u32>,
+  pin: &Option<String>,
+  token: &Option<String>,
+) -> Result<u32, ApiError> {
+  if let Some(token) = token {
+    let uid = crate::login::verify_token(token).map_err(|e| ApiError::from(e))?;
+    require_role(services, uid, RoleForm::Manager)?;
+    return Ok(uid);
+  }
+  if let Some(pin) = pin {
+    let uid = manager_uid.ok_or(ApiError::bad_request(
+      "A jóváhagyó vezető megadása kötelező!",
+    ))?;
+    check_pin_lock(uid)?;
+    let valid = match services.user_roles.get(&uid.to_string()) {
+      Some(r) => r.pin_hash.len() > 0 && verify_pin(&r, pin),
+      None => false,
+    };
+    if !valid || get_role(services, uid) < RoleForm::Manager {
+      register_pin_result(uid, false);
+      return Err(ApiError::forbidden("Érvénytelen vezetői PIN!"));
+    }
+    register_pin_result(uid, true);
+    return Ok(uid);
+  }
+  Err(ApiError::forbidden(
+    "A művelethez vezetői jóváhagyás szükséges!",
+  ))
+}
+
 #[derive(Serialize, Deserialize, Debug)]
 pub struct NewPasswordForm {
   password1: String,
@@ -135,3 +276,50 @@ pub async fn create_new(userid: u32, mut services: Services, uo: UserNew) -> Api
     .into();
   Ok(reply::json(&user))
 }
+
+pub async fn get_user_role(uid: u32, _uid: u32, services: Services) -> ApiResult {
+  Ok(reply::json(&get_role(&services, uid)))
+}
+
+pub async fn set_role(userid: u32, services: Services, f: SetRoleForm) -> ApiResult {
+  require_role(&services, userid, RoleForm::Admin)?;
+  let key = f.uid.to_string();
+  let res = match services.user_roles.get(&key) {
+    Some(_) => services.user_roles.update(&key, |r| {
+      r.role = f.role;
+      Ok(())
+    })?,
+    None => services.user_roles.insert(
+      &key,
+      UserRoleForm {
+        uid: f.uid,
+        role: f.role,
+        pin_hash: String::new(),
+      },
+    )?,
+  };
+  Ok(reply::json(&res.role))
+}
+
+pub async fn set_pin(userid: u32, services: Services, f: SetPinForm) -> ApiResult {
+  if f.pin.len() < 4 || f.pin.len() > 8 || !f.pin.chars().all(|c| c.is_ascii_digit()) {
+    return Err(ApiError::bad_request("A PIN 4-8 számjegyből állhat!").into());
+  }
+  let key = userid.to_string();
+  let pin_hash = hash_pin(&f.pin)?;
+  match services.user_roles.get(&key) {
+    Some(_) => services.user_roles.update(&key, |r| {
+      r.pin_hash = pin_hash;
+      Ok(())
+    })?,
+    None => services.user_roles.insert(
+      &key,
+      UserRoleForm {
+        uid: userid,
+        role: RoleForm::Cashier,
+        pin_hash,
+      },
+    )?,
+  };
+  Ok(reply::json(&()))
+}
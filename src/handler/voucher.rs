@@ -0,0 +1,457 @@
+use super::{cart::CartForm, purchase::VAT_OUT_OF_SCOPE};
+use crate::{cart_expiry, prelude::*, services::Services};
+use chrono::{DateTime, Duration, Local, NaiveDate, Utc};
+use gzlib::proto::purchase::{CartAddSkuRequest, CartByIdRequest, CartRemoveSkuRequest};
+use rand::{rngs::OsRng, Rng};
+use serde::{Deserialize, Serialize};
+use std::{convert::TryInto, env};
+use warp::reply;
+
+const VALID_DAYS_ENV_KEY: &'static str = "VOUCHER_VALID_DAYS";
+// SKU of the cart line vouchers are sold on
+const SKU_ENV_KEY: &'static str = "VOUCHER_SKU_ID";
+// Redemption payment IDs start with this prefix
+pub const PAYMENT_PREFIX: &'static str = "voucher";
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum VoucherStatusForm {
+  // Sold in an open cart, not paid yet
+  Pending,
+  // Paid, can be redeemed
+  Active,
+  // Cart was dropped before close
+  Cancelled,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct VoucherRedemptionForm {
+  pub payment_id: String,
+  pub cart_id: String,
+  pub amount: i32,
+  pub voided: bool,
+  pub created_by: u32,
+  pub created_at: DateTime<Utc>,
+}
+
+/// Multi-purpose gift voucher
+/// Its sale is outside of VAT, VAT is paid on the goods bought with it
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct VoucherForm {
+  pub code: String,
+  pub value: i32,
+  pub balance: i32,
+  pub status: VoucherStatusForm,
+  // Cart (and purchase) the voucher was sold in
+  pub issued_cart_id: String,
+  pub expires_at: Option<NaiveDate>,
+  pub redemptions: Vec<VoucherRedemptionForm>,
+  pub created_by: u32,
+  pub created_at: DateTime<Utc>,
+}
+
+impl VoucherForm {
+  // Hide the code, only its last 4 digits are kept
+  fn masked(mut self) -> Self {
+    let visible = self.code.len().saturating_sub(4);
+    let masked = format!("{}{}", "*".repeat(visible), &self.code[visible..]);
+    for r in self.redemptions.iter_mut() {
+      r.payment_id = r.payment_id.replace(&self.code, &masked);
+    }
+    self.code = masked;
+    self
+  }
+
+  pub fn is_expired(&self) -> bool {
+    match self.expires_at {
+      Some(e) => e < Local::today().naive_local(),
+      None => false,
+    }
+  }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct VoucherIssueForm {
+  cart_id: String,
+  value: i32,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct VoucherCancelForm {
+  cart_id: String,
+  code: String,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct VoucherBalanceForm {
+  code: String,
+  balance: i32,
+  status: VoucherStatusForm,
+  expires_at: Option<NaiveDate>,
+  expired: bool,
+}
+
+// Voucher validity after sale
+fn valid_days() -> i64 {
+  env::var(VALID_DAYS_ENV_KEY)
+    .ok()
+    .and_then(|v| v.parse::<i64>().ok())
+    .unwrap_or(365)
+}
+
+/// SKU of the cart line that holds the vouchers sold in the cart
+pub fn voucher_sku() -> Option<u32> {
+  env::var(SKU_ENV_KEY)
+    .ok()
+    .and_then(|v| v.parse::<u32>().ok())
+}
+
+pub fn is_voucher_sku(sku: u32) -> bool {
+  voucher_sku() == Some(sku)
+}
+
+/// Voucher line is managed by issuing and cancelling vouchers only
+pub fn check_not_voucher_sku(sku: u32) -> Result<(), ApiError> {
+  match is_voucher_sku(sku) {
+    true => Err(ApiError::bad_request(
+      "Az utalvány sor csak utalvány kiadásával vagy törlésével módosítható!",
+    )),
+    false => Ok(()),
+  }
+}
+
+// Unique 12 digit voucher code from the OS random source
+fn new_code(services: &Services) -> String {
+  let mut rng = OsRng;
+  loop {
+    let code: String = (0..12)
+      .map(|_| std::char::from_digit(rng.gen_range(0..10), 10).unwrap())
+      .collect();
+    if !services.vouchers.contains(&code) {
+      return code;
+    }
+  }
+}
+
+/// Vouchers sold in the given cart or purchase
+pub fn cart_vouchers(services: &Services, cart_id: &str) -> Vec<VoucherForm> {
+  services
+    .vouchers
+    .filter(|v| v.issued_cart_id == cart_id && v.status != VoucherStatusForm::Cancelled)
+}
+
+/// Set the voucher line of the cart to the total of its pending vouchers
+/// The line is out of the VAT scope, so its net and gross price are equal
+async fn sync_cart_line(services: &mut Services, cart_id: &str) -> Result<CartForm, ApiError> {
+  let sku = voucher_sku().ok_or(ApiError::internal_error(
+    "Nincs beállítva az utalványok SKU-ja!",
+  ))?;
+  let pending: Vec<VoucherForm> = cart_vouchers(services, cart_id)
+    .into_iter()
+    .filter(|v| v.status == VoucherStatusForm::Pending)
+    .collect();
+  let total: i32 = pending.iter().map(|v| v.value).sum();
+
+  let mut cart: CartForm = services
+    .purchase
+    .cart_get_by_id(CartByIdRequest {
+      cart_id: cart_id.to_string(),
+    })
+    .await
+    .map_err(|e| ApiError::from(e))?
+    .into_inner()
+    .try_into()?;
+  if let Some(line) = cart.shopping_list.iter().find(|i| i.sku == sku) {
+    if line.piece == 1 && line.retail_price_gross as i32 == total {
+      return Ok(cart);
+    }
+    cart = services
+      .purchase
+      .cart_remove_sku(CartRemoveSkuRequest {
+        cart_id: cart_id.to_string(),
+        sku_id: sku,
+      })
+      .await
+      .map_err(|e| ApiError::from(e))?
+      .into_inner()
+      .try_into()?;
+  }
+  if total > 0 {
+    cart = services
+      .purchase
+      .cart_add_sku(CartAddSkuRequest {
+        cart_id: cart_id.to_string(),
+        sku_id: sku,
+        piece: 1,
+        name: format!("Ajándékutalvány ({} db)", pending.len()),
+        vat: VAT_OUT_OF_SCOPE.to_string(),
+        retail_price_net: total as u32,
+        retail_price_gross: total as u32,
+      })
+      .await
+      .map_err(|e| ApiError::from(e))?
+      .into_inner()
+      .try_into()?;
+  }
+  Ok(cart)
+}
+
+/// Cancel vouchers of a cart dropped before close
+pub fn cancel_cart_vouchers(services: &Services, cart_id: &str) -> Result<(), ApiError> {
+  for v in cart_vouchers(services, cart_id) {
+    if v.status == VoucherStatusForm::Pending {
+      services.vouchers.update(&v.code, |v| {
+        v.status = VoucherStatusForm::Cancelled;
+        Ok(())
+      })?;
+    }
+  }
+  Ok(())
+}
+
+/// Activate vouchers sold in a closed cart
+pub fn activate_cart_vouchers(services: &Services, cart_id: &str) -> Result<(), ApiError> {
+  let expires_at = Local::today().naive_local() + Duration::days(valid_days());
+  for v in cart_vouchers(services, cart_id) {
+    if v.status == VoucherStatusForm::Pending {
+      services.vouchers.update(&v.code, |v| {
+        v.status = VoucherStatusForm::Active;
+        v.expires_at = Some(expires_at);
+        Ok(())
+      })?;
+    }
+  }
+  Ok(())
+}
+
+/// Redemptions of vouchers used as payment in the given cart or purchase
+pub fn cart_redemptions(
+  services: &Services,
+  cart_id: &str,
+) -> Vec<(String, VoucherRedemptionForm)> {
+  services
+    .vouchers
+    .filter(|v| v.redemptions.iter().any(|r| r.cart_id == cart_id))
+    .into_iter()
+    .flat_map(|v| {
+      let code = v.code.clone();
+      v.redemptions
+        .into_iter()
+        .filter(|r| r.cart_id == cart_id && !r.voided)
+        .map(move |r| (code.clone(), r))
+    })
+    .collect()
+}
+
+/// Use voucher as payment
+/// Returns the payment ID
+pub fn redeem(
+  services: &Services,
+  code: &str,
+  cart_id: &str,
+  amount: i32,
+  uid: u32,
+) -> Result<String, ApiError> {
+  if amount <= 0 {
+    return Err(ApiError::bad_request(
+      "A beváltott összegnek pozitívnak kell lennie!",
+    ));
+  }
+  if !services.vouchers.contains(code) {
+    return Err(ApiError::bad_request("Nincs ilyen utalvány!"));
+  }
+  // Check and reduce balance under the same lock
+  let mut payment_id = String::new();
+  services.vouchers.update(code, |v| {
+    if v.status != VoucherStatusForm::Active {
+      return Err(ApiError::bad_request("Az utalvány nem aktív!"));
+    }
+    if v.is_expired() {
+      return Err(ApiError::bad_request("Az utalvány lejárt!"));
+    }
+    if v.balance < amount {
+      return Err(ApiError::bad_request(&format!(
+        "Az utalvány egyenlege nem elegendő! Egyenleg: {} HUF",
+        v.balance
+      )));
+    }
+    payment_id = format!("{}_{}_{}", PAYMENT_PREFIX, code, v.redemptions.len() + 1);
+    v.balance -= amount;
+    v.redemptions.push(VoucherRedemptionForm {
+      payment_id: payment_id.clone(),
+      cart_id: cart_id.to_string(),
+      amount,
+      voided: false,
+      created_by: uid,
+      created_at: Utc::now(),
+    });
+    Ok(())
+  })?;
+  Ok(payment_id)
+}
+
+/// Give back a voided redemption to its voucher
+pub fn void_redemption(services: &Services, payment_id: &str) -> Result<i32, ApiError> {
+  let voucher = services
+    .vouchers
+    .filter(|v| v.redemptions.iter().any(|r| r.payment_id == payment_id))
+    .pop()
+    .ok_or(ApiError::not_found())?;
+  let mut amount = 0;
+  services.vouchers.update(&voucher.code, |v| {
+    if let Some(r) = v
+      .redemptions
+      .iter_mut()
+      .find(|r| r.payment_id == payment_id && !r.voided)
+    {
+      r.voided = true;
+      amount = r.amount;
+    }
+    v.balance += amount;
+    Ok(())
+  })?;
+  Ok(amount)
+}
+
+/// Undo a voided redemption, when its reversal could not be recorded
+pub fn restore_redemption(services: &Services, payment_id: &str) -> Result<(), ApiError> {
+  let voucher = services
+    .vouchers
+    .filter(|v| v.redemptions.iter().any(|r| r.payment_id == payment_id))
+    .pop()
+    .ok_or(ApiError::not_found())?;
+  services.vouchers.update(&voucher.code, |v| {
+    if let Some(r) = v
+      .redemptions
+      .iter_mut()
+      .find(|r| r.payment_id == payment_id && r.voided)
+    {
+      r.voided = false;
+      v.balance -= r.amount;
+    }
+    Ok(())
+  })?;
+  Ok(())
+}
+
+pub async fn issue(uid: u32, mut services: Services, f: VoucherIssueForm) -> ApiResult {
+  if f.value <= 0 {
+    return Err(ApiError::bad_request("Az utalvány értékének pozitívnak kell lennie!").into());
+  }
+  // Keep cash paid totals roundable
+  if f.value % 5 != 0 {
+    return Err(ApiError::bad_request("Az utalvány értéke csak 0-ra vagy 5-re végződhet!").into());
+  }
+
+  cart_expiry::touch(&services, &f.cart_id).await?;
+
+  // Check if cart exists
+  let cart = services
+    .purchase
+    .cart_get_by_id(CartByIdRequest {
+      cart_id: f.cart_id.clone(),
+    })
+    .await
+    .map_err(|e| ApiError::from(e))?
+    .into_inner();
+
+  let code = new_code(&services);
+  let res = services.vouchers.insert(
+    &code,
+    VoucherForm {
+      code: code.clone(),
+      value: f.value,
+      balance: f.value,
+      status: VoucherStatusForm::Pending,
+      issued_cart_id: cart.id,
+      expires_at: None,
+      redemptions: Vec::new(),
+      created_by: uid,
+      created_at: Utc::now(),
+    },
+  )?;
+
+  // Voucher is paid as a cart line
+  if let Err(e) = sync_cart_line(&mut services, &f.cart_id).await {
+    services.vouchers.update(&code, |v| {
+      v.status = VoucherStatusForm::Cancelled;
+      Ok(())
+    })?;
+    return Err(e.into());
+  }
+  Ok(reply::json(&res))
+}
+
+pub async fn cancel(_uid: u32, mut services: Services, f: VoucherCancelForm) -> ApiResult {
+  cart_expiry::touch(&services, &f.cart_id).await?;
+  let res = services.vouchers.update(&f.code, |v| {
+    if v.issued_cart_id != f.cart_id || v.status != VoucherStatusForm::Pending {
+      return Err(ApiError::bad_request(
+        "Csak a kosárban lévő, még ki nem fizetett utalvány törölhető!",
+      ));
+    }
+    v.status = VoucherStatusForm::Cancelled;
+    Ok(())
+  })?;
+  sync_cart_line(&mut services, &f.cart_id).await?;
+  Ok(reply::json(&res))
+}
+
+pub async fn get_by_cart(cart_id: String, _uid: u32, services: Services) -> ApiResult {
+  let res = cart_vouchers(&services, &cart_id);
+  Ok(reply::json(&res))
+}
+
+pub async fn get_balance(code: String, _uid: u32, services: Services) -> ApiResult {
+  let v = services.vouchers.get(&code).ok_or(ApiError::not_found())?;
+  Ok(reply::json(&VoucherBalanceForm {
+    expired: v.is_expired(),
+    code: v.code,
+    balance: v.balance,
+    status: v.status,
+    expires_at: v.expires_at,
+  }))
+}
+
+pub async fn get_all(uid: u32, services: Services) -> ApiResult {
+  super::user::require_role(&services, uid, super::user::RoleForm::Manager)?;
+  let res: Vec<VoucherForm> = services
+    .vouchers
+    .get_all()
+    .into_iter()
+    .map(|v| v.masked())
+    .collect();
+  Ok(reply::json(&res))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_masked() {
+    let voucher = VoucherForm {
+      code: "123456789012".to_string(),
+      value: 5000,
+      balance: 3000,
+      status: VoucherStatusForm::Active,
+      issued_cart_id: "C_1".to_string(),
+      expires_at: None,
+      redemptions: vec![VoucherRedemptionForm {
+        payment_id: format!("{}_123456789012_1", PAYMENT_PREFIX),
+        cart_id: "C_2".to_string(),
+        amount: 2000,
+        voided: false,
+        created_by: 1,
+        created_at: Utc::now(),
+      }],
+      created_by: 1,
+      created_at: Utc::now(),
+    }
+    .masked();
+    assert_eq!(voucher.code, "********9012");
+    assert_eq!(
+      voucher.redemptions[0].payment_id,
+      format!("{}_********9012_1", PAYMENT_PREFIX)
+    );
+  }
+}
@@ -0,0 +1,374 @@
+use crypto::{digest::Digest, sha2::Sha256};
+use hyper::{
+  body::{Bytes, HttpBody},
+  header::{HeaderValue, CONTENT_LENGTH, CONTENT_TYPE},
+  service::Service,
+  Body, Method, Request, Response, StatusCode,
+};
+use serde::Serialize;
+use std::{
+  collections::HashMap,
+  convert::Infallible,
+  env,
+  sync::{Arc, Mutex},
+  time::{Duration, Instant},
+};
+
+const IDEMPOTENCY_HEADER: &'static str = "Idempotency-Key";
+const TTL_ENV_KEY: &'static str = "IDEMPOTENCY_TTL_SECS";
+// Default replay window is one day
+const DEFAULT_TTL_SECS: u64 = 86_400;
+const MAX_BODY_ENV_KEY: &'static str = "IDEMPOTENCY_MAX_BODY_BYTES";
+// Above the largest route limit (SKU image upload)
+const DEFAULT_MAX_BODY_BYTES: usize = 4_000_000;
+
+#[derive(Serialize)]
+struct ErrorMessage {
+  code: u16,
+  message: String,
+}
+
+#[derive(Debug, Clone)]
+struct StoredResponse {
+  status: StatusCode,
+  content_type: Option<HeaderValue>,
+  body: Bytes,
+}
+
+#[derive(Debug, Clone)]
+enum EntryState {
+  // First request is still being processed
+  InFlight,
+  // First request is done, its response can be replayed
+  Done(StoredResponse),
+}
+
+#[derive(Debug, Clone)]
+struct Entry {
+  fingerprint: String,
+  state: EntryState,
+  created_at: Instant,
+}
+
+enum Begin {
+  New,
+  Replay(StoredResponse),
+  Conflict,
+  InFlight,
+}
+
+/// In-memory store of responses given to POST/PUT requests
+/// carrying an Idempotency-Key header
+#[derive(Debug, Clone)]
+pub struct IdempotencyStore {
+  ttl: Duration,
+  // Largest request and stored response body
+  max_body: usize,
+  entries: Arc<Mutex<HashMap<String, Entry>>>,
+}
+
+impl IdempotencyStore {
+  pub fn init() -> Self {
+    let ttl = env::var(TTL_ENV_KEY)
+      .ok()
+      .and_then(|v| v.parse::<u64>().ok())
+      .unwrap_or(DEFAULT_TTL_SECS);
+    let max_body = env::var(MAX_BODY_ENV_KEY)
+      .ok()
+      .and_then(|v| v.parse::<usize>().ok())
+      .unwrap_or(DEFAULT_MAX_BODY_BYTES);
+    Self {
+      ttl: Duration::from_secs(ttl),
+      max_body,
+      entries: Arc::new(Mutex::new(HashMap::new())),
+    }
+  }
+
+  fn begin(&self, key: &str, fingerprint: &str) -> Begin {
+    let mut entries = self.entries.lock().unwrap();
+    // Remove expired entries
+    let ttl = self.ttl;
+    entries.retain(|_, e| e.created_at.elapsed() < ttl);
+    match entries.get(key) {
+      Some(entry) => {
+        if entry.fingerprint != fingerprint {
+          return Begin::Conflict;
+        }
+        match &entry.state {
+          EntryState::InFlight => Begin::InFlight,
+          EntryState::Done(res) => Begin::Replay(res.clone()),
+        }
+      }
+      None => {
+        entries.insert(
+          key.to_string(),
+          Entry {
+            fingerprint: fingerprint.to_string(),
+            state: EntryState::InFlight,
+            created_at: Instant::now(),
+          },
+        );
+        Begin::New
+      }
+    }
+  }
+
+  fn finish(&self, key: &str, res: StoredResponse) {
+    if let Some(entry) = self.entries.lock().unwrap().get_mut(key) {
+      entry.state = EntryState::Done(res);
+    }
+  }
+
+  fn abort(&self, key: &str) {
+    self.entries.lock().unwrap().remove(key);
+  }
+}
+
+// Removes the in-flight entry if the request future is dropped
+// before finishing, e.g. on client disconnect, so retries are not blocked
+struct InFlightGuard<'a> {
+  store: &'a IdempotencyStore,
+  key: &'a str,
+  finished: bool,
+}
+
+impl<'a> InFlightGuard<'a> {
+  fn finish(mut self, res: StoredResponse) {
+    self.store.finish(self.key, res);
+    self.finished = true;
+  }
+}
+
+impl<'a> Drop for InFlightGuard<'a> {
+  fn drop(&mut self) {
+    if !self.finished {
+      self.store.abort(self.key);
+    }
+  }
+}
+
+impl StoredResponse {
+  fn to_response(&self) -> Response<Body> {
+    let mut res = Response::new(Body::from(self.body.clone()));
+    *res.status_mut() = self.status;
+    if let Some(ct) = &self.content_type {
+      res.headers_mut().insert(CONTENT_TYPE, ct.clone());
+    }
+    res
+  }
+}
+
+fn error_response(code: StatusCode, message: &str) -> Response<Body> {
+  let body = serde_json::to_vec(&ErrorMessage {
+    code: code.as_u16(),
+    message: message.to_string(),
+  })
+  .unwrap_or_default();
+  let mut res = Response::new(Body::from(body));
+  *res.status_mut() = code;
+  res
+    .headers_mut()
+    .insert(CONTENT_TYPE, HeaderValue::from_static("application/json"));
+  res
+}
+
+// Read the whole body, None if it is larger than the limit
+async fn read_body(mut body: Body, limit: usize) -> Result<Option<Bytes>, hyper::Error> {
+  let mut buf: Vec<u8> = Vec::new();
+  while let Some(chunk) = body.data().await {
+    let chunk = chunk?;
+    if buf.len() + chunk.len() > limit {
+      return Ok(None);
+    }
+    buf.extend_from_slice(&chunk);
+  }
+  Ok(Some(Bytes::from(buf)))
+}
+
+/// Wraps the API service
+/// POST and PUT requests with an Idempotency-Key header are executed only once;
+/// retries with the same key and body get back the stored first response,
+/// while a different body with an already used key gets 409.
+pub async fn handle<S>(
+  store: IdempotencyStore,
+  mut service: S,
+  req: Request<Body>,
+) -> Result<Response<Body>, Infallible>
+where
+  S: Service<Request<Body>, Response = Response<Body>, Error = Infallible>,
+{
+  let key = match *req.method() {
+    Method::POST | Method::PUT => req
+      .headers()
+      .get(IDEMPOTENCY_HEADER)
+      .and_then(|k| k.to_str().ok())
+      .map(|k| k.to_string()),
+    _ => None,
+  };
+
+  // No key, nothing to do
+  let key = match key {
+    Some(k) => k,
+    None => return service.call(req).await,
+  };
+
+  // Scope key by token, so clients cannot replay each other's responses
+  let key = format!(
+    "{}:{}",
+    req
+      .headers()
+      .get("Token")
+      .and_then(|t| t.to_str().ok())
+      .unwrap_or(""),
+    key
+  );
+
+  // Request body is buffered for the fingerprint, so its size is capped
+  let too_large = || error_response(StatusCode::PAYLOAD_TOO_LARGE, "Túl nagy kérés törzs");
+  let content_length = req
+    .headers()
+    .get(CONTENT_LENGTH)
+    .and_then(|l| l.to_str().ok())
+    .and_then(|l| l.parse::<usize>().ok());
+  if content_length.map(|l| l > store.max_body).unwrap_or(false) {
+    return Ok(too_large());
+  }
+  let (parts, body) = req.into_parts();
+  let body = match read_body(body, store.max_body).await {
+    Ok(Some(b)) => b,
+    Ok(None) => return Ok(too_large()),
+    Err(_) => return Ok(error_response(StatusCode::BAD_REQUEST, "Hibás kérés törzs")),
+  };
+
+  // Request fingerprint; same key must come with the same request
+  let mut hasher = Sha256::new();
+  hasher.input_str(parts.method.as_str());
+  hasher.input_str(&parts.uri.to_string());
+  hasher.input(&body);
+  let fingerprint = hasher.result_str();
+
+  match store.begin(&key, &fingerprint) {
+    Begin::New => (),
+    Begin::Replay(res) => return Ok(res.to_response()),
+    Begin::Conflict => {
+      return Ok(error_response(
+        StatusCode::CONFLICT,
+        "Az Idempotency-Key már egy másik kéréshez tartozik!",
+      ))
+    }
+    Begin::InFlight => {
+      return Ok(error_response(
+        StatusCode::CONFLICT,
+        "Az Idempotency-Key-hez tartozó kérés még feldolgozás alatt van!",
+      ))
+    }
+  }
+
+  let guard = InFlightGuard {
+    store: &store,
+    key: &key,
+    finished: false,
+  };
+
+  let res = service
+    .call(Request::from_parts(parts, Body::from(body)))
+    .await?;
+
+  // Server errors are not stored, retry is allowed
+  // (dropping the guard removes the entry)
+  if res.status().is_server_error() {
+    return Ok(res);
+  }
+
+  // Responses of unknown or too large size are not stored
+  // (dropping the guard removes the entry)
+  match res.body().size_hint().upper() {
+    Some(size) if size <= store.max_body as u64 => (),
+    _ => return Ok(res),
+  }
+
+  let (parts, body) = res.into_parts();
+  let body = match read_body(body, store.max_body).await {
+    Ok(Some(b)) => b,
+    _ => {
+      return Ok(error_response(
+        StatusCode::INTERNAL_SERVER_ERROR,
+        "Válasz olvasási hiba",
+      ));
+    }
+  };
+
+  guard.finish(StoredResponse {
+    status: parts.status,
+    content_type: parts.headers.get(CONTENT_TYPE).cloned(),
+    body: body.clone(),
+  });
+
+  Ok(Response::from_parts(parts, Body::from(body)))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn store() -> IdempotencyStore {
+    IdempotencyStore {
+      ttl: Duration::from_secs(60),
+      max_body: DEFAULT_MAX_BODY_BYTES,
+      entries: Arc::new(Mutex::new(HashMap::new())),
+    }
+  }
+
+  fn response() -> StoredResponse {
+    StoredResponse {
+      status: StatusCode::OK,
+      content_type: None,
+      body: Bytes::from("ok"),
+    }
+  }
+
+  #[test]
+  fn test_replay_and_conflict() {
+    let store = store();
+    assert!(matches!(store.begin("k", "a"), Begin::New));
+    assert!(matches!(store.begin("k", "a"), Begin::InFlight));
+    store.finish("k", response());
+    assert!(matches!(store.begin("k", "a"), Begin::Replay(_)));
+    assert!(matches!(store.begin("k", "b"), Begin::Conflict));
+  }
+
+  #[test]
+  fn test_dropped_guard_releases_key() {
+    let store = store();
+    assert!(matches!(store.begin("k", "a"), Begin::New));
+    {
+      let _guard = InFlightGuard {
+        store: &store,
+        key: "k",
+        finished: false,
+      };
+    }
+    assert!(matches!(store.begin("k", "a"), Begin::New));
+  }
+
+  #[test]
+  fn test_finished_guard_keeps_response() {
+    let store = store();
+    assert!(matches!(store.begin("k", "a"), Begin::New));
+    let guard = InFlightGuard {
+      store: &store,
+      key: "k",
+      finished: false,
+    };
+    guard.finish(response());
+    assert!(matches!(store.begin("k", "a"), Begin::Replay(_)));
+  }
+
+  #[tokio::test]
+  async fn test_read_body_limit() {
+    let body = read_body(Body::from("12345"), 5).await.unwrap();
+    assert_eq!(body, Some(Bytes::from("12345")));
+    let body = read_body(Body::from("123456"), 5).await.unwrap();
+    assert_eq!(body, None);
+  }
+}
@@ -1,16 +1,24 @@
 #[macro_use]
 mod balance;
+mod cart_expiry;
+mod display;
 mod error;
 mod handler;
+mod idempotency;
 mod login;
+mod outbox;
 mod prelude;
+mod quote;
 mod receipt;
 mod routes;
 mod services;
+mod signature;
+mod storage;
 // use error::*;
 // use login::UserId;
 use error::handle_rejection;
-use std::error::Error;
+use hyper::service::{make_service_fn, service_fn};
+use std::{convert::Infallible, error::Error, net::SocketAddr};
 use tokio::{signal, sync::oneshot};
 use warp::Filter;
 // use warp::*;
@@ -19,19 +27,46 @@ use warp::Filter;
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn Error>> {
   let services = services::Services::init().await;
+
+  // Start outbox worker and reconciliation job
+  tokio::task::spawn(outbox::run(services.clone()));
+  tokio::task::spawn(outbox::run_reconcile(services.clone()));
+  // Start cart expiry job
+  tokio::task::spawn(cart_expiry::run(services.clone()));
+  // Index purchases closed before purchase search
+  tokio::task::spawn(handler::purchase::run_index_backfill(services.clone()));
   // Create shutdown channel
   let (tx, rx) = oneshot::channel();
 
-  // Init server
-  let (addr, server) = warp::serve(
+  // Init idempotency store
+  let idempotency_store = idempotency::IdempotencyStore::init();
+
+  // Init API service
+  let api = warp::service(
     warp::any()
       .and(routes::get_all(services).await)
       .recover(handle_rejection),
-  )
-  .bind_with_graceful_shutdown(([0, 0, 0, 0], 3030), async {
-    rx.await.ok();
+  );
+
+  // Wrap API service with idempotency handling
+  let make_svc = make_service_fn(move |_| {
+    let api = api.clone();
+    let store = idempotency_store.clone();
+    async move {
+      Ok::<_, Infallible>(service_fn(move |req| {
+        idempotency::handle(store.clone(), api.clone(), req)
+      }))
+    }
   });
 
+  // Init server
+  let addr = SocketAddr::from(([0, 0, 0, 0], 3030));
+  let server = hyper::Server::bind(&addr)
+    .serve(make_svc)
+    .with_graceful_shutdown(async {
+      rx.await.ok();
+    });
+
   println!("API is running at {}", addr);
 
   // Spawn the server into a runtime
@@ -0,0 +1,591 @@
+use crate::{
+  handler::{
+    purchase::{PurchaseForm, PurchaseInfoForm},
+    voucher,
+  },
+  prelude::*,
+  services::Services,
+};
+use chrono::{DateTime, Duration, Utc};
+use gzlib::proto::{
+  commitment::{AddPurchaseRequest, CustomerRequest},
+  invoice::InvoiceForm,
+  loyalty::{
+    transaction::TransactionKind, ClosePurchaseRequest, PurchaseSummary, TransactionAllRequest,
+  },
+  purchase::{PurchaseBulkRequest, PurchaseByIdRequest, PurchaseSetInvoiceIdRequest},
+  upl::CloseCartRequest,
+};
+use serde::{Deserialize, Serialize};
+use std::env;
+
+// After this many failed attempts an item is reported as stuck
+pub const STUCK_ATTEMPTS: u32 = 5;
+// Outbox is checked this often
+const WORKER_INTERVAL_SECS: u64 = 30;
+// Backoff limit between two attempts
+const MAX_BACKOFF_SECS: i64 = 3600;
+// Claim of an item is released after this,
+// so an attempt interrupted by a crash is retried
+const CLAIM_TIMEOUT_SECS: i64 = 600;
+
+const RECONCILE_INTERVAL_ENV_KEY: &'static str = "RECONCILE_INTERVAL_SECS";
+const RECONCILE_DAYS_ENV_KEY: &'static str = "RECONCILE_DAYS";
+
+/// Post-sale side effect that must reach its service
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub enum OutboxTask {
+  CommitmentAddPurchase {
+    customer_id: u32,
+    commitment_id: String,
+    purchase_id: String,
+    total_net: u32,
+    total_gross: u32,
+    applied_discount: u32,
+  },
+  LoyaltyClosePurchase {
+    account_id: String,
+    purchase_id: String,
+    total_gross: u32,
+    created_by: u32,
+  },
+  LoyaltySetSummary {
+    account_id: String,
+    purchase_id: String,
+    balance_opening: i32,
+    burned_points: i32,
+    earned_points: i32,
+    balance_closing: i32,
+  },
+  UplCloseCart {
+    cart_id: String,
+    created_by: u32,
+  },
+  VoucherActivate {
+    purchase_id: String,
+  },
+  // Creates the invoice and sets its ID to the purchase
+  InvoiceCreate {
+    purchase_id: String,
+  },
+  InvoiceSetId {
+    purchase_id: String,
+    invoice_id: String,
+  },
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct OutboxItem {
+  pub id: String,
+  pub task: OutboxTask,
+  pub attempts: u32,
+  pub last_error: String,
+  pub next_attempt_at: DateTime<Utc>,
+  // Set while the item is executed
+  #[serde(default)]
+  pub taken_at: Option<DateTime<Utc>>,
+  pub created_at: DateTime<Utc>,
+}
+
+impl OutboxItem {
+  pub fn is_taken(&self, now: DateTime<Utc>) -> bool {
+    match self.taken_at {
+      Some(taken_at) => taken_at + Duration::seconds(CLAIM_TIMEOUT_SECS) > now,
+      None => false,
+    }
+  }
+}
+
+impl OutboxTask {
+  pub fn loyalty_set_summary(summary: PurchaseSummary) -> Self {
+    OutboxTask::LoyaltySetSummary {
+      account_id: summary.account_id,
+      purchase_id: summary.purchase_id,
+      balance_opening: summary.balance_opening,
+      burned_points: summary.burned_points,
+      earned_points: summary.earned_points,
+      balance_closing: summary.balance_closing,
+    }
+  }
+
+  // Task ID; one purchase can have only one task per kind
+  pub fn id(&self) -> String {
+    match self {
+      OutboxTask::CommitmentAddPurchase { purchase_id, .. } => {
+        format!("commitment_add_purchase_{}", purchase_id)
+      }
+      OutboxTask::LoyaltyClosePurchase { purchase_id, .. } => {
+        format!("loyalty_close_purchase_{}", purchase_id)
+      }
+      OutboxTask::LoyaltySetSummary { purchase_id, .. } => {
+        format!("loyalty_set_summary_{}", purchase_id)
+      }
+      OutboxTask::UplCloseCart { cart_id, .. } => format!("upl_close_cart_{}", cart_id),
+      OutboxTask::VoucherActivate { purchase_id } => {
+        format!("voucher_activate_{}", purchase_id)
+      }
+      OutboxTask::InvoiceCreate { purchase_id } => format!("invoice_create_{}", purchase_id),
+      OutboxTask::InvoiceSetId { purchase_id, .. } => {
+        format!("invoice_set_id_{}", purchase_id)
+      }
+    }
+  }
+
+  /// Purchase the task belongs to
+  pub fn purchase_id(&self) -> &str {
+    match self {
+      OutboxTask::CommitmentAddPurchase { purchase_id, .. } => purchase_id,
+      OutboxTask::LoyaltyClosePurchase { purchase_id, .. } => purchase_id,
+      OutboxTask::LoyaltySetSummary { purchase_id, .. } => purchase_id,
+      // Closed cart ID is the purchase ID
+      OutboxTask::UplCloseCart { cart_id, .. } => cart_id,
+      OutboxTask::VoucherActivate { purchase_id } => purchase_id,
+      OutboxTask::InvoiceCreate { purchase_id } => purchase_id,
+      OutboxTask::InvoiceSetId { purchase_id, .. } => purchase_id,
+    }
+  }
+
+  // Execute task, and return its follow-up task if it has any
+  // Tasks that are not idempotent check first if they have been applied
+  async fn execute(&self, services: &mut Services) -> Result<Option<OutboxTask>, tonic::Status> {
+    match self {
+      OutboxTask::CommitmentAddPurchase {
+        customer_id,
+        commitment_id,
+        purchase_id,
+        total_net,
+        total_gross,
+        applied_discount,
+      } => {
+        if commitment_has_purchase(services, *customer_id, commitment_id, purchase_id).await? {
+          return Ok(None);
+        }
+        services
+          .commitment
+          .add_purchase(AddPurchaseRequest {
+            customer_id: *customer_id,
+            commitment_id: commitment_id.clone(),
+            purchase_id: purchase_id.clone(),
+            total_net: *total_net,
+            total_gross: *total_gross,
+            applied_discount: *applied_discount,
+          })
+          .await?;
+      }
+      OutboxTask::LoyaltyClosePurchase {
+        account_id,
+        purchase_id,
+        total_gross,
+        created_by,
+      } => {
+        // Closing it again would earn the points twice
+        if loyalty_has_purchase(services, account_id, purchase_id).await? {
+          return Ok(None);
+        }
+        let summary = services
+          .loyalty
+          .close_purchase(ClosePurchaseRequest {
+            account_id: account_id.clone(),
+            purchase_id: purchase_id.clone(),
+            total_gross: *total_gross,
+            created_by: *created_by,
+          })
+          .await?
+          .into_inner();
+        // Points are earned, summary is set as a separate step
+        return Ok(Some(OutboxTask::loyalty_set_summary(summary)));
+      }
+      OutboxTask::LoyaltySetSummary {
+        account_id,
+        purchase_id,
+        balance_opening,
+        burned_points,
+        earned_points,
+        balance_closing,
+      } => {
+        services
+          .purchase
+          .purchase_set_loyalty_summary(PurchaseSummary {
+            account_id: account_id.clone(),
+            purchase_id: purchase_id.clone(),
+            balance_opening: *balance_opening,
+            burned_points: *burned_points,
+            earned_points: *earned_points,
+            balance_closing: *balance_closing,
+          })
+          .await?;
+      }
+      OutboxTask::UplCloseCart {
+        cart_id,
+        created_by,
+      } => {
+        services
+          .upl
+          .close_cart(CloseCartRequest {
+            cart_id: cart_id.clone(),
+            created_by: *created_by,
+          })
+          .await?;
+      }
+      OutboxTask::VoucherActivate { purchase_id } => {
+        voucher::activate_cart_vouchers(services, purchase_id)
+          .map_err(|e| tonic::Status::internal(e.message()))?;
+      }
+      OutboxTask::InvoiceCreate { purchase_id } => {
+        let purchase: PurchaseForm = services
+          .purchase
+          .purchase_get_by_id(PurchaseByIdRequest {
+            purchase_id: purchase_id.clone(),
+          })
+          .await?
+          .into_inner()
+          .into();
+        // Invoice has been created already
+        if purchase.invoice_id.len() > 0 {
+          return Ok(None);
+        }
+        let invoice_request: InvoiceForm = purchase
+          .with_discounts(services)
+          .with_vouchers(services)
+          .with_promotions(services)
+          .into();
+        let invoice_data = services
+          .invoice
+          .create_new(invoice_request)
+          .await?
+          .into_inner();
+        services
+          .purchase
+          .purchase_set_invoice_id(PurchaseSetInvoiceIdRequest {
+            purchase_id: purchase_id.clone(),
+            invoice_id: invoice_data.id,
+          })
+          .await?;
+      }
+      OutboxTask::InvoiceSetId {
+        purchase_id,
+        invoice_id,
+      } => {
+        services
+          .purchase
+          .purchase_set_invoice_id(PurchaseSetInvoiceIdRequest {
+            purchase_id: purchase_id.clone(),
+            invoice_id: invoice_id.clone(),
+          })
+          .await?;
+      }
+    }
+    Ok(None)
+  }
+}
+
+// Check if commitment has the purchase in its log
+async fn commitment_has_purchase(
+  services: &mut Services,
+  customer_id: u32,
+  commitment_id: &str,
+  purchase_id: &str,
+) -> Result<bool, tonic::Status> {
+  let commitment_customer = services
+    .commitment
+    .get_customer(CustomerRequest { customer_id })
+    .await?
+    .into_inner();
+  Ok(
+    commitment_customer
+      .commitments
+      .iter()
+      .filter(|c| c.commitment_id == commitment_id)
+      .any(|c| c.purchase_log.iter().any(|p| p.purchase_id == purchase_id)),
+  )
+}
+
+// Check if loyalty account has an earn transaction for the purchase
+async fn loyalty_has_purchase(
+  services: &mut Services,
+  account_id: &str,
+  purchase_id: &str,
+) -> Result<bool, tonic::Status> {
+  let mut all = services
+    .loyalty
+    .get_transactions_all(TransactionAllRequest {
+      account_id: account_id.to_string(),
+    })
+    .await?
+    .into_inner();
+  let mut registered = false;
+  while let Some(tr) = all.message().await? {
+    if tr.purchase_id == purchase_id && tr.transaction_kind == TransactionKind::Earn as i32 {
+      registered = true;
+    }
+  }
+  Ok(registered)
+}
+
+/// Store failed side effect into the outbox
+/// The background worker retries it later
+pub fn enqueue(services: &Services, task: OutboxTask, error: &str) -> Result<(), ApiError> {
+  let id = task.id();
+  // Already queued
+  if services.outbox.contains(&id) {
+    return Ok(());
+  }
+  let now = Utc::now();
+  services.outbox.insert(
+    &id,
+    OutboxItem {
+      id: id.clone(),
+      task,
+      attempts: 0,
+      last_error: error.to_string(),
+      next_attempt_at: now + Duration::seconds(WORKER_INTERVAL_SECS as i64),
+      taken_at: None,
+      created_at: now,
+    },
+  )?;
+  Ok(())
+}
+
+// Exponential backoff after the given number of attempts
+fn backoff(attempts: u32) -> Duration {
+  let secs = (WORKER_INTERVAL_SECS as i64) << attempts.min(10);
+  Duration::seconds(secs.min(MAX_BACKOFF_SECS))
+}
+
+// Mark item as taken, so the worker and a manual retry
+// cannot execute it at the same time
+fn claim(services: &Services, id: &str) -> Result<OutboxItem, ApiError> {
+  services.outbox.update(id, |i| {
+    let now = Utc::now();
+    if i.is_taken(now) {
+      return Err(ApiError::bad_request(
+        "A tétel végrehajtása már folyamatban van!",
+      ));
+    }
+    i.taken_at = Some(now);
+    Ok(())
+  })
+}
+
+/// Claim and execute one outbox item
+pub async fn process_item(services: &mut Services, id: &str) -> Result<(), ApiError> {
+  let item = claim(services, id)?;
+  match item.task.execute(services).await {
+    Ok(next) => {
+      services.outbox.remove(&item.id)?;
+      if let Some(next) = next {
+        enqueue(services, next, "")?;
+      }
+    }
+    Err(e) => {
+      eprintln!("Outbox item {} failed: {}", item.id, e.message());
+      services.outbox.update(&item.id, |i| {
+        i.attempts += 1;
+        i.last_error = e.message().to_string();
+        i.next_attempt_at = Utc::now() + backoff(i.attempts);
+        i.taken_at = None;
+        Ok(())
+      })?;
+    }
+  }
+  Ok(())
+}
+
+/// Try to execute every due outbox item once
+pub async fn process(services: &mut Services) {
+  let now = Utc::now();
+  for item in services
+    .outbox
+    .filter(|i| i.next_attempt_at <= now && !i.is_taken(now))
+  {
+    if let Err(e) = process_item(services, &item.id).await {
+      eprintln!("Could not process outbox item {}: {:?}", item.id, e);
+    }
+  }
+}
+
+/// Outbox worker
+/// Runs forever, retries due items with backoff
+pub async fn run(mut services: Services) {
+  loop {
+    process(&mut services).await;
+    tokio::time::sleep(std::time::Duration::from_secs(WORKER_INTERVAL_SECS)).await;
+  }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub enum ReconcileIssueKind {
+  // Commitment does not know about the purchase
+  MissingCommitmentPurchase,
+  // Loyalty account has no earn transaction for the purchase
+  MissingLoyaltyTransaction,
+  // Could not check purchase
+  CheckFailed { error: String },
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ReconcileIssue {
+  pub purchase_id: String,
+  pub kind: ReconcileIssueKind,
+  // Fix has been put into the outbox
+  pub queued: bool,
+}
+
+// Check one purchase against its commitment and loyalty records
+async fn reconcile_purchase(
+  services: &mut Services,
+  purchase: &PurchaseForm,
+) -> Result<Vec<ReconcileIssue>, tonic::Status> {
+  let mut issues = Vec::new();
+
+  // Check commitment
+  if let Some(customer) = &purchase.customer {
+    if purchase.commitment_id.len() > 0 {
+      let registered = commitment_has_purchase(
+        services,
+        customer.id,
+        &purchase.commitment_id,
+        &purchase.purchase_id,
+      )
+      .await?;
+      if !registered {
+        let task = OutboxTask::CommitmentAddPurchase {
+          customer_id: customer.id,
+          commitment_id: purchase.commitment_id.clone(),
+          purchase_id: purchase.purchase_id.clone(),
+          total_net: purchase.total_net_price,
+          total_gross: purchase.total_gross_price,
+          applied_discount: purchase.commitment_discount_percentage,
+        };
+        issues.push(ReconcileIssue {
+          purchase_id: purchase.purchase_id.clone(),
+          kind: ReconcileIssueKind::MissingCommitmentPurchase,
+          queued: enqueue(services, task, "Reconciliation").is_ok(),
+        });
+      }
+    }
+  }
+
+  // Check loyalty
+  if let Some(lc) = &purchase.loyalty_card {
+    if !loyalty_has_purchase(services, &lc.account_id, &purchase.purchase_id).await? {
+      let task = OutboxTask::LoyaltyClosePurchase {
+        account_id: lc.account_id.clone(),
+        purchase_id: purchase.purchase_id.clone(),
+        total_gross: purchase.total_gross_price,
+        created_by: purchase.created_by,
+      };
+      issues.push(ReconcileIssue {
+        purchase_id: purchase.purchase_id.clone(),
+        kind: ReconcileIssueKind::MissingLoyaltyTransaction,
+        queued: enqueue(services, task, "Reconciliation").is_ok(),
+      });
+    }
+  }
+
+  Ok(issues)
+}
+
+/// Compare purchases closed in the last given days
+/// against commitment and loyalty records.
+/// Missing records are put into the outbox.
+pub async fn reconcile(
+  services: &mut Services,
+  days: i64,
+) -> Result<Vec<ReconcileIssue>, ApiError> {
+  let since = Utc::now() - Duration::days(days);
+
+  let purchase_ids = services
+    .purchase
+    .purchase_get_all(())
+    .await
+    .map_err(|e| ApiError::from(e))?
+    .into_inner()
+    .purchase_ids;
+
+  let mut all = services
+    .purchase
+    .purchase_get_info_bulk(PurchaseBulkRequest { purchase_ids })
+    .await
+    .map_err(|e| ApiError::from(e))?
+    .into_inner();
+
+  // Select recent purchases
+  let mut recent: Vec<String> = Vec::new();
+  while let Some(pinfo) = all.message().await.map_err(|e| ApiError::from(e))? {
+    let info: PurchaseInfoForm = pinfo.into();
+    if let Ok(created_at) = DateTime::parse_from_rfc3339(&info.created_at) {
+      if created_at.with_timezone(&Utc) >= since && !info.restored {
+        recent.push(info.purchase_id);
+      }
+    }
+  }
+
+  let mut issues = Vec::new();
+  for purchase_id in recent {
+    // Already queued, no need to check
+    if services
+      .outbox
+      .get_all()
+      .iter()
+      .any(|i| i.task.purchase_id() == purchase_id)
+    {
+      continue;
+    }
+    let purchase: PurchaseForm = match services
+      .purchase
+      .purchase_get_by_id(PurchaseByIdRequest {
+        purchase_id: purchase_id.clone(),
+      })
+      .await
+    {
+      Ok(p) => p.into_inner().into(),
+      Err(e) => {
+        issues.push(ReconcileIssue {
+          purchase_id,
+          kind: ReconcileIssueKind::CheckFailed {
+            error: e.message().to_string(),
+          },
+          queued: false,
+        });
+        continue;
+      }
+    };
+    match reconcile_purchase(services, &purchase).await {
+      Ok(mut i) => issues.append(&mut i),
+      Err(e) => issues.push(ReconcileIssue {
+        purchase_id,
+        kind: ReconcileIssueKind::CheckFailed {
+          error: e.message().to_string(),
+        },
+        queued: false,
+      }),
+    }
+  }
+
+  Ok(issues)
+}
+
+/// Reconciliation job
+/// Runs forever, checks recent purchases periodically
+pub async fn run_reconcile(mut services: Services) {
+  let interval = env::var(RECONCILE_INTERVAL_ENV_KEY)
+    .ok()
+    .and_then(|v| v.parse::<u64>().ok())
+    .unwrap_or(6 * 3600);
+  let days = env::var(RECONCILE_DAYS_ENV_KEY)
+    .ok()
+    .and_then(|v| v.parse::<i64>().ok())
+    .unwrap_or(2);
+  loop {
+    tokio::time::sleep(std::time::Duration::from_secs(interval)).await;
+    match reconcile(&mut services, days).await {
+      Ok(issues) => {
+        for issue in issues {
+          eprintln!("Reconciliation issue: {:?}", issue);
+        }
+      }
+      Err(e) => eprintln!("Reconciliation failed: {:?}", e),
+    }
+  }
+}
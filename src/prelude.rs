@@ -9,6 +9,7 @@ pub enum ApiError {
   BadRequest(String),
   InternalError(String),
   Unauthorized,
+  Forbidden(String),
 }
 
 impl ApiError {
@@ -24,6 +25,18 @@ impl ApiError {
   pub fn unauthorized() -> Self {
     ApiError::Unauthorized
   }
+  pub fn forbidden(msg: &str) -> Self {
+    ApiError::Forbidden(msg.into())
+  }
+  pub fn message(&self) -> String {
+    match self {
+      ApiError::NotFound => "Nem található".to_string(),
+      ApiError::BadRequest(msg) => msg.clone(),
+      ApiError::InternalError(msg) => msg.clone(),
+      ApiError::Unauthorized => "Nincs jogosultság".to_string(),
+      ApiError::Forbidden(msg) => msg.clone(),
+    }
+  }
 }
 
 impl From<LoginError> for ApiError {
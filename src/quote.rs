@@ -0,0 +1,166 @@
+use crate::receipt::escape_latex;
+use chrono::prelude::*;
+use serde::Serialize;
+use thousands::Separable;
+use tinytemplate::{format_unescaped, TinyTemplate};
+
+#[derive(Serialize)]
+pub struct Quote {
+  quote_id: String,            // Quote ID
+  has_customer: bool,          //
+  customer_name: String,       //
+  customer_address: String,    // Zip, location and street in one line
+  customer_tax_number: String, //
+  items: Vec<Item>,            // Quoted lines
+  total_net: u32,              // Total net price
+  total_vat: u32,              // Total VAT
+  total_gross: u32,            // Total gross price
+  valid_until: String,         // Last day the quote is valid
+  note: String,                // Free text note
+  date: String,                //
+}
+
+impl Quote {
+  pub fn new(
+    quote_id: String,
+    customer_name: Option<String>,
+    customer_address: String,
+    customer_tax_number: String,
+    items: Vec<Item>,
+    total_net: u32,
+    total_vat: u32,
+    total_gross: u32,
+    valid_until: NaiveDate,
+    note: String,
+    date: DateTime<Utc>,
+  ) -> Self {
+    let items = items
+      .into_iter()
+      .map(|mut i| {
+        i.name = escape_latex(&i.name);
+        i
+      })
+      .collect();
+    Self {
+      quote_id,
+      has_customer: customer_name.is_some(),
+      customer_name: escape_latex(&customer_name.unwrap_or_default()),
+      customer_address: escape_latex(&customer_address),
+      customer_tax_number: escape_latex(&customer_tax_number),
+      items,
+      total_net,
+      total_vat,
+      total_gross,
+      valid_until: valid_until.format("%Y-%m-%d").to_string(),
+      note: escape_latex(&note),
+      date: date.format("%Y-%m-%d").to_string(),
+    }
+  }
+
+  pub fn to_latex(&self) -> String {
+    let template: &'static str = r#"
+        \documentclass[a4paper]\{article}
+        \usepackage[margin=2cm]\{geometry}
+        \usepackage\{graphicx}
+        \usepackage\{tabularx}
+        \usepackage[utf8]\{inputenc}
+        \usepackage[T1]\{fontenc}
+
+        \pagestyle\{empty}
+
+        \begin\{document}
+
+          \begin\{minipage}[c]\{2cm}
+            \includegraphics[width=50px]\{logo.jpg}
+          \end\{minipage}
+          \begin\{minipage}[c]\{8cm}
+            \Huge\{\textbf\{GardenZilla}} \\
+            \normalsize\{\textmd\{Kert és Otthon}} \\
+            \footnotesize\{4522 Nyírtass, Ady út 11.}
+          \end\{minipage}
+          \hfill
+          \begin\{minipage}[c]\{5cm}
+            \raggedleft
+            \LARGE\{Árajánlat} \\
+            \small\{Azonosító: {quote_id}} \\
+            \small\{Kelt: {date}}
+          \end\{minipage}
+
+          \vspace\{1cm}
+
+          {{ if has_customer }}
+          \noindent
+          \textbf\{Ajánlatkérő:} \\
+          {customer_name} \\
+          {customer_address} \\
+          Adószám: {customer_tax_number}
+          \vspace\{0.7cm}
+          {{ endif }}
+
+          \small
+          \noindent
+          \begin\{tabularx}\{\textwidth}\{r X r r r}
+            \hline
+            Cikkszám & Megnevezés & Mennyiség & Egységár & Összesen \\
+            \hline
+            {{for item in items}}
+            {item.sku} & {item.name} & {item.piece} db & {item.unit_price_gross | number} HUF & {item.total_price_gross | number} HUF \\
+            {{endfor}}
+            \hline
+          \end\{tabularx}
+
+          \vspace\{0.7cm}
+
+          \hfill
+          \begin\{minipage}[c]\{7cm}
+            Nettó összesen:\hfill {total_net | number} HUF \\
+            ÁFA:\hfill {total_vat | number} HUF \\
+            \large\{Bruttó összesen:}\hfill \textbf\{\underline\{{total_gross | number} HUF}}
+          \end\{minipage}
+
+          \vspace\{1cm}
+
+          \normalsize
+          \noindent
+          Az ajánlat érvényes: \textbf\{{valid_until}}-ig. \\
+          Az árak bruttó árak, a készlet erejéig érvényesek.
+
+          \vspace\{0.5cm}
+
+          \noindent
+          {note}
+
+          \vfill
+
+          \begin\{center}
+            \scriptsize\{Köszönjük megkeresését!}
+          \end\{center}
+
+        \end\{document}
+    "#;
+
+    let mut tt = TinyTemplate::new();
+    // Disable HTML escape
+    tt.set_default_formatter(&format_unescaped);
+    tt.add_template("quote", template).unwrap();
+    tt.add_formatter("number", |i, o| match i.as_u64() {
+      Some(n) => {
+        o.push_str(&format!("{}", n.separate_with_spaces()));
+        Ok(())
+      }
+      None => Err(tinytemplate::error::Error::GenericError {
+        msg: "only number can be formatted".to_string(),
+      }),
+    });
+    tt.render("quote", &self).unwrap()
+  }
+}
+
+#[derive(Serialize)]
+pub struct Item {
+  pub sku: String,
+  pub name: String,
+  pub piece: u32,
+  pub unit_price_gross: u32,
+  pub total_price_gross: u32,
+}
@@ -1,9 +1,87 @@
 use chrono::prelude::*;
+use image::GenericImageView;
+use once_cell::sync::Lazy;
 use serde::Serialize;
+use std::{
+  collections::{hash_map::DefaultHasher, HashMap},
+  hash::{Hash, Hasher},
+  sync::{Arc, Mutex},
+};
 use thousands::Separable;
 use tinytemplate::{format_unescaped, TinyTemplate};
 
-#[derive(Serialize)]
+// ESC/POS commands
+const ESC: u8 = 0x1b;
+const GS: u8 = 0x1d;
+// PC852 (Latin-2) code page, it has every Hungarian letter
+const CODE_PAGE_PC852: u8 = 18;
+// Logo width on paper, in dots
+const LOGO_WIDTH: u32 = 160;
+
+// Logo raster commands by logo content hash
+// JPEG decoding and resizing is done only once per logo
+static LOGO_RASTERS: Lazy<Mutex<HashMap<u64, Arc<Vec<u8>>>>> =
+  Lazy::new(|| Mutex::new(HashMap::new()));
+
+/// Thermal printer paper width
+#[derive(Debug, Clone, Copy)]
+pub enum PaperWidth {
+  Mm58,
+  Mm80,
+}
+
+impl PaperWidth {
+  // Characters per line with font A
+  fn chars(&self) -> usize {
+    match self {
+      PaperWidth::Mm58 => 32,
+      PaperWidth::Mm80 => 48,
+    }
+  }
+}
+
+/// Store details printed on documents
+#[derive(Serialize, Clone)]
+pub struct Branding {
+  pub name: String,
+  pub subtitle: String,
+  pub address: String,
+  pub tax_number: String,
+  // Can have more lines
+  pub footer: String,
+  // JPEG image
+  #[serde(skip)]
+  pub logo: Vec<u8>,
+}
+
+impl Default for Branding {
+  fn default() -> Self {
+    Self {
+      name: "GardenZilla".to_string(),
+      subtitle: "Kert és Otthon".to_string(),
+      address: "4522 Nyírtass, Ady út 11.".to_string(),
+      tax_number: "".to_string(),
+      footer: "Köszönjük,\nhogy nálunk vásárolt!".to_string(),
+      logo: include_bytes!("../static/icon.jpg").to_vec(),
+    }
+  }
+}
+
+impl Branding {
+  // Branding with LaTeX special characters escaped
+  // Footer lines are kept as LaTeX line breaks
+  pub fn latex_escaped(&self) -> Self {
+    let mut res = self.clone();
+    res.name = escape_latex(&res.name);
+    res.subtitle = escape_latex(&res.subtitle);
+    res.address = escape_latex(&res.address);
+    res.tax_number = escape_latex(&res.tax_number);
+    res.footer = escape_latex(&res.footer).replace("\n", "\\\\");
+    res
+  }
+}
+
+#[derive(Serialize, Clone)]
 pub struct Receipt {
   purchase_id: String,         // Purchase ID
   items: Vec<Item>,            // Cart items
@@ -18,7 +96,16 @@ pub struct Receipt {
   loyalty_balance_before: i32, //
   loyalty_balance_after: i32,  //
   total_gross: i32,            // Total gross after applying discount and burned points
+  promotions: Vec<Promotion>,  // Applied promotions
+  has_rounding: bool,          //
+  rounding: String,            // Cash rounding with sign
+  total_payable: i32,          // Total gross after discounts and cash rounding
+  has_vouchers: bool,          //
+  vouchers: Vec<Voucher>,      // Sold gift vouchers
+  has_redeemed: bool,          //
+  redeemed: Vec<Voucher>,      // Gift vouchers used as payment
   date: String,                //
+  branding: Branding,          // Store name, address and logo
 }
 
 impl Receipt {
@@ -36,24 +123,23 @@ impl Receipt {
     loyalty_balance_before: i32,
     loyalty_balance_after: i32,
     total_gross: i32,
+    promotions: Vec<Promotion>,
+    payable: i32,
+    cash_rounding: i32,
+    vouchers: Vec<Voucher>,
+    redeemed: Vec<Voucher>,
     date: DateTime<Utc>,
+    branding: Branding,
   ) -> Self {
-    let items = items
-      .into_iter()
-      .map(|mut i| {
-        i.name = i.name.replace("\\", "\\\\");
-        i.name = i.name.replace("&", "\\&");
-        i.name = i.name.replace("%", "\\%");
-        i.name = i.name.replace("$", "\\$");
-        i.name = i.name.replace("#", "\\#");
-        i.name = i.name.replace("_", "\\_");
-        i.name = i.name.replace("{", "\\{");
-        i.name = i.name.replace("}", "\\}");
-        i.name = i.name.replace("~", "\\~");
-        i.name = i.name.replace("^", "\\^");
-        i
-      })
-      .collect();
+    // Promotion lines are split by VAT, show them once
+    let mut promotions_merged: Vec<Promotion> = Vec::new();
+    for p in promotions {
+      match promotions_merged.iter_mut().find(|m| m.name == p.name) {
+        Some(m) => m.discount += p.discount,
+        None => promotions_merged.push(p),
+      }
+    }
+    let promotions = promotions_merged;
     Self {
       purchase_id,
       items,
@@ -68,6 +154,15 @@ impl Receipt {
       loyalty_balance_before,
       loyalty_balance_after,
       total_gross,
+      promotions,
+      has_rounding: cash_rounding != 0,
+      rounding: format!("{:+}", cash_rounding),
+      // Payable already contains every discount, burned point and sold voucher
+      total_payable: payable + cash_rounding,
+      has_vouchers: vouchers.len() > 0,
+      vouchers,
+      has_redeemed: redeemed.len() > 0,
+      redeemed,
       date: format!(
         "{}-{:0>2}-{:0>2} {:0>2}:{:>2}:{:>2}",
         date.year(),
@@ -77,9 +172,29 @@ impl Receipt {
         date.minute(),
         date.second()
       ),
+      branding,
     }
   }
 
+  /// Logo to attach as logo.jpg
+  pub fn logo(&self) -> &[u8] {
+    &self.branding.logo
+  }
+
+  // Receipt with LaTeX special characters escaped
+  fn latex_escaped(&self) -> Self {
+    let mut res = self.clone();
+    for i in res.items.iter_mut() {
+      i.name = escape_latex(&i.name);
+      i.discount = escape_latex(&i.discount);
+    }
+    for p in res.promotions.iter_mut() {
+      p.name = escape_latex(&p.name);
+    }
+    res.branding = res.branding.latex_escaped();
+    res
+  }
+
   pub fn to_latex(&self) -> String {
     let template: &'static str = r#"
         \documentclass\{standalone}
@@ -94,9 +209,9 @@ impl Receipt {
             \centering
               \vspace\{0.1cm}
               \includegraphics[width=50px]\{logo.jpg} \\
-              \Huge\{\textbf\{GardenZilla}} \\
+              \Huge\{\textbf\{{branding.name}}} \\
               \vspace\{0.2cm}
-              \normalsize\{\textmd\{Kert és Otthon}}\\
+              \normalsize\{\textmd\{{branding.subtitle}}}\\
               \vspace\{1cm}
               \Large\{Nyugtamelléklet}\\
               \vspace\{0.1cm}
@@ -112,8 +227,23 @@ impl Receipt {
                 \vspace\{0.2cm}
                 \newline
                 \tabto\{1cm} {item.piece} db \tabto\{5cm} {item.gross_price_total | number} HUF
+                {{ if item.has_discount }}
+                \newline
+                \tabto\{1cm} \{\scriptsize Kedvezmény: {item.discount}}
+                {{ endif }}
                 \vspace\{0.5cm}
               {{endfor}}
+              {{ if has_vouchers }}
+              {{for voucher in vouchers}}
+                - \tabto\{1.5cm} Ajándékutalvány
+                \vspace\{0.2cm}
+                \newline
+                \tabto\{1cm} 1 db \tabto\{5cm} {voucher.amount | number} HUF
+                \newline
+                \tabto\{1cm} \{\scriptsize Kód: {voucher.code}, ÁFA hatályán kívül}
+                \vspace\{0.5cm}
+              {{endfor}}
+              {{ endif }}
             \end\{minipage}
         
             \vspace\{1.5cm}
@@ -122,8 +252,20 @@ impl Receipt {
               Összesen:\hfill {gross | number} HUF\\
               Kedvezmény* (egyedi):\hfill -{discount_value | number} HUF \\
               Kedvezmény (Pont):\hfill -{loyalty_burned_points | number} HUF\\
+              {{for promotion in promotions}}
+              Ebből akció ({promotion.name}):\hfill {promotion.discount | number} HUF\\
+              {{endfor}}
+              {{ if has_rounding }}
+              Kerekítés:\hfill {rounding} HUF\\
+              {{ endif }}
               
-              \large\{Fizetendő:}\hfill \textbf\{\underline\{{total_gross | number} HUF}}\\
+              \large\{Fizetendő:}\hfill \textbf\{\underline\{{total_payable | number} HUF}}\\
+              {{ if has_redeemed }}
+              \small
+              {{for voucher in redeemed}}
+              Utalvánnyal fizetve ({voucher.code}):\hfill {voucher.amount | number} HUF\\
+              {{endfor}}
+              {{ endif }}
             \end\{minipage}
             
             \vspace\{0.3cm}
@@ -158,12 +300,15 @@ impl Receipt {
             
             \vspace\{1cm}
             
-            Köszönjük,\\hogy nálunk vásárolt!
+            {branding.footer}
             
             \scriptsize
             \vspace\{1cm}
             Vásárlás azonosító: {purchase_id}\\
-            4522 Nyírtass, Ady út 11.\\
+            {branding.address}\\
+            {{ if branding.tax_number }}
+            Adószám: {branding.tax_number}\\
+            {{ endif }}
             {date}
             \vspace\{1cm}
             \end\{center}
@@ -185,14 +330,444 @@ impl Receipt {
         msg: "only number can be formatted".to_string(),
       }),
     });
-    tt.render("receipt", &self).unwrap()
+    tt.render("receipt", &self.latex_escaped()).unwrap()
+  }
+
+  /// ESC/POS byte stream for thermal receipt printers
+  pub fn to_escpos(&self, paper: PaperWidth) -> Vec<u8> {
+    let width = paper.chars();
+    let mut p = EscPos::new();
+
+    // Header
+    p.align_center();
+    p.logo(&self.branding.logo);
+    p.size(true, true);
+    p.bold(true);
+    p.line(&self.branding.name);
+    p.size(false, false);
+    p.bold(false);
+    p.line(&self.branding.subtitle);
+    p.feed(1);
+    p.size(false, true);
+    p.line("Nyugtamelléklet");
+    p.size(false, false);
+    p.line("(Nem adóügyi bizonylat!)");
+    p.feed(1);
+
+    // Items
+    p.align_left();
+    p.line(&"-".repeat(width));
+    for item in &self.items {
+      p.wrapped(&format!("{} {}", item.sku, item.name), width);
+      p.line(&two_columns(
+        &format!("  {} db", item.piece),
+        &format!("{} HUF", number(item.gross_price_total as i32)),
+        width,
+      ));
+      if item.has_discount {
+        p.wrapped(&format!("  Kedvezmény: {}", item.discount), width);
+      }
+    }
+    for voucher in &self.vouchers {
+      p.line("- Ajándékutalvány");
+      p.line(&two_columns(
+        "  1 db",
+        &format!("{} HUF", number(voucher.amount as i32)),
+        width,
+      ));
+      p.wrapped(
+        &format!("  Kód: {}, ÁFA hatályán kívül", voucher.code),
+        width,
+      );
+    }
+    p.line(&"-".repeat(width));
+
+    // Totals
+    p.line(&two_columns(
+      "Összesen:",
+      &format!("{} HUF", number(self.gross)),
+      width,
+    ));
+    p.line(&two_columns(
+      "Kedvezmény* (egyedi):",
+      &format!("-{} HUF", number(self.discount_value)),
+      width,
+    ));
+    p.line(&two_columns(
+      "Kedvezmény (Pont):",
+      &format!("-{} HUF", number(self.loyalty_burned_points)),
+      width,
+    ));
+    for promotion in &self.promotions {
+      p.wrapped_two_columns(
+        &format!("Ebből akció ({}):", promotion.name),
+        &format!("{} HUF", number(promotion.discount as i32)),
+        width,
+      );
+    }
+    if self.has_rounding {
+      p.line(&two_columns(
+        "Kerekítés:",
+        &format!("{} HUF", self.rounding),
+        width,
+      ));
+    }
+    p.bold(true);
+    p.size(false, true);
+    p.line(&two_columns(
+      "Fizetendő:",
+      &format!("{} HUF", number(self.total_payable)),
+      width,
+    ));
+    p.size(false, false);
+    p.bold(false);
+    for voucher in &self.redeemed {
+      p.wrapped_two_columns(
+        &format!("Utalvánnyal fizetve ({}):", voucher.code),
+        &format!("{} HUF", number(voucher.amount as i32)),
+        width,
+      );
+    }
+    p.feed(1);
+    p.wrapped(
+      &format!(
+        "* A vásárláshoz egyedi kedvezmény lett felhasználva, melynek mértéke {}%",
+        self.discount_percentage
+      ),
+      width,
+    );
+    p.feed(1);
+
+    // Loyalty
+    p.align_center();
+    p.line("Törzsvásárlói tájékoztató");
+    if self.has_loyalty_card {
+      p.line(&format!("Kártya azonosító: {}", self.loyalty_card_id));
+      p.align_left();
+      p.line(&".".repeat(width));
+      p.line(&two_columns(
+        "Nyitó egyenleg:",
+        &format!("{} pont", self.loyalty_balance_before),
+        width,
+      ));
+      p.line(&two_columns(
+        "Felhasznált pont:",
+        &format!("{} pont", self.loyalty_burned_points),
+        width,
+      ));
+      p.line(&two_columns(
+        "Kapott pont:",
+        &format!("{} pont", self.loyalty_earned_points),
+        width,
+      ));
+      p.line(&two_columns(
+        "Záró egyenleg:",
+        &format!("{} pont", self.loyalty_balance_after),
+        width,
+      ));
+      p.line(&".".repeat(width));
+      p.align_center();
+    }
+    p.feed(1);
+
+    // Footer
+    for l in self.branding.footer.lines() {
+      p.wrapped(l, width);
+    }
+    p.feed(1);
+    p.qr_code(&self.purchase_id);
+    p.line(&format!("Vásárlás azonosító: {}", self.purchase_id));
+    p.wrapped(&self.branding.address, width);
+    if self.branding.tax_number.len() > 0 {
+      p.line(&format!("Adószám: {}", self.branding.tax_number));
+    }
+    p.line(&self.date);
+    p.cut();
+    p.into_bytes()
   }
 }
 
-#[derive(Serialize)]
+// Number with space separated thousands
+fn number(n: i32) -> String {
+  n.separate_with_spaces()
+}
+
+// Left and right aligned text in one line
+fn two_columns(left: &str, right: &str, width: usize) -> String {
+  let used = left.chars().count() + right.chars().count();
+  match used < width {
+    true => format!("{}{}{}", left, " ".repeat(width - used), right),
+    false => format!("{} {}", left, right),
+  }
+}
+
+// Split text into lines of the given width, by words
+fn wrap(text: &str, width: usize) -> Vec<String> {
+  let mut res: Vec<String> = Vec::new();
+  let mut line = String::new();
+  for word in text.split_whitespace() {
+    let len = line.chars().count();
+    if len > 0 && len + 1 + word.chars().count() > width {
+      res.push(line);
+      line = String::new();
+    }
+    if line.len() > 0 {
+      line.push(' ');
+    }
+    line.push_str(word);
+  }
+  if line.len() > 0 {
+    res.push(line);
+  }
+  res
+}
+
+// Hungarian letters in PC852, other non ASCII characters are replaced
+fn to_pc852(c: char) -> u8 {
+  match c {
+    'á' => 0xa0,
+    'é' => 0x82,
+    'í' => 0xa1,
+    'ó' => 0xa2,
+    'ö' => 0x94,
+    'ő' => 0x8b,
+    'ú' => 0xa3,
+    'ü' => 0x81,
+    'ű' => 0xfb,
+    'Á' => 0xb5,
+    'É' => 0x90,
+    'Í' => 0xd6,
+    'Ó' => 0xe0,
+    'Ö' => 0x99,
+    'Ő' => 0x8a,
+    'Ú' => 0xe9,
+    'Ü' => 0x9a,
+    'Ű' => 0xeb,
+    c if c.is_ascii() => c as u8,
+    _ => b'?',
+  }
+}
+
+// Logo as raster bit image command (GS v 0)
+// None if the image cannot be decoded
+fn logo_raster(jpg: &[u8]) -> Option<Vec<u8>> {
+  let img = image::load_from_memory(jpg).ok()?;
+  let height = (img.height() * LOGO_WIDTH / img.width().max(1)).max(1);
+  let img = img.resize_exact(LOGO_WIDTH, height, image::imageops::FilterType::Triangle);
+  let gray = image::imageops::grayscale(&img);
+  let width_bytes = (LOGO_WIDTH + 7) / 8;
+  let mut bytes = vec![
+    GS,
+    b'v',
+    b'0',
+    0,
+    (width_bytes & 0xff) as u8,
+    (width_bytes >> 8) as u8,
+    (height & 0xff) as u8,
+    (height >> 8) as u8,
+  ];
+  for y in 0..height {
+    for xb in 0..width_bytes {
+      let mut byte = 0u8;
+      for bit in 0..8 {
+        let x = xb * 8 + bit;
+        // Dark pixels are printed
+        if x < LOGO_WIDTH && gray.get_pixel(x, y)[0] < 128 {
+          byte |= 0x80 >> bit;
+        }
+      }
+      bytes.push(byte);
+    }
+  }
+  bytes.push(b'\n');
+  Some(bytes)
+}
+
+// ESC/POS byte stream builder
+struct EscPos {
+  bytes: Vec<u8>,
+}
+
+impl EscPos {
+  fn new() -> Self {
+    let mut bytes = vec![ESC, b'@'];
+    bytes.extend_from_slice(&[ESC, b't', CODE_PAGE_PC852]);
+    Self { bytes }
+  }
+
+  fn text(&mut self, text: &str) {
+    self.bytes.extend(text.chars().map(to_pc852));
+  }
+
+  fn line(&mut self, text: &str) {
+    self.text(text);
+    self.bytes.push(b'\n');
+  }
+
+  fn wrapped(&mut self, text: &str, width: usize) {
+    for l in wrap(text, width) {
+      self.line(&l);
+    }
+  }
+
+  // Long left column is wrapped, the right one goes to its last line
+  fn wrapped_two_columns(&mut self, left: &str, right: &str, width: usize) {
+    let right_len = right.chars().count() + 1;
+    let mut lines = wrap(left, width.saturating_sub(right_len).max(1));
+    let last = lines.pop().unwrap_or_default();
+    for l in lines {
+      self.line(&l);
+    }
+    self.line(&two_columns(&last, right, width));
+  }
+
+  fn feed(&mut self, lines: u8) {
+    self.bytes.extend_from_slice(&[ESC, b'd', lines]);
+  }
+
+  fn align_left(&mut self) {
+    self.bytes.extend_from_slice(&[ESC, b'a', 0]);
+  }
+
+  fn align_center(&mut self) {
+    self.bytes.extend_from_slice(&[ESC, b'a', 1]);
+  }
+
+  fn bold(&mut self, on: bool) {
+    self.bytes.extend_from_slice(&[ESC, b'E', on as u8]);
+  }
+
+  fn size(&mut self, double_width: bool, double_height: bool) {
+    let n = ((double_width as u8) << 4) | double_height as u8;
+    self.bytes.extend_from_slice(&[GS, b'!', n]);
+  }
+
+  // Print image as raster bit image (GS v 0)
+  // Skipped if the image cannot be decoded
+  fn logo(&mut self, jpg: &[u8]) {
+    let mut hasher = DefaultHasher::new();
+    jpg.hash(&mut hasher);
+    let key = hasher.finish();
+    let cached = LOGO_RASTERS.lock().unwrap().get(&key).cloned();
+    let raster = match cached {
+      Some(r) => r,
+      None => {
+        let r = Arc::new(logo_raster(jpg).unwrap_or_default());
+        LOGO_RASTERS.lock().unwrap().insert(key, r.clone());
+        r
+      }
+    };
+    self.bytes.extend_from_slice(&raster);
+  }
+
+  // Printer rendered QR code (GS ( k), model 2
+  fn qr_code(&mut self, data: &str) {
+    let data = data.as_bytes();
+    // Module size
+    self
+      .bytes
+      .extend_from_slice(&[GS, b'(', b'k', 3, 0, 49, 67, 6]);
+    // Error correction level M
+    self
+      .bytes
+      .extend_from_slice(&[GS, b'(', b'k', 3, 0, 49, 69, 49]);
+    // Store data
+    let len = data.len() + 3;
+    self.bytes.extend_from_slice(&[
+      GS,
+      b'(',
+      b'k',
+      (len & 0xff) as u8,
+      (len >> 8) as u8,
+      49,
+      80,
+      48,
+    ]);
+    self.bytes.extend_from_slice(data);
+    // Print
+    self
+      .bytes
+      .extend_from_slice(&[GS, b'(', b'k', 3, 0, 49, 81, 48]);
+    self.bytes.push(b'\n');
+  }
+
+  // Feed paper and partial cut
+  fn cut(&mut self) {
+    self.bytes.extend_from_slice(&[GS, b'V', 66, 3]);
+  }
+
+  fn into_bytes(self) -> Vec<u8> {
+    self.bytes
+  }
+}
+
+#[derive(Serialize, Clone)]
 pub struct Item {
   pub sku: String,
   pub name: String,
   pub piece: u32,
   pub gross_price_total: u32,
+  pub has_discount: bool,
+  pub discount: String,
+}
+
+#[derive(Serialize, Clone)]
+pub struct Promotion {
+  pub name: String,
+  pub discount: u32,
+}
+
+#[derive(Serialize, Clone)]
+pub struct Voucher {
+  pub code: String,
+  pub amount: u32,
+}
+
+// Escape LaTeX special characters
+pub fn escape_latex(s: &str) -> String {
+  s.replace("\\", "\\\\")
+    .replace("&", "\\&")
+    .replace("%", "\\%")
+    .replace("$", "\\$")
+    .replace("#", "\\#")
+    .replace("_", "\\_")
+    .replace("{", "\\{")
+    .replace("}", "\\}")
+    .replace("~", "\\~")
+    .replace("^", "\\^")
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_to_pc852() {
+    let bytes: Vec<u8> = "Árvíztűrő tükörfúrógép".chars().map(to_pc852).collect();
+    assert_eq!(
+      bytes,
+      vec![
+        0xb5, b'r', b'v', 0xa1, b'z', b't', 0xfb, b'r', 0x8b, b' ', b't', 0x81, b'k', 0x94, b'r',
+        b'f', 0xa3, b'r', 0xa2, b'g', 0x82, b'p'
+      ]
+    );
+    assert_eq!(to_pc852('€'), b'?');
+  }
+
+  #[test]
+  fn test_wrap() {
+    assert_eq!(
+      wrap("Virágföld univerzális 50 literes", 16),
+      vec!["Virágföld", "univerzális 50", "literes"]
+    );
+    assert_eq!(wrap("  ", 10), Vec::<String>::new());
+    // Too long word gets its own line
+    assert_eq!(wrap("a kertészkedéshez", 5), vec!["a", "kertészkedéshez"]);
+  }
+
+  #[test]
+  fn test_two_columns() {
+    assert_eq!(two_columns("Összesen:", "1 250", 16), "Összesen:  1 250");
+    assert_eq!(two_columns("Összesen:", "1 250", 10), "Összesen: 1 250");
+  }
 }
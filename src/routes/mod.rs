@@ -5,17 +5,23 @@ mod route_customer;
 mod route_invoice;
 mod route_login;
 mod route_loyalty;
+mod route_outbox;
 mod route_pricing;
 mod route_procurement;
 mod route_product;
 mod route_profile;
+mod route_promotion;
 mod route_purchase;
+mod route_quote;
+mod route_receivable;
+mod route_regulated;
 mod route_sku;
 mod route_sku_image;
 mod route_source;
 mod route_stock;
 mod route_upl;
 mod route_user;
+mod route_voucher;
 
 use crate::login;
 use crate::prelude::*;
@@ -66,7 +72,13 @@ pub async fn get_all(services: Services) -> warp::filters::BoxedFilter<(impl Rep
     route_commitment::routes(services.clone()),
     route_loyalty::routes(services.clone()),
     route_sku_image::routes(services.clone()),
-    route_purchase::routes(services.clone())
+    route_purchase::routes(services.clone()),
+    route_outbox::routes(services.clone()),
+    route_quote::routes(services.clone()),
+    route_voucher::routes(services.clone()),
+    route_promotion::routes(services.clone()),
+    route_regulated::routes(services.clone()),
+    route_receivable::routes(services.clone())
   ));
   // let routes = warp::any().and(balanced_or_tree!(welcome
   //   .or(route_login::routes(services.clone()))
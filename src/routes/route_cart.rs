@@ -19,6 +19,44 @@ pub fn routes(services: Services) -> warp::filters::BoxedFilter<(impl Reply,)> {
     .and(add(services.clone()))
     .and_then(handler::cart::cart_get_all);
 
+  let get_expiring = warp::path!("expiring")
+    .and(warp::get())
+    .and(auth())
+    .and(add(services.clone()))
+    .and_then(handler::cart::cart_get_expiring);
+
+  let get_expired = warp::path!("expired")
+    .and(warp::get())
+    .and(auth())
+    .and(add(services.clone()))
+    .and_then(handler::cart::cart_get_expired);
+
+  let extend = warp::path!("extend")
+    .and(warp::put())
+    .and(auth())
+    .and(add(services.clone()))
+    .and(warp::body::json())
+    .and_then(handler::cart::cart_extend);
+
+  let validate = warp::path!(String / "validate")
+    .and(warp::get())
+    .and(auth())
+    .and(add(services.clone()))
+    .and_then(handler::cart::cart_validate);
+
+  let display_token = warp::path!(String / "display_token")
+    .and(warp::get())
+    .and(auth())
+    .and(add(services.clone()))
+    .and_then(handler::cart::cart_display_token);
+
+  // Display devices have no login, they use the cart display token
+  let display_feed = warp::path!(String / "display")
+    .and(warp::get())
+    .and(warp::query::<handler::cart::DisplayTokenQuery>())
+    .and(add(services.clone()))
+    .and_then(handler::cart::cart_display_feed);
+
   let get_by_id = warp::path::param()
     .and(warp::get())
     .and(auth())
@@ -53,6 +91,13 @@ pub fn routes(services: Services) -> warp::filters::BoxedFilter<(impl Reply,)> {
     .and(warp::body::json())
     .and_then(handler::cart::cart_add_sku);
 
+  let add_items = warp::path!("add_items")
+    .and(warp::put())
+    .and(auth())
+    .and(add(services.clone()))
+    .and(warp::body::json())
+    .and_then(handler::cart::cart_add_items);
+
   let set_sku_piece = warp::path!("set_sku_piece")
     .and(warp::put())
     .and(auth())
@@ -74,6 +119,34 @@ pub fn routes(services: Services) -> warp::filters::BoxedFilter<(impl Reply,)> {
     .and(warp::body::json())
     .and_then(handler::cart::cart_add_upl);
 
+  let add_weighed = warp::path!("add_weighed")
+    .and(warp::put())
+    .and(auth())
+    .and(add(services.clone()))
+    .and(warp::body::json())
+    .and_then(handler::cart::cart_add_weighed);
+
+  let scan = warp::path!("scan")
+    .and(warp::put())
+    .and(auth())
+    .and(add(services.clone()))
+    .and(warp::body::json())
+    .and_then(handler::cart::cart_scan);
+
+  let set_discount = warp::path!("set_discount")
+    .and(warp::put())
+    .and(auth())
+    .and(add(services.clone()))
+    .and(warp::body::json())
+    .and_then(handler::cart::cart_set_discount);
+
+  let remove_discount = warp::path!("remove_discount")
+    .and(warp::put())
+    .and(auth())
+    .and(add(services.clone()))
+    .and(warp::body::json())
+    .and_then(handler::cart::cart_remove_discount);
+
   let remove_upl = warp::path!("remove_upl")
     .and(warp::put())
     .and(auth())
@@ -95,6 +168,13 @@ pub fn routes(services: Services) -> warp::filters::BoxedFilter<(impl Reply,)> {
     .and(warp::body::json())
     .and_then(handler::cart::cart_add_payment);
 
+  let void_payment = warp::path!("void_payment")
+    .and(warp::put())
+    .and(auth())
+    .and(add(services.clone()))
+    .and(warp::body::json())
+    .and_then(handler::cart::cart_void_payment);
+
   let set_document = warp::path!("set_document")
     .and(warp::put())
     .and(auth())
@@ -137,27 +217,46 @@ pub fn routes(services: Services) -> warp::filters::BoxedFilter<(impl Reply,)> {
     .and(warp::body::json())
     .and_then(handler::cart::cart_close);
 
+  let close_report = warp::path!("close_report" / String)
+    .and(warp::get())
+    .and(auth())
+    .and(add(services.clone()))
+    .and_then(handler::cart::cart_get_close_report);
+
   warp::path!("cart" / ..)
     .and(combine!(
       get_all,
+      get_expiring,
+      get_expired,
       new,
+      validate,
+      display_token,
+      display_feed,
       get_by_id,
       get_bulk,
       add_customer,
       remove_customer,
       remove_sku,
       add_sku,
+      add_items,
       set_sku_piece,
       add_upl,
+      add_weighed,
+      scan,
+      set_discount,
+      remove_discount,
       remove_upl,
       set_payment,
       add_payment,
+      void_payment,
       set_document,
       add_loyalty_card,
       remove_loyalty_card,
       remove_commitment,
       burn_points,
-      close
+      extend,
+      close,
+      close_report
     ))
     .boxed()
 }
@@ -0,0 +1,38 @@
+use crate::{
+  handler,
+  routes::{add, auth},
+  services::Services,
+};
+use warp::{Filter, Reply};
+
+pub fn routes(services: Services) -> warp::filters::BoxedFilter<(impl Reply,)> {
+  let get_all = warp::path!("all")
+    .and(warp::get())
+    .and(auth())
+    .and(add(services.clone()))
+    .and_then(handler::outbox::get_all);
+
+  let get_stuck = warp::path!("stuck")
+    .and(warp::get())
+    .and(auth())
+    .and(add(services.clone()))
+    .and_then(handler::outbox::get_stuck);
+
+  let retry = warp::path!("retry")
+    .and(warp::put())
+    .and(auth())
+    .and(add(services.clone()))
+    .and(warp::body::json())
+    .and_then(handler::outbox::retry);
+
+  let reconcile = warp::path!("reconcile")
+    .and(warp::post())
+    .and(auth())
+    .and(add(services.clone()))
+    .and(warp::body::json())
+    .and_then(handler::outbox::reconcile);
+
+  warp::path!("outbox" / ..)
+    .and(combine!(get_all, get_stuck, retry, reconcile))
+    .boxed()
+}
@@ -40,13 +40,28 @@ pub fn routes(services: Services) -> warp::filters::BoxedFilter<(impl Reply,)> {
     .and(warp::body::json())
     .and_then(handler::pricing::get_latest_price_changes);
 
+  let get_tiers = warp::path!("tiers" / u32)
+    .and(warp::get())
+    .and(auth())
+    .and(add(services.clone()))
+    .and_then(handler::pricing::get_tiers);
+
+  let set_tiers = warp::path!("set_tiers")
+    .and(warp::put())
+    .and(auth())
+    .and(add(services.clone()))
+    .and(warp::body::json())
+    .and_then(handler::pricing::set_tiers);
+
   warp::path!("price" / ..)
     .and(combine!(
       set_price,
       get_by_id,
       get_bulk,
       get_price_history,
-      get_price_changes
+      get_price_changes,
+      get_tiers,
+      set_tiers
     ))
     .boxed()
 }
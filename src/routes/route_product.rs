@@ -60,6 +60,13 @@ pub fn routes(services: Services) -> warp::filters::BoxedFilter<(impl Reply,)> {
     .and(warp::body::json())
     .and_then(handler::product::product_set_perishable);
 
+  let product_set_regulated = warp::path!("set_regulated")
+    .and(warp::put())
+    .and(auth())
+    .and(add(services.clone()))
+    .and(warp::body::json())
+    .and_then(handler::product::product_set_regulated);
+
   warp::path!("product" / ..)
     .and(combine!(
       product_get_all,
@@ -69,7 +76,8 @@ pub fn routes(services: Services) -> warp::filters::BoxedFilter<(impl Reply,)> {
       product_update,
       product_find,
       product_set_discontinued,
-      product_set_perishable
+      product_set_perishable,
+      product_set_regulated
     ))
     .boxed()
 }
@@ -26,7 +26,19 @@ pub fn routes(services: Services) -> warp::filters::BoxedFilter<(impl Reply,)> {
     .and(warp::body::json())
     .and_then(handler::user::update_profile);
 
+  let profile_set_pin = warp::path!("set_pin")
+    .and(warp::put())
+    .and(auth())
+    .and(add(services.clone()))
+    .and(warp::body::json())
+    .and_then(handler::user::set_pin);
+
   warp::path!("profile" / ..)
-    .and(combine!(profile_new_password, profile_get, profile_update))
+    .and(combine!(
+      profile_new_password,
+      profile_get,
+      profile_update,
+      profile_set_pin
+    ))
     .boxed()
 }
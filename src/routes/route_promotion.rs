@@ -0,0 +1,44 @@
+use crate::{
+  handler,
+  routes::{add, auth},
+  services::Services,
+};
+use warp::{Filter, Reply};
+
+pub fn routes(services: Services) -> warp::filters::BoxedFilter<(impl Reply,)> {
+  let new = warp::path!("new")
+    .and(warp::post())
+    .and(auth())
+    .and(add(services.clone()))
+    .and(warp::body::json())
+    .and_then(handler::promotion::new_promotion);
+
+  let set_enabled = warp::path!("set_enabled")
+    .and(warp::put())
+    .and(auth())
+    .and(add(services.clone()))
+    .and(warp::body::json())
+    .and_then(handler::promotion::set_enabled);
+
+  let get_all = warp::path!("all")
+    .and(warp::get())
+    .and(auth())
+    .and(add(services.clone()))
+    .and_then(handler::promotion::get_all);
+
+  let get_active = warp::path!("active" / u32)
+    .and(warp::get())
+    .and(auth())
+    .and(add(services.clone()))
+    .and_then(handler::promotion::get_active);
+
+  let get_by_id = warp::path::param()
+    .and(warp::get())
+    .and(auth())
+    .and(add(services.clone()))
+    .and_then(handler::promotion::get_by_id);
+
+  warp::path!("promotion" / ..)
+    .and(combine!(new, set_enabled, get_all, get_active, get_by_id))
+    .boxed()
+}
@@ -13,6 +13,45 @@ pub fn routes(services: Services) -> warp::filters::BoxedFilter<(impl Reply,)> {
     .and(add(services.clone()))
     .and_then(handler::purchase::purchase_get_by_id);
 
+  let reorder = warp::path!(String / "reorder")
+    .and(warp::post())
+    .and(auth())
+    .and(add(services.clone()))
+    .and(warp::body::json())
+    .and_then(handler::purchase::purchase_reorder);
+
+  let new_return = warp::path!(String / "return")
+    .and(warp::post())
+    .and(auth())
+    .and(add(services.clone()))
+    .and(warp::body::json())
+    .and_then(handler::purchase_return::new_return);
+
+  let get_returns = warp::path!(String / "returns")
+    .and(warp::get())
+    .and(auth())
+    .and(add(services.clone()))
+    .and_then(handler::purchase_return::get_returns);
+
+  let get_store_returns = warp::path!("returns" / "store" / u32)
+    .and(warp::get())
+    .and(auth())
+    .and(add(services.clone()))
+    .and_then(handler::purchase_return::get_store_returns);
+
+  let send = warp::path!(String / "send")
+    .and(warp::post())
+    .and(auth())
+    .and(add(services.clone()))
+    .and(warp::body::json())
+    .and_then(handler::purchase_email::send);
+
+  let download_document = warp::path!("document" / String)
+    .and(warp::get())
+    .and(warp::query::<handler::purchase_email::DocumentLinkQuery>())
+    .and(add(services.clone()))
+    .and_then(handler::purchase_email::download);
+
   let get_info_by_id = warp::path!("info")
     .and(warp::post())
     .and(auth())
@@ -34,6 +73,13 @@ pub fn routes(services: Services) -> warp::filters::BoxedFilter<(impl Reply,)> {
     .and(add(services.clone()))
     .and_then(handler::purchase::purchase_get_all);
 
+  let query = warp::path!("query")
+    .and(warp::post())
+    .and(auth())
+    .and(add(services.clone()))
+    .and(warp::body::json())
+    .and_then(handler::purchase::purchase_query);
+
   let get_bulk = warp::path!("bulk")
     .and(warp::post())
     .and(warp::path::end())
@@ -47,7 +93,14 @@ pub fn routes(services: Services) -> warp::filters::BoxedFilter<(impl Reply,)> {
       get_info_by_id,
       get_by_id,
       get_receipt_by_id,
+      reorder,
+      new_return,
+      get_returns,
+      get_store_returns,
+      send,
+      download_document,
       get_all,
+      query,
       get_bulk
     ))
     .boxed()
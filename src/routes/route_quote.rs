@@ -0,0 +1,45 @@
+use crate::{
+  handler,
+  routes::{add, auth},
+  services::Services,
+};
+use warp::{Filter, Reply};
+
+pub fn routes(services: Services) -> warp::filters::BoxedFilter<(impl Reply,)> {
+  let new = warp::path!("new")
+    .and(warp::post())
+    .and(auth())
+    .and(add(services.clone()))
+    .and(warp::body::json())
+    .and_then(handler::quote::new_quote);
+
+  let get_all = warp::path!("all")
+    .and(warp::get())
+    .and(auth())
+    .and(add(services.clone()))
+    .and_then(handler::quote::get_all);
+
+  let get_by_id = warp::path::param()
+    .and(warp::get())
+    .and(auth())
+    .and(add(services.clone()))
+    .and_then(handler::quote::get_by_id);
+
+  let get_pdf = warp::path!("pdf")
+    .and(warp::post())
+    .and(auth())
+    .and(add(services.clone()))
+    .and(warp::body::json())
+    .and_then(handler::quote::get_pdf);
+
+  let to_cart = warp::path!("to_cart")
+    .and(warp::put())
+    .and(auth())
+    .and(add(services.clone()))
+    .and(warp::body::json())
+    .and_then(handler::quote::to_cart);
+
+  warp::path!("quote" / ..)
+    .and(combine!(get_all, new, get_by_id, get_pdf, to_cart))
+    .boxed()
+}
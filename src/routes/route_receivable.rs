@@ -0,0 +1,44 @@
+use crate::{
+  handler,
+  routes::{add, auth},
+  services::Services,
+};
+use warp::{Filter, Reply};
+
+pub fn routes(services: Services) -> warp::filters::BoxedFilter<(impl Reply,)> {
+  let add_payment = warp::path!("add_payment")
+    .and(warp::put())
+    .and(auth())
+    .and(add(services.clone()))
+    .and(warp::body::json())
+    .and_then(handler::receivable::add_payment);
+
+  let get_payments = warp::path!("payments" / String)
+    .and(warp::get())
+    .and(auth())
+    .and(add(services.clone()))
+    .and_then(handler::receivable::get_payments);
+
+  let get_aging = warp::path!("aging")
+    .and(warp::post())
+    .and(auth())
+    .and(add(services.clone()))
+    .and(warp::body::json())
+    .and_then(handler::receivable::get_aging);
+
+  let send_reminders = warp::path!("send_reminders")
+    .and(warp::post())
+    .and(auth())
+    .and(add(services.clone()))
+    .and(warp::body::json())
+    .and_then(handler::receivable::send_reminders);
+
+  warp::path!("receivable" / ..)
+    .and(combine!(
+      add_payment,
+      get_payments,
+      get_aging,
+      send_reminders
+    ))
+    .boxed()
+}
@@ -0,0 +1,44 @@
+use crate::{
+  handler,
+  routes::{add, auth},
+  services::Services,
+};
+use warp::{Filter, Reply};
+
+pub fn routes(services: Services) -> warp::filters::BoxedFilter<(impl Reply,)> {
+  let set_permit = warp::path!("set_permit")
+    .and(warp::put())
+    .and(auth())
+    .and(add(services.clone()))
+    .and(warp::body::json())
+    .and_then(handler::regulated::set_permit);
+
+  let get_permit = warp::path!("permit" / String)
+    .and(warp::get())
+    .and(auth())
+    .and(add(services.clone()))
+    .and_then(handler::regulated::get_permit);
+
+  let get_register = warp::path!("register")
+    .and(warp::post())
+    .and(auth())
+    .and(add(services.clone()))
+    .and(warp::body::json())
+    .and_then(handler::regulated::get_register);
+
+  let get_register_csv = warp::path!("register" / "csv")
+    .and(warp::post())
+    .and(auth())
+    .and(add(services.clone()))
+    .and(warp::body::json())
+    .and_then(handler::regulated::get_register_csv);
+
+  warp::path!("regulated" / ..)
+    .and(combine!(
+      set_permit,
+      get_permit,
+      get_register,
+      get_register_csv
+    ))
+    .boxed()
+}
@@ -60,6 +60,27 @@ pub fn routes(services: Services) -> warp::filters::BoxedFilter<(impl Reply,)> {
     .and(warp::body::json())
     .and_then(handler::product::sku_set_discontinued);
 
+  let sku_set_barcode = warp::path!("set_barcode")
+    .and(warp::put())
+    .and(auth())
+    .and(add(services.clone()))
+    .and(warp::body::json())
+    .and_then(handler::product::sku_set_barcode);
+
+  let sku_remove_barcode = warp::path!("remove_barcode")
+    .and(warp::put())
+    .and(auth())
+    .and(add(services.clone()))
+    .and(warp::body::json())
+    .and_then(handler::product::sku_remove_barcode);
+
+  let sku_get_barcodes = warp::path!("barcodes" / ..)
+    .and(warp::path::param())
+    .and(warp::get())
+    .and(auth())
+    .and(add(services.clone()))
+    .and_then(handler::product::sku_get_barcodes);
+
   warp::path!("sku" / ..)
     .and(combine!(
       sku_get_all,
@@ -69,7 +90,10 @@ pub fn routes(services: Services) -> warp::filters::BoxedFilter<(impl Reply,)> {
       sku_update,
       sku_find,
       sku_set_divide,
-      sku_set_discontinued
+      sku_set_discontinued,
+      sku_set_barcode,
+      sku_remove_barcode,
+      sku_get_barcodes
     ))
     .boxed()
 }
@@ -32,7 +32,42 @@ pub fn routes(services: Services) -> warp::filters::BoxedFilter<(impl Reply,)> {
     .and(warp::body::json())
     .and_then(handler::stock::update);
 
+  let get_sale_policy = warp::path!("sale_policy" / u32)
+    .and(warp::get())
+    .and(auth())
+    .and(add(services.clone()))
+    .and_then(handler::stock::get_sale_policy);
+
+  let set_sale_policy = warp::path!("set_sale_policy")
+    .and(warp::put())
+    .and(auth())
+    .and(add(services.clone()))
+    .and(warp::body::json())
+    .and_then(handler::stock::set_sale_policy);
+
+  let get_document_settings = warp::path!("documents" / u32)
+    .and(warp::get())
+    .and(auth())
+    .and(add(services.clone()))
+    .and_then(handler::stock::get_document_settings);
+
+  let set_document_settings = warp::path!("set_documents")
+    .and(warp::put())
+    .and(auth())
+    .and(add(services.clone()))
+    .and(warp::body::json())
+    .and_then(handler::stock::set_document_settings);
+
   warp::path!("stock" / ..)
-    .and(combine!(get_all, create_new, get_by_id, update))
+    .and(combine!(
+      get_all,
+      get_sale_policy,
+      get_document_settings,
+      create_new,
+      get_by_id,
+      set_sale_policy,
+      set_document_settings,
+      update
+    ))
     .boxed()
 }
@@ -25,7 +25,27 @@ pub fn routes(services: Services) -> warp::filters::BoxedFilter<(impl Reply,)> {
     .and(warp::body::json())
     .and_then(handler::user::create_new);
 
+  let user_get_role = warp::path!("role" / ..)
+    .and(warp::path::param())
+    .and(warp::get())
+    .and(auth())
+    .and(add(services.clone()))
+    .and_then(handler::user::get_user_role);
+
+  let user_set_role = warp::path!("set_role")
+    .and(warp::put())
+    .and(auth())
+    .and(add(services.clone()))
+    .and(warp::body::json())
+    .and_then(handler::user::set_role);
+
   warp::path!("user" / ..)
-    .and(combine!(user_get_all, user_get_by_id, user_new))
+    .and(combine!(
+      user_get_all,
+      user_get_by_id,
+      user_new,
+      user_get_role,
+      user_set_role
+    ))
     .boxed()
 }
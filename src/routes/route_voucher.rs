@@ -0,0 +1,44 @@
+use crate::{
+  handler,
+  routes::{add, auth},
+  services::Services,
+};
+use warp::{Filter, Reply};
+
+pub fn routes(services: Services) -> warp::filters::BoxedFilter<(impl Reply,)> {
+  let issue = warp::path!("issue")
+    .and(warp::put())
+    .and(auth())
+    .and(add(services.clone()))
+    .and(warp::body::json())
+    .and_then(handler::voucher::issue);
+
+  let cancel = warp::path!("cancel")
+    .and(warp::put())
+    .and(auth())
+    .and(add(services.clone()))
+    .and(warp::body::json())
+    .and_then(handler::voucher::cancel);
+
+  let get_all = warp::path!("all")
+    .and(warp::get())
+    .and(auth())
+    .and(add(services.clone()))
+    .and_then(handler::voucher::get_all);
+
+  let get_by_cart = warp::path!("cart" / String)
+    .and(warp::get())
+    .and(auth())
+    .and(add(services.clone()))
+    .and_then(handler::voucher::get_by_cart);
+
+  let get_balance = warp::path::param()
+    .and(warp::get())
+    .and(auth())
+    .and(add(services.clone()))
+    .and_then(handler::voucher::get_balance);
+
+  warp::path!("voucher" / ..)
+    .and(combine!(issue, cancel, get_all, get_by_cart, get_balance))
+    .boxed()
+}
@@ -1,5 +1,27 @@
 use std::env;
 
+use crate::{
+  cart_expiry::CartActivity,
+  display::DisplayHub,
+  handler::{
+    cart::{CartCloseReport, CartDiscountsForm, CartForm, PaymentVoidForm},
+    pricing::SkuPriceTiersForm,
+    product::{RegulatedProductForm, SkuBarcodeForm},
+    promotion::{CartPromotionsForm, PromotionForm},
+    purchase::PurchaseIndexForm,
+    purchase_email::PurchaseEmailsForm,
+    purchase_return::PurchaseReturnForm,
+    quote::QuoteForm,
+    receivable::{PurchasePaymentsForm, ReminderForm},
+    regulated::{PermitForm, RegulatedSaleForm},
+    stock::{StockSalePolicyForm, StoreDocumentSettingsForm},
+    user::UserRoleForm,
+    voucher::VoucherForm,
+  },
+  outbox::OutboxItem,
+  storage::{LogStorage, Storage},
+};
+
 use gzlib::proto::{
   cash::cash_client::CashClient,
   commitment::commitment_client::CommitmentClient,
@@ -51,6 +73,30 @@ pub struct Services {
   pub loyalty: LoyaltyClient<Channel>,
   pub sku_image: SkuImageClient<Channel>,
   pub sku_img_processer: SkuImageProcesserClient<Channel>,
+  pub outbox: Storage<OutboxItem>,
+  pub close_reports: Storage<CartCloseReport>,
+  pub sku_barcodes: Storage<SkuBarcodeForm>,
+  pub user_roles: Storage<UserRoleForm>,
+  pub cart_discounts: Storage<CartDiscountsForm>,
+  pub cart_activity: Storage<CartActivity>,
+  pub expired_carts: Storage<CartForm>,
+  pub quotes: Storage<QuoteForm>,
+  pub payment_voids: Storage<PaymentVoidForm>,
+  pub display: DisplayHub,
+  pub stock_policies: Storage<StockSalePolicyForm>,
+  pub vouchers: Storage<VoucherForm>,
+  pub promotions: Storage<PromotionForm>,
+  pub cart_promotions: Storage<CartPromotionsForm>,
+  pub price_tiers: Storage<SkuPriceTiersForm>,
+  pub regulated_products: Storage<RegulatedProductForm>,
+  pub cart_permits: Storage<PermitForm>,
+  pub regulated_sales: Storage<RegulatedSaleForm>,
+  pub purchase_returns: Storage<PurchaseReturnForm>,
+  pub purchase_index: LogStorage<PurchaseIndexForm>,
+  pub purchase_payments: Storage<PurchasePaymentsForm>,
+  pub receivable_reminders: Storage<ReminderForm>,
+  pub purchase_emails: Storage<PurchaseEmailsForm>,
+  pub store_documents: Storage<StoreDocumentSettingsForm>,
 }
 
 impl Services {
@@ -111,6 +157,30 @@ impl Services {
       ))
       .await
       .expect("Could not connect to sku image processer service"),
+      outbox: Storage::load("outbox"),
+      close_reports: Storage::load("close_reports"),
+      sku_barcodes: Storage::load("sku_barcodes"),
+      user_roles: Storage::load("user_roles"),
+      cart_discounts: Storage::load("cart_discounts"),
+      cart_activity: Storage::load("cart_activity"),
+      expired_carts: Storage::load("expired_carts"),
+      quotes: Storage::load("quotes"),
+      payment_voids: Storage::load("payment_voids"),
+      display: DisplayHub::new(),
+      stock_policies: Storage::load("stock_policies"),
+      vouchers: Storage::load("vouchers"),
+      promotions: Storage::load("promotions"),
+      cart_promotions: Storage::load("cart_promotions"),
+      price_tiers: Storage::load("price_tiers"),
+      regulated_products: Storage::load("regulated_products"),
+      cart_permits: Storage::load("cart_permits"),
+      regulated_sales: Storage::load("regulated_sales"),
+      purchase_returns: Storage::load("purchase_returns"),
+      purchase_index: LogStorage::load("purchase_search"),
+      purchase_payments: Storage::load("purchase_payments"),
+      receivable_reminders: Storage::load("receivable_reminders"),
+      purchase_emails: Storage::load("purchase_emails"),
+      store_documents: Storage::load("store_documents"),
     }
   }
 }
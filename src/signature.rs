@@ -0,0 +1,66 @@
+use chrono::{DateTime, Utc};
+use crypto::{hmac::Hmac, mac::Mac, sha2::Sha256, util::fixed_time_eq};
+use rustc_serialize::hex::ToHex;
+
+const SECRET_ENV_KEY: &'static str = "API_SECRET";
+
+// HMAC signature of the payload with the API secret
+fn sign(payload: &str) -> String {
+  let secret = std::env::var(SECRET_ENV_KEY).expect("NO API SECRET ENV");
+  let mut hmac = Hmac::new(Sha256::new(), secret.as_bytes());
+  hmac.input(payload.as_bytes());
+  hmac.result().code().to_hex()
+}
+
+/// Signed token for the given payload, valid until the given time
+/// Format: <expiry unix timestamp>.<signature>
+pub fn create_token(payload: &str, valid_until: DateTime<Utc>) -> String {
+  let expires = valid_until.timestamp();
+  format!("{}.{}", expires, sign(&format!("{}:{}", payload, expires)))
+}
+
+/// Check token of the given payload
+/// Signature is compared in constant time
+pub fn verify_token(payload: &str, token: &str) -> bool {
+  let mut parts = token.splitn(2, '.');
+  let expires = match parts.next().and_then(|e| e.parse::<i64>().ok()) {
+    Some(e) => e,
+    None => return false,
+  };
+  let signature = match parts.next() {
+    Some(s) => s,
+    None => return false,
+  };
+  if expires < Utc::now().timestamp() {
+    return false;
+  }
+  fixed_time_eq(
+    sign(&format!("{}:{}", payload, expires)).as_bytes(),
+    signature.as_bytes(),
+  )
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use chrono::Duration;
+
+  #[test]
+  fn test_token() {
+    std::env::set_var(SECRET_ENV_KEY, "test_secret");
+    let token = create_token("display:cart1", Utc::now() + Duration::hours(1));
+    assert!(verify_token("display:cart1", &token));
+    // Other payload
+    assert!(!verify_token("display:cart2", &token));
+    // Tampered expiry
+    let (_, signature) = token.split_at(token.find('.').unwrap());
+    let forged = format!("{}{}", Utc::now().timestamp() + 999_999, signature);
+    assert!(!verify_token("display:cart1", &forged));
+    // Expired
+    let expired = create_token("display:cart1", Utc::now() - Duration::seconds(1));
+    assert!(!verify_token("display:cart1", &expired));
+    // Garbage
+    assert!(!verify_token("display:cart1", ""));
+    assert!(!verify_token("display:cart1", "123"));
+  }
+}
@@ -0,0 +1,284 @@
+use crate::prelude::*;
+use chrono::Utc;
+use serde::{de::DeserializeOwned, Serialize};
+use std::{
+  collections::BTreeMap,
+  env, fs,
+  io::{BufRead, BufReader, Write},
+  path::{Path, PathBuf},
+  sync::{mpsc, Arc, Mutex},
+  thread,
+};
+
+const DATA_DIR_ENV_KEY: &'static str = "API_DATA_DIR";
+
+// Storage file path by name in the API data directory
+fn data_path(name: &str, extension: &str) -> PathBuf {
+  let dir = env::var(DATA_DIR_ENV_KEY).unwrap_or("data".to_string());
+  fs::create_dir_all(&dir).expect("Could not create API data directory");
+  Path::new(&dir).join(format!("{}.{}", name, extension))
+}
+
+/// Small JSON file backed key-value store
+/// for records the API process owns itself.
+/// Every change is written through to disk.
+#[derive(Debug, Clone)]
+pub struct Storage<T> {
+  path: PathBuf,
+  items: Arc<Mutex<BTreeMap<String, T>>>,
+}
+
+impl<T> Storage<T>
+where
+  T: Serialize + DeserializeOwned + Clone,
+{
+  /// Load storage by name from the API data directory
+  /// or create an empty one
+  pub fn load(name: &str) -> Self {
+    let path = data_path(name, "json");
+    let items = match fs::read(&path) {
+      Ok(bytes) => match serde_json::from_slice(&bytes) {
+        Ok(items) => items,
+        Err(e) => {
+          // Keep the corrupt file aside and start from an empty store
+          let corrupt = path.with_extension(format!("json.corrupt.{}", Utc::now().timestamp()));
+          eprintln!(
+            "Could not parse storage file {}: {}; moved to {}",
+            path.display(),
+            e,
+            corrupt.display()
+          );
+          let _ = fs::rename(&path, &corrupt);
+          BTreeMap::new()
+        }
+      },
+      Err(_) => BTreeMap::new(),
+    };
+    Self {
+      path,
+      items: Arc::new(Mutex::new(items)),
+    }
+  }
+
+  pub fn get(&self, id: &str) -> Option<T> {
+    self.items.lock().unwrap().get(id).cloned()
+  }
+
+  pub fn get_all(&self) -> Vec<T> {
+    self.items.lock().unwrap().values().cloned().collect()
+  }
+
+  pub fn filter<F>(&self, f: F) -> Vec<T>
+  where
+    F: Fn(&T) -> bool,
+  {
+    self
+      .items
+      .lock()
+      .unwrap()
+      .values()
+      .filter(|i| f(i))
+      .cloned()
+      .collect()
+  }
+
+  pub fn contains(&self, id: &str) -> bool {
+    self.items.lock().unwrap().contains_key(id)
+  }
+
+  /// Insert or replace item
+  pub fn insert(&self, id: &str, item: T) -> Result<T, ApiError> {
+    let mut items = self.items.lock().unwrap();
+    items.insert(id.to_string(), item.clone());
+    self.save(&items)?;
+    Ok(item)
+  }
+
+  /// Insert item only if its ID is not taken yet
+  /// Returns false if there is already an item with the ID
+  pub fn insert_absent(&self, id: &str, item: T) -> Result<bool, ApiError> {
+    let mut items = self.items.lock().unwrap();
+    if items.contains_key(id) {
+      return Ok(false);
+    }
+    items.insert(id.to_string(), item);
+    self.save(&items)?;
+    Ok(true)
+  }
+
+  /// Insert or replace many items with a single write
+  pub fn insert_many(&self, new_items: Vec<(String, T)>) -> Result<(), ApiError> {
+    let mut items = self.items.lock().unwrap();
+    items.extend(new_items);
+    self.save(&items)
+  }
+
+  /// Insert item with an ID generated from the current items
+  /// ID is generated under the storage lock, so concurrent inserts
+  /// cannot get the same ID; nothing is inserted if the closure fails
+  pub fn insert_new<F>(&self, f: F) -> Result<T, ApiError>
+  where
+    F: FnOnce(&BTreeMap<String, T>) -> Result<(String, T), ApiError>,
+  {
+    let mut items = self.items.lock().unwrap();
+    let (id, item) = f(&items)?;
+    if items.contains_key(&id) {
+      return Err(ApiError::internal_error("Tároló azonosító ütközés"));
+    }
+    items.insert(id, item.clone());
+    self.save(&items)?;
+    Ok(item)
+  }
+
+  /// Update item by ID
+  /// NotFound if there is no item with the given ID
+  pub fn update<F>(&self, id: &str, f: F) -> Result<T, ApiError>
+  where
+    F: FnOnce(&mut T) -> Result<(), ApiError>,
+  {
+    let mut items = self.items.lock().unwrap();
+    let item = items.get_mut(id).ok_or(ApiError::not_found())?;
+    f(item)?;
+    let res = item.clone();
+    self.save(&items)?;
+    Ok(res)
+  }
+
+  pub fn remove(&self, id: &str) -> Result<Option<T>, ApiError> {
+    let mut items = self.items.lock().unwrap();
+    let res = items.remove(id);
+    self.save(&items)?;
+    Ok(res)
+  }
+
+  // Write items into a temp file, then move it in place
+  fn save(&self, items: &BTreeMap<String, T>) -> Result<(), ApiError> {
+    let bytes = serde_json::to_vec(items)
+      .map_err(|_| ApiError::internal_error("Tároló szerializációs hiba"))?;
+    let tmp = self.path.with_extension("json.tmp");
+    fs::write(&tmp, bytes).map_err(|_| ApiError::internal_error("Tároló írási hiba"))?;
+    fs::rename(&tmp, &self.path).map_err(|_| ApiError::internal_error("Tároló írási hiba"))?;
+    Ok(())
+  }
+}
+
+/// Append-only JSON lines store for frequently written records
+/// that can be rebuilt, like the purchase index.
+/// Changes are appended to the log by a writer thread in batches,
+/// so callers never wait for the disk; the log is compacted on load.
+#[derive(Debug, Clone)]
+pub struct LogStorage<T> {
+  items: Arc<Mutex<BTreeMap<String, T>>>,
+  writer: mpsc::Sender<String>,
+}
+
+impl<T> LogStorage<T>
+where
+  T: Serialize + DeserializeOwned + Clone,
+{
+  /// Load log storage by name from the API data directory
+  /// or create an empty one
+  pub fn load(name: &str) -> Self {
+    let path = data_path(name, "jsonl");
+    // Later lines replace earlier ones; an unreadable line,
+    // like one cut off by a crash, is skipped
+    let mut items = BTreeMap::new();
+    if let Ok(file) = fs::File::open(&path) {
+      for line in BufReader::new(file).lines() {
+        match line.map(|l| serde_json::from_str::<(String, T)>(&l)) {
+          Ok(Ok((id, item))) => {
+            items.insert(id, item);
+          }
+          _ => eprintln!(
+            "Skipping unreadable line in storage file {}",
+            path.display()
+          ),
+        }
+      }
+    }
+
+    // Compact the log into one line per item
+    let tmp = path.with_extension("jsonl.tmp");
+    let mut compacted = String::new();
+    for entry in items.iter() {
+      compacted.push_str(&serde_json::to_string(&entry).expect("Could not serialize storage"));
+      compacted.push('\n');
+    }
+    fs::write(&tmp, compacted).expect("Could not write storage file");
+    fs::rename(&tmp, &path).expect("Could not write storage file");
+
+    let mut file = fs::OpenOptions::new()
+      .append(true)
+      .open(&path)
+      .expect("Could not open storage file");
+    let (writer, lines) = mpsc::channel::<String>();
+    thread::spawn(move || {
+      // Wait for a change, then write everything queued meanwhile at once
+      while let Ok(line) = lines.recv() {
+        let mut batch = line;
+        while let Ok(line) = lines.try_recv() {
+          batch.push_str(&line);
+        }
+        if let Err(e) = file.write_all(batch.as_bytes()) {
+          eprintln!("Could not write storage file {}: {}", path.display(), e);
+        }
+      }
+    });
+
+    Self {
+      items: Arc::new(Mutex::new(items)),
+      writer,
+    }
+  }
+
+  pub fn get(&self, id: &str) -> Option<T> {
+    self.items.lock().unwrap().get(id).cloned()
+  }
+
+  pub fn get_all(&self) -> Vec<T> {
+    self.items.lock().unwrap().values().cloned().collect()
+  }
+
+  pub fn filter<F>(&self, f: F) -> Vec<T>
+  where
+    F: Fn(&T) -> bool,
+  {
+    self
+      .items
+      .lock()
+      .unwrap()
+      .values()
+      .filter(|i| f(i))
+      .cloned()
+      .collect()
+  }
+
+  pub fn contains(&self, id: &str) -> bool {
+    self.items.lock().unwrap().contains_key(id)
+  }
+
+  /// Insert or replace item
+  pub fn insert(&self, id: &str, item: T) -> Result<T, ApiError> {
+    self.insert_many(vec![(id.to_string(), item.clone())])?;
+    Ok(item)
+  }
+
+  /// Insert or replace many items with a single append
+  pub fn insert_many(&self, new_items: Vec<(String, T)>) -> Result<(), ApiError> {
+    let mut lines = String::new();
+    for entry in new_items.iter() {
+      let line = serde_json::to_string(entry)
+        .map_err(|_| ApiError::internal_error("Tároló szerializációs hiba"))?;
+      lines.push_str(&line);
+      lines.push('\n');
+    }
+    // Lines are queued under the lock, so the log keeps the order of changes
+    let mut items = self.items.lock().unwrap();
+    self
+      .writer
+      .send(lines)
+      .map_err(|_| ApiError::internal_error("Tároló írási hiba"))?;
+    items.extend(new_items);
+    Ok(())
+  }
+}